@@ -0,0 +1,758 @@
+//! Binds the configured listeners and serves them until shutdown.
+
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use cng::{
+    proxy::{
+        access_log::{AccessLog, AccessLogConfig, JsonLines},
+        activation::{ActivatedListener, ActivatedSocket},
+        auth::{AuthLayer, PrefixAuthenticator, TokenAuthenticator},
+        balancer::Balancer,
+        connector::{BackendConnector, PreambleConnector},
+        deadline::DeadlineLayer,
+        grpc_web::CorsConfig,
+        health::spawn_health_check,
+        message::MessageLimitLayer,
+        metrics::{serve_admin, Metrics},
+        passthrough::{serve_passthrough, PassthroughOptions, PassthroughRouter, PassthroughStats},
+        proxy_protocol::{self, ForwardClientAddr},
+        proxy_service::ProxyService,
+        rate_limit::RateLimitLayer,
+        retry::RetryLayer,
+        router::{HeaderRouter, SniRouter},
+        serve_with_options,
+        trace::{OtlpExporter, TraceLayer},
+        BoxError, CallLimit, ProxyBody, ServeOptions, ServerStats,
+    },
+    tls::{ReloadingCertResolver, TlsConfigError, TlsIdentityConfig},
+};
+use http::{header::AUTHORIZATION, HeaderValue, Request, Response};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    task::{JoinHandle, JoinSet},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tower::{util::BoxCloneService, Layer};
+
+use crate::config::{
+    AuthSettings, BackendConfig, Config, LimitSettings, ListenAddr, ListenerConfig,
+};
+
+type BridgeService = BoxCloneService<Request<ProxyBody>, Response<ProxyBody>, BoxError>;
+
+/// Name of the admin listener in messages, and the `FileDescriptorName=`
+/// of its socket when passed by systemd.
+const ADMIN: &str = "admin";
+
+/// Startup failure of one listener.
+#[derive(Debug)]
+pub enum StartError {
+    Tls {
+        listener: String,
+        source: TlsConfigError,
+    },
+    /// A backend CA bundle or server name is unusable.
+    UpstreamTls {
+        listener: String,
+        source: TlsConfigError,
+    },
+    Auth {
+        listener: String,
+        source: std::io::Error,
+    },
+    AccessLog {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Bind {
+        listener: String,
+        addr: ListenAddr,
+        source: std::io::Error,
+    },
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartError::Tls { listener, source } => {
+                write!(f, "{listener}: cannot load tls identity: {source}")
+            }
+            StartError::UpstreamTls { listener, source } => {
+                write!(f, "{listener}: cannot load backend tls settings: {source}")
+            }
+            StartError::Auth { listener, source } => {
+                write!(f, "{listener}: cannot load auth tokens: {source}")
+            }
+            StartError::AccessLog { path, source } => {
+                write!(f, "cannot open access log {}: {source}", path.display())
+            }
+            StartError::Bind {
+                listener,
+                addr,
+                source,
+            } => write!(f, "{listener}: cannot bind {addr}: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for StartError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StartError::Tls { source, .. } | StartError::UpstreamTls { source, .. } => Some(source),
+            StartError::Auth { source, .. }
+            | StartError::AccessLog { source, .. }
+            | StartError::Bind { source, .. } => Some(source),
+        }
+    }
+}
+
+struct BoundListener {
+    name: String,
+    addr: ListenAddr,
+    socket: BoundSocket,
+    /// Passed by systemd rather than bound here, so its unix socket file
+    /// belongs to systemd.
+    inherited: bool,
+    mode: ListenerMode,
+}
+
+enum ListenerMode {
+    /// Ends TLS, or serves h2c, and forwards calls.
+    Proxy {
+        /// `None` for plaintext listeners.
+        tls: Option<ListenerTls>,
+        /// Serves gRPC-Web besides gRPC, over HTTP/1.1 too.
+        grpc_web: Option<CorsConfig>,
+        proxy_protocol: bool,
+        limits: LimitSettings,
+        service: BridgeService,
+        stats: Arc<ServerStats>,
+    },
+    /// Splices TLS connections to the backend of their SNI name.
+    Passthrough {
+        router: PassthroughRouter<BackendConnector>,
+        options: PassthroughOptions,
+    },
+}
+
+enum BoundSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+struct ListenerTls {
+    acceptor: TlsAcceptor,
+    /// One per certificate, several with `sni_routes`.
+    resolvers: Vec<Arc<ReloadingCertResolver>>,
+    reload_interval: Option<Duration>,
+}
+
+/// All listeners of a validated [`Config`], bound and ready to serve.
+pub struct Bridge {
+    listeners: Vec<BoundListener>,
+    admin: Option<TcpListener>,
+    metrics: Metrics,
+    drain_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    /// `max_in_flight_calls`, shared by all listeners.
+    call_limit: Option<CallLimit>,
+    /// Stops the health checkers started by [`Self::bind`].
+    health_checks: CancellationToken,
+    /// Span export task and its stop token, when `tracing` is configured.
+    span_export: Option<(JoinHandle<()>, CancellationToken)>,
+}
+
+impl Bridge {
+    /// Loads TLS settings and binds every listener, so a bad cert or a
+    /// taken port fails startup before anything is served.
+    ///
+    /// Listeners take a socket from `activated` (see
+    /// [`cng::proxy::activation::listen_fds`]) instead of binding when its
+    /// name equals the listener name or it is bound to the listener
+    /// address. Sockets matching no listener are closed.
+    pub async fn bind(
+        config: &Config,
+        mut activated: Vec<ActivatedSocket>,
+    ) -> Result<Self, StartError> {
+        let health_checks = CancellationToken::new();
+        // stop checkers already started if a later listener fails
+        let guard = health_checks.clone().drop_guard();
+        let metrics = Metrics::new();
+        let span_export_token = CancellationToken::new();
+        let span_export_guard = span_export_token.clone().drop_guard();
+        let (trace, span_export) = match &config.tracing {
+            Some(tracing) => {
+                let otlp = tracing
+                    .otlp_config()
+                    .expect("config is validated before binding");
+                let (exporter, task) = OtlpExporter::spawn(otlp, span_export_token.clone())
+                    .expect("config is validated before binding");
+                (
+                    Some(TraceLayer::new(exporter)),
+                    Some((task, span_export_token)),
+                )
+            }
+            None => (None, None),
+        };
+        let access_log = match &config.access_log {
+            Some(settings) => Some(open_access_log(
+                settings
+                    .config()
+                    .expect("config is validated before binding"),
+                settings.path.as_deref(),
+            )?),
+            None => None,
+        };
+        let layers = ServiceLayers {
+            health_checks: &health_checks,
+            metrics: &metrics,
+            trace: trace.as_ref(),
+            access_log: access_log.as_ref(),
+        };
+        let mut listeners = Vec::with_capacity(config.listeners.len());
+        for (i, listener_config) in config.listeners.iter().enumerate() {
+            listeners.push(BoundListener::bind(i, listener_config, &mut activated, &layers).await?);
+        }
+        let admin = match &config.admin {
+            Some(admin) => Some(
+                bind_admin(
+                    admin.addr().expect("config is validated before binding"),
+                    &mut activated,
+                )
+                .await?,
+            ),
+            None => None,
+        };
+        guard.disarm();
+        span_export_guard.disarm();
+        for socket in activated {
+            eprintln!(
+                "warning: inherited socket {} matches no listener",
+                socket.name.as_deref().unwrap_or("(unnamed)")
+            );
+        }
+        Ok(Self {
+            listeners,
+            admin,
+            metrics,
+            drain_timeout: config.drain_timeout(),
+            idle_timeout: config.idle_timeout(),
+            call_limit: config.max_in_flight_calls.map(CallLimit::new),
+            health_checks,
+            span_export,
+        })
+    }
+
+    /// Serves all listeners until `token` is cancelled, then drains them.
+    /// Returns the first listener error, after which the remaining
+    /// listeners are stopped.
+    pub async fn run(self, token: CancellationToken) -> Result<(), BoxError> {
+        let token = token.child_token();
+        let mut tasks = JoinSet::new();
+        if let Some(listener) = self.admin {
+            let token = token.clone();
+            tasks.spawn(async move {
+                if let Ok(addr) = listener.local_addr() {
+                    println!("{ADMIN}: serving metrics on http://{addr}/metrics");
+                }
+                let res =
+                    serve_admin(listener, self.metrics, token.clone().cancelled_owned()).await;
+                token.cancel();
+                res.map_err(|e| format!("{ADMIN}: {e}").into())
+            });
+        }
+        let options = ServeOptions {
+            drain_timeout: self.drain_timeout,
+            idle_timeout: self.idle_timeout,
+            call_limit: self.call_limit,
+            ..ServeOptions::default()
+        };
+        for bound in self.listeners {
+            let token = token.clone();
+            let options = options.clone();
+            tasks.spawn(async move {
+                let name = bound.name.clone();
+                let res = bound.serve(options, token.clone()).await;
+                // stop the other listeners if this one failed on its own
+                token.cancel();
+                res.map_err(|e| format!("{name}: {e}").into())
+            });
+        }
+        let mut result = Ok(());
+        while let Some(res) = tasks.join_next().await {
+            let res = res.map_err(BoxError::from).and_then(|r| r);
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        self.health_checks.cancel();
+        // send the spans of the last calls
+        if let Some((task, token)) = self.span_export {
+            token.cancel();
+            let _ = task.await;
+        }
+        result
+    }
+}
+
+impl BoundListener {
+    async fn bind(
+        index: usize,
+        config: &ListenerConfig,
+        activated: &mut Vec<ActivatedSocket>,
+        layers: &ServiceLayers<'_>,
+    ) -> Result<Self, StartError> {
+        let name = config.context(index);
+        let addr = config
+            .listen_addr(index)
+            .expect("config is validated before binding");
+        let mode = match &config.passthrough {
+            Some(passthrough) => {
+                let stats = Arc::new(PassthroughStats::default());
+                layers
+                    .metrics
+                    .register_passthrough(route_label(&name, config), stats.clone());
+                ListenerMode::Passthrough {
+                    router: passthrough_router(&name, config)?,
+                    options: PassthroughOptions {
+                        max_connections: config.limits.as_ref().and_then(|l| l.max_connections),
+                        stats,
+                        ..passthrough.options()
+                    },
+                }
+            }
+            None => {
+                let tls = load_tls(&name, config)?;
+                let service = build_service(&name, config, layers)?;
+                let stats = Arc::new(ServerStats::default());
+                layers
+                    .metrics
+                    .register_server(route_label(&name, config), stats.clone());
+                ListenerMode::Proxy {
+                    tls,
+                    grpc_web: config.grpc_web.as_ref().map(|grpc_web| {
+                        grpc_web
+                            .cors(&name)
+                            .expect("config is validated before binding")
+                    }),
+                    proxy_protocol: config.proxy_protocol,
+                    limits: config.limits.clone().unwrap_or_default(),
+                    service,
+                    stats,
+                }
+            }
+        };
+
+        let bind_error = |source| StartError::Bind {
+            listener: name.clone(),
+            addr: addr.clone(),
+            source,
+        };
+        let activated = take_activated(activated, config.name.as_deref(), &addr);
+        let inherited = activated.is_some();
+        let socket = match (activated, &addr) {
+            (Some(ActivatedListener::Tcp(listener)), _) => BoundSocket::Tcp(listener),
+            (Some(ActivatedListener::Unix(listener)), _) => BoundSocket::Unix(listener),
+            (None, ListenAddr::Tcp(addr)) => {
+                BoundSocket::Tcp(TcpListener::bind(addr).await.map_err(bind_error)?)
+            }
+            (None, ListenAddr::Unix(path)) => {
+                remove_stale_socket(path).map_err(bind_error)?;
+                BoundSocket::Unix(UnixListener::bind(path).map_err(bind_error)?)
+            }
+        };
+        Ok(Self {
+            name,
+            addr,
+            socket,
+            inherited,
+            mode,
+        })
+    }
+
+    /// Serves until `token` is cancelled, with the server-wide settings of
+    /// `options`.
+    async fn serve(self, options: ServeOptions, token: CancellationToken) -> Result<(), BoxError> {
+        let origin = if self.inherited { ", from systemd" } else { "" };
+        let signal = token.clone().cancelled_owned();
+        let res = match self.mode {
+            ListenerMode::Proxy {
+                tls,
+                grpc_web,
+                proxy_protocol,
+                limits,
+                service,
+                stats,
+            } => {
+                let mut watchers = Vec::new();
+                if let Some(tls) = &tls {
+                    if let Some(interval) = tls.reload_interval {
+                        for resolver in &tls.resolvers {
+                            watchers.push(resolver.clone().watch(interval, token.child_token()));
+                        }
+                    }
+                }
+                let mode = if tls.is_some() { "tls" } else { "h2c" };
+                println!("{}: serving {} ({mode}{origin})", self.name, self.addr);
+                let options = ServeOptions {
+                    grpc_web,
+                    proxy_protocol,
+                    max_connections: limits.max_connections,
+                    max_handshakes: limits.max_handshakes,
+                    max_concurrent_streams: limits.max_concurrent_streams,
+                    stats,
+                    ..options
+                };
+                let acceptor = tls.map(|tls| tls.acceptor);
+                let res = match self.socket {
+                    BoundSocket::Tcp(listener) => {
+                        serve_with_options(listener, acceptor, service, options, signal).await
+                    }
+                    BoundSocket::Unix(listener) => {
+                        serve_with_options(listener, acceptor, service, options, signal).await
+                    }
+                };
+                if !watchers.is_empty() {
+                    token.cancel();
+                    for watcher in watchers {
+                        watcher.await?;
+                    }
+                }
+                res
+            }
+            ListenerMode::Passthrough {
+                router,
+                options: passthrough,
+            } => {
+                println!("{}: serving {} (passthrough{origin})", self.name, self.addr);
+                let options = PassthroughOptions {
+                    drain_timeout: options.drain_timeout,
+                    ..passthrough
+                };
+                match self.socket {
+                    BoundSocket::Tcp(listener) => {
+                        serve_passthrough(listener, router, options, signal).await
+                    }
+                    BoundSocket::Unix(listener) => {
+                        serve_passthrough(listener, router, options, signal).await
+                    }
+                }
+            }
+        };
+        if let (false, ListenAddr::Unix(path)) = (self.inherited, &self.addr) {
+            let _ = std::fs::remove_file(path);
+        }
+        res
+    }
+}
+
+/// Certificates of a TLS listener, `None` for plaintext listeners.
+fn load_tls(name: &str, config: &ListenerConfig) -> Result<Option<ListenerTls>, StartError> {
+    let Some((identity, client_auth)) = config.tls_identity() else {
+        return Ok(None);
+    };
+    let tls_error = |source| StartError::Tls {
+        listener: name.to_string(),
+        source,
+    };
+    let (mut server_config, resolvers) = if config.sni_routes.is_empty() {
+        let (server_config, resolver) = identity
+            .load_reloadable_server_config(client_auth.as_ref())
+            .map_err(tls_error)?;
+        (server_config, vec![resolver])
+    } else {
+        let names = config.sni_identities();
+        let default = (!config.reject_unknown_sni).then_some(&identity);
+        let (server_config, resolver) = TlsIdentityConfig::load_sni_server_config(
+            names.iter().map(|(name, identity)| (*name, identity)),
+            default,
+            client_auth.as_ref(),
+        )
+        .map_err(tls_error)?;
+        (server_config, resolver.resolvers().cloned().collect())
+    };
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    if config.grpc_web.is_some() {
+        server_config.alpn_protocols.push(b"http/1.1".to_vec());
+    }
+    let reload_interval = config
+        .tls
+        .as_ref()
+        .map(|tls| tls.reload_interval_secs)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    Ok(Some(ListenerTls {
+        acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        resolvers,
+        reload_interval,
+    }))
+}
+
+/// Binds the admin listener, or takes it from systemd like a listener
+/// named [`ADMIN`].
+async fn bind_admin(
+    addr: SocketAddr,
+    activated: &mut Vec<ActivatedSocket>,
+) -> Result<TcpListener, StartError> {
+    let listen_addr = ListenAddr::Tcp(addr);
+    if let Some(ActivatedListener::Tcp(listener)) =
+        take_activated(activated, Some(ADMIN), &listen_addr)
+    {
+        return Ok(listener);
+    }
+    TcpListener::bind(addr)
+        .await
+        .map_err(|source| StartError::Bind {
+            listener: ADMIN.to_string(),
+            addr: listen_addr,
+            source,
+        })
+}
+
+/// Removes the inherited socket named like the listener, or else the one
+/// bound to `addr`, from `activated`.
+fn take_activated(
+    activated: &mut Vec<ActivatedSocket>,
+    name: Option<&str>,
+    addr: &ListenAddr,
+) -> Option<ActivatedListener> {
+    let position = activated
+        .iter()
+        .position(|socket| name.is_some() && socket.name.as_deref() == name)
+        .or_else(|| {
+            activated
+                .iter()
+                .position(|socket| is_bound_to(&socket.listener, addr))
+        })?;
+    Some(activated.remove(position).listener)
+}
+
+fn is_bound_to(listener: &ActivatedListener, addr: &ListenAddr) -> bool {
+    match (listener, addr) {
+        (ActivatedListener::Tcp(listener), ListenAddr::Tcp(addr)) => {
+            listener.local_addr().is_ok_and(|local| local == *addr)
+        }
+        (ActivatedListener::Unix(listener), ListenAddr::Unix(path)) => listener
+            .local_addr()
+            .is_ok_and(|local| local.as_pathname() == Some(path.as_path())),
+        _ => false,
+    }
+}
+
+/// Removes a socket file left behind by a previous run, so binding does
+/// not fail with `AddrInUse`. Anything else at `path` is left alone.
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Access log writing to the file at `path`, or stdout.
+fn open_access_log(config: AccessLogConfig, path: Option<&Path>) -> Result<AccessLog, StartError> {
+    let Some(path) = path else {
+        return Ok(AccessLog::new(config, JsonLines::stdout()));
+    };
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|source| StartError::AccessLog {
+            path: path.to_path_buf(),
+            source,
+        })?;
+    Ok(AccessLog::new(config, JsonLines::new(file)))
+}
+
+/// What the services of all listeners share.
+struct ServiceLayers<'a> {
+    health_checks: &'a CancellationToken,
+    metrics: &'a Metrics,
+    trace: Option<&'a TraceLayer>,
+    access_log: Option<&'a AccessLog>,
+}
+
+/// Service of a listener, its layers outermost first:
+///
+/// 1. metrics, see [`route_label`]
+/// 2. trace, when configured
+/// 3. access log, when configured
+/// 4. `deadline`
+/// 5. `rate_limits`, which see the client's own `authorization`
+/// 6. `auth` token check
+/// 7. message limits
+/// 8. `retries`, picking a backend (or replica) again on every attempt
+/// 9. a single backend, a balancer over replicas, the instance router or
+///    the SNI router
+///
+/// gRPC-Web calls are translated by the server, before all of that.
+fn build_service(
+    name: &str,
+    config: &ListenerConfig,
+    layers: &ServiceLayers<'_>,
+) -> Result<BridgeService, StartError> {
+    let upstream_error = |source| StartError::UpstreamTls {
+        listener: name.to_string(),
+        source,
+    };
+    let forward_client_addr = config.forward_client_addr.map(ForwardClientAddr::from);
+    // backends reading PROXY protocol headers get a `LOCAL` one from probes
+    let probe_preamble = match forward_client_addr {
+        Some(ForwardClientAddr::ProxyProtocol) => proxy_protocol::encode_v2(None),
+        _ => Default::default(),
+    };
+    // a proxy service per backend, gated by its health checker if enabled
+    let proxy_service = |backend: &BackendConfig| -> Result<_, StartError> {
+        let connector = backend.connector().map_err(upstream_error)?;
+        let mut service = ProxyService::new(connector.clone()).with_name(backend.to_string());
+        if let Some(forward) = forward_client_addr {
+            service = service.with_client_addr(forward);
+        }
+        Ok(match &config.health_check {
+            Some(health_check) => service.with_health(spawn_health_check(
+                backend.to_string(),
+                PreambleConnector::new(connector, probe_preamble.clone()),
+                health_check.config(),
+                layers.health_checks.child_token(),
+            )),
+            None => service,
+        })
+    };
+    let service: BoxCloneService<Request<ProxyBody>, _, _> =
+        match (&config.backend, &config.backends, &config.routes) {
+            _ if !config.sni_routes.is_empty() => {
+                let mut services = Vec::with_capacity(config.sni_routes.len());
+                for route in &config.sni_routes {
+                    services.push((route.server_name.clone(), proxy_service(&route.backend)?));
+                }
+                let default = config.backend.as_ref().map(proxy_service).transpose()?;
+                BoxCloneService::new(SniRouter::new(services, default))
+            }
+            (Some(backend), _, _) => BoxCloneService::new(proxy_service(backend)?),
+            (None, Some(backends), _) => {
+                let policy = config
+                    .balance_policy(name)
+                    .expect("config is validated before binding");
+                let mut replicas = Vec::with_capacity(backends.len());
+                for backend in backends {
+                    replicas.push((backend.to_string(), proxy_service(backend)?));
+                }
+                BoxCloneService::new(Balancer::new(policy, replicas))
+            }
+            (None, None, Some(routes)) => {
+                let mut services = Vec::with_capacity(routes.instances.len());
+                for (id, backend) in &routes.instances {
+                    services.push((id.clone(), proxy_service(backend)?));
+                }
+                BoxCloneService::new(HeaderRouter::with_services(routes.prefix.clone(), services))
+            }
+            (None, None, None) => unreachable!("config is validated before binding"),
+        };
+    let retries = config
+        .retry_rules(name)
+        .expect("config is validated before binding");
+    let service = if retries.is_empty() {
+        service
+    } else {
+        let mut layer = RetryLayer::new(retries);
+        if let Some(bytes) = config.retry_buffer_bytes {
+            layer = layer.with_buffer_limit(bytes);
+        }
+        BoxCloneService::new(layer.layer(service))
+    };
+    // outside of retries, so a large request message is not replayed
+    let limits = config.message_limits();
+    let service = if limits == Default::default() {
+        service
+    } else {
+        BoxCloneService::new(MessageLimitLayer::new(limits).layer(service))
+    };
+    let service = match &config.auth {
+        Some(auth) => {
+            let layer = auth_layer(auth).map_err(|source| StartError::Auth {
+                listener: name.to_string(),
+                source,
+            })?;
+            BoxCloneService::new(layer.layer(service))
+        }
+        None => service,
+    };
+    let rules = config
+        .rate_limit_rules(name)
+        .expect("config is validated before binding");
+    let service = if rules.is_empty() {
+        service
+    } else {
+        BoxCloneService::new(RateLimitLayer::new(rules).layer(service))
+    };
+    let service = match &config.deadline {
+        Some(deadline) => {
+            BoxCloneService::new(DeadlineLayer::new(deadline.config()).layer(service))
+        }
+        None => service,
+    };
+    let service = match layers.access_log {
+        Some(log) => BoxCloneService::new(log.layer(route_label(name, config)).layer(service)),
+        None => service,
+    };
+    let service = match layers.trace {
+        Some(trace) => BoxCloneService::new(trace.layer(service)),
+        None => service,
+    };
+    // gRPC-Web is translated by the server, outside of its call limit
+    let service = layers
+        .metrics
+        .layer(route_label(name, config))
+        .layer(service);
+    Ok(BoxCloneService::new(service))
+}
+
+/// Backends of a `passthrough` listener by SNI name, `backend` for other
+/// names.
+fn passthrough_router(
+    name: &str,
+    config: &ListenerConfig,
+) -> Result<PassthroughRouter<BackendConnector>, StartError> {
+    let connector = |backend: &BackendConfig| {
+        backend
+            .connector()
+            .map_err(|source| StartError::UpstreamTls {
+                listener: name.to_string(),
+                source,
+            })
+    };
+    let mut routes = Vec::with_capacity(config.sni_routes.len());
+    for route in &config.sni_routes {
+        routes.push((route.server_name.clone(), connector(&route.backend)?));
+    }
+    let default = config.backend.as_ref().map(connector).transpose()?;
+    Ok(PassthroughRouter::new(routes, default))
+}
+
+/// `route` label of the metrics of a listener: its name, or `listeners[i]`.
+fn route_label(context: &str, config: &ListenerConfig) -> String {
+    config.name.clone().unwrap_or_else(|| context.to_string())
+}
+
+fn auth_layer(auth: &AuthSettings) -> std::io::Result<AuthLayer> {
+    let mut layer = match (&auth.token_prefix, &auth.bearer_tokens_file) {
+        (Some(prefix), _) => AuthLayer::new(PrefixAuthenticator::authorization(prefix.clone())),
+        (None, Some(path)) => AuthLayer::new(TokenAuthenticator::bearer_from_file(path)?),
+        (None, None) => unreachable!("config is validated before binding"),
+    }
+    .strip_header(AUTHORIZATION);
+    if let Some(value) = &auth.forward_authorization {
+        let value = HeaderValue::from_str(value).expect("config is validated before binding");
+        layer = layer.insert_header(AUTHORIZATION, value);
+    }
+    Ok(layer)
+}
@@ -0,0 +1,2524 @@
+//! Config file (TOML or YAML) of the bridge.
+//!
+//! ```toml
+//! [[listeners]]
+//! name = "public"
+//! address = "0.0.0.0:5047"
+//! backend = { uds = "/run/greeter.sock" }
+//!
+//! [listeners.tls]
+//! cert = "/etc/grpc-bridge/server.crt"
+//! key = "/etc/grpc-bridge/server.key"
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use cng::{
+    proxy::{
+        access_log::AccessLogConfig,
+        auth::TokenAuthenticator,
+        balancer::BalancePolicy,
+        connector::{BackendAddr, BackendConnector, TlsTcpConnector},
+        deadline::DeadlineConfig,
+        grpc_web::CorsConfig,
+        health::HealthCheckConfig,
+        message::MessageLimits,
+        passthrough::PassthroughOptions,
+        proxy_protocol::ForwardClientAddr,
+        rate_limit::{ClientKey, RateLimitRule},
+        retry::{AttemptPolicy, HedgingPolicy, RetryPolicy, RetryRule},
+        server::DEFAULT_DRAIN_TIMEOUT,
+        status::Code,
+        trace::OtlpConfig,
+    },
+    tls::{ClientAuthConfig, ClientAuthMode, TlsConfigError, TlsIdentityConfig, UpstreamTlsConfig},
+};
+use http::HeaderName;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// How long in-flight calls may continue after a shutdown signal
+    /// before they are cancelled with `UNAVAILABLE`, see
+    /// [`cng::proxy::serve_with_options`]. Defaults to 30.
+    pub drain_timeout_secs: Option<u64>,
+    /// Client connections without calls for this long are closed with a
+    /// GOAWAY. Never by default.
+    pub idle_timeout_secs: Option<u64>,
+    /// Calls in flight over all listeners; further calls fail right away
+    /// with `RESOURCE_EXHAUSTED`. Unlimited by default.
+    pub max_in_flight_calls: Option<usize>,
+    pub admin: Option<AdminSettings>,
+    pub tracing: Option<TracingSettings>,
+    pub access_log: Option<AccessLogSettings>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+/// Admin listener serving Prometheus metrics on `GET /metrics`, see
+/// [`cng::proxy::metrics`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminSettings {
+    /// `ip:port` to bind.
+    pub address: String,
+}
+
+impl AdminSettings {
+    pub fn addr(&self) -> Result<SocketAddr, ConfigError> {
+        self.address
+            .parse()
+            .map_err(|e| invalid("admin", format!("invalid address {:?}: {e}", self.address)))
+    }
+}
+
+/// Exports a span per call to an OpenTelemetry collector, see
+/// [`cng::proxy::trace`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TracingSettings {
+    /// OTLP/HTTP collector, `http://host:port`.
+    #[serde(default = "default_otlp_endpoint")]
+    pub otlp_endpoint: String,
+    /// `service.name` of the exported spans.
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_otlp_endpoint() -> String {
+    OtlpConfig::default().endpoint
+}
+
+fn default_service_name() -> String {
+    "grpc-bridge".to_string()
+}
+
+impl TracingSettings {
+    pub fn otlp_config(&self) -> Result<OtlpConfig, ConfigError> {
+        let config = OtlpConfig {
+            endpoint: self.otlp_endpoint.clone(),
+            service_name: self.service_name.clone(),
+            ..OtlpConfig::default()
+        };
+        config
+            .authority()
+            .map_err(|e| invalid("tracing", e.to_string()))?;
+        Ok(config)
+    }
+}
+
+/// JSON line per call of every listener, see [`cng::proxy::access_log`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogSettings {
+    /// File the lines are appended to, stdout when unset.
+    pub path: Option<PathBuf>,
+    /// Request headers whose values are not logged.
+    #[serde(default = "default_redact_headers")]
+    pub redact_headers: Vec<String>,
+    /// Fraction of successful calls logged; failed calls always are.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+}
+
+fn default_redact_headers() -> Vec<String> {
+    vec!["authorization".to_string()]
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+impl AccessLogSettings {
+    pub fn config(&self) -> Result<AccessLogConfig, ConfigError> {
+        if !(0.0..=1.0).contains(&self.sample_rate) {
+            return Err(invalid(
+                "access_log",
+                format!("sample_rate {} is not between 0 and 1", self.sample_rate),
+            ));
+        }
+        let redact_headers = self
+            .redact_headers
+            .iter()
+            .map(|name| {
+                HeaderName::try_from(name.as_str()).map_err(|_| {
+                    invalid(
+                        "access_log",
+                        format!("redact_headers {name:?} is not a valid header name"),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(AccessLogConfig {
+            redact_headers,
+            sample_rate: self.sample_rate,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Used in logs and error messages, defaults to the index.
+    pub name: Option<String>,
+    /// `ip:port` to bind, or `unix:/path` for a unix domain socket.
+    pub address: String,
+    /// Required unless `plaintext` is set.
+    pub tls: Option<TlsSettings>,
+    /// Accept cleartext h2 (prior knowledge) instead of TLS, for pods
+    /// where the mesh sidecar terminates TLS.
+    #[serde(default)]
+    pub plaintext: bool,
+    /// Every connection starts with a PROXY protocol v1 or v2 header, from
+    /// an L4 load balancer in front; its source is then the client address
+    /// in logs, metrics and rate limits.
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// How backends learn the client address, not at all by default.
+    pub forward_client_addr: Option<ForwardClientAddrSetting>,
+    /// Forward every call to this backend. Exclusive with `backends` and
+    /// `routes`.
+    pub backend: Option<BackendConfig>,
+    /// Replicas of one backend; each call goes to one of them as picked
+    /// by `balance`.
+    pub backends: Option<Vec<BackendConfig>>,
+    /// How calls are spread over `backends`, round-robin by default.
+    pub balance: Option<BalanceSettings>,
+    /// Forward by `X-Target-InstanceId`. Exclusive with `backend`.
+    pub routes: Option<RoutesConfig>,
+    /// Backends, and optionally certificates, by TLS SNI name; `backend`
+    /// is the default route for other names.
+    #[serde(default)]
+    pub sni_routes: Vec<SniRouteSettings>,
+    /// Fail handshakes asking for a name not in `sni_routes` instead of
+    /// presenting `tls.cert`.
+    #[serde(default)]
+    pub reject_unknown_sni: bool,
+    pub auth: Option<AuthSettings>,
+    /// Probes the backend (or every route instance) with
+    /// `grpc.health.v1.Health`.
+    pub health_check: Option<HealthCheckSettings>,
+    /// Calls over any of these limits fail with `RESOURCE_EXHAUSTED`.
+    #[serde(default)]
+    pub rate_limits: Vec<RateLimitSettings>,
+    /// Deadlines of calls, which are unlimited unless the client sends a
+    /// `grpc-timeout`.
+    pub deadline: Option<DeadlineSettings>,
+    /// Bounds on the connections and streams of the listener, none by
+    /// default. Only `max_connections` applies to `passthrough`.
+    pub limits: Option<LimitSettings>,
+    /// Longest request message in bytes; calls sending a larger one fail
+    /// with `RESOURCE_EXHAUSTED` before it reaches the backend. Unlimited
+    /// by default.
+    pub max_request_message_bytes: Option<usize>,
+    /// Longest response message in bytes; the call ends with
+    /// `RESOURCE_EXHAUSTED` after the messages before it. Unlimited by
+    /// default.
+    pub max_response_message_bytes: Option<usize>,
+    /// Calls failing before any response data are sent again; only list
+    /// idempotent methods.
+    #[serde(default)]
+    pub retries: Vec<RetrySettings>,
+    /// Request bytes buffered per call so `retries` can replay it, 64 KiB
+    /// by default.
+    pub retry_buffer_bytes: Option<usize>,
+    /// Also accept gRPC-Web calls from browsers, over HTTP/1.1 as well as
+    /// h2.
+    pub grpc_web: Option<GrpcWebSettings>,
+    /// Splice TLS connections to `backend`, or to one of `sni_routes` by
+    /// SNI name, without ending TLS. Instead of `tls` or `plaintext`, for
+    /// backends holding their own keys.
+    pub passthrough: Option<PassthroughSettings>,
+}
+
+/// Connection and stream limits of a listener, see
+/// [`cng::proxy::ServeOptions`] (and [`cng::proxy::passthrough::PassthroughOptions`]
+/// for `max_connections`).
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Connections open at once; further clients wait in the backlog.
+    pub max_connections: Option<usize>,
+    /// TLS handshakes in progress at once.
+    pub max_handshakes: Option<usize>,
+    /// Streams per connection, advertised in the HTTP/2 SETTINGS; 200 by
+    /// default.
+    pub max_concurrent_streams: Option<u32>,
+}
+
+impl LimitSettings {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        let connections = [self.max_connections, self.max_handshakes];
+        if connections.contains(&Some(0)) || self.max_concurrent_streams == Some(0) {
+            return Err(invalid(
+                context,
+                "limits max_connections, max_handshakes and max_concurrent_streams must be positive",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Timeouts of a `passthrough` listener.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PassthroughSettings {
+    /// How long connecting to a backend may take, 5 by default.
+    pub connect_timeout_secs: Option<u64>,
+    /// Connections without bytes either way for this long are closed.
+    /// Never by default.
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl PassthroughSettings {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        if [self.connect_timeout_secs, self.idle_timeout_secs].contains(&Some(0)) {
+            return Err(invalid(
+                context,
+                "passthrough connect_timeout_secs and idle_timeout_secs must be positive",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn options(&self) -> PassthroughOptions {
+        let defaults = PassthroughOptions::default();
+        PassthroughOptions {
+            connect_timeout: (self.connect_timeout_secs)
+                .map_or(defaults.connect_timeout, Duration::from_secs),
+            idle_timeout: self.idle_timeout_secs.map(Duration::from_secs),
+            ..defaults
+        }
+    }
+}
+
+/// One service of a listener hosting several, see `sni_routes`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRouteSettings {
+    pub server_name: String,
+    pub backend: BackendConfig,
+    /// PEM certificate chain presented for `server_name`, `tls.cert` when
+    /// unset.
+    pub cert: Option<PathBuf>,
+    /// Private key of `cert`.
+    pub key: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSettings {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key.
+    pub key: PathBuf,
+    /// PEM CA bundle; enables mTLS.
+    pub client_ca: Option<PathBuf>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    /// How often cert/key files are checked for rotation, 0 disables.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval_secs: u64,
+}
+
+fn default_reload_interval() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    #[default]
+    Required,
+    Optional,
+}
+
+/// `{ uds = "/path" }`, `{ tcp = "host:port" }` or, for a TLS backend,
+/// `{ tcp = "host:port", tls = { ca = "/path" } }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BackendTable")]
+pub enum BackendConfig {
+    Uds(PathBuf),
+    Tcp(String),
+    TlsTcp {
+        addr: String,
+        tls: UpstreamTlsSettings,
+    },
+}
+
+/// TLS toward a backend.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsSettings {
+    /// PEM CA bundle trusted for the backend certificate.
+    pub ca: PathBuf,
+    /// SNI and verified name, defaults to the host of `tcp`.
+    pub server_name: Option<String>,
+}
+
+/// Plain table form of [`BackendConfig`]; YAML has no map syntax for
+/// externally tagged enums.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackendTable {
+    uds: Option<PathBuf>,
+    tcp: Option<String>,
+    tls: Option<UpstreamTlsSettings>,
+}
+
+impl TryFrom<BackendTable> for BackendConfig {
+    type Error = &'static str;
+
+    fn try_from(table: BackendTable) -> Result<Self, Self::Error> {
+        match table {
+            BackendTable {
+                uds: Some(path),
+                tcp: None,
+                tls: None,
+            } => Ok(BackendConfig::Uds(path)),
+            BackendTable {
+                uds: None,
+                tcp: Some(addr),
+                tls: None,
+            } => Ok(BackendConfig::Tcp(addr)),
+            BackendTable {
+                uds: None,
+                tcp: Some(addr),
+                tls: Some(tls),
+            } => Ok(BackendConfig::TlsTcp { addr, tls }),
+            BackendTable {
+                uds: Some(_),
+                tls: Some(_),
+                ..
+            } => Err("backend tls needs a tcp address"),
+            _ => Err("backend needs exactly one of uds or tcp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutesConfig {
+    /// e.g. `/proxy`; empty routes every path.
+    #[serde(default)]
+    pub prefix: String,
+    /// Instance id to backend.
+    pub instances: BTreeMap<String, BackendConfig>,
+}
+
+/// See [`BalancePolicy`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BalanceSettings {
+    #[serde(default)]
+    pub policy: BalancePolicySetting,
+    /// Header hashed by `consistent_hash`, e.g. `x-session-id`.
+    pub header: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancePolicySetting {
+    #[default]
+    RoundRobin,
+    LeastOutstanding,
+    ConsistentHash,
+}
+
+impl BalanceSettings {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        self.policy(context).map(|_| ())
+    }
+
+    fn policy(&self, context: &str) -> Result<BalancePolicy, ConfigError> {
+        match (self.policy, &self.header) {
+            (BalancePolicySetting::RoundRobin, None) => Ok(BalancePolicy::RoundRobin),
+            (BalancePolicySetting::LeastOutstanding, None) => Ok(BalancePolicy::LeastOutstanding),
+            (BalancePolicySetting::ConsistentHash, Some(header)) => header
+                .parse::<HeaderName>()
+                .map(BalancePolicy::ConsistentHash)
+                .map_err(|_| {
+                    invalid(
+                        context,
+                        format!("balance.header {header:?} is not a valid header name"),
+                    )
+                }),
+            (BalancePolicySetting::ConsistentHash, None) => Err(invalid(
+                context,
+                "balance.header is required for consistent_hash",
+            )),
+            (_, Some(_)) => Err(invalid(
+                context,
+                "balance.header is only used by consistent_hash",
+            )),
+        }
+    }
+}
+
+/// Token bucket of [`cng::proxy::rate_limit`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitSettings {
+    /// `/package.Service/Method`, or `/package.Service/` for a whole
+    /// service; all calls when unset.
+    pub method: Option<String>,
+    #[serde(default)]
+    pub client: RateLimitClient,
+    /// Header keying `client = "header"`, e.g. `authorization`.
+    pub header: Option<String>,
+    /// Calls per second.
+    pub rate: f64,
+    /// Calls allowed at once, defaults to `rate` rounded up.
+    pub burst: Option<u32>,
+}
+
+/// See [`ForwardClientAddr`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardClientAddrSetting {
+    /// A PROXY protocol v2 header on a backend connection per client
+    /// connection.
+    ProxyProtocol,
+    XForwardedFor,
+}
+
+impl From<ForwardClientAddrSetting> for ForwardClientAddr {
+    fn from(setting: ForwardClientAddrSetting) -> Self {
+        match setting {
+            ForwardClientAddrSetting::ProxyProtocol => ForwardClientAddr::ProxyProtocol,
+            ForwardClientAddrSetting::XForwardedFor => ForwardClientAddr::XForwardedFor,
+        }
+    }
+}
+
+/// Who a bucket belongs to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitClient {
+    /// One bucket for all clients.
+    #[default]
+    All,
+    /// mTLS client certificate subject.
+    Subject,
+    PeerIp,
+    Header,
+}
+
+impl RateLimitSettings {
+    fn rule(&self, context: &str) -> Result<RateLimitRule, ConfigError> {
+        if let Some(method) = &self.method {
+            if !method.starts_with('/') || method.len() < 2 {
+                return Err(invalid(
+                    context,
+                    format!("method {method:?} is not /package.Service/Method"),
+                ));
+            }
+        }
+        if !(self.rate.is_finite() && self.rate > 0.0) {
+            return Err(invalid(context, "rate must be positive"));
+        }
+        if self.burst == Some(0) {
+            return Err(invalid(context, "burst must be at least 1"));
+        }
+        let burst = self.burst.unwrap_or(self.rate.ceil() as u32).max(1);
+        let client = match (self.client, &self.header) {
+            (RateLimitClient::All, None) => None,
+            (RateLimitClient::Subject, None) => Some(ClientKey::Subject),
+            (RateLimitClient::PeerIp, None) => Some(ClientKey::PeerIp),
+            (RateLimitClient::Header, Some(header)) => {
+                Some(ClientKey::Header(header.parse().map_err(|_| {
+                    invalid(
+                        context,
+                        format!("header {header:?} is not a valid header name"),
+                    )
+                })?))
+            }
+            (RateLimitClient::Header, None) => {
+                return Err(invalid(context, "header is required for client = header"))
+            }
+            (_, Some(_)) => return Err(invalid(context, "header is only used by client = header")),
+        };
+        Ok(RateLimitRule {
+            method: self.method.clone(),
+            client,
+            rate: self.rate,
+            burst,
+        })
+    }
+}
+
+/// `retryPolicy` or, with `hedging_delay_ms`, `hedgingPolicy` of a gRPC
+/// service config, see [`cng::proxy::retry`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetrySettings {
+    /// `/package.Service/Method`, or `/package.Service/` for a whole
+    /// service; all calls when unset.
+    pub method: Option<String>,
+    /// Including the first attempt, 2 to 5.
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_ms: u64,
+    #[serde(default = "default_backoff_multiplier")]
+    pub backoff_multiplier: f64,
+    /// Status names such as `UNAVAILABLE`, which is the default.
+    pub retryable_codes: Option<Vec<String>>,
+    /// Hedge instead of retrying: start another attempt this often.
+    pub hedging_delay_ms: Option<u64>,
+    /// Failures that let the other hedged attempts continue, also
+    /// `UNAVAILABLE` by default.
+    pub non_fatal_codes: Option<Vec<String>>,
+}
+
+fn default_initial_backoff() -> u64 {
+    100
+}
+
+fn default_max_backoff() -> u64 {
+    1000
+}
+
+fn default_backoff_multiplier() -> f64 {
+    2.0
+}
+
+/// At most as many attempts as gRPC clients make.
+const MAX_ATTEMPTS: u32 = 5;
+
+impl RetrySettings {
+    fn rule(&self, context: &str) -> Result<RetryRule, ConfigError> {
+        if let Some(method) = &self.method {
+            if !method.starts_with('/') || method.len() < 2 {
+                return Err(invalid(
+                    context,
+                    format!("method {method:?} is not /package.Service/Method"),
+                ));
+            }
+        }
+        if !(2..=MAX_ATTEMPTS).contains(&self.max_attempts) {
+            return Err(invalid(
+                context,
+                format!("max_attempts must be between 2 and {MAX_ATTEMPTS}"),
+            ));
+        }
+        let codes = |key: &str, names: &Option<Vec<String>>| match names {
+            Some(names) => (names.iter())
+                .map(|name| {
+                    Code::from_name(name).ok_or_else(|| {
+                        invalid(context, format!("{key} has unknown status {name:?}"))
+                    })
+                })
+                .collect(),
+            None => Ok(vec![Code::Unavailable]),
+        };
+        let policy = match self.hedging_delay_ms {
+            Some(delay) => {
+                if self.retryable_codes.is_some() {
+                    return Err(invalid(
+                        context,
+                        "retryable_codes is not used with hedging_delay_ms, see non_fatal_codes",
+                    ));
+                }
+                AttemptPolicy::Hedge(HedgingPolicy {
+                    max_attempts: self.max_attempts,
+                    hedging_delay: Duration::from_millis(delay),
+                    non_fatal_codes: codes("non_fatal_codes", &self.non_fatal_codes)?,
+                })
+            }
+            None => {
+                if self.non_fatal_codes.is_some() {
+                    return Err(invalid(
+                        context,
+                        "non_fatal_codes requires hedging_delay_ms",
+                    ));
+                }
+                if self.initial_backoff_ms == 0 || self.initial_backoff_ms > self.max_backoff_ms {
+                    return Err(invalid(
+                        context,
+                        "initial_backoff_ms must be positive and at most max_backoff_ms",
+                    ));
+                }
+                if !(self.backoff_multiplier.is_finite() && self.backoff_multiplier > 0.0) {
+                    return Err(invalid(context, "backoff_multiplier must be positive"));
+                }
+                AttemptPolicy::Retry(RetryPolicy {
+                    max_attempts: self.max_attempts,
+                    initial_backoff: Duration::from_millis(self.initial_backoff_ms),
+                    max_backoff: Duration::from_millis(self.max_backoff_ms),
+                    backoff_multiplier: self.backoff_multiplier,
+                    retryable_codes: codes("retryable_codes", &self.retryable_codes)?,
+                })
+            }
+        };
+        Ok(RetryRule {
+            method: self.method.clone(),
+            policy,
+        })
+    }
+}
+
+/// See [`DeadlineConfig`]; calls over their deadline fail with
+/// `DEADLINE_EXCEEDED`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeadlineSettings {
+    /// Deadline of calls without `grpc-timeout`.
+    pub default_secs: Option<u64>,
+    /// Caps the deadline of every call.
+    pub max_secs: Option<u64>,
+    /// Streams without data either way for this long are ended.
+    pub stream_idle_secs: Option<u64>,
+}
+
+impl DeadlineSettings {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        let secs = [self.default_secs, self.max_secs, self.stream_idle_secs];
+        if secs.contains(&Some(0)) {
+            return Err(invalid(
+                context,
+                "deadline default_secs, max_secs and stream_idle_secs must be positive",
+            ));
+        }
+        if let (Some(default), Some(max)) = (self.default_secs, self.max_secs) {
+            if default > max {
+                return Err(invalid(
+                    context,
+                    "deadline.default_secs is over deadline.max_secs",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> DeadlineConfig {
+        DeadlineConfig {
+            default: self.default_secs.map(Duration::from_secs),
+            max: self.max_secs.map(Duration::from_secs),
+            stream_idle: self.stream_idle_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+/// CORS of gRPC-Web calls, see [`CorsConfig`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcWebSettings {
+    /// Origins allowed to call, e.g. `https://app.example`; any origin
+    /// when empty.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// Request headers allowed besides the gRPC-Web ones.
+    #[serde(default = "default_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache a preflight, 600 by default.
+    pub max_age_secs: Option<u64>,
+}
+
+fn default_allowed_headers() -> Vec<String> {
+    vec!["authorization".to_string()]
+}
+
+impl GrpcWebSettings {
+    pub fn cors(&self, context: &str) -> Result<CorsConfig, ConfigError> {
+        let allowed_origins = (self.allowed_origins.iter())
+            .map(|origin| {
+                http::HeaderValue::from_str(origin).map_err(|_| {
+                    invalid(
+                        context,
+                        format!("grpc_web.allowed_origins {origin:?} is not a valid header"),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let allowed_headers = (self.allowed_headers.iter())
+            .map(|name| {
+                HeaderName::try_from(name.as_str()).map_err(|_| {
+                    invalid(
+                        context,
+                        format!("grpc_web.allowed_headers {name:?} is not a valid header name"),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let defaults = CorsConfig::default();
+        Ok(CorsConfig {
+            allowed_origins,
+            allowed_headers,
+            max_age: self
+                .max_age_secs
+                .map_or(defaults.max_age, Duration::from_secs),
+        })
+    }
+}
+
+/// Header-token check, see `cng::proxy::auth`. Exactly one of
+/// `token_prefix` and `bearer_tokens_file` must be set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthSettings {
+    /// Accept `Authorization: <prefix>...`, e.g. `ProxyToken-`.
+    pub token_prefix: Option<String>,
+    /// Accept `Authorization: Bearer <token>` for tokens in this file.
+    pub bearer_tokens_file: Option<PathBuf>,
+    /// `Authorization` value sent to the backend instead of the client's.
+    pub forward_authorization: Option<String>,
+}
+
+/// See [`HealthCheckConfig`] for the meaning of each key.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckSettings {
+    #[serde(default = "default_health_interval")]
+    pub interval_secs: u64,
+    #[serde(default = "default_health_timeout")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    #[serde(default)]
+    pub service: String,
+    #[serde(default)]
+    pub watch: bool,
+}
+
+fn default_health_interval() -> u64 {
+    5
+}
+
+fn default_health_timeout() -> u64 {
+    1
+}
+
+fn default_healthy_threshold() -> u32 {
+    1
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+impl HealthCheckSettings {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        if self.interval_secs == 0 || self.timeout_secs == 0 {
+            return Err(invalid(
+                context,
+                "health_check interval_secs and timeout_secs must be positive",
+            ));
+        }
+        if self.healthy_threshold == 0 || self.unhealthy_threshold == 0 {
+            return Err(invalid(
+                context,
+                "health_check thresholds must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> HealthCheckConfig {
+        HealthCheckConfig {
+            interval: Duration::from_secs(self.interval_secs),
+            timeout: Duration::from_secs(self.timeout_secs),
+            healthy_threshold: self.healthy_threshold,
+            unhealthy_threshold: self.unhealthy_threshold,
+            service: self.service.clone(),
+            watch: self.watch,
+        }
+    }
+}
+
+/// Where a listener accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Values from the command line that replace the config file ones.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub address: Option<String>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub backend: Option<BackendConfig>,
+    /// Serve cleartext h2, dropping any `tls` section.
+    pub plaintext: bool,
+    /// Applies to all listeners.
+    pub drain_timeout_secs: Option<u64>,
+    /// Address of the admin listener.
+    pub admin_address: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    /// Unsupported file extension.
+    Format(PathBuf),
+    /// A setting failed validation; `context` names the listener and key.
+    Invalid {
+        context: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, source } => {
+                write!(f, "cannot read config {}: {source}", path.display())
+            }
+            ConfigError::Parse { path, message } => {
+                write!(f, "cannot parse config {}: {message}", path.display())
+            }
+            ConfigError::Format(path) => write!(
+                f,
+                "unknown config format {}, expected .toml, .yaml or .yml",
+                path.display()
+            ),
+            ConfigError::Invalid { context, message } => write!(f, "{context}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn invalid(context: impl Into<String>, message: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        context: context.into(),
+        message: message.into(),
+    }
+}
+
+impl Config {
+    /// Reads `path`, picking the format from its extension.
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let parse_error = |message: String| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message,
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| parse_error(e.to_string())),
+            Some("yaml" | "yml") => {
+                serde_yaml::from_str(&text).map_err(|e| parse_error(e.to_string()))
+            }
+            _ => Err(ConfigError::Format(path.to_path_buf())),
+        }
+    }
+
+    /// Applies command line values. Without a config file they describe
+    /// the only listener; otherwise the config must have exactly one.
+    pub fn apply(&mut self, overrides: Overrides) -> Result<(), ConfigError> {
+        let Overrides {
+            address,
+            cert,
+            key,
+            backend,
+            plaintext,
+            drain_timeout_secs,
+            admin_address,
+        } = overrides;
+        if drain_timeout_secs.is_some() {
+            self.drain_timeout_secs = drain_timeout_secs;
+        }
+        if let Some(address) = admin_address {
+            self.admin = Some(AdminSettings { address });
+        }
+        if address.is_none() && cert.is_none() && key.is_none() && backend.is_none() && !plaintext {
+            return Ok(());
+        }
+        if self.listeners.is_empty() {
+            self.listeners.push(ListenerConfig::default());
+        }
+        let [listener] = self.listeners.as_mut_slice() else {
+            return Err(invalid(
+                "command line",
+                format!(
+                    "overrides need exactly one listener, the config has {}",
+                    self.listeners.len()
+                ),
+            ));
+        };
+        if let Some(address) = address {
+            listener.address = address;
+        }
+        if cert.is_some() || key.is_some() {
+            let tls = listener.tls.get_or_insert_with(|| TlsSettings {
+                reload_interval_secs: default_reload_interval(),
+                ..Default::default()
+            });
+            if let Some(cert) = cert {
+                tls.cert = cert;
+            }
+            if let Some(key) = key {
+                tls.key = key;
+            }
+        }
+        if plaintext {
+            listener.plaintext = true;
+            listener.tls = None;
+        }
+        if let Some(backend) = backend {
+            listener.backend = Some(backend);
+            listener.backends = None;
+            listener.balance = None;
+            listener.routes = None;
+        }
+        Ok(())
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        self.drain_timeout_secs
+            .map_or(DEFAULT_DRAIN_TIMEOUT, Duration::from_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_secs.map(Duration::from_secs)
+    }
+
+    /// Checks everything that can be checked without touching the network.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listeners.is_empty() {
+            return Err(invalid("config", "no listeners configured"));
+        }
+        if self.idle_timeout_secs == Some(0) {
+            return Err(invalid("config", "idle_timeout_secs must be positive"));
+        }
+        if self.max_in_flight_calls == Some(0) {
+            return Err(invalid("config", "max_in_flight_calls must be positive"));
+        }
+        let mut addrs = BTreeMap::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let context = listener.context(i);
+            let addr = listener.listen_addr(i)?;
+            if let Some(other) = addrs.insert(addr.clone(), context.clone()) {
+                return Err(invalid(
+                    context,
+                    format!("address {addr} is already used by {other}"),
+                ));
+            }
+            listener.validate(&context)?;
+        }
+        if let Some(admin) = &self.admin {
+            let addr = ListenAddr::Tcp(admin.addr()?);
+            if let Some(other) = addrs.get(&addr) {
+                return Err(invalid(
+                    "admin",
+                    format!("address {addr} is already used by {other}"),
+                ));
+            }
+        }
+        if let Some(tracing) = &self.tracing {
+            tracing.otlp_config()?;
+        }
+        if let Some(access_log) = &self.access_log {
+            access_log.config()?;
+        }
+        Ok(())
+    }
+}
+
+impl ListenerConfig {
+    /// `listener "name"` or `listeners[i]` for messages.
+    pub fn context(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("listener {name:?}"),
+            None => format!("listeners[{index}]"),
+        }
+    }
+
+    pub fn listen_addr(&self, index: usize) -> Result<ListenAddr, ConfigError> {
+        if self.address.is_empty() {
+            return Err(invalid(self.context(index), "address is required"));
+        }
+        if let Some(path) = self.address.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(invalid(self.context(index), "unix socket path is empty"));
+            }
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        self.address.parse().map(ListenAddr::Tcp).map_err(|e| {
+            invalid(
+                self.context(index),
+                format!("invalid address {:?}: {e}", self.address),
+            )
+        })
+    }
+
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        if let Some(passthrough) = &self.passthrough {
+            return self.validate_passthrough(passthrough, context);
+        }
+        match (&self.tls, self.plaintext) {
+            (Some(tls), false) => tls.validate(context)?,
+            (None, true) => {}
+            (None, false) => {
+                return Err(invalid(context, "tls is required unless plaintext = true"))
+            }
+            (Some(_), true) => return Err(invalid(context, "tls and plaintext are exclusive")),
+        }
+
+        let sni = !self.sni_routes.is_empty();
+        match (&self.backend, &self.backends, &self.routes) {
+            (Some(backend), None, None) => backend.validate(&format!("{context} backend"))?,
+            (None, None, None) if sni && !self.reject_unknown_sni => {
+                return Err(invalid(
+                    context,
+                    "sni_routes need a default backend unless reject_unknown_sni = true",
+                ))
+            }
+            (None, None, None) if sni => {}
+            (None, Some(backends), None) => {
+                if backends.is_empty() {
+                    return Err(invalid(context, "backends is empty"));
+                }
+                for (i, backend) in backends.iter().enumerate() {
+                    backend.validate(&format!("{context} backends[{i}]"))?;
+                }
+            }
+            (None, None, Some(routes)) => routes.validate(context)?,
+            (None, None, None) => {
+                return Err(invalid(
+                    context,
+                    "one of backend, backends, routes or sni_routes is required",
+                ))
+            }
+            _ => {
+                return Err(invalid(
+                    context,
+                    "backend, backends and routes are exclusive",
+                ));
+            }
+        }
+        self.validate_sni_routes(context)?;
+        match (&self.balance, &self.backends) {
+            (Some(balance), Some(_)) => balance.validate(context)?,
+            (Some(_), None) => return Err(invalid(context, "balance requires backends")),
+            (None, _) => {}
+        }
+
+        if let Some(auth) = &self.auth {
+            match (&auth.token_prefix, &auth.bearer_tokens_file) {
+                (Some(prefix), None) if prefix.is_empty() => {
+                    return Err(invalid(context, "auth.token_prefix is empty"));
+                }
+                (Some(_), None) => {}
+                (None, Some(path)) => {
+                    check_file(context, "auth.bearer_tokens_file", path)?;
+                    TokenAuthenticator::bearer_from_file(path)
+                        .map_err(|e| invalid(context, format!("auth.bearer_tokens_file: {e}")))?;
+                }
+                _ => {
+                    return Err(invalid(
+                        context,
+                        "auth needs exactly one of token_prefix or bearer_tokens_file",
+                    ));
+                }
+            }
+            if let Some(value) = &auth.forward_authorization {
+                http::HeaderValue::from_str(value).map_err(|_| {
+                    invalid(context, "auth.forward_authorization is not a valid header")
+                })?;
+            }
+        }
+        if let Some(health_check) = &self.health_check {
+            health_check.validate(context)?;
+        }
+        if let Some(deadline) = &self.deadline {
+            deadline.validate(context)?;
+        }
+        if let Some(limits) = &self.limits {
+            limits.validate(context)?;
+        }
+        let limits = self.message_limits();
+        if [limits.max_request, limits.max_response].contains(&Some(0)) {
+            return Err(invalid(
+                context,
+                "max_request_message_bytes and max_response_message_bytes must be positive",
+            ));
+        }
+        self.retry_rules(context)?;
+        if let Some(grpc_web) = &self.grpc_web {
+            grpc_web.cors(context)?;
+        }
+        if self.retries.is_empty() && self.retry_buffer_bytes.is_some() {
+            return Err(invalid(context, "retry_buffer_bytes requires retries"));
+        }
+        for (i, rate_limit) in self.rate_limits.iter().enumerate() {
+            let context = format!("{context} rate_limits[{i}]");
+            let rule = rate_limit.rule(&context)?;
+            let mtls = self.tls.as_ref().is_some_and(|tls| tls.client_ca.is_some());
+            if rule.client == Some(ClientKey::Subject) && !mtls {
+                return Err(invalid(context, "client = subject requires tls.client_ca"));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_sni_routes(&self, context: &str) -> Result<(), ConfigError> {
+        if self.sni_routes.is_empty() {
+            if self.reject_unknown_sni {
+                return Err(invalid(context, "reject_unknown_sni requires sni_routes"));
+            }
+            return Ok(());
+        }
+        if self.tls.is_none() && self.passthrough.is_none() {
+            return Err(invalid(context, "sni_routes require tls or passthrough"));
+        }
+        if self.backends.is_some() || self.routes.is_some() {
+            return Err(invalid(
+                context,
+                "sni_routes are exclusive with backends and routes",
+            ));
+        }
+        let mut names = std::collections::HashSet::new();
+        for (i, route) in self.sni_routes.iter().enumerate() {
+            let context = format!("{context} sni_routes[{i}]");
+            if route.server_name.is_empty() {
+                return Err(invalid(context, "server_name is empty"));
+            }
+            if !names.insert(route.server_name.to_ascii_lowercase()) {
+                return Err(invalid(
+                    context,
+                    format!("server_name {} is listed twice", route.server_name),
+                ));
+            }
+            route.backend.validate(&format!("{context} backend"))?;
+            match (&route.cert, &route.key) {
+                (Some(cert), Some(key)) => {
+                    check_file(&context, "cert", cert)?;
+                    check_file(&context, "key", key)?;
+                }
+                (None, None) => {}
+                _ => return Err(invalid(context, "cert and key go together")),
+            }
+            if self.passthrough.is_some() && route.cert.is_some() {
+                return Err(invalid(
+                    context,
+                    "cert and key do not apply to passthrough, the backend ends TLS",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Passthrough listeners never see calls, so only `backend`,
+    /// `sni_routes` and `limits.max_connections` apply.
+    fn validate_passthrough(
+        &self,
+        passthrough: &PassthroughSettings,
+        context: &str,
+    ) -> Result<(), ConfigError> {
+        if self.tls.is_some() || self.plaintext {
+            return Err(invalid(
+                context,
+                "passthrough is exclusive with tls and plaintext",
+            ));
+        }
+        let unsupported = [
+            ("backends", self.backends.is_some()),
+            ("balance", self.balance.is_some()),
+            ("routes", self.routes.is_some()),
+            ("proxy_protocol", self.proxy_protocol),
+            ("forward_client_addr", self.forward_client_addr.is_some()),
+            ("auth", self.auth.is_some()),
+            ("health_check", self.health_check.is_some()),
+            ("rate_limits", !self.rate_limits.is_empty()),
+            ("deadline", self.deadline.is_some()),
+            (
+                "limits max_handshakes",
+                self.limits
+                    .as_ref()
+                    .is_some_and(|l| l.max_handshakes.is_some()),
+            ),
+            (
+                "limits max_concurrent_streams",
+                self.limits
+                    .as_ref()
+                    .is_some_and(|l| l.max_concurrent_streams.is_some()),
+            ),
+            (
+                "max_request_message_bytes",
+                self.max_request_message_bytes.is_some(),
+            ),
+            (
+                "max_response_message_bytes",
+                self.max_response_message_bytes.is_some(),
+            ),
+            ("retries", !self.retries.is_empty()),
+            ("retry_buffer_bytes", self.retry_buffer_bytes.is_some()),
+            ("grpc_web", self.grpc_web.is_some()),
+        ];
+        if let Some((key, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(invalid(
+                context,
+                format!("{key} does not apply to passthrough"),
+            ));
+        }
+        passthrough.validate(context)?;
+        if let Some(limits) = &self.limits {
+            limits.validate(context)?;
+        }
+        match &self.backend {
+            Some(backend) => backend.validate(&format!("{context} backend"))?,
+            None if self.sni_routes.is_empty() => {
+                return Err(invalid(context, "one of backend or sni_routes is required"))
+            }
+            None if !self.reject_unknown_sni => {
+                return Err(invalid(
+                    context,
+                    "sni_routes need a default backend unless reject_unknown_sni = true",
+                ))
+            }
+            None => {}
+        }
+        self.validate_sni_routes(context)?;
+        let backends = (self
+            .backend
+            .iter()
+            .map(|b| (format!("{context} backend"), b)))
+        .chain(
+            (self.sni_routes.iter().enumerate())
+                .map(|(i, route)| (format!("{context} sni_routes[{i}] backend"), &route.backend)),
+        );
+        for (context, backend) in backends {
+            if let BackendConfig::TlsTcp { .. } = backend {
+                return Err(invalid(
+                    context,
+                    "tls does not apply to passthrough, the backend ends TLS",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Identity presented for each of `sni_routes`: its own or `tls`.
+    pub fn sni_identities(&self) -> Vec<(&str, TlsIdentityConfig)> {
+        let Some(tls) = &self.tls else {
+            return Vec::new();
+        };
+        (self.sni_routes.iter())
+            .map(|route| {
+                let identity = match (&route.cert, &route.key) {
+                    (Some(cert), Some(key)) => TlsIdentityConfig::new(cert, key),
+                    _ => TlsIdentityConfig::new(&tls.cert, &tls.key),
+                };
+                (route.server_name.as_str(), identity)
+            })
+            .collect()
+    }
+
+    /// Rules of `rate_limits`, in order.
+    pub fn rate_limit_rules(&self, context: &str) -> Result<Vec<RateLimitRule>, ConfigError> {
+        (self.rate_limits.iter().enumerate())
+            .map(|(i, rate_limit)| rate_limit.rule(&format!("{context} rate_limits[{i}]")))
+            .collect()
+    }
+
+    /// `max_request_message_bytes` and `max_response_message_bytes`.
+    pub fn message_limits(&self) -> MessageLimits {
+        MessageLimits {
+            max_request: self.max_request_message_bytes,
+            max_response: self.max_response_message_bytes,
+        }
+    }
+
+    /// Rules of `retries`, in order.
+    pub fn retry_rules(&self, context: &str) -> Result<Vec<RetryRule>, ConfigError> {
+        (self.retries.iter().enumerate())
+            .map(|(i, retry)| retry.rule(&format!("{context} retries[{i}]")))
+            .collect()
+    }
+
+    /// Policy for `backends`, round-robin unless `balance` says otherwise.
+    pub fn balance_policy(&self, context: &str) -> Result<BalancePolicy, ConfigError> {
+        self.balance
+            .as_ref()
+            .map_or(Ok(BalancePolicy::RoundRobin), |balance| {
+                balance.policy(context)
+            })
+    }
+
+    /// TLS identity and optional client auth from the `tls` section.
+    pub fn tls_identity(&self) -> Option<(TlsIdentityConfig, Option<ClientAuthConfig>)> {
+        let tls = self.tls.as_ref()?;
+        let client_auth = tls.client_ca.as_ref().map(|ca| {
+            let mode = match tls.client_auth {
+                ClientAuth::Required => ClientAuthMode::Required,
+                ClientAuth::Optional => ClientAuthMode::Optional,
+            };
+            ClientAuthConfig::new(ca, mode)
+        });
+        Some((TlsIdentityConfig::new(&tls.cert, &tls.key), client_auth))
+    }
+}
+
+impl TlsSettings {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        for (key, path) in [("tls.cert", &self.cert), ("tls.key", &self.key)] {
+            check_file(context, key, path)?;
+        }
+        if let Some(ca) = &self.client_ca {
+            check_file(context, "tls.client_ca", ca)?;
+        } else if self.client_auth == ClientAuth::Optional {
+            return Err(invalid(context, "tls.client_auth requires tls.client_ca"));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for BackendConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendConfig::Uds(path) => write!(f, "unix:{}", path.display()),
+            BackendConfig::Tcp(addr) => write!(f, "tcp:{addr}"),
+            BackendConfig::TlsTcp { addr, .. } => write!(f, "tls:{addr}"),
+        }
+    }
+}
+
+impl BackendConfig {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        match self {
+            BackendConfig::Uds(path) if path.as_os_str().is_empty() => {
+                Err(invalid(context, "uds path is empty"))
+            }
+            BackendConfig::Uds(_) => Ok(()),
+            BackendConfig::Tcp(addr) => check_host_port(context, addr),
+            BackendConfig::TlsTcp { addr, tls } => {
+                check_host_port(context, addr)?;
+                check_file(context, "tls.ca", &tls.ca)?;
+                self.upstream_tls()
+                    .unwrap()
+                    .server_name(addr)
+                    .map(|_| ())
+                    .map_err(|e| invalid(context, e.to_string()))
+            }
+        }
+    }
+
+    fn upstream_tls(&self) -> Option<UpstreamTlsConfig> {
+        match self {
+            BackendConfig::TlsTcp { tls, .. } => {
+                Some(UpstreamTlsConfig::new(&tls.ca, tls.server_name.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Connector for this backend, loading the CA bundle of TLS backends.
+    pub fn connector(&self) -> Result<BackendConnector, TlsConfigError> {
+        Ok(match self {
+            BackendConfig::Uds(path) => BackendAddr::Uds(path.clone()).into(),
+            BackendConfig::Tcp(addr) => BackendAddr::Tcp(addr.clone()).into(),
+            BackendConfig::TlsTcp { addr, .. } => BackendConnector::TlsTcp(
+                TlsTcpConnector::from_config(addr.clone(), &self.upstream_tls().unwrap())?,
+            ),
+        })
+    }
+}
+
+fn check_host_port(context: &str, addr: &str) -> Result<(), ConfigError> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(invalid(
+            context,
+            format!("tcp address {addr:?} is not host:port"),
+        )),
+    }
+}
+
+impl RoutesConfig {
+    fn validate(&self, context: &str) -> Result<(), ConfigError> {
+        if !self.prefix.is_empty() && !self.prefix.starts_with('/') {
+            return Err(invalid(
+                context,
+                format!("routes.prefix {:?} must start with '/'", self.prefix),
+            ));
+        }
+        if self.instances.is_empty() {
+            return Err(invalid(context, "routes.instances is empty"));
+        }
+        for (id, backend) in &self.instances {
+            backend.validate(&format!("{context} routes.instances.{id}"))?;
+        }
+        Ok(())
+    }
+}
+
+fn check_file(context: &str, key: &str, path: &Path) -> Result<(), ConfigError> {
+    if path.as_os_str().is_empty() {
+        return Err(invalid(context, format!("{key} is required")));
+    }
+    if !path.is_file() {
+        return Err(invalid(
+            context,
+            format!("{key} {} does not exist", path.display()),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use cng::proxy::{
+        balancer::BalancePolicy,
+        deadline::DeadlineConfig,
+        grpc_web::CorsConfig,
+        message::MessageLimits,
+        proxy_protocol::ForwardClientAddr,
+        rate_limit::{ClientKey, RateLimitRule},
+        retry::{AttemptPolicy, HedgingPolicy, RetryPolicy, RetryRule},
+        status::Code,
+    };
+    use http::{HeaderName, HeaderValue};
+
+    use super::{
+        AdminSettings, AuthSettings, BackendConfig, BalancePolicySetting, BalanceSettings,
+        ClientAuth, Config, ConfigError, DeadlineSettings, LimitSettings, ListenAddr, Overrides,
+        PassthroughSettings, RateLimitClient, RateLimitSettings, RetrySettings, TracingSettings,
+    };
+
+    /// Temp dir with empty `server.crt`/`server.key` so file checks pass.
+    fn cert_dir(name: &str) -> PathBuf {
+        let dir = cng::util::temp_dir(name);
+        std::fs::write(dir.join("server.crt"), "").unwrap();
+        std::fs::write(dir.join("server.key"), "").unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, name: &str, content: &str) -> Config {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        Config::from_file(&path).unwrap()
+    }
+
+    fn error(config: &Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_toml_and_yaml() {
+        let dir = cert_dir("parse");
+        let d = dir.display();
+        let toml = write(
+            &dir,
+            "bridge.toml",
+            &format!(
+                r#"
+[[listeners]]
+name = "public"
+address = "127.0.0.1:5047"
+backend = {{ uds = "/run/greeter.sock" }}
+[listeners.tls]
+cert = "{d}/server.crt"
+key = "{d}/server.key"
+
+[[listeners]]
+address = "127.0.0.1:5048"
+[listeners.tls]
+cert = "{d}/server.crt"
+key = "{d}/server.key"
+client_ca = "{d}/server.crt"
+client_auth = "optional"
+[listeners.routes]
+prefix = "/proxy"
+instances = {{ a = {{ uds = "/run/a.sock" }}, b = {{ tcp = "localhost:50051" }} }}
+[listeners.health_check]
+interval_secs = 2
+watch = true
+"#
+            ),
+        );
+        let yaml = write(
+            &dir,
+            "bridge.yaml",
+            &format!(
+                r#"
+listeners:
+  - name: public
+    address: 127.0.0.1:5047
+    backend: {{ uds: /run/greeter.sock }}
+    tls: {{ cert: {d}/server.crt, key: {d}/server.key }}
+  - address: 127.0.0.1:5048
+    tls:
+      cert: {d}/server.crt
+      key: {d}/server.key
+      client_ca: {d}/server.crt
+      client_auth: optional
+    routes:
+      prefix: /proxy
+      instances:
+        a: {{ uds: /run/a.sock }}
+        b: {{ tcp: "localhost:50051" }}
+    health_check: {{ interval_secs: 2, watch: true }}
+"#
+            ),
+        );
+        for config in [toml, yaml] {
+            config.validate().unwrap();
+            let [public, routed] = config.listeners.as_slice() else {
+                panic!("{config:?}");
+            };
+            assert_eq!(
+                public.backend,
+                Some(BackendConfig::Uds("/run/greeter.sock".into()))
+            );
+            assert_eq!(public.tls.as_ref().unwrap().reload_interval_secs, 30);
+            assert_eq!(routed.context(1), "listeners[1]");
+            assert_eq!(
+                routed.tls.as_ref().unwrap().client_auth,
+                ClientAuth::Optional
+            );
+            let routes = routed.routes.as_ref().unwrap();
+            assert_eq!(
+                routes.instances["b"],
+                BackendConfig::Tcp("localhost:50051".into())
+            );
+            assert!(public.health_check.is_none());
+            let health = routed.health_check.as_ref().unwrap().config();
+            assert_eq!(health.interval, Duration::from_secs(2));
+            assert_eq!(health.unhealthy_threshold, 3);
+            assert!(health.watch);
+        }
+    }
+
+    #[test]
+    fn unix_listener_and_tls_backend() {
+        let dir = cert_dir("reverse");
+        let d = dir.display();
+        let config = write(
+            &dir,
+            "bridge.yml",
+            &format!(
+                r#"
+listeners:
+  - address: unix:{d}/bridge.sock
+    plaintext: true
+    backend:
+      tcp: greeter.example:443
+      tls: {{ ca: {d}/server.crt, server_name: greeter.internal }}
+"#
+            ),
+        );
+        config.validate().unwrap();
+        let listener = &config.listeners[0];
+        assert_eq!(
+            listener.listen_addr(0).unwrap(),
+            ListenAddr::Unix(dir.join("bridge.sock"))
+        );
+        let Some(BackendConfig::TlsTcp { addr, tls }) = &listener.backend else {
+            panic!("{listener:?}");
+        };
+        assert_eq!(addr, "greeter.example:443");
+        assert_eq!(tls.server_name.as_deref(), Some("greeter.internal"));
+
+        let path = dir.join("bad.yml");
+        std::fs::write(
+            &path,
+            "listeners:\n  - address: unix:/a\n    backend: { uds: /b, tls: { ca: /c } }\n",
+        )
+        .unwrap();
+        let err = Config::from_file(&path).unwrap_err();
+        assert!(
+            err.to_string().contains("backend tls needs a tcp address"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn rate_limits() {
+        let dir = cert_dir("rate-limits");
+        let config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backend = { uds = "/run/greeter.sock" }
+
+[[listeners.rate_limits]]
+rate = 100
+
+[[listeners.rate_limits]]
+method = "/helloworld.Greeter/SayHello"
+client = "header"
+header = "authorization"
+rate = 0.5
+burst = 5
+"#,
+        );
+        config.validate().unwrap();
+        let rules = config.listeners[0]
+            .rate_limit_rules("listeners[0]")
+            .unwrap();
+        assert_eq!(
+            rules,
+            [
+                RateLimitRule {
+                    method: None,
+                    client: None,
+                    rate: 100.0,
+                    burst: 100,
+                },
+                RateLimitRule {
+                    method: Some("/helloworld.Greeter/SayHello".to_string()),
+                    client: Some(ClientKey::Header(HeaderName::from_static("authorization"))),
+                    rate: 0.5,
+                    burst: 5,
+                },
+            ]
+        );
+
+        let cases = [
+            (
+                RateLimitSettings {
+                    rate: 0.0,
+                    ..Default::default()
+                },
+                "rate must be positive",
+            ),
+            (
+                RateLimitSettings {
+                    rate: 1.0,
+                    burst: Some(0),
+                    ..Default::default()
+                },
+                "burst must be at least 1",
+            ),
+            (
+                RateLimitSettings {
+                    method: Some("helloworld.Greeter".to_string()),
+                    rate: 1.0,
+                    ..Default::default()
+                },
+                "method \"helloworld.Greeter\" is not /package.Service/Method",
+            ),
+            (
+                RateLimitSettings {
+                    client: RateLimitClient::Header,
+                    rate: 1.0,
+                    ..Default::default()
+                },
+                "header is required for client = header",
+            ),
+            (
+                RateLimitSettings {
+                    client: RateLimitClient::PeerIp,
+                    header: Some("x-user".to_string()),
+                    rate: 1.0,
+                    ..Default::default()
+                },
+                "header is only used by client = header",
+            ),
+            (
+                RateLimitSettings {
+                    client: RateLimitClient::Subject,
+                    rate: 1.0,
+                    ..Default::default()
+                },
+                "client = subject requires tls.client_ca",
+            ),
+        ];
+        for (rate_limit, message) in cases {
+            let mut config = config.clone();
+            config.listeners[0].rate_limits = vec![rate_limit];
+            assert_eq!(
+                error(&config),
+                format!("listeners[0] rate_limits[0]: {message}")
+            );
+        }
+    }
+
+    #[test]
+    fn deadlines_and_idle_timeout() {
+        let dir = cert_dir("deadlines");
+        let mut config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+idle_timeout_secs = 300
+
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backend = { uds = "/run/greeter.sock" }
+deadline = { default_secs = 30, max_secs = 600, stream_idle_secs = 60 }
+"#,
+        );
+        config.validate().unwrap();
+        assert_eq!(config.idle_timeout(), Some(Duration::from_secs(300)));
+        assert_eq!(
+            config.listeners[0].deadline.as_ref().unwrap().config(),
+            DeadlineConfig {
+                default: Some(Duration::from_secs(30)),
+                max: Some(Duration::from_secs(600)),
+                stream_idle: Some(Duration::from_secs(60)),
+            }
+        );
+
+        let deadline = config.listeners[0].deadline.as_mut().unwrap();
+        deadline.default_secs = Some(900);
+        assert_eq!(
+            error(&config),
+            "listeners[0]: deadline.default_secs is over deadline.max_secs"
+        );
+        let deadline = config.listeners[0].deadline.as_mut().unwrap();
+        deadline.default_secs = None;
+        deadline.stream_idle_secs = Some(0);
+        assert_eq!(
+            error(&config),
+            "listeners[0]: deadline default_secs, max_secs and stream_idle_secs must be positive"
+        );
+        config.listeners[0].deadline = None;
+        config.idle_timeout_secs = Some(0);
+        assert_eq!(error(&config), "config: idle_timeout_secs must be positive");
+    }
+
+    #[test]
+    fn retries() {
+        let dir = cert_dir("retries");
+        let config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backend = { uds = "/run/greeter.sock" }
+retry_buffer_bytes = 1024
+
+[[listeners.retries]]
+method = "/helloworld.Greeter/"
+max_attempts = 3
+
+[[listeners.retries]]
+method = "/helloworld.Greeter/SayHello"
+max_attempts = 2
+hedging_delay_ms = 50
+non_fatal_codes = ["UNAVAILABLE", "RESOURCE_EXHAUSTED"]
+"#,
+        );
+        config.validate().unwrap();
+        assert_eq!(
+            config.listeners[0].retry_rules("listeners[0]").unwrap(),
+            [
+                RetryRule {
+                    method: Some("/helloworld.Greeter/".to_string()),
+                    policy: AttemptPolicy::Retry(RetryPolicy {
+                        max_attempts: 3,
+                        initial_backoff: Duration::from_millis(100),
+                        max_backoff: Duration::from_secs(1),
+                        backoff_multiplier: 2.0,
+                        retryable_codes: vec![Code::Unavailable],
+                    }),
+                },
+                RetryRule {
+                    method: Some("/helloworld.Greeter/SayHello".to_string()),
+                    policy: AttemptPolicy::Hedge(HedgingPolicy {
+                        max_attempts: 2,
+                        hedging_delay: Duration::from_millis(50),
+                        non_fatal_codes: vec![Code::Unavailable, Code::ResourceExhausted],
+                    }),
+                },
+            ]
+        );
+
+        let retry = |max_attempts| RetrySettings {
+            max_attempts,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            backoff_multiplier: 2.0,
+            ..Default::default()
+        };
+        let cases = [
+            (retry(6), "max_attempts must be between 2 and 5"),
+            (
+                RetrySettings {
+                    retryable_codes: Some(vec!["Unavailable".to_string()]),
+                    ..retry(2)
+                },
+                "retryable_codes has unknown status \"Unavailable\"",
+            ),
+            (
+                RetrySettings {
+                    initial_backoff_ms: 2000,
+                    ..retry(2)
+                },
+                "initial_backoff_ms must be positive and at most max_backoff_ms",
+            ),
+            (
+                RetrySettings {
+                    non_fatal_codes: Some(Vec::new()),
+                    ..retry(2)
+                },
+                "non_fatal_codes requires hedging_delay_ms",
+            ),
+            (
+                RetrySettings {
+                    hedging_delay_ms: Some(10),
+                    retryable_codes: Some(Vec::new()),
+                    ..retry(2)
+                },
+                "retryable_codes is not used with hedging_delay_ms, see non_fatal_codes",
+            ),
+        ];
+        for (retry, message) in cases {
+            let mut config = config.clone();
+            config.listeners[0].retries = vec![retry];
+            assert_eq!(
+                error(&config),
+                format!("listeners[0] retries[0]: {message}")
+            );
+        }
+        let mut config = config.clone();
+        config.listeners[0].retries.clear();
+        assert_eq!(
+            error(&config),
+            "listeners[0]: retry_buffer_bytes requires retries"
+        );
+    }
+
+    #[test]
+    fn proxy_protocol() {
+        let dir = cert_dir("proxy-protocol");
+        let config = write(
+            &dir,
+            "bridge.yaml",
+            r#"
+listeners:
+  - address: "127.0.0.1:5047"
+    plaintext: true
+    proxy_protocol: true
+    forward_client_addr: x_forwarded_for
+    backend: { uds: /run/greeter.sock }
+  - address: "127.0.0.1:5048"
+    plaintext: true
+    forward_client_addr: proxy_protocol
+    backend: { tcp: "localhost:50051" }
+"#,
+        );
+        config.validate().unwrap();
+        let forward = |i: usize| config.listeners[i].forward_client_addr.map(Into::into);
+        assert!(config.listeners[0].proxy_protocol);
+        assert_eq!(forward(0), Some(ForwardClientAddr::XForwardedFor));
+        assert!(!config.listeners[1].proxy_protocol);
+        assert_eq!(forward(1), Some(ForwardClientAddr::ProxyProtocol));
+    }
+
+    #[test]
+    fn grpc_web() {
+        let dir = cert_dir("grpc-web");
+        let mut config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backend = { uds = "/run/greeter.sock" }
+grpc_web = { allowed_origins = ["https://app.example"], max_age_secs = 60 }
+"#,
+        );
+        config.validate().unwrap();
+        let grpc_web = config.listeners[0].grpc_web.as_mut().unwrap();
+        assert_eq!(
+            grpc_web.cors("listeners[0]").unwrap(),
+            CorsConfig {
+                allowed_origins: vec![HeaderValue::from_static("https://app.example")],
+                allowed_headers: vec![HeaderName::from_static("authorization")],
+                max_age: Duration::from_secs(60),
+            }
+        );
+
+        grpc_web.allowed_headers = vec!["x user".to_string()];
+        assert_eq!(
+            error(&config),
+            "listeners[0]: grpc_web.allowed_headers \"x user\" is not a valid header name"
+        );
+        let grpc_web = config.listeners[0].grpc_web.as_mut().unwrap();
+        grpc_web.allowed_headers.clear();
+        grpc_web.allowed_origins = vec!["https://app.example\n".to_string()];
+        assert_eq!(
+            error(&config),
+            "listeners[0]: grpc_web.allowed_origins \"https://app.example\\n\" is not a valid header"
+        );
+    }
+
+    #[test]
+    fn sni_routes() {
+        let dir = cert_dir("sni");
+        let d = dir.display();
+        std::fs::write(dir.join("b.crt"), "").unwrap();
+        std::fs::write(dir.join("b.key"), "").unwrap();
+        let config = write(
+            &dir,
+            "bridge.toml",
+            &format!(
+                r#"
+[[listeners]]
+address = "127.0.0.1:5047"
+backend = {{ uds = "/run/default.sock" }}
+[listeners.tls]
+cert = "{d}/server.crt"
+key = "{d}/server.key"
+[[listeners.sni_routes]]
+server_name = "a.example"
+backend = {{ uds = "/run/a.sock" }}
+[[listeners.sni_routes]]
+server_name = "b.example"
+backend = {{ tcp = "localhost:50051" }}
+cert = "{d}/b.crt"
+key = "{d}/b.key"
+"#
+            ),
+        );
+        config.validate().unwrap();
+        let identities: Vec<_> = (config.listeners[0].sni_identities().into_iter())
+            .map(|(name, identity)| (name, identity.cert_chain))
+            .collect();
+        assert_eq!(
+            identities,
+            [
+                ("a.example", dir.join("server.crt")),
+                ("b.example", dir.join("b.crt")),
+            ]
+        );
+
+        let mut strict = config.clone();
+        strict.listeners[0].backend = None;
+        assert_eq!(
+            error(&strict),
+            "listeners[0]: sni_routes need a default backend unless reject_unknown_sni = true"
+        );
+        strict.listeners[0].reject_unknown_sni = true;
+        strict.validate().unwrap();
+
+        let mut config = config;
+        let listener = &mut config.listeners[0];
+        listener.sni_routes[1].server_name = "A.example".to_string();
+        assert_eq!(
+            error(&config),
+            "listeners[0] sni_routes[1]: server_name A.example is listed twice"
+        );
+        let listener = &mut config.listeners[0];
+        listener.sni_routes[1].server_name = "b.example".to_string();
+        listener.sni_routes[1].key = None;
+        assert_eq!(
+            error(&config),
+            "listeners[0] sni_routes[1]: cert and key go together"
+        );
+        let listener = &mut config.listeners[0];
+        listener.sni_routes.clear();
+        listener.reject_unknown_sni = true;
+        assert_eq!(
+            error(&config),
+            "listeners[0]: reject_unknown_sni requires sni_routes"
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let dir = cert_dir("limits");
+        let mut config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+max_in_flight_calls = 1000
+
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backend = { uds = "/run/greeter.sock" }
+limits = { max_connections = 512, max_handshakes = 64, max_concurrent_streams = 100 }
+"#,
+        );
+        config.validate().unwrap();
+        assert_eq!(config.max_in_flight_calls, Some(1000));
+        let limits = config.listeners[0].limits.as_ref().unwrap();
+        assert_eq!(limits.max_connections, Some(512));
+        assert_eq!(limits.max_handshakes, Some(64));
+        assert_eq!(limits.max_concurrent_streams, Some(100));
+
+        config.listeners[0]
+            .limits
+            .as_mut()
+            .unwrap()
+            .max_concurrent_streams = Some(0);
+        assert_eq!(
+            error(&config),
+            "listeners[0]: limits max_connections, max_handshakes and max_concurrent_streams must be positive"
+        );
+        config.listeners[0].limits = None;
+        config.max_in_flight_calls = Some(0);
+        assert_eq!(
+            error(&config),
+            "config: max_in_flight_calls must be positive"
+        );
+    }
+
+    #[test]
+    fn message_limits() {
+        let dir = cert_dir("message-limits");
+        let mut config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backend = { uds = "/run/greeter.sock" }
+max_request_message_bytes = 4194304
+max_response_message_bytes = 16777216
+"#,
+        );
+        config.validate().unwrap();
+        assert_eq!(
+            config.listeners[0].message_limits(),
+            MessageLimits {
+                max_request: Some(4 << 20),
+                max_response: Some(16 << 20),
+            }
+        );
+
+        config.listeners[0].max_response_message_bytes = Some(0);
+        assert_eq!(
+            error(&config),
+            "listeners[0]: max_request_message_bytes and max_response_message_bytes must be positive"
+        );
+    }
+
+    #[test]
+    fn passthrough() {
+        let dir = cert_dir("passthrough");
+        let config = write(
+            &dir,
+            "bridge.yaml",
+            r#"
+listeners:
+  - address: "0.0.0.0:443"
+    passthrough: { connect_timeout_secs: 2, idle_timeout_secs: 300 }
+    limits: { max_connections: 1024 }
+    reject_unknown_sni: true
+    sni_routes:
+      - server_name: billing.example
+        backend: { tcp: "10.0.0.2:443" }
+      - server_name: ledger.example
+        backend: { uds: /run/ledger.sock }
+"#,
+        );
+        config.validate().unwrap();
+        let options = config.listeners[0].passthrough.as_ref().unwrap().options();
+        assert_eq!(options.connect_timeout, Duration::from_secs(2));
+        assert_eq!(options.idle_timeout, Some(Duration::from_secs(300)));
+        let defaults = PassthroughSettings::default().options();
+        assert_eq!(defaults.connect_timeout, Duration::from_secs(5));
+        assert_eq!(defaults.idle_timeout, None);
+
+        let mut config = config;
+        config.listeners[0].plaintext = true;
+        assert_eq!(
+            error(&config),
+            "listeners[0]: passthrough is exclusive with tls and plaintext"
+        );
+        config.listeners[0].plaintext = false;
+        config.listeners[0].deadline = Some(DeadlineSettings::default());
+        assert_eq!(
+            error(&config),
+            "listeners[0]: deadline does not apply to passthrough"
+        );
+        config.listeners[0].deadline = None;
+        config.listeners[0].limits = Some(LimitSettings {
+            max_handshakes: Some(64),
+            ..LimitSettings::default()
+        });
+        assert_eq!(
+            error(&config),
+            "listeners[0]: limits max_handshakes does not apply to passthrough"
+        );
+        config.listeners[0].limits = None;
+        config.listeners[0].sni_routes[1].cert = Some(dir.join("server.crt"));
+        config.listeners[0].sni_routes[1].key = Some(dir.join("server.key"));
+        assert_eq!(
+            error(&config),
+            "listeners[0] sni_routes[1]: cert and key do not apply to passthrough, the backend ends TLS"
+        );
+        config.listeners[0].sni_routes[1].cert = None;
+        config.listeners[0].sni_routes[1].key = None;
+        config.listeners[0].reject_unknown_sni = false;
+        assert_eq!(
+            error(&config),
+            "listeners[0]: sni_routes need a default backend unless reject_unknown_sni = true"
+        );
+        config.listeners[0].sni_routes.clear();
+        assert_eq!(
+            error(&config),
+            "listeners[0]: one of backend or sni_routes is required"
+        );
+        config.listeners[0].passthrough = Some(PassthroughSettings {
+            idle_timeout_secs: Some(0),
+            ..PassthroughSettings::default()
+        });
+        config.listeners[0].backend = Some(BackendConfig::Tcp("localhost:443".into()));
+        assert_eq!(
+            error(&config),
+            "listeners[0]: passthrough connect_timeout_secs and idle_timeout_secs must be positive"
+        );
+    }
+
+    #[test]
+    fn backends_and_balance() {
+        let dir = cert_dir("balance");
+        let config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backends = [{ uds = "/run/greeter-0.sock" }, { uds = "/run/greeter-1.sock" }]
+balance = { policy = "consistent_hash", header = "x-session-id" }
+"#,
+        );
+        config.validate().unwrap();
+        let listener = &config.listeners[0];
+        assert_eq!(listener.backends.as_ref().unwrap().len(), 2);
+        assert_eq!(
+            listener.balance_policy("listeners[0]").unwrap(),
+            BalancePolicy::ConsistentHash(HeaderName::from_static("x-session-id"))
+        );
+
+        let mut config = config;
+        config.listeners[0].balance = None;
+        config.validate().unwrap();
+        assert_eq!(
+            config.listeners[0].balance_policy("listeners[0]").unwrap(),
+            BalancePolicy::RoundRobin
+        );
+
+        config.listeners[0].balance = Some(BalanceSettings {
+            policy: BalancePolicySetting::ConsistentHash,
+            header: None,
+        });
+        assert_eq!(
+            error(&config),
+            "listeners[0]: balance.header is required for consistent_hash"
+        );
+        config.listeners[0].balance = Some(BalanceSettings {
+            policy: BalancePolicySetting::ConsistentHash,
+            header: Some("bad header".to_string()),
+        });
+        assert_eq!(
+            error(&config),
+            "listeners[0]: balance.header \"bad header\" is not a valid header name"
+        );
+
+        config.listeners[0].backends = Some(Vec::new());
+        assert_eq!(error(&config), "listeners[0]: backends is empty");
+        config.listeners[0].backends = Some(vec![BackendConfig::Tcp("localhost".into())]);
+        assert_eq!(
+            error(&config),
+            "listeners[0] backends[0]: tcp address \"localhost\" is not host:port"
+        );
+        config.listeners[0].backend = Some(BackendConfig::Uds("/run/greeter.sock".into()));
+        assert_eq!(
+            error(&config),
+            "listeners[0]: backend, backends and routes are exclusive"
+        );
+        config.listeners[0].backends = None;
+        assert_eq!(error(&config), "listeners[0]: balance requires backends");
+    }
+
+    #[test]
+    fn reports_unknown_keys_and_formats() {
+        let dir = cert_dir("unknown");
+        let path = dir.join("bridge.toml");
+        std::fs::write(
+            &path,
+            "[[listeners]]\naddress = \"127.0.0.1:1\"\nport = 1\n",
+        )
+        .unwrap();
+        let err = Config::from_file(&path).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }), "{err}");
+        assert!(err.to_string().contains("unknown field `port`"), "{err}");
+
+        std::fs::write(
+            &path,
+            "[[listeners]]\naddress = \"127.0.0.1:1\"\nbackend = { uds = \"a\", tcp = \"b:1\" }\n",
+        )
+        .unwrap();
+        let err = Config::from_file(&path).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("backend needs exactly one of uds or tcp"),
+            "{err}"
+        );
+
+        let path = dir.join("bridge.json");
+        std::fs::write(&path, "{}").unwrap();
+        assert!(matches!(
+            Config::from_file(&path),
+            Err(ConfigError::Format(_))
+        ));
+    }
+
+    #[test]
+    fn validation_errors_name_the_listener() {
+        let dir = cert_dir("validate");
+        let overrides = || Overrides {
+            address: Some("127.0.0.1:5047".to_string()),
+            cert: Some(dir.join("server.crt")),
+            key: Some(dir.join("server.key")),
+            backend: Some(BackendConfig::Uds("/run/greeter.sock".into())),
+            ..Default::default()
+        };
+        let valid = || {
+            let mut config = Config::default();
+            config.apply(overrides()).unwrap();
+            config
+        };
+        valid().validate().unwrap();
+
+        assert_eq!(error(&Config::default()), "config: no listeners configured");
+
+        let mut config = valid();
+        config.listeners[0].address = "localhost".to_string();
+        assert!(error(&config).starts_with("listeners[0]: invalid address \"localhost\""));
+
+        let mut config = valid();
+        config.listeners[0].name = Some("public".to_string());
+        config.listeners[0].tls.as_mut().unwrap().key = dir.join("missing.key");
+        assert_eq!(
+            error(&config),
+            format!(
+                "listener \"public\": tls.key {} does not exist",
+                dir.join("missing.key").display()
+            )
+        );
+
+        let mut config = valid();
+        config.listeners[0].backend = Some(BackendConfig::Tcp("localhost".into()));
+        assert_eq!(
+            error(&config),
+            "listeners[0] backend: tcp address \"localhost\" is not host:port"
+        );
+
+        let mut config = valid();
+        config.listeners[0].plaintext = true;
+        assert_eq!(
+            error(&config),
+            "listeners[0]: tls and plaintext are exclusive"
+        );
+        config.listeners[0].tls = None;
+        config.validate().unwrap();
+        config.listeners[0].plaintext = false;
+        assert_eq!(
+            error(&config),
+            "listeners[0]: tls is required unless plaintext = true"
+        );
+
+        let mut config = valid();
+        config.listeners[0].backend = None;
+        assert_eq!(
+            error(&config),
+            "listeners[0]: one of backend, backends, routes or sni_routes is required"
+        );
+
+        let mut config = valid();
+        config.listeners.push(config.listeners[0].clone());
+        assert_eq!(
+            error(&config),
+            "listeners[1]: address 127.0.0.1:5047 is already used by listeners[0]"
+        );
+        // overrides are ambiguous with several listeners
+        assert!(config.apply(overrides()).is_err());
+
+        let mut config = valid();
+        config.admin = Some(AdminSettings {
+            address: "127.0.0.1:5047".to_string(),
+        });
+        assert_eq!(
+            error(&config),
+            "admin: address 127.0.0.1:5047 is already used by listeners[0]"
+        );
+        config.admin.as_mut().unwrap().address = "localhost:9090".to_string();
+        assert!(error(&config).starts_with("admin: invalid address \"localhost:9090\""));
+
+        let mut config = valid();
+        let tokens = dir.join("tokens.txt");
+        std::fs::write(&tokens, "# rotated out\n\n").unwrap();
+        config.listeners[0].auth = Some(AuthSettings {
+            bearer_tokens_file: Some(tokens.clone()),
+            ..Default::default()
+        });
+        assert_eq!(
+            error(&config),
+            format!(
+                "listeners[0]: auth.bearer_tokens_file: no tokens in {}",
+                tokens.display()
+            )
+        );
+        std::fs::write(&tokens, "token-a\n").unwrap();
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn tracing_settings() {
+        let dir = cert_dir("tracing");
+        let config = write(
+            &dir,
+            "bridge.toml",
+            r#"
+[tracing]
+
+[[listeners]]
+address = "127.0.0.1:5047"
+plaintext = true
+backend = { uds = "/run/greeter.sock" }
+"#,
+        );
+        config.validate().unwrap();
+        let otlp = config.tracing.as_ref().unwrap().otlp_config().unwrap();
+        assert_eq!(otlp.endpoint, "http://127.0.0.1:4318");
+        assert_eq!(otlp.service_name, "grpc-bridge");
+
+        for endpoint in [
+            "https://collector:4318",
+            "collector:4318",
+            "http://collector/v1",
+        ] {
+            let mut config = config.clone();
+            config.tracing = Some(TracingSettings {
+                otlp_endpoint: endpoint.to_string(),
+                service_name: "bridge".to_string(),
+            });
+            assert_eq!(
+                error(&config),
+                format!("tracing: otlp endpoint {endpoint:?} is not http://host:port")
+            );
+        }
+    }
+
+    #[test]
+    fn access_log_settings() {
+        let dir = cert_dir("access-log");
+        let mut config = write(
+            &dir,
+            "bridge.yaml",
+            r#"
+access_log:
+  redact_headers: [authorization, x-api-key]
+  sample_rate: 0.25
+listeners:
+  - address: 127.0.0.1:5047
+    plaintext: true
+    backend: { uds: /run/greeter.sock }
+"#,
+        );
+        config.validate().unwrap();
+        let access_log = config.access_log.as_ref().unwrap();
+        assert_eq!(access_log.path, None);
+        let log = access_log.config().unwrap();
+        assert_eq!(
+            log.redact_headers,
+            [
+                HeaderName::from_static("authorization"),
+                HeaderName::from_static("x-api-key")
+            ]
+        );
+        assert_eq!(log.sample_rate, 0.25);
+
+        let access_log = config.access_log.as_mut().unwrap();
+        access_log.sample_rate = 1.5;
+        assert_eq!(
+            error(&config),
+            "access_log: sample_rate 1.5 is not between 0 and 1"
+        );
+        let access_log = config.access_log.as_mut().unwrap();
+        access_log.sample_rate = 1.0;
+        access_log.redact_headers = vec!["bad header".to_string()];
+        assert_eq!(
+            error(&config),
+            "access_log: redact_headers \"bad header\" is not a valid header name"
+        );
+    }
+
+    #[test]
+    fn overrides_replace_file_values() {
+        let dir = cert_dir("overrides");
+        let mut config = Config::default();
+        config
+            .apply(Overrides {
+                address: Some("127.0.0.1:5047".to_string()),
+                cert: Some(dir.join("server.crt")),
+                key: Some(dir.join("server.key")),
+                backend: Some(BackendConfig::Uds("/run/greeter.sock".into())),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(config.drain_timeout(), Duration::from_secs(30));
+        config
+            .apply(Overrides {
+                address: Some("127.0.0.1:6000".to_string()),
+                backend: Some(BackendConfig::Tcp("localhost:50051".into())),
+                drain_timeout_secs: Some(5),
+                admin_address: Some("127.0.0.1:9090".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.drain_timeout(), Duration::from_secs(5));
+        assert_eq!(
+            config.admin.as_ref().unwrap().addr().unwrap(),
+            "127.0.0.1:9090".parse().unwrap()
+        );
+        let listener = &config.listeners[0];
+        assert_eq!(listener.address, "127.0.0.1:6000");
+        assert_eq!(listener.tls.as_ref().unwrap().cert, dir.join("server.crt"));
+        assert_eq!(
+            listener.backend,
+            Some(BackendConfig::Tcp("localhost:50051".into()))
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! `grpc-bridge`: TLS-terminating gRPC proxy configured from a file, the
+//! deployable replacement of the C# proxy in front of greeter_server.
+
+#[cfg(unix)]
+mod bridge;
+#[cfg(unix)]
+mod config;
+
+#[cfg(unix)]
+use std::{path::PathBuf, process::ExitCode};
+
+#[cfg(unix)]
+use clap::Parser;
+
+#[cfg(unix)]
+#[derive(Debug, Parser)]
+#[command(version, about = "TLS-terminating gRPC proxy")]
+struct Args {
+    /// Config file (.toml, .yaml or .yml).
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+    /// Listen address, replaces the one of the only listener.
+    #[arg(long, value_name = "IP:PORT|unix:PATH")]
+    listen: Option<String>,
+    /// PEM certificate chain of the only listener.
+    #[arg(long, value_name = "PEM")]
+    cert: Option<PathBuf>,
+    /// PEM private key of the only listener.
+    #[arg(long, value_name = "PEM")]
+    key: Option<PathBuf>,
+    /// Forward to this unix socket.
+    #[arg(long, value_name = "PATH", conflicts_with = "backend_tcp")]
+    backend_uds: Option<PathBuf>,
+    /// Forward to this tcp address.
+    #[arg(long, value_name = "HOST:PORT")]
+    backend_tcp: Option<String>,
+    /// Open TLS to the tcp backend trusting this PEM CA bundle.
+    #[arg(long, value_name = "PEM", requires = "backend_tcp")]
+    backend_ca: Option<PathBuf>,
+    /// SNI and verified name of the TLS backend.
+    #[arg(long, value_name = "NAME", requires = "backend_ca")]
+    backend_server_name: Option<String>,
+    /// Serve cleartext h2 (prior knowledge) on the only listener.
+    #[arg(long, conflicts_with_all = ["cert", "key"])]
+    plaintext: bool,
+    /// Seconds in-flight calls may continue after SIGTERM before they are
+    /// cancelled.
+    #[arg(long, value_name = "SECS")]
+    drain_timeout: Option<u64>,
+    /// Serve Prometheus metrics on http://IP:PORT/metrics.
+    #[arg(long, value_name = "IP:PORT")]
+    admin: Option<String>,
+    /// Validate the config and exit.
+    #[arg(long)]
+    check: bool,
+}
+
+#[cfg(unix)]
+impl Args {
+    fn overrides(&self) -> config::Overrides {
+        let backend = match (&self.backend_uds, &self.backend_tcp, &self.backend_ca) {
+            (Some(path), _, _) => Some(config::BackendConfig::Uds(path.clone())),
+            (None, Some(addr), None) => Some(config::BackendConfig::Tcp(addr.clone())),
+            (None, Some(addr), Some(ca)) => Some(config::BackendConfig::TlsTcp {
+                addr: addr.clone(),
+                tls: config::UpstreamTlsSettings {
+                    ca: ca.clone(),
+                    server_name: self.backend_server_name.clone(),
+                },
+            }),
+            (None, None, _) => None,
+        };
+        config::Overrides {
+            address: self.listen.clone(),
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            backend,
+            plaintext: self.plaintext,
+            drain_timeout_secs: self.drain_timeout,
+            admin_address: self.admin.clone(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn main() -> ExitCode {
+    let args = Args::parse();
+    // before the runtime starts its threads, see cng::proxy::activation
+    if let Err(e) = cng::proxy::activation::take_listen_fds() {
+        eprintln!("error: cannot take sockets from systemd: {e}");
+        return ExitCode::FAILURE;
+    }
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("error: cannot start tokio runtime: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    runtime.block_on(run(args))
+}
+
+#[cfg(unix)]
+async fn run(args: Args) -> ExitCode {
+    let config = match load_config(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    if args.check {
+        println!("config ok");
+        return ExitCode::SUCCESS;
+    }
+
+    // sockets passed by systemd, taken in main
+    let activated = match cng::proxy::activation::listen_fds() {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            eprintln!("error: cannot take sockets from systemd: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let bridge = match bridge::Bridge::bind(&config, activated).await {
+        Ok(bridge) => bridge,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let token = tokio_util::sync::CancellationToken::new();
+    tokio::spawn(shutdown_on_signal(token.clone()));
+    match bridge.run(token).await {
+        Ok(()) => {
+            println!("server end");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(unix)]
+fn load_config(args: &Args) -> Result<config::Config, config::ConfigError> {
+    let mut config = match &args.config {
+        Some(path) => config::Config::from_file(path)?,
+        None => config::Config::default(),
+    };
+    config.apply(args.overrides())?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Cancels `token` on SIGINT or SIGTERM.
+#[cfg(unix)]
+async fn shutdown_on_signal(token: tokio_util::sync::CancellationToken) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut term = signal(SignalKind::terminate()).expect("cannot install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = term.recv() => {}
+    }
+    println!("shutting down");
+    token.cancel();
+}
+
+#[cfg(windows)]
+fn main() -> std::process::ExitCode {
+    eprintln!("grpc-bridge runs on Linux only, use the cng sample on Windows");
+    std::process::ExitCode::FAILURE
+}
@@ -0,0 +1,573 @@
+#![cfg(unix)]
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::Arc,
+    time::Duration,
+};
+
+use grpc_tests::helloworld_tonic::{greeter_client::GreeterClient, HelloRequest};
+use hyper_util::rt::TokioIo;
+use rustls::{
+    pki_types::{CertificateDer, ServerName},
+    ClientConfig, RootCertStore,
+};
+use tokio::net::{TcpStream, UnixListener, UnixStream};
+use tokio_rustls::TlsConnector;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+const BIN: &str = env!("CARGO_BIN_EXE_grpc-bridge");
+
+/// Fresh temp dir holding a self-signed `localhost` server.crt/server.key.
+fn temp_dir_with_cert(name: &str) -> (PathBuf, CertificateDer<'static>) {
+    let dir = cng::util::temp_dir(name);
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(dir.join("server.crt"), cert.cert.pem()).unwrap();
+    std::fs::write(dir.join("server.key"), cert.signing_key.serialize_pem()).unwrap();
+    (dir, cert.cert.der().clone())
+}
+
+fn free_port() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn write_config(dir: &Path, addr: SocketAddr, socket: &Path) -> PathBuf {
+    let path = dir.join("bridge.toml");
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+[[listeners]]
+name = "public"
+address = "{addr}"
+backend = {{ uds = "{}" }}
+[listeners.tls]
+cert = "{}"
+key = "{}"
+"#,
+            socket.display(),
+            dir.join("server.crt").display(),
+            dir.join("server.key").display(),
+        ),
+    )
+    .unwrap();
+    path
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn invalid_config_exits_with_2() {
+    let (dir, _) = temp_dir_with_cert("invalid");
+    let path = dir.join("bridge.toml");
+    std::fs::write(&path, "[[listeners]]\naddress = \"127.0.0.1:5047\"\n").unwrap();
+    let output = Command::new(BIN)
+        .arg("--config")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(
+        stderr(&output),
+        "error: listeners[0]: tls is required unless plaintext = true\n"
+    );
+}
+
+#[test]
+fn check_validates_without_binding() {
+    let (dir, _) = temp_dir_with_cert("check");
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let path = write_config(&dir, taken.local_addr().unwrap(), &dir.join("my.sock"));
+    let output = Command::new(BIN)
+        .arg("--config")
+        .arg(&path)
+        .arg("--check")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn bind_failure_exits_non_zero() {
+    let (dir, _) = temp_dir_with_cert("bind");
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = taken.local_addr().unwrap();
+    // no config file, everything from the command line
+    let output = Command::new(BIN)
+        .args(["--listen", &addr.to_string()])
+        .arg("--cert")
+        .arg(dir.join("server.crt"))
+        .arg("--key")
+        .arg(dir.join("server.key"))
+        .arg("--backend-uds")
+        .arg(dir.join("my.sock"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = stderr(&output);
+    assert!(
+        stderr.starts_with(&format!("error: listeners[0]: cannot bind {addr}: ")),
+        "{stderr}"
+    );
+}
+
+async fn tls_channel(addr: SocketAddr, root: CertificateDer<'static>) -> Channel {
+    let mut root_store = RootCertStore::empty();
+    root_store.add(root).unwrap();
+    let mut config = ClientConfig::builder_with_provider(cng::tls::default_provider().into())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = TlsConnector::from(Arc::new(config));
+    Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let connector = connector.clone();
+            async move {
+                let tcp = TcpStream::connect(addr).await?;
+                let domain = ServerName::try_from("localhost").unwrap();
+                let tls = connector.connect(domain, tcp).await?;
+                Ok::<_, std::io::Error>(TokioIo::new(tls))
+            }
+        }))
+        .await
+        .unwrap()
+}
+
+/// Runs the bridge with `args` and waits until it accepts on `addr`
+/// (`ip:port` or `unix:/path`).
+async fn spawn_bridge(args: &[&std::ffi::OsStr], addr: &str) -> tokio::process::Child {
+    let bridge = tokio::process::Command::new(BIN)
+        .args(args)
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    wait_accepting(addr).await;
+    bridge
+}
+
+async fn wait_accepting(addr: &str) {
+    for _ in 0..100 {
+        let ready = match addr.strip_prefix("unix:") {
+            Some(path) => UnixStream::connect(path).await.is_ok(),
+            None => TcpStream::connect(addr).await.is_ok(),
+        };
+        if ready {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn uds_channel(path: PathBuf) -> Channel {
+    Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            let path = path.clone();
+            async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(path).await?)) }
+        }))
+        .await
+        .unwrap()
+}
+
+async fn say_hello(channel: Channel, name: &str) -> String {
+    let mut client = GreeterClient::new(channel);
+    let reply = client
+        .say_hello(HelloRequest {
+            name: name.to_string(),
+        })
+        .await
+        .unwrap();
+    reply.into_inner().message
+}
+
+#[tokio::test]
+async fn forwards_to_uds_greeter() {
+    let (dir, cert) = temp_dir_with_cert("forward");
+    let socket = dir.join("greeter.sock");
+    let token = CancellationToken::new();
+    let listener = UnixListener::bind(&socket).unwrap();
+    let greeter = tokio::spawn(grpc_tests::server::serve_uds(
+        listener,
+        token.clone().cancelled_owned(),
+    ));
+
+    let addr = free_port();
+    let config = write_config(&dir, addr, &socket);
+    let mut bridge = spawn_bridge(
+        &["--config".as_ref(), config.as_os_str()],
+        &addr.to_string(),
+    )
+    .await;
+    assert_eq!(
+        say_hello(tls_channel(addr, cert).await, "bridge").await,
+        "Hello bridge!"
+    );
+
+    // reverse direction: cleartext on a unix socket, TLS to the bridge above
+    let reverse_socket = dir.join("reverse.sock");
+    let reverse_addr = format!("unix:{}", reverse_socket.display());
+    let backend_tcp = addr.to_string();
+    let mut reverse = spawn_bridge(
+        &[
+            "--listen".as_ref(),
+            reverse_addr.as_ref(),
+            "--plaintext".as_ref(),
+            "--backend-tcp".as_ref(),
+            backend_tcp.as_ref(),
+            "--backend-ca".as_ref(),
+            dir.join("server.crt").as_os_str(),
+            "--backend-server-name".as_ref(),
+            "localhost".as_ref(),
+        ],
+        &reverse_addr,
+    )
+    .await;
+    let channel = uds_channel(reverse_socket).await;
+    assert_eq!(say_hello(channel, "reverse").await, "Hello reverse!");
+    reverse.kill().await.unwrap();
+
+    // same listener switched to h2c from the command line
+    bridge.kill().await.unwrap();
+    let addr = free_port();
+    let listen = addr.to_string();
+    let mut bridge = spawn_bridge(
+        &[
+            "--config".as_ref(),
+            config.as_os_str(),
+            "--plaintext".as_ref(),
+            "--listen".as_ref(),
+            listen.as_ref(),
+        ],
+        &listen,
+    )
+    .await;
+    let channel = Endpoint::from_shared(format!("http://{addr}"))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(say_hello(channel, "h2c").await, "Hello h2c!");
+
+    bridge.kill().await.unwrap();
+    token.cancel();
+    greeter.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn serves_sockets_from_systemd() {
+    use std::os::{fd::AsRawFd, unix::process::CommandExt};
+
+    let (dir, cert) = temp_dir_with_cert("activation");
+    let socket = dir.join("greeter.sock");
+    let token = CancellationToken::new();
+    let greeter = tokio::spawn(grpc_tests::server::serve_uds(
+        UnixListener::bind(&socket).unwrap(),
+        token.clone().cancelled_owned(),
+    ));
+
+    // pre-bound like a .socket unit; still held here, so the bridge cannot
+    // bind the tcp address itself
+    let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let local_socket = dir.join("local.sock");
+    let unix = std::os::unix::net::UnixListener::bind(&local_socket).unwrap();
+    let config = write_config(&dir, addr, &socket);
+    let mut text = std::fs::read_to_string(&config).unwrap();
+    text.push_str(&format!(
+        "[[listeners]]\naddress = \"unix:{}\"\nplaintext = true\nbackend = {{ uds = \"{}\" }}\n",
+        local_socket.display(),
+        socket.display(),
+    ));
+    std::fs::write(&config, text).unwrap();
+
+    // "public" is matched by name, the unix socket by its path
+    let (tcp_fd, unix_fd) = (tcp.as_raw_fd(), unix.as_raw_fd());
+    let mut command = std::process::Command::new("/bin/sh");
+    command
+        .arg("-c")
+        .arg(r#"LISTEN_PID=$$ exec "$0" "$@""#)
+        .arg(BIN)
+        .arg("--config")
+        .arg(&config)
+        .env("LISTEN_FDS", "2")
+        .env("LISTEN_FDNAMES", "public:grpc-bridge.socket");
+    unsafe {
+        command.pre_exec(move || {
+            // move out of the way of 3 and 4 first, dup2 clears CLOEXEC
+            let tcp = libc::fcntl(tcp_fd, libc::F_DUPFD, 10);
+            let unix = libc::fcntl(unix_fd, libc::F_DUPFD, 10);
+            if tcp < 0 || unix < 0 || libc::dup2(tcp, 3) < 0 || libc::dup2(unix, 4) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut bridge = tokio::process::Command::from(command)
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+
+    assert_eq!(
+        say_hello(tls_channel(addr, cert).await, "systemd").await,
+        "Hello systemd!"
+    );
+    wait_accepting(&format!("unix:{}", local_socket.display())).await;
+    let channel = uds_channel(local_socket).await;
+    assert_eq!(say_hello(channel, "local").await, "Hello local!");
+
+    bridge.kill().await.unwrap();
+    token.cancel();
+    greeter.await.unwrap().unwrap();
+}
+
+/// Body of `GET /metrics` on the admin listener.
+async fn scrape(addr: SocketAddr) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+    body.to_string()
+}
+
+#[tokio::test]
+async fn exports_metrics_on_admin_address() {
+    let (dir, _) = temp_dir_with_cert("metrics");
+    let socket = dir.join("greeter.sock");
+    let token = CancellationToken::new();
+    let greeter = tokio::spawn(grpc_tests::server::serve_uds(
+        UnixListener::bind(&socket).unwrap(),
+        token.clone().cancelled_owned(),
+    ));
+
+    let listen = format!("unix:{}", dir.join("bridge.sock").display());
+    let admin = free_port();
+    let admin_arg = admin.to_string();
+    let mut bridge = spawn_bridge(
+        &[
+            "--listen".as_ref(),
+            listen.as_ref(),
+            "--plaintext".as_ref(),
+            "--backend-uds".as_ref(),
+            socket.as_os_str(),
+            "--admin".as_ref(),
+            admin_arg.as_ref(),
+        ],
+        &listen,
+    )
+    .await;
+    wait_accepting(&admin_arg).await;
+    let channel = uds_channel(dir.join("bridge.sock")).await;
+    for _ in 0..2 {
+        assert_eq!(
+            say_hello(channel.clone(), "metrics").await,
+            "Hello metrics!"
+        );
+    }
+
+    // calls are recorded once the bridge dropped their response body
+    let expected = format!(
+        "grpc_proxy_requests_total{{route=\"listeners[0]\",backend=\"unix:{}\",grpc_code=\"OK\"}} 2",
+        socket.display()
+    );
+    let mut text = String::new();
+    for _ in 0..100 {
+        text = scrape(admin).await;
+        if text.lines().any(|line| line == expected) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(text.lines().any(|line| line == expected), "{text}");
+    assert!(
+        text.contains("grpc_proxy_active_connections{route=\"listeners[0]\"} 1"),
+        "{text}"
+    );
+
+    bridge.kill().await.unwrap();
+    token.cancel();
+    greeter.await.unwrap().unwrap();
+}
+
+/// Accepts one OTLP/HTTP export, answers 200 and returns the request.
+async fn receive_export(listener: tokio::net::TcpListener) -> (String, Vec<u8>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let head_end = loop {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before the request head");
+        request.extend_from_slice(&chunk[..n]);
+        if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+    };
+    let head = String::from_utf8(request[..head_end].to_vec()).unwrap();
+    let length: usize = head
+        .lines()
+        .find_map(|line| {
+            line.to_ascii_lowercase()
+                .strip_prefix("content-length: ")
+                .map(str::to_string)
+        })
+        .unwrap()
+        .parse()
+        .unwrap();
+    while request.len() < head_end + length {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await.unwrap();
+        assert!(n > 0, "connection closed before the request body");
+        request.extend_from_slice(&chunk[..n]);
+    }
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    (head, request[head_end..].to_vec())
+}
+
+#[tokio::test]
+async fn exports_spans_on_shutdown() {
+    let (dir, _) = temp_dir_with_cert("tracing");
+    let socket = dir.join("greeter.sock");
+    let token = CancellationToken::new();
+    let greeter = tokio::spawn(grpc_tests::server::serve_uds(
+        UnixListener::bind(&socket).unwrap(),
+        token.clone().cancelled_owned(),
+    ));
+    let collector = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let collector_addr = collector.local_addr().unwrap();
+    let export = tokio::spawn(receive_export(collector));
+
+    let listen = dir.join("bridge.sock");
+    let config = dir.join("bridge.toml");
+    std::fs::write(
+        &config,
+        format!(
+            r#"
+[tracing]
+otlp_endpoint = "http://{collector_addr}"
+service_name = "bridge-test"
+
+[[listeners]]
+address = "unix:{}"
+plaintext = true
+backend = {{ uds = "{}" }}
+"#,
+            listen.display(),
+            socket.display(),
+        ),
+    )
+    .unwrap();
+    let mut bridge = spawn_bridge(
+        &["--config".as_ref(), config.as_os_str()],
+        &format!("unix:{}", listen.display()),
+    )
+    .await;
+    let channel = uds_channel(listen).await;
+    assert_eq!(say_hello(channel.clone(), "traced").await, "Hello traced!");
+    drop(channel);
+
+    // queued spans are sent before the bridge exits
+    let pid = bridge.id().unwrap() as libc::pid_t;
+    assert_eq!(unsafe { libc::kill(pid, libc::SIGTERM) }, 0);
+    let (head, body) = tokio::time::timeout(Duration::from_secs(10), export)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(head.starts_with("POST /v1/traces HTTP/1.1\r\n"), "{head}");
+    assert!(
+        head.to_ascii_lowercase()
+            .contains("content-type: application/x-protobuf"),
+        "{head}"
+    );
+    let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"bridge-test"));
+    assert!(contains(b"helloworld.Greeter/SayHello"));
+    assert!(bridge.wait().await.unwrap().success());
+
+    token.cancel();
+    greeter.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn writes_access_log_file() {
+    let (dir, _) = temp_dir_with_cert("access-log");
+    let socket = dir.join("greeter.sock");
+    let token = CancellationToken::new();
+    let greeter = tokio::spawn(grpc_tests::server::serve_uds(
+        UnixListener::bind(&socket).unwrap(),
+        token.clone().cancelled_owned(),
+    ));
+
+    let listen = dir.join("bridge.sock");
+    let log = dir.join("access.log");
+    let config = dir.join("bridge.toml");
+    std::fs::write(
+        &config,
+        format!(
+            r#"
+[access_log]
+path = "{}"
+
+[[listeners]]
+name = "local"
+address = "unix:{}"
+plaintext = true
+backend = {{ uds = "{}" }}
+"#,
+            log.display(),
+            listen.display(),
+            socket.display(),
+        ),
+    )
+    .unwrap();
+    let mut bridge = spawn_bridge(
+        &["--config".as_ref(), config.as_os_str()],
+        &format!("unix:{}", listen.display()),
+    )
+    .await;
+    let channel = uds_channel(listen).await;
+    assert_eq!(say_hello(channel, "logged").await, "Hello logged!");
+
+    // the line is written once the bridge dropped the response body
+    let mut text = String::new();
+    for _ in 0..100 {
+        text = std::fs::read_to_string(&log).unwrap();
+        if !text.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let line = text.lines().next().unwrap();
+    for expected in [
+        r#""route":"local""#,
+        r#""peer":"unix""#,
+        r#""path":"/helloworld.Greeter/SayHello""#,
+        r#""grpc_status":0"#,
+        r#""request_messages":1,"response_messages":1"#,
+    ] {
+        assert!(line.contains(expected), "{line}");
+    }
+
+    bridge.kill().await.unwrap();
+    token.cancel();
+    greeter.await.unwrap().unwrap();
+}
@@ -0,0 +1,12 @@
+//! TLS-terminating gRPC proxy pieces shared by the `cng` sample and the
+//! `grpc-bridge` binary.
+
+#[cfg(windows)]
+#[cfg(test)]
+mod test;
+
+#[cfg(unix)]
+pub mod proxy;
+
+pub mod tls;
+pub mod util;
@@ -1,35 +1,50 @@
-#[cfg(windows)]
-#[cfg(test)]
-mod test;
+use cng::{tls, util};
+use tokio_util::sync::CancellationToken;
 
 #[cfg(windows)]
-pub mod util;
+const USAGE: &str = "usage: cng [cert.pem key.pem]";
+#[cfg(unix)]
+const USAGE: &str = "usage: cng [cert.pem key.pem | --h2c]";
 
 // copies tls unencrypted data to uds
-#[tokio::main]
-async fn main() {
+fn main() {
+    // before the runtime starts its threads, see cng::proxy::activation
     #[cfg(unix)]
-    unix_main().await;
+    cng::proxy::activation::take_listen_fds()
+        .unwrap_or_else(|e| panic!("cannot take sockets from systemd: {e}"));
 
-    #[cfg(windows)]
-    win_main().await;
+    let runtime = tokio::runtime::Runtime::new().expect("cannot start tokio runtime");
+    runtime.block_on(run());
 }
 
-#[cfg(windows)]
-async fn win_main() {
-    use yarrp::CancellationToken;
-
-    let addr = "127.0.0.1:5047";
+/// `cng [cert.pem key.pem | --h2c]`: serves the given identity, reloading
+/// it when the files change, or the test certificate when no files are
+/// passed (see [`util::serve_proxy`]). `--h2c` serves cleartext h2 and is
+/// Linux only.
+async fn run() {
+    let addr = "127.0.0.1:5047".parse().unwrap();
     println!("start proxy at {addr}");
     let token = CancellationToken::new();
-    util::serve_proxy(addr.parse().unwrap(), token)
-        .await
-        .unwrap();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.as_slice() {
+        [cert, key] => {
+            let (server_config, resolver) = tls::TlsIdentityConfig::new(cert, key)
+                .load_reloadable_server_config(None)
+                .unwrap_or_else(|e| panic!("cannot load tls identity: {e}"));
+            // pick up rotated cert/key files without a restart
+            let watch_token = CancellationToken::new();
+            let watcher = resolver.watch(std::time::Duration::from_secs(30), watch_token.clone());
+            util::serve_proxy_with_config(addr, server_config, token)
+                .await
+                .unwrap();
+            watch_token.cancel();
+            watcher.await.unwrap();
+        }
+        #[cfg(unix)]
+        [flag] if flag == "--h2c" => util::serve_proxy_plaintext(addr, token).await.unwrap(),
+        [] => util::serve_proxy(addr, token).await.unwrap(),
+        _ => panic!("{USAGE}"),
+    }
 
     println!("server end")
 }
-
-#[cfg(unix)]
-async fn unix_main() {
-    panic!("unix net supported yet");
-}
@@ -0,0 +1,694 @@
+//! Structured access log of proxied calls, one JSON object per call.
+//!
+//! [`AccessLogLayer`] writes an [`AccessLogEntry`] once the response of a
+//! call ended (or was dropped): who called (peer address, mTLS subject),
+//! what (`:authority`, `:path`, request headers), where it went (the
+//! [`BackendName`] the [`ProxyService`] tags responses with) and how it
+//! ended (gRPC status and message, duration, messages in both directions).
+//! Values of [`AccessLogConfig::redact_headers`] are replaced, and
+//! successful calls are sampled at [`AccessLogConfig::sample_rate`]; failed
+//! ones are always logged.
+//!
+//! [`ProxyService`]: super::proxy_service::ProxyService
+
+use std::{
+    fmt::Write as _,
+    future::Future,
+    io::{self, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use http::{header::AUTHORIZATION, HeaderMap, HeaderName, Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use super::{
+    boxed_call,
+    message::MessageCounter,
+    proxy_service::BackendName,
+    status::{Code, GRPC_MESSAGE, GRPC_STATUS},
+    trace::CallTrace,
+    BoxError, ConnectionInfo, ProxyBody,
+};
+
+/// Logged in place of redacted header values.
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    /// Request headers logged as [`REDACTED`], `authorization` by default.
+    pub redact_headers: Vec<HeaderName>,
+    /// Fraction of successful calls logged, from 0 to 1.
+    pub sample_rate: f64,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            redact_headers: vec![AUTHORIZATION],
+            sample_rate: 1.0,
+        }
+    }
+}
+
+/// One finished call.
+#[derive(Debug, Clone)]
+pub struct AccessLogEntry {
+    /// When the request headers arrived.
+    pub time: SystemTime,
+    /// Route the call came in on, see [`AccessLog::layer`].
+    pub route: Arc<str>,
+    pub peer: Option<String>,
+    /// Subject of the verified client certificate.
+    pub identity: Option<String>,
+    /// Of the W3C trace the call is part of, when traced.
+    pub trace_id: Option<String>,
+    pub authority: Option<String>,
+    pub path: String,
+    /// Request headers in arrival order, repeated names joined by `, `.
+    pub headers: Vec<(String, String)>,
+    pub backend: Option<String>,
+    /// `UNKNOWN` when the response ended without a status, `CANCELLED`
+    /// when it was dropped before its end.
+    pub grpc_status: Code,
+    /// Percent-decoded `grpc-message`.
+    pub grpc_message: Option<String>,
+    pub duration: Duration,
+    pub request_messages: u64,
+    pub response_messages: u64,
+}
+
+impl AccessLogEntry {
+    /// The entry as a single line JSON object.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        field(&mut out, "time", &rfc3339(self.time));
+        field(&mut out, "route", &self.route);
+        for (key, value) in [
+            ("peer", &self.peer),
+            ("identity", &self.identity),
+            ("trace_id", &self.trace_id),
+            ("authority", &self.authority),
+        ] {
+            if let Some(value) = value {
+                field(&mut out, key, value);
+            }
+        }
+        field(&mut out, "path", &self.path);
+        if let Some(backend) = &self.backend {
+            field(&mut out, "backend", backend);
+        }
+        let _ = write!(out, ",\"grpc_status\":{}", self.grpc_status.as_i32());
+        field(&mut out, "grpc_code", self.grpc_status.name());
+        if let Some(message) = &self.grpc_message {
+            field(&mut out, "grpc_message", message);
+        }
+        let _ = write!(
+            out,
+            ",\"duration_ms\":{:.3},\"request_messages\":{},\"response_messages\":{}",
+            self.duration.as_secs_f64() * 1000.0,
+            self.request_messages,
+            self.response_messages
+        );
+        out.push_str(",\"headers\":{");
+        for (i, (name, value)) in self.headers.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            json_string(&mut out, name);
+            out.push(':');
+            json_string(&mut out, value);
+        }
+        out.push_str("}}");
+        out
+    }
+}
+
+/// `,"key":"value"`, or without the comma for the first field.
+fn field(out: &mut String, key: &str, value: &str) {
+    if out.len() > 1 {
+        out.push(',');
+    }
+    json_string(out, key);
+    out.push(':');
+    json_string(out, value);
+}
+
+fn json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// UTC timestamp with milliseconds, e.g. `2024-05-01T12:00:00.250Z`.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // civil date from days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// `grpc-message` is percent-encoded on the wire.
+fn percent_decode(value: &[u8]) -> String {
+    let mut out = Vec::with_capacity(value.len());
+    let mut i = 0;
+    while i < value.len() {
+        let hex = value
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (value[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Receives the entries of logged calls; must not block for long, as it
+/// runs on the connection task.
+pub trait AccessLogSink: Send + Sync + 'static {
+    fn log(&self, entry: &AccessLogEntry);
+}
+
+/// Writes entries as JSON lines, e.g. to stdout or a file.
+pub struct JsonLines<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write + Send + 'static> JsonLines<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl JsonLines<io::Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+}
+
+impl<W: Write + Send + 'static> AccessLogSink for JsonLines<W> {
+    fn log(&self, entry: &AccessLogEntry) {
+        let mut line = entry.to_json();
+        line.push('\n');
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            eprintln!("access log write failed: {e}");
+        }
+    }
+}
+
+/// Access log shared by the routes of a proxy; clones write to the same
+/// sink.
+#[derive(Clone)]
+pub struct AccessLog {
+    config: Arc<AccessLogConfig>,
+    sink: Arc<dyn AccessLogSink>,
+}
+
+impl AccessLog {
+    pub fn new(config: AccessLogConfig, sink: impl AccessLogSink) -> Self {
+        Self {
+            config: Arc::new(config),
+            sink: Arc::new(sink),
+        }
+    }
+
+    /// Layer logging the calls of `route`.
+    pub fn layer(&self, route: impl Into<String>) -> AccessLogLayer {
+        AccessLogLayer {
+            log: self.clone(),
+            route: route.into().into(),
+        }
+    }
+
+    fn sampled(&self) -> bool {
+        let rate = self.config.sample_rate;
+        if rate >= 1.0 {
+            return true;
+        }
+        let mut bytes = [0; 4];
+        if SystemRandom::new().fill(&mut bytes).is_err() {
+            return true;
+        }
+        f64::from(u32::from_le_bytes(bytes)) < rate * f64::from(u32::MAX)
+    }
+}
+
+/// Logs every call of one route, see [`AccessLog::layer`].
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    log: AccessLog,
+    route: Arc<str>,
+}
+
+impl<S> tower::Layer<S> for AccessLogLayer {
+    type Service = AccessLogService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLogService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Request bodies reach the inner service as a [`ProxyBody`] counting
+/// the messages received.
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+    layer: AccessLogLayer,
+}
+
+impl<S, B> tower::Service<Request<B>> for AccessLogService<S>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let info = req.extensions().get::<ConnectionInfo>();
+        let entry = AccessLogEntry {
+            time: SystemTime::now(),
+            route: self.layer.route.clone(),
+            peer: info.map(|info| info.peer_addr.to_string()),
+            identity: info
+                .and_then(|info| info.client_identity.as_ref())
+                .map(|identity| identity.subject.clone()),
+            trace_id: req
+                .extensions()
+                .get::<CallTrace>()
+                .map(|trace| trace.trace_id().to_string()),
+            authority: req.uri().authority().map(|a| a.to_string()),
+            path: req.uri().path().to_string(),
+            headers: logged_headers(req.headers(), &self.layer.log.config.redact_headers),
+            backend: None,
+            grpc_status: Code::Unknown,
+            grpc_message: None,
+            duration: Duration::ZERO,
+            request_messages: 0,
+            response_messages: 0,
+        };
+        let request_messages = Arc::new(AtomicU64::new(0));
+        let mut call = Call {
+            log: self.layer.log.clone(),
+            entry: Some(entry),
+            start: Instant::now(),
+            request_messages: request_messages.clone(),
+            response_messages: MessageCounter::default(),
+            code: None,
+            message: None,
+            ended: false,
+        };
+        let mut counter = MessageCounter::default();
+        let req = req.map(|body| {
+            body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref() {
+                    counter.feed(data);
+                    request_messages.store(counter.count(), Ordering::Relaxed);
+                }
+                frame
+            })
+            .map_err(Into::into)
+            .boxed()
+        });
+        let fut = boxed_call(self.inner.call(req));
+        Box::pin(async move {
+            // an error resets the stream, the call is logged as UNKNOWN
+            let resp = match fut.await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    call.end();
+                    return Err(e);
+                }
+            };
+            call.status_from(resp.headers());
+            if let Some(entry) = &mut call.entry {
+                entry.backend = resp
+                    .extensions()
+                    .get::<BackendName>()
+                    .map(|b| b.as_str().to_string());
+            }
+            Ok(resp.map(|inner| AccessLogBody { inner, call }.boxed()))
+        })
+    }
+}
+
+fn logged_headers(headers: &HeaderMap, redact: &[HeaderName]) -> Vec<(String, String)> {
+    headers
+        .keys()
+        .map(|name| {
+            let value = if redact.contains(name) {
+                REDACTED.to_string()
+            } else {
+                headers
+                    .get_all(name)
+                    .iter()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// One call in flight, logged when dropped.
+struct Call {
+    log: AccessLog,
+    /// Taken on drop.
+    entry: Option<AccessLogEntry>,
+    start: Instant,
+    request_messages: Arc<AtomicU64>,
+    response_messages: MessageCounter,
+    code: Option<Code>,
+    message: Option<String>,
+    /// The response body ended, see [`AccessLogEntry::grpc_status`].
+    ended: bool,
+}
+
+impl Call {
+    fn end(&mut self) {
+        self.ended = true;
+    }
+
+    /// Takes the status from trailers, or the headers of a trailers-only
+    /// response.
+    fn status_from(&mut self, headers: &HeaderMap) {
+        if let Some(code) = headers.get(GRPC_STATUS).and_then(Code::from_header) {
+            self.code = Some(code);
+            self.message = headers
+                .get(GRPC_MESSAGE)
+                .map(|m| percent_decode(m.as_bytes()));
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let Some(mut entry) = self.entry.take() else {
+            return;
+        };
+        let code = self.code.unwrap_or(if self.ended {
+            Code::Unknown
+        } else {
+            Code::Cancelled
+        });
+        if code == Code::Ok && !self.log.sampled() {
+            return;
+        }
+        entry.grpc_status = code;
+        entry.grpc_message = self.message.take();
+        entry.duration = self.start.elapsed();
+        entry.request_messages = self.request_messages.load(Ordering::Relaxed);
+        entry.response_messages = self.response_messages.count();
+        self.log.sink.log(&entry);
+    }
+}
+
+/// Response body counting the messages sent and reading the status from
+/// the trailers.
+struct AccessLogBody {
+    inner: ProxyBody,
+    call: Call,
+}
+
+impl Body for AccessLogBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    self.call.response_messages.feed(data);
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    self.call.status_from(trailers);
+                }
+            }
+            Some(Err(_)) => {}
+            None => self.call.end(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use grpc_tests::helloworld_tonic::{greeter_client::GreeterClient, HelloRequest};
+    use http::Request;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        percent_decode, rfc3339, AccessLog, AccessLogConfig, AccessLogEntry, AccessLogSink,
+        REDACTED,
+    };
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        status::Code,
+        test_util::{
+            self, client_config, h2_client, post, spawn_proxy_service, spawn_uds_greeter,
+            temp_socket_path, tls_channel, wait_until,
+        },
+    };
+
+    #[test]
+    fn formats_json_line() {
+        let entry = AccessLogEntry {
+            time: SystemTime::UNIX_EPOCH + Duration::from_millis(1_714_564_800_250),
+            route: Arc::from("public"),
+            peer: Some("127.0.0.1:5000".to_string()),
+            identity: None,
+            trace_id: None,
+            authority: Some("localhost".to_string()),
+            path: "/helloworld.Greeter/SayHello".to_string(),
+            headers: vec![
+                ("authorization".to_string(), "[redacted]".to_string()),
+                ("x-note".to_string(), "a \"b\"\n".to_string()),
+            ],
+            backend: Some("unix:/run/greeter.sock".to_string()),
+            grpc_status: Code::NotFound,
+            grpc_message: Some(percent_decode(b"no %E2%9C%93 here")),
+            duration: Duration::from_micros(1500),
+            request_messages: 1,
+            response_messages: 0,
+        };
+        assert_eq!(
+            entry.to_json(),
+            concat!(
+                r#"{"time":"2024-05-01T12:00:00.250Z","route":"public","#,
+                r#""peer":"127.0.0.1:5000","authority":"localhost","#,
+                r#""path":"/helloworld.Greeter/SayHello","#,
+                r#""backend":"unix:/run/greeter.sock","grpc_status":5,"#,
+                r#""grpc_code":"NOT_FOUND","grpc_message":"no ✓ here","#,
+                r#""duration_ms":1.500,"request_messages":1,"response_messages":0,"#,
+                r#""headers":{"authorization":"[redacted]","x-note":"a \"b\"\n"}}"#,
+            )
+        );
+        assert_eq!(
+            rfc3339(SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    /// Keeps logged entries for assertions.
+    #[derive(Clone, Default)]
+    struct CollectedLog(Arc<std::sync::Mutex<Vec<AccessLogEntry>>>);
+
+    impl AccessLogSink for CollectedLog {
+        fn log(&self, entry: &AccessLogEntry) {
+            self.0.lock().unwrap().push(entry.clone());
+        }
+    }
+
+    impl CollectedLog {
+        fn entries(&self) -> Vec<AccessLogEntry> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_calls_as_json() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("access-log");
+        let backend = spawn_uds_greeter(&socket, token.child_token());
+        let collected = CollectedLog::default();
+        let log = AccessLog::new(
+            AccessLogConfig {
+                redact_headers: vec![
+                    http::header::AUTHORIZATION,
+                    http::HeaderName::from_static("x-secret"),
+                ],
+                ..AccessLogConfig::default()
+            },
+            collected.clone(),
+        );
+        let service = tower::Layer::layer(
+            &log.layer("public"),
+            ProxyService::new(UdsConnector::new(&socket)).with_name("greeter"),
+        );
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) =
+            spawn_proxy_service(server_config.clone(), service, token.child_token()).await;
+
+        let mut client = GreeterClient::new(tls_channel(addr, cert.clone()).await);
+        let mut req = tonic::Request::new(HelloRequest {
+            name: "log".to_string(),
+        });
+        req.metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        req.metadata_mut()
+            .insert("x-secret", "hunter2".parse().unwrap());
+        req.metadata_mut().insert("x-note", "kept".parse().unwrap());
+        client.say_hello(req).await.unwrap();
+        wait_until(|| collected.entries().len() == 1).await;
+
+        let entry = &collected.entries()[0];
+        assert_eq!(&*entry.route, "public");
+        assert!(entry.peer.as_deref().unwrap().starts_with("127.0.0.1:"));
+        assert_eq!(entry.identity, None);
+        assert_eq!(entry.path, "/helloworld.Greeter/SayHello");
+        assert_eq!(entry.backend.as_deref(), Some("greeter"));
+        assert_eq!(entry.grpc_status, Code::Ok);
+        assert_eq!((entry.request_messages, entry.response_messages), (1, 1));
+        let header = |name: &str| {
+            entry
+                .headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(header("authorization"), Some(REDACTED));
+        assert_eq!(header("x-secret"), Some(REDACTED));
+        assert_eq!(header("x-note"), Some("kept"));
+        let json = entry.to_json();
+        assert!(!json.contains("hunter2"), "{json}");
+        assert!(json.contains(r#""grpc_code":"OK""#), "{json}");
+
+        // with sampling off only failures are logged
+        let sampled = CollectedLog::default();
+        let log = AccessLog::new(
+            AccessLogConfig {
+                sample_rate: 0.0,
+                ..AccessLogConfig::default()
+            },
+            sampled.clone(),
+        );
+        let service = tower::Layer::layer(
+            &log.layer("sampled"),
+            ProxyService::new(UdsConnector::new(&socket)),
+        );
+        let (sampled_addr, sampled_proxy) =
+            spawn_proxy_service(server_config, service, token.child_token()).await;
+        let mut client = GreeterClient::new(tls_channel(sampled_addr, cert.clone()).await);
+        client
+            .say_hello(HelloRequest {
+                name: "log".to_string(),
+            })
+            .await
+            .unwrap();
+        let mut sender = h2_client(sampled_addr, client_config(cert, None))
+            .await
+            .unwrap();
+        let resp = post(
+            &mut sender,
+            Request::builder().uri("/helloworld.Greeter/Nope"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.grpc_status(), Some("12"));
+        wait_until(|| sampled.entries().len() == 1).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let entries = sampled.entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].grpc_status, Code::Unimplemented);
+        assert_eq!(entries[0].path, "/helloworld.Greeter/Nope");
+        assert_eq!(
+            (entries[0].request_messages, entries[0].response_messages),
+            (0, 0)
+        );
+
+        drop((client, sender));
+        token.cancel();
+        proxy.await.unwrap();
+        sampled_proxy.await.unwrap();
+        backend.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
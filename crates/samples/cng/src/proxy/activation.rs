@@ -0,0 +1,271 @@
+//! systemd socket activation, see `sd_listen_fds(3)`.
+//!
+//! systemd passes the sockets of a `.socket` unit as file descriptors
+//! starting at 3 and describes them in `LISTEN_PID`, `LISTEN_FDS` and
+//! `LISTEN_FDNAMES`. Both TCP and unix stream sockets are accepted.
+//!
+//! Changing the environment while another thread reads it is undefined
+//! behaviour, so the variables are read and cleared by [`take_listen_fds`]
+//! from a synchronous `main`, before the tokio runtime starts any thread.
+//! The listeners are made from the taken sockets later, on the runtime,
+//! by [`listen_fds`].
+
+use std::{
+    env, io,
+    net::SocketAddr,
+    os::fd::{BorrowedFd, FromRawFd, RawFd},
+    sync::Mutex,
+};
+
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::net::{TcpListener, UnixListener};
+
+/// First file descriptor passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+const LISTEN_PID: &str = "LISTEN_PID";
+const LISTEN_FDS: &str = "LISTEN_FDS";
+const LISTEN_FDNAMES: &str = "LISTEN_FDNAMES";
+
+/// Sockets taken by [`take_listen_fds`] until [`listen_fds`] wants them.
+static TAKEN: Mutex<Vec<TakenSocket>> = Mutex::new(Vec::new());
+
+/// A listening stream socket owned by this process, with its name.
+type TakenSocket = (Option<String>, Socket);
+
+#[derive(Debug)]
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A listening socket inherited from systemd.
+#[derive(Debug)]
+pub struct ActivatedSocket {
+    /// Entry of `LISTEN_FDNAMES` (`FileDescriptorName=` of the socket unit),
+    /// `None` when systemd did not pass names.
+    pub name: Option<String>,
+    pub listener: ActivatedListener,
+}
+
+/// Takes the sockets passed to this process, if any, for [`listen_fds`].
+/// The variables are removed so child processes do not pick them up,
+/// hence later calls find nothing.
+///
+/// Must be called before any other thread is started, in particular before
+/// the tokio runtime: use a synchronous `main` that builds the runtime
+/// after this.
+pub fn take_listen_fds() -> io::Result<()> {
+    let pid = env::var(LISTEN_PID).ok();
+    let fds = env::var(LISTEN_FDS).ok();
+    let names = env::var(LISTEN_FDNAMES).ok();
+    for var in [LISTEN_PID, LISTEN_FDS, LISTEN_FDNAMES] {
+        env::remove_var(var);
+    }
+    let count = fd_count(pid.as_deref(), fds.as_deref(), std::process::id())?;
+    let names = fd_names(names.as_deref(), count)?;
+    // SAFETY: systemd hands these fds over to this process and nothing else
+    // in it owns them; the variables are gone so this happens once.
+    let sockets = unsafe { take_fds(LISTEN_FDS_START, names) }?;
+    TAKEN.lock().unwrap().extend(sockets);
+    Ok(())
+}
+
+/// The sockets taken by [`take_listen_fds`] as tokio listeners, empty when
+/// the process was not socket activated. Only the first call returns them.
+///
+/// Must be called from a tokio runtime.
+pub fn listen_fds() -> io::Result<Vec<ActivatedSocket>> {
+    let taken = std::mem::take(&mut *TAKEN.lock().unwrap());
+    (taken.into_iter())
+        .map(|(name, socket)| {
+            let listener = into_listener(socket)?;
+            Ok(ActivatedSocket { name, listener })
+        })
+        .collect()
+}
+
+/// The first inherited TCP socket, or a new one bound to `addr` when the
+/// process was not socket activated. Any other inherited sockets, unix
+/// ones included, are closed; use [`listen_fds`] to serve several.
+pub async fn tcp_listener_or_bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    for socket in listen_fds()? {
+        if let ActivatedListener::Tcp(listener) = socket.listener {
+            return Ok(listener);
+        }
+    }
+    TcpListener::bind(addr).await
+}
+
+/// Number of passed fds; 0 when the variables are missing or meant for
+/// another process (`LISTEN_PID` differs).
+fn fd_count(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> io::Result<usize> {
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return Ok(0);
+    };
+    let pid: u32 = pid
+        .parse()
+        .map_err(|_| invalid(format!("invalid {LISTEN_PID} {pid:?}")))?;
+    if pid != own_pid {
+        return Ok(0);
+    }
+    fds.parse()
+        .map_err(|_| invalid(format!("invalid {LISTEN_FDS} {fds:?}")))
+}
+
+fn fd_names(names: Option<&str>, count: usize) -> io::Result<Vec<Option<String>>> {
+    let Some(names) = names else {
+        return Ok(vec![None; count]);
+    };
+    let names: Vec<_> = names
+        .split(':')
+        .map(|name| Some(name.to_string()))
+        .collect();
+    if names.len() != count {
+        return Err(invalid(format!(
+            "{LISTEN_FDNAMES} has {} names for {count} fds",
+            names.len()
+        )));
+    }
+    Ok(names)
+}
+
+/// Takes ownership of the fds `start..start + names.len()` once each was
+/// checked to be a listening stream socket. An fd failing the check is left
+/// alone.
+///
+/// # Safety
+///
+/// The fds must be open and not owned by anything else.
+unsafe fn take_fds(start: RawFd, names: Vec<Option<String>>) -> io::Result<Vec<TakenSocket>> {
+    let mut sockets = Vec::with_capacity(names.len());
+    for (fd, name) in (start..).zip(names) {
+        check_listener(BorrowedFd::borrow_raw(fd))
+            .map_err(|e| io::Error::new(e.kind(), format!("inherited fd {fd}: {e}")))?;
+        let socket = Socket::from_raw_fd(fd);
+        socket.set_cloexec(true)?;
+        sockets.push((name, socket));
+    }
+    Ok(sockets)
+}
+
+fn check_listener(fd: BorrowedFd<'_>) -> io::Result<()> {
+    let socket = SockRef::from(&fd);
+    if socket.r#type()? != Type::STREAM || !socket.is_listener()? {
+        return Err(invalid("not a listening stream socket".to_string()));
+    }
+    let domain = socket.domain()?;
+    if domain != Domain::IPV4 && domain != Domain::IPV6 && domain != Domain::UNIX {
+        return Err(invalid(format!("unsupported socket domain {domain:?}")));
+    }
+    Ok(())
+}
+
+fn into_listener(socket: Socket) -> io::Result<ActivatedListener> {
+    socket.set_nonblocking(true)?;
+    if socket.domain()? == Domain::UNIX {
+        Ok(ActivatedListener::Unix(UnixListener::from_std(
+            socket.into(),
+        )?))
+    } else {
+        Ok(ActivatedListener::Tcp(TcpListener::from_std(
+            socket.into(),
+        )?))
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
+    #[test]
+    fn env_parsing() {
+        assert_eq!(fd_count(None, None, 7).unwrap(), 0);
+        assert_eq!(fd_count(Some("7"), None, 7).unwrap(), 0);
+        assert_eq!(fd_count(Some("8"), Some("2"), 7).unwrap(), 0);
+        assert_eq!(fd_count(Some("7"), Some("2"), 7).unwrap(), 2);
+        assert!(fd_count(Some("7"), Some("two"), 7).is_err());
+
+        assert_eq!(fd_names(None, 2).unwrap(), vec![None, None]);
+        assert_eq!(
+            fd_names(Some("public:local"), 2).unwrap(),
+            vec![Some("public".to_string()), Some("local".to_string())]
+        );
+        let err = fd_names(Some("public"), 2).unwrap_err();
+        assert_eq!(err.to_string(), "LISTEN_FDNAMES has 1 names for 2 fds");
+    }
+
+    /// Duplicates `fds` onto consecutive free fds, like systemd passes them
+    /// from 3 on, and returns the first. Unlike `dup2`, `F_DUPFD` never
+    /// replaces an fd that other tests running in parallel have open.
+    fn dup_consecutive(fds: &[RawFd]) -> RawFd {
+        let mut min = 100;
+        'retry: loop {
+            let start = unsafe { libc::fcntl(fds[0], libc::F_DUPFD_CLOEXEC, min) };
+            assert!(start >= 0, "{}", io::Error::last_os_error());
+            for (fd, want) in fds[1..].iter().zip(start + 1..) {
+                let dup = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, want) };
+                assert!(dup >= 0, "{}", io::Error::last_os_error());
+                if dup != want {
+                    // `want` was taken meanwhile, try again past it
+                    for fd in (start..want).chain([dup]) {
+                        unsafe { libc::close(fd) };
+                    }
+                    min = dup + 1;
+                    continue 'retry;
+                }
+            }
+            return start;
+        }
+    }
+
+    #[tokio::test]
+    async fn takes_tcp_and_unix_listeners() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp_addr = tcp.local_addr().unwrap();
+        let dir = crate::util::temp_dir("activation");
+        let path = dir.join("activated.sock");
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let stream = std::net::TcpStream::connect(tcp_addr).unwrap();
+        let start = dup_consecutive(&[tcp.as_raw_fd(), unix.as_raw_fd(), stream.as_raw_fd()]);
+
+        let names = vec![Some("public".to_string()), Some("local".to_string())];
+        let sockets: Vec<_> = (unsafe { take_fds(start, names) }.unwrap().into_iter())
+            .map(|(name, socket)| {
+                let listener = into_listener(socket).unwrap();
+                ActivatedSocket { name, listener }
+            })
+            .collect();
+        assert_eq!(sockets.len(), 2);
+        assert_eq!(sockets[0].name.as_deref(), Some("public"));
+        match &sockets[0].listener {
+            ActivatedListener::Tcp(listener) => {
+                assert_eq!(listener.local_addr().unwrap(), tcp_addr)
+            }
+            other => panic!("expected tcp, got {other:?}"),
+        }
+        match &sockets[1].listener {
+            ActivatedListener::Unix(listener) => assert_eq!(
+                listener.local_addr().unwrap().as_pathname(),
+                Some(path.as_path())
+            ),
+            other => panic!("expected unix, got {other:?}"),
+        }
+
+        let err = unsafe { take_fds(start + 2, vec![None]) }.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("inherited fd {}: not a listening stream socket", start + 2)
+        );
+        // checked without being taken, so it is still open
+        assert!(unsafe { libc::fcntl(start + 2, libc::F_GETFD) } >= 0);
+        unsafe { libc::close(start + 2) };
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,50 @@
+//! When data last moved on a stream or connection, for idle timeouts.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Last time data moved, touched from the I/O path without locking.
+#[derive(Debug)]
+pub(super) struct Activity {
+    start: Instant,
+    /// Since `start`.
+    last_millis: AtomicU64,
+}
+
+impl Activity {
+    pub(super) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_millis: AtomicU64::new(0),
+        }
+    }
+
+    /// When this tracker was created, the activity before any `touch`.
+    pub(super) fn start(&self) -> Instant {
+        self.start
+    }
+
+    pub(super) fn touch(&self) {
+        let millis = self.start.elapsed().as_millis() as u64;
+        self.last_millis.fetch_max(millis, Ordering::Relaxed);
+    }
+
+    pub(super) fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last_millis.load(Ordering::Relaxed))
+    }
+
+    /// Resolves once nothing moved for `timeout`.
+    pub(super) async fn idle(&self, timeout: Duration) {
+        loop {
+            let due = self.last() + timeout;
+            if due <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(due).await;
+        }
+    }
+}
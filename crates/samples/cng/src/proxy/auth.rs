@@ -0,0 +1,358 @@
+//! Header-token authentication in front of the forwarding service, the Rust
+//! port of `HeaderAuthentication.cs`.
+
+use std::{
+    future::Future,
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use http::{header::AUTHORIZATION, HeaderMap, HeaderName, HeaderValue, Request, Response};
+use ring::digest::{digest, SHA256, SHA256_OUTPUT_LEN};
+
+use super::{
+    status::{self, Code},
+    BoxError, ProxyBody,
+};
+
+/// Token prefix of clients talking to the proxy (`HeaderAuthConstants`).
+pub const TOKEN_PROXY_PREFIX: &str = "ProxyToken-";
+/// Token prefix the proxy uses toward the backend.
+pub const TOKEN_DIRECT_PREFIX: &str = "DirectToken-";
+
+/// Validates the request headers. The error message is returned to the
+/// client as the `grpc-message` of an `UNAUTHENTICATED` status.
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate(&self, headers: &HeaderMap) -> Result<(), String>;
+}
+
+/// Reads `header` as a non-empty string.
+fn header_str<'a>(headers: &'a HeaderMap, header: &HeaderName) -> Result<&'a str, String> {
+    let value = headers
+        .get(header)
+        .ok_or_else(|| format!("Missing {header} header"))?
+        .to_str()
+        .map_err(|_| format!("Invalid {header} header"))?;
+    if value.is_empty() {
+        return Err(format!("Empty {header} header"));
+    }
+    Ok(value)
+}
+
+/// Accepts any value of `header` that starts with `prefix`, e.g.
+/// `Authorization: ProxyToken-...`.
+#[derive(Debug, Clone)]
+pub struct PrefixAuthenticator {
+    header: HeaderName,
+    prefix: String,
+}
+
+impl PrefixAuthenticator {
+    pub fn new(header: HeaderName, prefix: impl Into<String>) -> Self {
+        Self {
+            header,
+            prefix: prefix.into(),
+        }
+    }
+
+    /// `Authorization` header with the given prefix.
+    pub fn authorization(prefix: impl Into<String>) -> Self {
+        Self::new(AUTHORIZATION, prefix)
+    }
+}
+
+impl Authenticator for PrefixAuthenticator {
+    fn authenticate(&self, headers: &HeaderMap) -> Result<(), String> {
+        let value = header_str(headers, &self.header)?;
+        if !value.starts_with(&self.prefix) {
+            return Err(format!("Invalid token prefix in {} header", self.header));
+        }
+        Ok(())
+    }
+}
+
+/// Accepts `header` values that exactly match one of a set of tokens.
+/// With a `scheme` (e.g. `Bearer`) the value must be `<scheme> <token>`.
+///
+/// Only SHA-256 digests of the tokens are kept, and a value is checked
+/// against all of them in constant time.
+#[derive(Debug, Clone)]
+pub struct TokenAuthenticator {
+    header: HeaderName,
+    scheme: Option<String>,
+    digests: Vec<TokenDigest>,
+}
+
+type TokenDigest = [u8; SHA256_OUTPUT_LEN];
+
+fn token_digest(token: &str) -> TokenDigest {
+    let mut out = [0; SHA256_OUTPUT_LEN];
+    out.copy_from_slice(digest(&SHA256, token.as_bytes()).as_ref());
+    out
+}
+
+/// Compares without branching on the contents, so the time taken says
+/// nothing about how much of a digest matched.
+fn digests_equal(a: &TokenDigest, b: &TokenDigest) -> bool {
+    let diff = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+impl TokenAuthenticator {
+    pub fn new(
+        header: HeaderName,
+        scheme: Option<String>,
+        tokens: impl IntoIterator<Item = String>,
+    ) -> Self {
+        Self {
+            header,
+            scheme,
+            digests: tokens.into_iter().map(|t| token_digest(&t)).collect(),
+        }
+    }
+
+    /// `Authorization: Bearer <token>` against `tokens`.
+    pub fn bearer(tokens: impl IntoIterator<Item = String>) -> Self {
+        Self::new(AUTHORIZATION, Some("Bearer".to_string()), tokens)
+    }
+
+    /// Bearer tokens from a file with one token per line. Blank lines and
+    /// lines starting with `#` are ignored. A file without tokens is an
+    /// error, since it would reject every call.
+    pub fn bearer_from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        let tokens: Vec<_> = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect();
+        if tokens.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no tokens in {}", path.display()),
+            ));
+        }
+        Ok(Self::bearer(tokens))
+    }
+}
+
+impl Authenticator for TokenAuthenticator {
+    fn authenticate(&self, headers: &HeaderMap) -> Result<(), String> {
+        let value = header_str(headers, &self.header)?;
+        let token = match &self.scheme {
+            Some(scheme) => value
+                .split_once(' ')
+                .filter(|(s, _)| s.eq_ignore_ascii_case(scheme))
+                .map(|(_, token)| token.trim())
+                .ok_or_else(|| format!("Expected {scheme} token in {} header", self.header))?,
+            None => value,
+        };
+        let digest = token_digest(token);
+        // every digest is compared, a match does not end the loop early
+        let matched = (self.digests.iter()).fold(false, |matched, known| {
+            matched | digests_equal(known, &digest)
+        });
+        if !matched {
+            return Err(format!("Invalid token in {} header", self.header));
+        }
+        Ok(())
+    }
+}
+
+/// Applies an [`Authenticator`] to every request and optionally rewrites
+/// the credential header before forwarding, like the C# `CustomTransformer`
+/// swapping the proxy token for the direct one.
+#[derive(Clone)]
+pub struct AuthLayer {
+    authenticator: Arc<dyn Authenticator>,
+    strip: Vec<HeaderName>,
+    insert: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl AuthLayer {
+    pub fn new(authenticator: impl Authenticator) -> Self {
+        Self {
+            authenticator: Arc::new(authenticator),
+            strip: Vec::new(),
+            insert: Vec::new(),
+        }
+    }
+
+    /// Removes `header` from authenticated requests.
+    pub fn strip_header(mut self, header: HeaderName) -> Self {
+        self.strip.push(header);
+        self
+    }
+
+    /// Sets `header` on authenticated requests, after stripping.
+    pub fn insert_header(mut self, header: HeaderName, value: HeaderValue) -> Self {
+        self.insert.push((header, value));
+        self
+    }
+}
+
+impl<S> tower::Layer<S> for AuthLayer {
+    type Service = AuthService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthService<S> {
+    inner: S,
+    layer: AuthLayer,
+}
+
+impl<S, B> tower::Service<Request<B>> for AuthService<S>
+where
+    S: tower::Service<Request<B>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        if let Err(message) = self.layer.authenticator.authenticate(req.headers()) {
+            eprintln!("authentication failed for {}: {message}", req.uri().path());
+            let resp = status::trailers_only(Code::Unauthenticated, message);
+            return Box::pin(async move { Ok(resp) });
+        }
+        let headers = req.headers_mut();
+        for header in &self.layer.strip {
+            headers.remove(header);
+        }
+        for (header, value) in &self.layer.insert {
+            headers.insert(header, value.clone());
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{header::AUTHORIZATION, HeaderMap, Request};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        AuthLayer, Authenticator, PrefixAuthenticator, TokenAuthenticator, TOKEN_DIRECT_PREFIX,
+        TOKEN_PROXY_PREFIX,
+    };
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        test_util::{
+            self, client_config, h2_client, post, spawn_echo_backend, spawn_proxy_service,
+            temp_socket_path,
+        },
+    };
+
+    fn headers(value: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = value {
+            headers.insert(AUTHORIZATION, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn prefix_authenticator() {
+        let auth = PrefixAuthenticator::authorization(TOKEN_PROXY_PREFIX);
+        auth.authenticate(&headers(Some("ProxyToken-abc"))).unwrap();
+        for (value, err) in [
+            (None, "Missing authorization header"),
+            (Some(""), "Empty authorization header"),
+            (
+                Some("DirectToken-abc"),
+                "Invalid token prefix in authorization header",
+            ),
+        ] {
+            assert_eq!(auth.authenticate(&headers(value)).unwrap_err(), err);
+        }
+    }
+
+    #[test]
+    fn bearer_tokens_from_file() {
+        let path = std::env::temp_dir().join(format!("cng-tokens-{}.txt", std::process::id()));
+        std::fs::write(&path, "# ops\ntoken-a\n\n  token-b  \n").unwrap();
+        let auth = TokenAuthenticator::bearer_from_file(&path).unwrap();
+
+        auth.authenticate(&headers(Some("Bearer token-a"))).unwrap();
+        auth.authenticate(&headers(Some("bearer token-b"))).unwrap();
+        for value in [
+            Some("Bearer token-c"),
+            Some("Bearer token-"),
+            Some("Bearer token-ab"),
+            Some("Bearer # ops"),
+            Some("token-a"),
+            Some("Basic token-a"),
+            None,
+        ] {
+            assert!(auth.authenticate(&headers(value)).is_err(), "{value:?}");
+        }
+
+        std::fs::write(&path, "# ops\n\n").unwrap();
+        let err = TokenAuthenticator::bearer_from_file(&path).err().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.to_string(), format!("no tokens in {}", path.display()));
+    }
+
+    #[tokio::test]
+    async fn rejects_unauthenticated_calls() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("auth");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let direct_token = format!("{TOKEN_DIRECT_PREFIX}directtoken-xyz");
+        let service = tower::Layer::layer(
+            &AuthLayer::new(PrefixAuthenticator::authorization(TOKEN_PROXY_PREFIX))
+                .strip_header(http::header::AUTHORIZATION)
+                .insert_header(http::header::AUTHORIZATION, direct_token.parse().unwrap()),
+            ProxyService::new(UdsConnector::new(&socket)),
+        );
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, service, token.child_token()).await;
+        let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+
+        for value in [None, Some("DirectToken-abc")] {
+            let mut req = Request::builder().uri("/helloworld.Greeter/SayHello");
+            if let Some(value) = value {
+                req = req.header("authorization", value);
+            }
+            let resp = post(&mut sender, req).await.unwrap();
+            assert_eq!(resp.grpc_status(), Some("16"));
+            assert!(resp.trailers.is_none());
+        }
+
+        let resp = post(
+            &mut sender,
+            Request::builder()
+                .uri("/helloworld.Greeter/SayHello")
+                .header("authorization", "ProxyToken-abc"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.grpc_status(), Some("0"));
+        // only the authenticated call reached the backend, with the direct token
+        let parts = backend.recv().await.unwrap();
+        assert_eq!(parts.headers["authorization"], direct_token.as_str());
+        assert!(backend.try_recv().is_err());
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+}
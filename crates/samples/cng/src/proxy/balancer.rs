@@ -0,0 +1,430 @@
+//! Spreads calls across replicas of a backend.
+//!
+//! Balancing happens per call, not per connection: every replica keeps its
+//! own shared h2 connection (see [`ProxyService`]) and each request picks a
+//! replica anew, so one long-lived client connection still reaches all of
+//! them. Replicas whose [`BackendHealth`] is unhealthy are skipped.
+//!
+//! [`BackendHealth`]: super::health::BackendHealth
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::Body;
+
+use super::{
+    connector::Connector,
+    proxy_service::ProxyService,
+    status::{self, Code},
+    BoxError, ProxyBody,
+};
+
+/// Points per replica on the consistent hash ring; more points spread
+/// keys more evenly.
+const RING_POINTS: usize = 64;
+
+/// How [`Balancer`] picks a replica for a call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BalancePolicy {
+    RoundRobin,
+    /// The replica with the fewest calls in flight, round-robin on ties.
+    LeastOutstanding,
+    /// The replica owning the hash of this header's value on a hash ring,
+    /// so calls with the same value stick to one replica and only the keys
+    /// of a replica move when it goes away. Calls without the header are
+    /// balanced round-robin.
+    ConsistentHash(HeaderName),
+}
+
+/// Forwards each call to one of several replicas, see [`BalancePolicy`].
+/// When no replica is healthy the call fails with `UNAVAILABLE`.
+pub struct Balancer<C> {
+    inner: Arc<Inner<C>>,
+}
+
+struct Inner<C> {
+    policy: BalancePolicy,
+    replicas: Vec<Replica<C>>,
+    next: AtomicUsize,
+    /// Sorted `(point, replica index)`, only for consistent hashing.
+    ring: Vec<(u64, usize)>,
+}
+
+struct Replica<C> {
+    name: String,
+    service: ProxyService<C>,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl<C> Clone for Balancer<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C: Connector> Balancer<C> {
+    /// `replicas` are named for status messages and hash ring placement;
+    /// it must not be empty.
+    pub fn new(
+        policy: BalancePolicy,
+        replicas: impl IntoIterator<Item = (String, ProxyService<C>)>,
+    ) -> Self {
+        let replicas: Vec<_> = replicas
+            .into_iter()
+            .map(|(name, service)| Replica {
+                name,
+                service,
+                outstanding: Arc::default(),
+            })
+            .collect();
+        assert!(!replicas.is_empty(), "balancer needs at least one replica");
+        let mut ring = Vec::new();
+        if let BalancePolicy::ConsistentHash(_) = policy {
+            for (i, replica) in replicas.iter().enumerate() {
+                for point in 0..RING_POINTS {
+                    ring.push((ring_hash(format!("{}#{point}", replica.name).as_bytes()), i));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Self {
+            inner: Arc::new(Inner {
+                policy,
+                replicas,
+                next: AtomicUsize::new(0),
+                ring,
+            }),
+        }
+    }
+
+    /// Calls in flight per replica, in the order they were given.
+    pub fn outstanding(&self) -> Vec<usize> {
+        self.inner
+            .replicas
+            .iter()
+            .map(|r| r.outstanding.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+impl<C: Connector> Inner<C> {
+    fn is_healthy(&self, i: usize) -> bool {
+        self.replicas[i]
+            .service
+            .health()
+            .is_none_or(|health| health.is_healthy())
+    }
+
+    /// Index of the replica for a call with `headers`, `None` when no
+    /// replica is healthy.
+    fn pick(&self, headers: &HeaderMap) -> Option<usize> {
+        let n = self.replicas.len();
+        match &self.policy {
+            BalancePolicy::ConsistentHash(header) => match headers.get(header) {
+                Some(value) => self.pick_hashed(ring_hash(value.as_bytes())),
+                None => self.pick_round_robin(),
+            },
+            BalancePolicy::RoundRobin => self.pick_round_robin(),
+            BalancePolicy::LeastOutstanding => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..n)
+                    .map(|k| (start + k) % n)
+                    .filter(|&i| self.is_healthy(i))
+                    .min_by_key(|&i| self.replicas[i].outstanding.load(Ordering::Relaxed))
+            }
+        }
+    }
+
+    fn pick_round_robin(&self) -> Option<usize> {
+        let n = self.replicas.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..n)
+            .map(|k| (start + k) % n)
+            .find(|&i| self.is_healthy(i))
+    }
+
+    /// First healthy replica clockwise from `hash` on the ring.
+    fn pick_hashed(&self, hash: u64) -> Option<usize> {
+        let start = self.ring.partition_point(|&(point, _)| point < hash);
+        (0..self.ring.len())
+            .map(|k| self.ring[(start + k) % self.ring.len()].1)
+            .find(|&i| self.is_healthy(i))
+    }
+
+    fn unavailable_message(&self) -> String {
+        let reasons: Vec<_> = self
+            .replicas
+            .iter()
+            .map(|replica| {
+                let reason = replica
+                    .service
+                    .health()
+                    .and_then(|health| health.reason())
+                    .unwrap_or_default();
+                format!("{}: {reason}", replica.name)
+            })
+            .collect();
+        format!("no healthy backend ({})", reasons.join("; "))
+    }
+}
+
+/// 64-bit FNV-1a followed by the murmur3 finalizer, stable across
+/// processes so proxy replicas agree on the ring. FNV alone barely moves
+/// the high bits for keys differing in their last bytes (`user-1`,
+/// `user-2`), which would bunch them up on the ring.
+fn ring_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in data {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+impl<C, B> tower::Service<Request<B>> for Balancer<C>
+where
+    C: Connector,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let Some(i) = self.inner.pick(req.headers()) else {
+            let resp = status::trailers_only(Code::Unavailable, self.inner.unavailable_message());
+            return Box::pin(async move { Ok(resp) });
+        };
+        let replica = &self.inner.replicas[i];
+        let mut service = replica.service.clone();
+        let outstanding = OutstandingGuard::new(replica.outstanding.clone());
+        Box::pin(async move {
+            let resp = tower::Service::call(&mut service, req).await?;
+            // the call is outstanding until its response body is done
+            Ok(resp.map(|body| {
+                body.map_frame(move |frame| {
+                    let _ = &outstanding;
+                    frame
+                })
+                .boxed()
+            }))
+        })
+    }
+}
+
+/// Holds one call in a replica's outstanding count until dropped.
+struct OutstandingGuard(Arc<AtomicUsize>);
+
+impl OutstandingGuard {
+    fn new(count: Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for OutstandingGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use grpc_tests::helloworld_tonic::{greeter_client::GreeterClient, HelloRequest};
+    use http::Request;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{ring_hash, BalancePolicy, Balancer};
+    use crate::proxy::{
+        connector::UdsConnector,
+        health::{spawn_health_check, HealthCheckConfig},
+        proxy_service::ProxyService,
+        test_util::{
+            self, client_config, h2_client, open_echo_stream, post, served_by, spawn_balanced_echo,
+            spawn_counting_greeter, spawn_health_backend, spawn_proxy_service, temp_socket_path,
+            tls_channel, unary, wait_until,
+        },
+    };
+
+    #[test]
+    fn ring_hash_is_stable() {
+        assert_eq!(ring_hash(b""), 0xefd0_1f60_ba99_2926);
+        assert_eq!(ring_hash(b"a"), 0x82a2_a958_a9be_ce5b);
+    }
+
+    #[tokio::test]
+    async fn round_robin_across_greeters() {
+        let token = CancellationToken::new();
+        let mut replicas = Vec::new();
+        let mut calls = Vec::new();
+        for i in 0..3 {
+            let socket = temp_socket_path(&format!("rr-{i}"));
+            calls.push(spawn_counting_greeter(&socket, token.child_token()));
+            replicas.push((
+                format!("greeter-{i}"),
+                ProxyService::new(UdsConnector::new(&socket)),
+            ));
+        }
+        let balancer = Balancer::new(BalancePolicy::RoundRobin, replicas);
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, balancer, token.child_token()).await;
+
+        // one client connection, balanced per call
+        let mut client = GreeterClient::new(tls_channel(addr, cert).await);
+        for _ in 0..30 {
+            let reply = client
+                .say_hello(HelloRequest {
+                    name: "replica".to_string(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(reply.into_inner().message, "Hello replica!");
+        }
+        let counts: Vec<_> = calls.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        assert_eq!(counts, [10, 10, 10]);
+
+        drop(client);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn least_outstanding_avoids_busy_replicas() {
+        let token = CancellationToken::new();
+        let (mut sender, mut heads, balancer) =
+            spawn_balanced_echo("lor", BalancePolicy::LeastOutstanding, &token).await;
+
+        // two long calls occupy two replicas
+        let (first, _first_body) = open_echo_stream(&mut sender).await;
+        let a = served_by(&mut heads);
+        let (second, _second_body) = open_echo_stream(&mut sender).await;
+        let b = served_by(&mut heads);
+        assert_ne!(a, b);
+        let idle = 3 - a - b;
+
+        // short calls all go to the idle one
+        for _ in 0..4 {
+            unary(&mut sender, None).await;
+            assert_eq!(served_by(&mut heads), idle);
+        }
+        let mut outstanding = [0; 3];
+        outstanding[a] = 1;
+        outstanding[b] = 1;
+        assert_eq!(balancer.outstanding(), outstanding);
+
+        drop((first, second));
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_sticks_to_replica() {
+        let token = CancellationToken::new();
+        let policy = BalancePolicy::ConsistentHash(http::HeaderName::from_static("x-user"));
+        let (mut sender, mut heads, _) = spawn_balanced_echo("hash", policy, &token).await;
+
+        let mut used = [false; 3];
+        for user in 0..20 {
+            let user = format!("user-{user}");
+            unary(&mut sender, Some(&user)).await;
+            let replica = served_by(&mut heads);
+            for _ in 0..3 {
+                unary(&mut sender, Some(&user)).await;
+                assert_eq!(served_by(&mut heads), replica, "{user}");
+            }
+            used[replica] = true;
+        }
+        assert_eq!(used, [true; 3]);
+
+        // without the header calls rotate
+        let mut served = Vec::new();
+        for _ in 0..3 {
+            unary(&mut sender, None).await;
+            served.push(served_by(&mut heads));
+        }
+        served.sort();
+        assert_eq!(served, [0, 1, 2]);
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn balancer_skips_unhealthy_replicas() {
+        let token = CancellationToken::new();
+        let mut replicas = Vec::new();
+        let mut statuses = Vec::new();
+        for name in ["a", "b"] {
+            let socket = temp_socket_path(&format!("balance-health-{name}"));
+            let status = Arc::new(AtomicU64::new(1));
+            spawn_health_backend(&socket, status.clone(), token.child_token());
+            let config = HealthCheckConfig {
+                interval: Duration::from_millis(20),
+                unhealthy_threshold: 1,
+                ..Default::default()
+            };
+            let health = spawn_health_check(
+                name,
+                UdsConnector::new(&socket),
+                config,
+                token.child_token(),
+            );
+            statuses.push((status, health.clone()));
+            let service = ProxyService::new(UdsConnector::new(&socket)).with_health(health);
+            replicas.push((name.to_string(), service));
+        }
+        let balancer = Balancer::new(BalancePolicy::RoundRobin, replicas);
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, balancer, token.child_token()).await;
+        let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+        let say_hello = || Request::builder().uri("/helloworld.Greeter/SayHello");
+
+        // with a down, every call lands on b
+        statuses[0].0.store(2, Ordering::Relaxed);
+        wait_until(|| !statuses[0].1.is_healthy()).await;
+        for _ in 0..4 {
+            let result = post(&mut sender, say_hello()).await.unwrap();
+            assert_eq!(result.grpc_status(), Some("0"));
+        }
+
+        statuses[1].0.store(3, Ordering::Relaxed);
+        wait_until(|| !statuses[1].1.is_healthy()).await;
+        let result = post(&mut sender, say_hello()).await.unwrap();
+        assert_eq!(result.grpc_status(), Some("14"));
+        assert_eq!(
+            result.head.headers["grpc-message"],
+            "no healthy backend (a: health check returned NOT_SERVING; \
+             b: health check returned SERVICE_UNKNOWN)"
+        );
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+}
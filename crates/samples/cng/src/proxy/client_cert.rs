@@ -0,0 +1,221 @@
+//! Forwarding of the verified mTLS client identity to the backend.
+
+use http::{HeaderMap, HeaderName, HeaderValue};
+use rustls::{pki_types::CertificateDer, ServerConnection};
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
+
+/// Prefix of every identity header. Client-sent headers with this prefix
+/// are always dropped so they cannot be spoofed.
+pub const CLIENT_CERT_HEADER_PREFIX: &str = "x-client-cert-";
+/// Subject distinguished name, e.g. `CN=client, O=org`.
+pub const CLIENT_CERT_SUBJECT: HeaderName = HeaderName::from_static("x-client-cert-subject");
+/// Comma separated subject alternative names, e.g. `DNS:a.example, IP:10.0.0.1`.
+pub const CLIENT_CERT_SAN: HeaderName = HeaderName::from_static("x-client-cert-san");
+/// Lowercase hex SHA-256 of the DER leaf certificate.
+pub const CLIENT_CERT_FINGERPRINT: HeaderName =
+    HeaderName::from_static("x-client-cert-fingerprint");
+
+/// Identity of a client whose certificate was verified during the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub subject: String,
+    pub sans: Vec<String>,
+    pub fingerprint: String,
+}
+
+impl ClientIdentity {
+    /// Reads the identity from the leaf certificate of a finished handshake.
+    /// Returns `None` for anonymous clients.
+    pub fn from_connection(conn: &ServerConnection) -> Option<Self> {
+        Self::from_der(conn.peer_certificates()?.first()?)
+    }
+
+    pub fn from_der(der: &CertificateDer<'_>) -> Option<Self> {
+        let (_, cert) = x509_parser::certificate::X509Certificate::from_der(der).ok()?;
+        let sans = match cert.subject_alternative_name() {
+            Ok(Some(ext)) => ext
+                .value
+                .general_names
+                .iter()
+                .filter_map(format_general_name)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let digest = ring::digest::digest(&ring::digest::SHA256, der);
+        Some(Self {
+            subject: cert.subject().to_string(),
+            sans,
+            fingerprint: digest.as_ref().iter().map(|b| format!("{b:02x}")).collect(),
+        })
+    }
+}
+
+fn format_general_name(name: &GeneralName<'_>) -> Option<String> {
+    match name {
+        GeneralName::DNSName(n) => Some(format!("DNS:{n}")),
+        GeneralName::RFC822Name(n) => Some(format!("email:{n}")),
+        GeneralName::URI(n) => Some(format!("URI:{n}")),
+        GeneralName::IPAddress(ip) => match ip.len() {
+            4 => Some(format!(
+                "IP:{}",
+                std::net::Ipv4Addr::from(<[u8; 4]>::try_from(*ip).ok()?)
+            )),
+            16 => Some(format!(
+                "IP:{}",
+                std::net::Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?)
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Drops any identity headers sent by the client and sets the verified
+/// ones, if there is an identity.
+pub fn set_identity_headers(headers: &mut HeaderMap, identity: Option<&ClientIdentity>) {
+    let spoofed = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with(CLIENT_CERT_HEADER_PREFIX))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in spoofed {
+        headers.remove(name);
+    }
+    let Some(identity) = identity else {
+        return;
+    };
+    let values = [
+        (CLIENT_CERT_SUBJECT, identity.subject.clone()),
+        (CLIENT_CERT_SAN, identity.sans.join(", ")),
+        (CLIENT_CERT_FINGERPRINT, identity.fingerprint.clone()),
+    ];
+    for (name, value) in values {
+        // non visible-ascii subjects cannot be carried in a header
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            if !value.is_empty() {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::Request;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{CLIENT_CERT_FINGERPRINT, CLIENT_CERT_SAN, CLIENT_CERT_SUBJECT};
+    use crate::{
+        proxy::test_util::{
+            cert_der, client_config, h2_client, post, spawn_echo_backend, spawn_proxy,
+            temp_socket_path,
+        },
+        tls::{
+            test_certs::{localhost_params, write_ca, write_self_signed, write_signed},
+            ClientAuthConfig, ClientAuthMode, TlsIdentityConfig,
+        },
+        util::temp_dir,
+    };
+
+    /// mTLS setup: server identity, client CA and a client cert issued by it.
+    fn mtls_certs(name: &str) -> (std::path::PathBuf, TlsIdentityConfig, TlsIdentityConfig) {
+        let dir = temp_dir(name);
+        let server = write_self_signed(&dir, "server", localhost_params());
+        let ca = write_ca(&dir, "clients");
+        let mut params = rcgen::CertificateParams::new(vec!["client.example".to_string()]).unwrap();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "test-client");
+        let client = write_signed(&dir, "client", params, &ca);
+        (dir.join("clients.crt"), server, client)
+    }
+
+    #[tokio::test]
+    async fn mtls_forwards_verified_identity() {
+        let token = CancellationToken::new();
+        let (ca, server, client) = mtls_certs("mtls-required");
+        let socket = temp_socket_path("mtls-required");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let client_auth = ClientAuthConfig::new(ca, ClientAuthMode::Required);
+        let server_config = server
+            .load_server_config_with_client_auth(Some(&client_auth))
+            .unwrap();
+        let (addr, proxy) = spawn_proxy(server_config, &socket, token.child_token()).await;
+
+        let mut sender = h2_client(addr, client_config(cert_der(&server), Some(&client)))
+            .await
+            .unwrap();
+        let resp = post(
+            &mut sender,
+            Request::builder()
+                .uri("/helloworld.Greeter/SayHello")
+                .header(CLIENT_CERT_SUBJECT, "CN=admin")
+                .header("x-client-cert-role", "admin"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.grpc_status(), Some("0"));
+
+        let parts = backend.recv().await.unwrap();
+        assert_eq!(parts.uri.path(), "/helloworld.Greeter/SayHello");
+        assert_eq!(parts.headers[CLIENT_CERT_SUBJECT], "CN=test-client");
+        assert_eq!(parts.headers[CLIENT_CERT_SAN], "DNS:client.example");
+        let expected =
+            crate::proxy::client_cert::ClientIdentity::from_der(&cert_der(&client)).unwrap();
+        assert_eq!(
+            parts.headers[CLIENT_CERT_FINGERPRINT],
+            expected.fingerprint.as_str()
+        );
+        assert_eq!(expected.fingerprint.len(), 64);
+        assert!(!parts.headers.contains_key("x-client-cert-role"));
+
+        // anonymous clients do not get through
+        let anonymous = async {
+            let mut sender = h2_client(addr, client_config(cert_der(&server), None)).await?;
+            post(
+                &mut sender,
+                Request::builder().uri("/helloworld.Greeter/SayHello"),
+            )
+            .await
+        };
+        assert!(anonymous.await.is_err());
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mtls_optional_strips_spoofed_identity() {
+        let token = CancellationToken::new();
+        let (ca, server, _) = mtls_certs("mtls-optional");
+        let socket = temp_socket_path("mtls-optional");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let client_auth = ClientAuthConfig::new(ca, ClientAuthMode::Optional);
+        let server_config = server
+            .load_server_config_with_client_auth(Some(&client_auth))
+            .unwrap();
+        let (addr, proxy) = spawn_proxy(server_config, &socket, token.child_token()).await;
+
+        let mut sender = h2_client(addr, client_config(cert_der(&server), None))
+            .await
+            .unwrap();
+        post(
+            &mut sender,
+            Request::builder()
+                .uri("/helloworld.Greeter/SayHello")
+                .header(CLIENT_CERT_SUBJECT, "CN=admin"),
+        )
+        .await
+        .unwrap();
+        let parts = backend.recv().await.unwrap();
+        assert!(parts
+            .headers
+            .keys()
+            .all(|name| !name.as_str().starts_with("x-client-cert-")));
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use std::{fmt, future::Future, io, path::PathBuf, sync::Arc};
+
+use bytes::Bytes;
+use rustls::{pki_types::ServerName, ClientConfig};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+};
+use tokio_rustls::client::TlsStream;
+use tokio_util::either::Either;
+
+use crate::tls::{TlsConfigError, UpstreamTlsConfig};
+
+/// Opens byte streams to the backend the proxy forwards to.
+pub trait Connector: Send + Sync + 'static {
+    type Io: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    fn connect(&self) -> impl Future<Output = io::Result<Self::Io>> + Send;
+
+    /// Connects and sends `preamble` first, e.g. a PROXY protocol header;
+    /// TLS connectors send it before the handshake.
+    fn connect_with_preamble(
+        &self,
+        preamble: &[u8],
+    ) -> impl Future<Output = io::Result<Self::Io>> + Send {
+        async move {
+            let mut io = self.connect().await?;
+            io.write_all(preamble).await?;
+            Ok(io)
+        }
+    }
+}
+
+/// Sends the same preamble on every connection of `C`, e.g. a `LOCAL`
+/// PROXY protocol header for health checks.
+#[derive(Debug, Clone)]
+pub struct PreambleConnector<C> {
+    inner: C,
+    preamble: Bytes,
+}
+
+impl<C> PreambleConnector<C> {
+    pub fn new(inner: C, preamble: Bytes) -> Self {
+        Self { inner, preamble }
+    }
+}
+
+impl<C: Connector> Connector for PreambleConnector<C> {
+    type Io = C::Io;
+
+    async fn connect(&self) -> io::Result<C::Io> {
+        self.inner.connect_with_preamble(&self.preamble).await
+    }
+}
+
+/// Connects to a backend listening on a unix domain socket.
+#[derive(Debug, Clone)]
+pub struct UdsConnector {
+    path: PathBuf,
+}
+
+impl UdsConnector {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Connector for UdsConnector {
+    type Io = UnixStream;
+
+    async fn connect(&self) -> io::Result<UnixStream> {
+        UnixStream::connect(&self.path).await
+    }
+}
+
+/// Connects to a plaintext (h2c) backend over TCP.
+#[derive(Debug, Clone)]
+pub struct TcpConnector {
+    addr: String,
+}
+
+impl TcpConnector {
+    /// `addr` is `host:port`, resolved on every connect.
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self { addr: addr.into() }
+    }
+}
+
+impl Connector for TcpConnector {
+    type Io = TcpStream;
+
+    async fn connect(&self) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+/// Connects to a backend over TCP and TLS, e.g. a remote gRPC server.
+#[derive(Clone)]
+pub struct TlsTcpConnector {
+    tcp: TcpConnector,
+    server_name: ServerName<'static>,
+    connector: tokio_rustls::TlsConnector,
+}
+
+impl TlsTcpConnector {
+    /// `config` should offer h2 via ALPN, see
+    /// [`UpstreamTlsConfig::load_client_config`].
+    pub fn new(
+        addr: impl Into<String>,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> Self {
+        Self {
+            tcp: TcpConnector::new(addr),
+            server_name,
+            connector: tokio_rustls::TlsConnector::from(config),
+        }
+    }
+
+    /// Loads the roots of `upstream` and resolves its server name.
+    pub fn from_config(
+        addr: impl Into<String>,
+        upstream: &UpstreamTlsConfig,
+    ) -> Result<Self, TlsConfigError> {
+        let addr = addr.into();
+        let server_name = upstream.server_name(&addr)?;
+        let config = upstream.load_client_config()?;
+        Ok(Self::new(addr, server_name, Arc::new(config)))
+    }
+}
+
+impl fmt::Debug for TlsTcpConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsTcpConnector")
+            .field("addr", &self.tcp.addr)
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl Connector for TlsTcpConnector {
+    type Io = TlsStream<TcpStream>;
+
+    async fn connect(&self) -> io::Result<Self::Io> {
+        self.connect_with_preamble(&[]).await
+    }
+
+    async fn connect_with_preamble(&self, preamble: &[u8]) -> io::Result<Self::Io> {
+        let stream = self.tcp.connect_with_preamble(preamble).await?;
+        self.connector
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+}
+
+/// Where a backend listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackendAddr {
+    Uds(PathBuf),
+    /// `host:port`
+    Tcp(String),
+}
+
+impl fmt::Display for BackendAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uds(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+        }
+    }
+}
+
+/// Connector for either kind of [`BackendAddr`], or a TLS backend.
+#[derive(Debug, Clone)]
+pub enum BackendConnector {
+    Uds(UdsConnector),
+    Tcp(TcpConnector),
+    TlsTcp(TlsTcpConnector),
+}
+
+impl From<BackendAddr> for BackendConnector {
+    fn from(addr: BackendAddr) -> Self {
+        match addr {
+            BackendAddr::Uds(path) => Self::Uds(UdsConnector::new(path)),
+            BackendAddr::Tcp(addr) => Self::Tcp(TcpConnector::new(addr)),
+        }
+    }
+}
+
+impl Connector for BackendConnector {
+    type Io = Either<UnixStream, Either<TcpStream, TlsStream<TcpStream>>>;
+
+    async fn connect(&self) -> io::Result<Self::Io> {
+        match self {
+            Self::Uds(c) => c.connect().await.map(Either::Left),
+            Self::Tcp(c) => c.connect().await.map(|s| Either::Right(Either::Left(s))),
+            Self::TlsTcp(c) => c.connect().await.map(|s| Either::Right(Either::Right(s))),
+        }
+    }
+
+    async fn connect_with_preamble(&self, preamble: &[u8]) -> io::Result<Self::Io> {
+        match self {
+            Self::Uds(c) => c.connect_with_preamble(preamble).await.map(Either::Left),
+            Self::Tcp(c) => {
+                (c.connect_with_preamble(preamble).await).map(|s| Either::Right(Either::Left(s)))
+            }
+            Self::TlsTcp(c) => {
+                (c.connect_with_preamble(preamble).await).map(|s| Either::Right(Either::Right(s)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use grpc_tests::helloworld_tonic::{greeter_client::GreeterClient, HelloRequest};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::{UnixListener, UnixStream};
+    use tokio_util::sync::CancellationToken;
+    use tonic::transport::{Channel, Endpoint, Uri};
+
+    use super::TlsTcpConnector;
+    use crate::{
+        proxy::{
+            proxy_service::ProxyService,
+            serve_plaintext_with_incoming,
+            test_util::{spawn_proxy, spawn_uds_greeter, temp_socket_path},
+        },
+        tls::{
+            test_certs::{localhost_params, write_self_signed},
+            UpstreamTlsConfig,
+        },
+        util::temp_dir,
+    };
+
+    /// Tonic channel over the unix socket at `path`.
+    async fn uds_channel(path: &Path) -> Channel {
+        let path = path.to_path_buf();
+        Endpoint::from_static("http://localhost")
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let path = path.clone();
+                async move { Ok::<_, std::io::Error>(TokioIo::new(UnixStream::connect(path).await?)) }
+            }))
+            .await
+            .unwrap()
+    }
+
+    /// Starts a cleartext UDS listener at `path` forwarding to `connector`.
+    fn spawn_uds_proxy(
+        path: &Path,
+        connector: TlsTcpConnector,
+        token: CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
+        let listener = UnixListener::bind(path).unwrap();
+        let service = ProxyService::new(connector);
+        tokio::spawn(async move {
+            serve_plaintext_with_incoming(
+                listener,
+                service,
+                async move { token.cancelled().await },
+            )
+            .await
+            .unwrap();
+        })
+    }
+
+    #[tokio::test]
+    async fn uds_listener_to_tls_backend() {
+        let token = CancellationToken::new();
+        let dir = temp_dir("uds-to-tls");
+        let greeter_socket = temp_socket_path("uds-to-tls-greeter");
+        let backend = spawn_uds_greeter(&greeter_socket, token.child_token());
+        // the remote gRPC server: TLS on tcp in front of the greeter
+        let server = write_self_signed(&dir, "server", localhost_params());
+        let (remote, remote_proxy) = spawn_proxy(
+            server.load_server_config().unwrap(),
+            &greeter_socket,
+            token.child_token(),
+        )
+        .await;
+
+        // the backend cert is for localhost, so SNI has to be set for an ip
+        let upstream = UpstreamTlsConfig::new(&server.cert_chain, Some("localhost".to_string()));
+        let socket = temp_socket_path("uds-to-tls");
+        let connector = TlsTcpConnector::from_config(remote.to_string(), &upstream).unwrap();
+        let proxy = spawn_uds_proxy(&socket, connector, token.child_token());
+        let mut client = GreeterClient::new(uds_channel(&socket).await);
+        let reply = client
+            .say_hello(HelloRequest {
+                name: "uds".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(reply.into_inner().message, "Hello uds!");
+        drop(client);
+
+        // without the override the certificate does not match the ip
+        let bad_socket = temp_socket_path("uds-to-tls-bad");
+        let connector = TlsTcpConnector::from_config(
+            remote.to_string(),
+            &UpstreamTlsConfig::new(&server.cert_chain, None),
+        )
+        .unwrap();
+        let bad_proxy = spawn_uds_proxy(&bad_socket, connector, token.child_token());
+        let mut client = GreeterClient::new(uds_channel(&bad_socket).await);
+        let status = client
+            .say_hello(HelloRequest {
+                name: "uds".to_string(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.message(), "backend unavailable");
+        drop(client);
+
+        token.cancel();
+        for task in [proxy, bad_proxy, remote_proxy, backend] {
+            task.await.unwrap();
+        }
+        for path in [&socket, &bad_socket, &greeter_socket] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
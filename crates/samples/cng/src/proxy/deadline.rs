@@ -0,0 +1,471 @@
+//! Call deadlines from `grpc-timeout`, and stream idle timeouts.
+//!
+//! [`DeadlineLayer`] reads the `grpc-timeout` of a call, falls back to
+//! [`DeadlineConfig::default`] without one and clamps it to
+//! [`DeadlineConfig::max`]. The result is forwarded as the new
+//! `grpc-timeout`, so the backend gives up at the same time. Once it
+//! passes, or once a stream sent no data either way for
+//! [`DeadlineConfig::stream_idle`], the call ends with `DEADLINE_EXCEEDED`
+//! (trailers-only, or trailers after the data already sent) and the
+//! backend stream is reset.
+
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::{HeaderName, HeaderValue, Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame};
+use tokio::time::{Instant, Sleep};
+
+use super::{
+    activity::Activity,
+    boxed_call,
+    status::{self, Code},
+    BoxError, ProxyBody,
+};
+
+pub const GRPC_TIMEOUT: HeaderName = HeaderName::from_static("grpc-timeout");
+
+/// Largest value of a `grpc-timeout`, which has at most 8 digits.
+const MAX_TIMEOUT_VALUE: u128 = 99_999_999;
+
+/// Parses a `grpc-timeout` value such as `100m` or `5S`.
+pub fn parse_timeout(value: &HeaderValue) -> Option<Duration> {
+    let value = value.as_bytes();
+    let (unit, digits) = value.split_last()?;
+    if digits.is_empty() || digits.len() > 8 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let n: u64 = std::str::from_utf8(digits).ok()?.parse().ok()?;
+    Some(match unit {
+        b'H' => Duration::from_secs(n * 3600),
+        b'M' => Duration::from_secs(n * 60),
+        b'S' => Duration::from_secs(n),
+        b'm' => Duration::from_millis(n),
+        b'u' => Duration::from_micros(n),
+        b'n' => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+/// `grpc-timeout` for `timeout` in the finest unit that fits, rounded up.
+pub fn encode_timeout(timeout: Duration) -> HeaderValue {
+    let nanos = timeout.as_nanos();
+    let (value, unit) = [
+        (1, 'n'),
+        (1_000, 'u'),
+        (1_000_000, 'm'),
+        (1_000_000_000, 'S'),
+        (60_000_000_000, 'M'),
+    ]
+    .into_iter()
+    .map(|(per, unit)| (nanos.div_ceil(per), unit))
+    .find(|(value, _)| *value <= MAX_TIMEOUT_VALUE)
+    .unwrap_or_else(|| {
+        let hours = nanos.div_ceil(3_600_000_000_000);
+        (hours.min(MAX_TIMEOUT_VALUE), 'H')
+    });
+    HeaderValue::from_str(&format!("{value}{unit}")).expect("digits and a unit")
+}
+
+/// Deadline settings of a route.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeadlineConfig {
+    /// Deadline of calls without `grpc-timeout`; unlimited when `None`.
+    pub default: Option<Duration>,
+    /// Longest deadline a call gets, whatever its `grpc-timeout`.
+    pub max: Option<Duration>,
+    /// Streams without data in either direction for this long are ended.
+    pub stream_idle: Option<Duration>,
+}
+
+impl DeadlineConfig {
+    /// Deadline of a call that asked for `requested`.
+    pub fn effective(&self, requested: Option<Duration>) -> Option<Duration> {
+        match (requested.or(self.default), self.max) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        }
+    }
+}
+
+/// Enforces deadlines, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct DeadlineLayer {
+    config: Arc<DeadlineConfig>,
+}
+
+impl DeadlineLayer {
+    pub fn new(config: DeadlineConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for DeadlineLayer {
+    type Service = DeadlineService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        DeadlineService {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Request bodies reach the inner service as a [`ProxyBody`] that keeps
+/// the stream from going idle.
+#[derive(Debug, Clone)]
+pub struct DeadlineService<S> {
+    inner: S,
+    config: Arc<DeadlineConfig>,
+}
+
+impl<S, B> tower::Service<Request<B>> for DeadlineService<S>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let requested = req.headers().get(GRPC_TIMEOUT).and_then(parse_timeout);
+        let timeout = self.config.effective(requested);
+        match timeout {
+            Some(timeout) => {
+                req.headers_mut()
+                    .insert(GRPC_TIMEOUT, encode_timeout(timeout));
+            }
+            None => {
+                // none asked for and no default, or malformed
+                req.headers_mut().remove(GRPC_TIMEOUT);
+            }
+        }
+        let mut timer = Timer::new(timeout, self.config.stream_idle);
+        let activity = timer.activity.clone();
+        let req = req.map(|body| {
+            body.map_frame(move |frame| {
+                activity.touch();
+                frame
+            })
+            .map_err(Into::into)
+            .boxed()
+        });
+        let mut fut = boxed_call(self.inner.call(req));
+        Box::pin(async move {
+            let expired = poll_fn(|cx| {
+                if let Poll::Ready(res) = fut.as_mut().poll(cx) {
+                    return Poll::Ready(Ok(res));
+                }
+                timer.poll_expired(cx).map(Err)
+            })
+            .await;
+            match expired {
+                Ok(resp) => Ok(resp?.map(|inner| {
+                    DeadlineBody {
+                        inner: Some(inner),
+                        timer,
+                    }
+                    .boxed()
+                })),
+                // dropping the call resets the backend stream
+                Err(message) => Ok(status::trailers_only(Code::DeadlineExceeded, message)),
+            }
+        })
+    }
+}
+
+/// Deadline and idle timeout of one call.
+struct Timer {
+    deadline: Option<(Duration, Pin<Box<Sleep>>)>,
+    idle: Option<(Duration, Pin<Box<Sleep>>)>,
+    activity: Arc<Activity>,
+}
+
+impl Timer {
+    fn new(timeout: Option<Duration>, idle: Option<Duration>) -> Self {
+        let activity = Arc::new(Activity::new());
+        let start = activity.start();
+        Self {
+            deadline: timeout.map(|t| (t, Box::pin(tokio::time::sleep_until(start + t)))),
+            idle: idle.map(|t| (t, Box::pin(tokio::time::sleep_until(start + t)))),
+            activity,
+        }
+    }
+
+    /// Resolves to the status message once the call has to end.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> Poll<String> {
+        if let Some((timeout, sleep)) = &mut self.deadline {
+            if sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(format!("deadline of {timeout:?} exceeded in proxy"));
+            }
+        }
+        if let Some((timeout, sleep)) = &mut self.idle {
+            loop {
+                ready!(sleep.as_mut().poll(cx));
+                let due = self.activity.last() + *timeout;
+                if due <= Instant::now() {
+                    return Poll::Ready(format!("stream idle for {timeout:?}"));
+                }
+                sleep.as_mut().reset(due);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Response body ending with `DEADLINE_EXCEEDED` trailers once the timer
+/// expires, dropping (and so resetting) the backend stream.
+struct DeadlineBody {
+    /// `None` once finished or expired.
+    inner: Option<ProxyBody>,
+    timer: Timer,
+}
+
+impl Body for DeadlineBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        let Some(inner) = this.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        if let Poll::Ready(frame) = Pin::new(inner).poll_frame(cx) {
+            match &frame {
+                Some(Ok(frame)) if frame.is_data() => this.timer.activity.touch(),
+                Some(_) => {}
+                None => this.inner = None,
+            }
+            return Poll::Ready(frame);
+        }
+        let message = ready!(this.timer.poll_expired(cx));
+        this.inner = None;
+        let trailers = status::trailers(Code::DeadlineExceeded, message);
+        Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|inner| inner.is_end_stream())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+    use http::{HeaderValue, Request};
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::{
+        body::{Frame, Incoming},
+        client::conn::http2::SendRequest,
+    };
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_rustls::TlsAcceptor;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{encode_timeout, parse_timeout, DeadlineConfig, DeadlineLayer};
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        serve_with_options,
+        test_util::{
+            self, client_config, echo, h2_client, open_echo_stream, spawn_echo_backend,
+            spawn_proxy_service, temp_socket_path, trailers, wait_until, ChannelBody, FrameSender,
+        },
+        ServeOptions,
+    };
+
+    #[test]
+    fn parses_and_encodes_grpc_timeout() {
+        let parse = |v: &'static str| parse_timeout(&HeaderValue::from_static(v));
+        assert_eq!(parse("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(parse("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse("99999999n"), Some(Duration::from_nanos(99_999_999)));
+        for invalid in ["", "m", "100", "100x", "123456789S", "-1S", "1.5S"] {
+            assert_eq!(parse(invalid), None, "{invalid}");
+        }
+
+        assert_eq!(encode_timeout(Duration::from_millis(100)), "100000u");
+        assert_eq!(encode_timeout(Duration::from_secs(30)), "30000000u");
+        assert_eq!(encode_timeout(Duration::from_secs(1000)), "1000000m");
+        // rounded up, never shorter
+        assert_eq!(encode_timeout(Duration::new(100_000, 1)), "100001S");
+        assert_eq!(
+            encode_timeout(Duration::from_secs(u64::MAX / 2)),
+            "99999999H"
+        );
+        for timeout in [Duration::from_nanos(1), Duration::from_secs(3600)] {
+            assert_eq!(parse_timeout(&encode_timeout(timeout)), Some(timeout));
+        }
+    }
+
+    #[test]
+    fn clamps_deadlines() {
+        let secs = |s| Some(Duration::from_secs(s));
+        let config = DeadlineConfig {
+            default: secs(10),
+            max: secs(60),
+            stream_idle: None,
+        };
+        assert_eq!(config.effective(None), secs(10));
+        assert_eq!(config.effective(secs(5)), secs(5));
+        assert_eq!(config.effective(secs(600)), secs(60));
+        let max_only = DeadlineConfig {
+            max: secs(60),
+            ..DeadlineConfig::default()
+        };
+        assert_eq!(max_only.effective(None), secs(60));
+        assert_eq!(DeadlineConfig::default().effective(secs(600)), secs(600));
+    }
+
+    /// Opens a call to the echo backend like [`open_echo_stream`], sending
+    /// `grpc-timeout`.
+    async fn open_echo_stream_with_timeout(
+        sender: &mut SendRequest<ChannelBody>,
+        timeout: &'static str,
+    ) -> (FrameSender, Incoming) {
+        let (frames, rx) = mpsc::channel(4);
+        sender.ready().await.unwrap();
+        let resp = sender
+            .send_request(
+                Request::post("/helloworld.Greeter/SayHello")
+                    .header("content-type", "application/grpc")
+                    .header("grpc-timeout", timeout)
+                    .body(StreamBody::new(ReceiverStream::new(rx)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        (frames, resp.into_body())
+    }
+
+    #[tokio::test]
+    async fn enforces_deadlines() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("deadline");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let service = tower::Layer::layer(
+            &DeadlineLayer::new(DeadlineConfig {
+                default: None,
+                max: Some(Duration::from_millis(600)),
+                stream_idle: Some(Duration::from_millis(200)),
+            }),
+            ProxyService::new(UdsConnector::new(&socket)),
+        );
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, service, token.child_token()).await;
+        let mut sender = h2_client::<ChannelBody>(addr, client_config(cert, None))
+            .await
+            .unwrap();
+
+        // clamped to the max and forwarded
+        let (frames, mut body) = open_echo_stream_with_timeout(&mut sender, "10S").await;
+        assert_eq!(
+            backend.recv().await.unwrap().headers["grpc-timeout"],
+            "600000u"
+        );
+        // data keeps the stream from going idle
+        for _ in 0..5 {
+            echo(&frames, &mut body, "ping").await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let trailers = trailers(&mut body).await;
+        assert_eq!(trailers["grpc-status"], "4");
+        assert_eq!(trailers["grpc-message"], "stream idle for 200ms");
+        drop(frames);
+
+        let (frames, mut body) = open_echo_stream_with_timeout(&mut sender, "300m").await;
+        assert_eq!(
+            backend.recv().await.unwrap().headers["grpc-timeout"],
+            "300000u"
+        );
+        let started = std::time::Instant::now();
+        let trailers = loop {
+            let frame = tokio::select! {
+                frame = body.frame() => frame.unwrap().unwrap(),
+                _ = tokio::time::sleep(Duration::from_millis(50)) => {
+                    frames.send(Ok(Frame::data(Bytes::from_static(b"ping")))).await.unwrap();
+                    continue;
+                }
+            };
+            if let Ok(trailers) = frame.into_trailers() {
+                break trailers;
+            }
+        };
+        assert!(started.elapsed() < Duration::from_millis(600));
+        assert_eq!(trailers["grpc-status"], "4");
+        assert_eq!(
+            trailers["grpc-message"],
+            "deadline of 300ms exceeded in proxy"
+        );
+
+        drop((frames, sender));
+        token.cancel();
+        proxy.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn closes_idle_connections() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("idle");
+        let _backend = spawn_echo_backend(&socket, token.child_token());
+        let (mut server_config, cert) = test_util::load_test_server_config();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ServeOptions {
+            idle_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let stats = options.stats.clone();
+        let proxy = tokio::spawn(serve_with_options(
+            listener,
+            Some(TlsAcceptor::from(Arc::new(server_config))),
+            ProxyService::new(UdsConnector::new(&socket)),
+            options,
+            token.child_token().cancelled_owned(),
+        ));
+
+        let mut sender = h2_client::<ChannelBody>(addr, client_config(cert, None))
+            .await
+            .unwrap();
+        let (frames, mut body) = open_echo_stream(&mut sender).await;
+        // an open stream keeps the connection, even without data
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        echo(&frames, &mut body, "still open").await;
+        drop(frames);
+        assert_eq!(trailers(&mut body).await["grpc-status"], "0");
+        assert_eq!(stats.active_connections(), 1);
+
+        wait_until(|| stats.active_connections() == 0).await;
+        assert!(sender.ready().await.is_err());
+
+        token.cancel();
+        proxy.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,673 @@
+//! gRPC-Web for browser clients, translated to native gRPC.
+//!
+//! Requests with an `application/grpc-web` (or base64 encoded
+//! `application/grpc-web-text`) content type, over HTTP/1.1 or h2, reach
+//! the inner service as `application/grpc` h2 requests. Responses go back
+//! in the client's encoding with the trailers as the last body frame, as
+//! browsers cannot read HTTP trailers. Other requests pass through.
+//!
+//! CORS preflights are answered here from [`CorsConfig`], and responses
+//! carry the headers letting browsers read the gRPC status.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{
+    header::{self, CONTENT_LENGTH, CONTENT_TYPE, HOST, TE},
+    uri, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode, Version,
+};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::{Body, Frame};
+
+use super::{
+    boxed_call,
+    status::{GRPC_MESSAGE, GRPC_STATUS},
+    BoxError, ProxyBody,
+};
+
+pub const GRPC_WEB: &str = "application/grpc-web";
+pub const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+const GRPC: &str = "application/grpc";
+
+/// Flag of the length-prefixed frame carrying the trailers.
+const TRAILERS_FLAG: u8 = 0x80;
+
+/// Request headers gRPC-Web clients send, always allowed.
+const GRPC_WEB_HEADERS: &str = "content-type,x-grpc-web,x-user-agent,grpc-timeout";
+
+/// Response headers browsers may read, for trailers-only responses.
+const EXPOSE_HEADERS: &str = "grpc-status,grpc-message";
+
+/// CORS settings for the browser clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsConfig {
+    /// e.g. `https://app.example`; any origin when empty.
+    pub allowed_origins: Vec<HeaderValue>,
+    /// Request headers allowed besides the gRPC-Web ones.
+    pub allowed_headers: Vec<HeaderName>,
+    /// How long browsers may cache a preflight.
+    pub max_age: Duration,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_headers: vec![header::AUTHORIZATION],
+            max_age: Duration::from_secs(600),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// `access-control-allow-origin` for a request from `origin`, `None`
+    /// when it is not allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if self.allowed_origins.is_empty() {
+            return Some(HeaderValue::from_static("*"));
+        }
+        origin
+            .filter(|origin| self.allowed_origins.contains(origin))
+            .cloned()
+    }
+
+    /// Adds the CORS headers of an actual (not preflight) response.
+    fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        if let Some(allow) = self.allow_origin(origin) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow);
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSE_HEADERS),
+            );
+        }
+        if !self.allowed_origins.is_empty() {
+            headers.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+    }
+
+    fn preflight(&self, origin: Option<&HeaderValue>) -> Response<ProxyBody> {
+        let mut resp = Response::new(empty());
+        let Some(allow) = self.allow_origin(origin) else {
+            *resp.status_mut() = StatusCode::FORBIDDEN;
+            return resp;
+        };
+        *resp.status_mut() = StatusCode::NO_CONTENT;
+        let mut allowed_headers = GRPC_WEB_HEADERS.to_string();
+        for name in &self.allowed_headers {
+            allowed_headers.push(',');
+            allowed_headers.push_str(name.as_str());
+        }
+        let headers = resp.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow);
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("POST"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_str(&allowed_headers).expect("header names"),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from(self.max_age.as_secs()),
+        );
+        if !self.allowed_origins.is_empty() {
+            headers.insert(header::VARY, HeaderValue::from_static("origin"));
+        }
+        resp
+    }
+}
+
+fn empty() -> ProxyBody {
+    Empty::<Bytes>::new().map_err(|e| match e {}).boxed()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Binary,
+    /// Base64.
+    Text,
+}
+
+impl Encoding {
+    /// Encoding of a gRPC-Web content type, and its `+proto` style suffix.
+    fn of(content_type: &HeaderValue) -> Option<(Self, &str)> {
+        let content_type = content_type.to_str().ok()?;
+        let (encoding, suffix) = match content_type.strip_prefix(GRPC_WEB_TEXT) {
+            Some(suffix) => (Encoding::Text, suffix),
+            None => (Encoding::Binary, content_type.strip_prefix(GRPC_WEB)?),
+        };
+        (suffix.is_empty() || suffix.starts_with('+')).then_some((encoding, suffix))
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Binary => GRPC_WEB,
+            Encoding::Text => GRPC_WEB_TEXT,
+        }
+    }
+}
+
+/// Translates gRPC-Web calls, see the [module docs](self).
+#[derive(Debug, Clone, Default)]
+pub struct GrpcWebLayer {
+    cors: CorsConfig,
+}
+
+impl GrpcWebLayer {
+    pub fn new(cors: CorsConfig) -> Self {
+        Self { cors }
+    }
+}
+
+impl<S> tower::Layer<S> for GrpcWebLayer {
+    type Service = GrpcWebService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GrpcWebService {
+            inner,
+            cors: self.cors.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GrpcWebService<S> {
+    inner: S,
+    cors: CorsConfig,
+}
+
+impl<S, B> tower::Service<Request<B>> for GrpcWebService<S>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        if req.method() == Method::OPTIONS
+            && req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            let resp = self.cors.preflight(origin.as_ref());
+            return Box::pin(async move { Ok(resp) });
+        }
+        let (mut parts, body) = req.into_parts();
+        let body = body.map_err(Into::into).boxed();
+        let Some((encoding, suffix)) = parts.headers.get(CONTENT_TYPE).and_then(Encoding::of)
+        else {
+            return Box::pin(self.inner.call(Request::from_parts(parts, body)));
+        };
+        let content_type =
+            HeaderValue::from_str(&format!("{GRPC}{suffix}")).expect("suffix of a valid header");
+        let headers = &mut parts.headers;
+        headers.insert(CONTENT_TYPE, content_type);
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        // changed by the base64 decoding, and never sent with h2 anyway
+        headers.remove(CONTENT_LENGTH);
+        if parts.uri.authority().is_none() {
+            if let Some(authority) = headers.get(HOST).and_then(|h| h.to_str().ok()) {
+                let mut uri = uri::Parts::from(parts.uri.clone());
+                if let Ok(authority) = authority.parse() {
+                    uri.scheme = Some(uri::Scheme::HTTP);
+                    uri.authority = Some(authority);
+                    if let Ok(uri) = http::Uri::from_parts(uri) {
+                        parts.uri = uri;
+                    }
+                }
+            }
+        }
+        headers.remove(HOST);
+        parts.version = Version::HTTP_2;
+        let body = match encoding {
+            Encoding::Binary => body,
+            Encoding::Text => Base64Body {
+                inner: body,
+                pending: BytesMut::new(),
+            }
+            .boxed(),
+        };
+        let fut = boxed_call(self.inner.call(Request::from_parts(parts, body)));
+        let cors = self.cors.clone();
+        Box::pin(async move {
+            let resp = fut.await?;
+            let (mut parts, body) = resp.into_parts();
+            let suffix = (parts.headers.get(CONTENT_TYPE))
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix(GRPC))
+                .unwrap_or_default();
+            let content_type =
+                HeaderValue::from_str(&format!("{}{suffix}", encoding.content_type()))
+                    .expect("suffix of a valid header");
+            parts.headers.insert(CONTENT_TYPE, content_type);
+            cors.apply(origin.as_ref(), &mut parts.headers);
+            let body = body.map_frame(move |frame| web_frame(frame, encoding));
+            Ok(Response::from_parts(parts, body.boxed()))
+        })
+    }
+}
+
+/// Response frame as sent to a gRPC-Web client: data as is and the
+/// trailers as a length-prefixed frame, base64 encoded for text.
+fn web_frame(frame: Frame<Bytes>, encoding: Encoding) -> Frame<Bytes> {
+    let data = match frame.into_data() {
+        Ok(data) => data,
+        Err(frame) => match frame.into_trailers() {
+            Ok(trailers) => encode_trailers(&trailers),
+            Err(frame) => return frame,
+        },
+    };
+    match encoding {
+        Encoding::Binary => Frame::data(data),
+        Encoding::Text => Frame::data(base64_encode(&data).into()),
+    }
+}
+
+/// Trailers frame of the gRPC-Web protocol: `name: value` lines.
+fn encode_trailers(trailers: &HeaderMap) -> Bytes {
+    let mut block = BytesMut::new();
+    // status first, for clients reading only that far
+    let first = [GRPC_STATUS, GRPC_MESSAGE];
+    let ordered = (first.iter())
+        .filter_map(|name| Some((*name, trailers.get(*name)?)))
+        .chain(
+            (trailers.iter())
+                .filter(|(name, _)| !first.contains(&name.as_str()))
+                .map(|(name, value)| (name.as_str(), value)),
+        );
+    for (name, value) in ordered {
+        block.put_slice(name.as_bytes());
+        block.put_slice(b": ");
+        block.put_slice(value.as_bytes());
+        block.put_slice(b"\r\n");
+    }
+    let mut frame = BytesMut::with_capacity(5 + block.len());
+    frame.put_u8(TRAILERS_FLAG);
+    frame.put_u32(block.len() as u32);
+    frame.put_slice(&block);
+    frame.freeze()
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding.
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Decodes whole groups of four characters; each may end in padding, as
+/// clients encode every message on its own.
+fn base64_decode(groups: &[u8], out: &mut BytesMut) -> Result<(), BoxError> {
+    for group in groups.chunks(4) {
+        let padding = group.iter().rev().take_while(|b| **b == b'=').count();
+        if group.len() != 4 || padding > 2 {
+            return Err("invalid base64 in grpc-web-text request".into());
+        }
+        let mut n = 0u32;
+        for (i, b) in group[..4 - padding].iter().enumerate() {
+            let value = BASE64
+                .iter()
+                .position(|c| c == b)
+                .ok_or("invalid base64 in grpc-web-text request")?;
+            n |= (value as u32) << (18 - 6 * i);
+        }
+        let bytes = n.to_be_bytes();
+        out.put_slice(&bytes[1..4 - padding]);
+    }
+    Ok(())
+}
+
+/// Base64 decoded `application/grpc-web-text` request body.
+struct Base64Body {
+    inner: ProxyBody,
+    /// Characters of an incomplete group.
+    pending: BytesMut,
+}
+
+impl Body for Base64Body {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        loop {
+            let frame = match std::task::ready!(Pin::new(&mut self.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None if self.pending.is_empty() => return Poll::Ready(None),
+                None => {
+                    self.pending.clear();
+                    return Poll::Ready(Some(Err(
+                        "truncated base64 in grpc-web-text request".into()
+                    )));
+                }
+            };
+            let data = match frame.into_data() {
+                Ok(data) => data,
+                Err(frame) => return Poll::Ready(Some(Ok(frame))),
+            };
+            self.pending.extend_from_slice(&data);
+            let whole = self.pending.len() / 4 * 4;
+            let groups = self.pending.split_to(whole);
+            let mut decoded = BytesMut::with_capacity(whole / 4 * 3);
+            if let Err(e) = base64_decode(&groups, &mut decoded) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            if !decoded.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(decoded.freeze()))));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use bytes::{Bytes, BytesMut};
+    use http::{HeaderMap, HeaderValue, Request};
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper_util::rt::TokioIo;
+    use rustls::pki_types::ServerName;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{base64_decode, base64_encode, encode_trailers, CorsConfig, Encoding};
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        serve_with_options,
+        test_util::{self, client_config, echo, spawn_echo_backend, temp_socket_path},
+        CallLimit, ServeOptions,
+    };
+
+    #[test]
+    fn base64_round_trip() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"\x00\xff\x10\x80", "AP8QgA=="),
+        ] {
+            assert_eq!(base64_encode(data), encoded);
+            let mut decoded = BytesMut::new();
+            base64_decode(encoded.as_bytes(), &mut decoded).unwrap();
+            assert_eq!(&decoded[..], data);
+        }
+        // messages encoded one by one, each padded
+        let mut decoded = BytesMut::new();
+        base64_decode(b"Zg==Zm8=Zm9v", &mut decoded).unwrap();
+        assert_eq!(&decoded[..], b"ffofoo");
+        assert!(base64_decode(b"Zm9", &mut decoded).is_err());
+        assert!(base64_decode(b"Z===", &mut decoded).is_err());
+        assert!(base64_decode(b"Zm9!", &mut decoded).is_err());
+    }
+
+    #[test]
+    fn encodes_trailers_frame() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-extra", HeaderValue::from_static("1"));
+        trailers.insert("grpc-message", HeaderValue::from_static("gone"));
+        trailers.insert("grpc-status", HeaderValue::from_static("5"));
+        let frame = encode_trailers(&trailers);
+        let block = b"grpc-status: 5\r\ngrpc-message: gone\r\nx-extra: 1\r\n";
+        assert_eq!(frame[0], 0x80);
+        assert_eq!(&frame[1..5], (block.len() as u32).to_be_bytes());
+        assert_eq!(&frame[5..], block);
+
+        let of = |v: &'static str| {
+            Encoding::of(&HeaderValue::from_static(v)).map(|(e, suffix)| (e, suffix.to_string()))
+        };
+        assert_eq!(
+            of("application/grpc-web"),
+            Some((Encoding::Binary, "".into()))
+        );
+        assert_eq!(
+            of("application/grpc-web-text+proto"),
+            Some((Encoding::Text, "+proto".into()))
+        );
+        assert_eq!(of("application/grpc"), None);
+        assert_eq!(of("application/grpc-webx"), None);
+    }
+
+    /// Plaintext HTTP/1.1 client to `addr`.
+    async fn h1c_client<B>(addr: SocketAddr) -> hyper::client::conn::http1::SendRequest<B>
+    where
+        B: hyper::body::Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tcp))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        sender
+    }
+
+    /// Sends `req` over HTTP/1.1 and waits for the whole response.
+    async fn http1_call(
+        sender: &mut hyper::client::conn::http1::SendRequest<http_body_util::Full<Bytes>>,
+        req: http::request::Builder,
+        body: &'static [u8],
+    ) -> (http::response::Parts, Bytes) {
+        sender.ready().await.unwrap();
+        let req = req
+            .header("host", "localhost")
+            .body(http_body_util::Full::new(Bytes::from_static(body)))
+            .unwrap();
+        let (head, body) = sender.send_request(req).await.unwrap().into_parts();
+        (head, body.collect().await.unwrap().to_bytes())
+    }
+
+    #[tokio::test]
+    async fn translates_grpc_web_calls() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("grpc-web");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let (mut server_config, cert) = test_util::load_test_server_config();
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cors = CorsConfig {
+            allowed_origins: vec![http::HeaderValue::from_static("https://app.example")],
+            ..CorsConfig::default()
+        };
+        let options = ServeOptions {
+            grpc_web: Some(cors),
+            ..Default::default()
+        };
+        let proxy = tokio::spawn(serve_with_options(
+            listener,
+            Some(TlsAcceptor::from(Arc::new(server_config))),
+            ProxyService::new(UdsConnector::new(&socket)),
+            options,
+            token.child_token().cancelled_owned(),
+        ));
+
+        let mut config = client_config(cert, None);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tls))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let (head, body) = http1_call(
+            &mut sender,
+            Request::options("/echo.Echo/Say")
+                .header("access-control-request-method", "POST")
+                .header("origin", "https://app.example"),
+            b"",
+        )
+        .await;
+        assert_eq!(head.status, http::StatusCode::NO_CONTENT);
+        assert_eq!(
+            head.headers["access-control-allow-origin"],
+            "https://app.example"
+        );
+        assert!(body.is_empty());
+        assert!(backend.try_recv().is_err());
+
+        // the trailers arrive as the last frame of the body
+        let message = b"\x00\x00\x00\x00\x05hello";
+        let (head, body) = http1_call(
+            &mut sender,
+            Request::post("/echo.Echo/Say")
+                .header("content-type", "application/grpc-web+proto")
+                .header("origin", "https://app.example"),
+            message,
+        )
+        .await;
+        // the echo backend answers `application/grpc`
+        assert_eq!(head.headers["content-type"], "application/grpc-web");
+        assert_eq!(
+            head.headers["access-control-allow-origin"],
+            "https://app.example"
+        );
+        assert_eq!(&body[..message.len()], message);
+        assert_eq!(
+            &body[message.len()..],
+            b"\x80\x00\x00\x00\x10grpc-status: 0\r\n"
+        );
+        let forwarded = backend.recv().await.unwrap();
+        assert_eq!(forwarded.version, http::Version::HTTP_2);
+        assert_eq!(forwarded.headers["content-type"], "application/grpc+proto");
+        assert_eq!(forwarded.headers["te"], "trailers");
+        assert_eq!(forwarded.uri.path(), "/echo.Echo/Say");
+
+        let (head, body) = http1_call(
+            &mut sender,
+            Request::post("/echo.Echo/Say").header("content-type", "application/grpc-web-text"),
+            b"AAAAAAVoZWxsbw==",
+        )
+        .await;
+        assert_eq!(head.headers["content-type"], "application/grpc-web-text");
+        assert_eq!(&body[..], b"AAAAAAVoZWxsbw==gAAAABBncnBjLXN0YXR1czogMA0K");
+        let forwarded = backend.recv().await.unwrap();
+        assert_eq!(forwarded.headers["content-type"], "application/grpc");
+
+        // other origins are refused before reaching the backend
+        let (head, _) = http1_call(
+            &mut sender,
+            Request::options("/echo.Echo/Say")
+                .header("access-control-request-method", "POST")
+                .header("origin", "https://evil.example"),
+            b"",
+        )
+        .await;
+        assert_eq!(head.status, http::StatusCode::FORBIDDEN);
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn translates_shed_and_drained_grpc_web_calls() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("grpc-web-drain");
+        let _backend = spawn_echo_backend(&socket, token.child_token());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ServeOptions {
+            drain_timeout: Duration::from_millis(200),
+            grpc_web: Some(CorsConfig::default()),
+            call_limit: Some(CallLimit::new(1)),
+            ..Default::default()
+        };
+        let shutdown = token.child_token();
+        let proxy = tokio::spawn(serve_with_options(
+            listener,
+            None,
+            ProxyService::new(UdsConnector::new(&socket)),
+            options,
+            shutdown.clone().cancelled_owned(),
+        ));
+        let mut held = h1c_client(addr).await;
+        let (frames, rx) = mpsc::channel(4);
+        let resp = held
+            .send_request(
+                Request::post("/echo.Echo/Say")
+                    .header("host", "localhost")
+                    .header("content-type", "application/grpc-web")
+                    .body(StreamBody::new(ReceiverStream::new(rx)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut held_body = resp.into_body();
+        echo(&frames, &mut held_body, "held").await;
+
+        // the shed call is translated and carries CORS headers
+        let mut shed = h1c_client(addr).await;
+        let (head, body) = http1_call(
+            &mut shed,
+            Request::post("/echo.Echo/Say")
+                .header("content-type", "application/grpc-web")
+                .header("origin", "https://app.example"),
+            b"\x00\x00\x00\x00\x00",
+        )
+        .await;
+        assert_eq!(head.headers["content-type"], "application/grpc-web");
+        assert_eq!(head.headers["access-control-allow-origin"], "*");
+        assert_eq!(head.headers["grpc-status"], "8");
+        assert!(body.is_empty());
+        drop(shed);
+
+        // the drain status arrives in the body, HTTP/1.1 has no trailers
+        shutdown.cancel();
+        let body = held_body.collect().await.unwrap().to_bytes();
+        let trailer = b"grpc-status: 14\r\ngrpc-message: proxy is shutting down\r\n";
+        assert_eq!(body[0], 0x80);
+        assert_eq!(&body[5..], trailer);
+        drop((frames, held));
+        proxy.await.unwrap().unwrap();
+
+        token.cancel();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,608 @@
+//! Active health checking of backends with the `grpc.health.v1` protocol,
+//! see <https://github.com/grpc/grpc/blob/master/doc/health-checking.md>.
+//!
+//! A checker task per backend calls `Health/Check` every interval (or
+//! follows `Health/Watch`) on a connection of its own and flips the shared
+//! [`BackendHealth`] after enough consecutive results. A [`ProxyService`]
+//! carrying an unhealthy [`BackendHealth`] answers `UNAVAILABLE` without
+//! touching the backend.
+//!
+//! The two messages involved are tiny, so they are encoded by hand instead
+//! of pulling in a protobuf runtime.
+//!
+//! [`ProxyService`]: super::proxy_service::ProxyService
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use http::{header::CONTENT_TYPE, HeaderMap, Request, Response};
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Incoming, client::conn::http2::SendRequest};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    connector::Connector,
+    proto,
+    status::{Code, GRPC_MESSAGE, GRPC_STATUS},
+    BoxError,
+};
+
+const CHECK_PATH: &str = "http://localhost/grpc.health.v1.Health/Check";
+const WATCH_PATH: &str = "http://localhost/grpc.health.v1.Health/Watch";
+
+/// `HealthCheckResponse.ServingStatus.SERVING`
+const SERVING: u64 = 1;
+
+/// Longest response message accepted; a real one is 2 bytes.
+const MAX_MESSAGE_LEN: usize = 1024;
+
+/// How a backend is probed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    /// Time between two `Check` calls, and before re-opening a `Watch`.
+    pub interval: Duration,
+    /// Deadline of one `Check` call, including connecting.
+    pub timeout: Duration,
+    /// Consecutive successful checks that make an unhealthy backend
+    /// healthy again.
+    pub healthy_threshold: u32,
+    /// Consecutive failed checks that make a healthy backend unhealthy.
+    pub unhealthy_threshold: u32,
+    /// `HealthCheckRequest.service`; empty for the whole server.
+    pub service: String,
+    /// Follow `Health/Watch` instead of polling `Health/Check`. Status
+    /// changes pushed by the backend apply at once; backends without
+    /// `Watch` are polled. The connection is pinged every `interval`, and
+    /// a backend not answering within `timeout` fails the watch.
+    pub watch: bool,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+            healthy_threshold: 1,
+            unhealthy_threshold: 3,
+            service: String::new(),
+            watch: false,
+        }
+    }
+}
+
+/// Health of one backend as last determined by its checker. Backends
+/// start out healthy so traffic flows before the first check completes.
+#[derive(Debug, Clone)]
+pub struct BackendHealth {
+    inner: Arc<HealthState>,
+}
+
+#[derive(Debug)]
+struct HealthState {
+    name: String,
+    healthy: AtomicBool,
+    /// Why the backend is unhealthy.
+    reason: Mutex<String>,
+}
+
+impl BackendHealth {
+    /// `name` identifies the backend in logs and status messages.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            inner: Arc::new(HealthState {
+                name: name.into(),
+                healthy: AtomicBool::new(true),
+                reason: Mutex::new(String::new()),
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.inner.name
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.inner.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Why the backend is unhealthy, `None` while it is healthy.
+    pub fn reason(&self) -> Option<String> {
+        if self.is_healthy() {
+            return None;
+        }
+        Some(self.inner.reason.lock().unwrap().clone())
+    }
+
+    fn set_healthy(&self) {
+        if !self.inner.healthy.swap(true, Ordering::Relaxed) {
+            println!("backend {} is healthy", self.inner.name);
+        }
+    }
+
+    fn set_unhealthy(&self, reason: String) {
+        let mut current = self.inner.reason.lock().unwrap();
+        if self.inner.healthy.swap(false, Ordering::Relaxed) {
+            eprintln!("backend {} is unhealthy: {reason}", self.inner.name);
+        }
+        *current = reason;
+    }
+}
+
+/// Starts checking the backend behind `connector` until `token` is
+/// cancelled.
+pub fn spawn_health_check<C: Connector>(
+    name: impl Into<String>,
+    connector: C,
+    config: HealthCheckConfig,
+    token: CancellationToken,
+) -> BackendHealth {
+    let health = BackendHealth::new(name);
+    let checker = Checker {
+        connector,
+        sender: None,
+        health: health.clone(),
+        successes: 0,
+        failures: 0,
+        config,
+    };
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = checker.run() => {}
+            _ = token.cancelled() => {}
+        }
+    });
+    health
+}
+
+struct Checker<C> {
+    connector: C,
+    /// Connection used for checks only, so a wedged proxy connection does
+    /// not hide a healthy backend and vice versa.
+    sender: Option<SendRequest<Full<Bytes>>>,
+    health: BackendHealth,
+    successes: u32,
+    failures: u32,
+    config: HealthCheckConfig,
+}
+
+impl<C: Connector> Checker<C> {
+    async fn run(mut self) {
+        let mut watch = self.config.watch;
+        loop {
+            if watch {
+                match self.watch().await {
+                    WatchError::Unimplemented => {
+                        println!(
+                            "backend {} does not implement Health/Watch, polling Health/Check",
+                            self.health.name()
+                        );
+                        watch = false;
+                        continue;
+                    }
+                    WatchError::Failed(reason) => self.record(Err(reason)),
+                }
+            } else {
+                let result = match tokio::time::timeout(self.config.timeout, self.check()).await {
+                    Ok(result) => result,
+                    Err(_) => Err(format!(
+                        "health check timed out after {:?}",
+                        self.config.timeout
+                    )),
+                };
+                self.record(result);
+            }
+            tokio::time::sleep(self.config.interval).await;
+        }
+    }
+
+    /// Applies one check result to the thresholds.
+    fn record(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                self.successes = self.successes.saturating_add(1);
+                if self.successes >= self.config.healthy_threshold {
+                    self.health.set_healthy();
+                }
+            }
+            Err(reason) => {
+                self.successes = 0;
+                self.failures = self.failures.saturating_add(1);
+                if self.failures >= self.config.unhealthy_threshold {
+                    self.health.set_unhealthy(reason);
+                }
+            }
+        }
+    }
+
+    async fn sender(&mut self) -> Result<SendRequest<Full<Bytes>>, BoxError> {
+        if let Some(sender) = &self.sender {
+            if !sender.is_closed() {
+                return Ok(sender.clone());
+            }
+        }
+        let io = self.connector.connect().await?;
+        // pings bound how long a `Watch` waits on a wedged backend, whose
+        // stream would otherwise stay silent and open
+        let (sender, conn) = hyper::client::conn::http2::Builder::new(TokioExecutor::new())
+            .timer(TokioTimer::new())
+            .keep_alive_interval(self.config.interval)
+            .keep_alive_timeout(self.config.timeout)
+            .keep_alive_while_idle(true)
+            .handshake(TokioIo::new(io))
+            .await?;
+        tokio::spawn(conn);
+        self.sender = Some(sender.clone());
+        Ok(sender)
+    }
+
+    async fn call(&mut self, path: &'static str) -> Result<Response<Incoming>, String> {
+        let mut sender = self
+            .sender()
+            .await
+            .map_err(|e| format!("cannot connect: {e}"))?;
+        let req = Request::post(path)
+            .header(CONTENT_TYPE, "application/grpc")
+            .header("te", "trailers")
+            .body(Full::new(encode_request(&self.config.service)))
+            .expect("valid health check request");
+        sender
+            .ready()
+            .await
+            .map_err(|e| format!("connection failed: {e}"))?;
+        let resp = sender
+            .send_request(req)
+            .await
+            .map_err(|e| format!("request failed: {e}"))?;
+        if !resp.status().is_success() {
+            return Err(format!("http status {}", resp.status()));
+        }
+        Ok(resp)
+    }
+
+    async fn check(&mut self) -> Result<(), String> {
+        let (head, body) = self.call(CHECK_PATH).await?.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| format!("response failed: {e}"))?;
+        let trailers = body.trailers().unwrap_or(&head.headers);
+        grpc_result(trailers)?;
+        let mut data = BytesMut::from(body.to_bytes());
+        let message = next_message(&mut data)?.ok_or("empty health check response")?;
+        serving_status(message)
+    }
+
+    /// Follows `Health/Watch` until the stream ends or fails.
+    async fn watch(&mut self) -> WatchError {
+        let resp = match tokio::time::timeout(self.config.timeout, self.call(WATCH_PATH)).await {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(reason)) => return WatchError::Failed(reason),
+            Err(_) => {
+                return WatchError::Failed(format!(
+                    "health watch timed out after {:?}",
+                    self.config.timeout
+                ))
+            }
+        };
+        let (head, mut body) = resp.into_parts();
+        let mut buf = BytesMut::new();
+        loop {
+            let frame = match body.frame().await {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return WatchError::Failed(format!("health watch failed: {e}")),
+                // trailers-only response
+                None => return watch_ended(&head.headers),
+            };
+            let data = match frame.into_data() {
+                Ok(data) => data,
+                Err(frame) => match frame.into_trailers() {
+                    Ok(trailers) => return watch_ended(&trailers),
+                    Err(_) => continue,
+                },
+            };
+            buf.extend_from_slice(&data);
+            loop {
+                let message = match next_message(&mut buf) {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(reason) => return WatchError::Failed(reason),
+                };
+                // pushed changes apply at once
+                self.successes = 0;
+                self.failures = 0;
+                match serving_status(message) {
+                    Ok(()) => self.health.set_healthy(),
+                    Err(reason) => self.health.set_unhealthy(reason),
+                }
+            }
+        }
+    }
+}
+
+enum WatchError {
+    Unimplemented,
+    Failed(String),
+}
+
+fn watch_ended(trailers: &HeaderMap) -> WatchError {
+    match grpc_result(trailers) {
+        Ok(()) => WatchError::Failed("health watch ended".to_string()),
+        Err(e) if e.code == Code::Unimplemented => WatchError::Unimplemented,
+        Err(e) => WatchError::Failed(e.to_string()),
+    }
+}
+
+struct GrpcError {
+    code: Code,
+    message: String,
+}
+
+impl std::fmt::Display for GrpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "health check failed with {}", self.code)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<GrpcError> for String {
+    fn from(e: GrpcError) -> Self {
+        e.to_string()
+    }
+}
+
+/// Checks the `grpc-status` of a response; a missing status counts as an
+/// error since the backend is then not speaking gRPC.
+fn grpc_result(trailers: &HeaderMap) -> Result<(), GrpcError> {
+    let code = trailers
+        .get(GRPC_STATUS)
+        .and_then(Code::from_header)
+        .unwrap_or(Code::Unknown);
+    if code == Code::Ok {
+        return Ok(());
+    }
+    let message = trailers
+        .get(GRPC_MESSAGE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    Err(GrpcError { code, message })
+}
+
+/// `HealthCheckRequest { service }` in a gRPC length-prefixed frame.
+fn encode_request(service: &str) -> Bytes {
+    let mut message = BytesMut::new();
+    proto::put_bytes(&mut message, 1, service.as_bytes());
+    proto::grpc_frame(&message)
+}
+
+/// Splits the next complete message off `data`, `None` when `data` does
+/// not hold one yet.
+fn next_message(data: &mut BytesMut) -> Result<Option<Bytes>, String> {
+    if data.len() < 5 {
+        return Ok(None);
+    }
+    if data[0] != 0 {
+        return Err("compressed health check response".to_string());
+    }
+    let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(format!(
+            "health check response of {len} bytes over the limit of {MAX_MESSAGE_LEN}"
+        ));
+    }
+    if data.len() < 5 + len {
+        return Ok(None);
+    }
+    data.advance(5);
+    Ok(Some(data.split_to(len).freeze()))
+}
+
+/// Decodes `HealthCheckResponse.status`, `Ok` when it is `SERVING`.
+fn serving_status(mut message: Bytes) -> Result<(), String> {
+    let malformed = || "malformed health check response".to_string();
+    let mut status = 0;
+    while message.has_remaining() {
+        let key = proto::get_varint(&mut message).ok_or_else(malformed)?;
+        match key & 0x7 {
+            0 => {
+                let value = proto::get_varint(&mut message).ok_or_else(malformed)?;
+                if key >> 3 == 1 {
+                    status = value;
+                }
+            }
+            1 if message.remaining() >= 8 => message.advance(8),
+            2 => {
+                let len = proto::get_varint(&mut message).ok_or_else(malformed)? as usize;
+                if message.remaining() < len {
+                    return Err(malformed());
+                }
+                message.advance(len);
+            }
+            5 if message.remaining() >= 4 => message.advance(4),
+            _ => return Err(malformed()),
+        }
+    }
+    match status {
+        SERVING => Ok(()),
+        0 => Err("health check returned UNKNOWN".to_string()),
+        2 => Err("health check returned NOT_SERVING".to_string()),
+        3 => Err("health check returned SERVICE_UNKNOWN".to_string()),
+        other => Err(format!("health check returned status {other}")),
+    }
+}
+
+/// `HealthCheckResponse { status }` in a gRPC frame, for test backends.
+#[cfg(test)]
+pub(crate) fn encode_response(status: u64) -> Bytes {
+    let mut message = BytesMut::new();
+    proto::put_uint(&mut message, 1, status);
+    proto::grpc_frame(&message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bytes::{Bytes, BytesMut};
+    use http::Request;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        encode_request, encode_response, next_message, serving_status, spawn_health_check,
+        HealthCheckConfig,
+    };
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        test_util::{
+            self, client_config, h2_client, post, spawn_freezable_relay, spawn_health_backend,
+            spawn_proxy_service, temp_socket_path, wait_until,
+        },
+    };
+
+    #[test]
+    fn messages() {
+        assert_eq!(&encode_request("")[..], [0, 0, 0, 0, 0]);
+        assert_eq!(
+            &encode_request("helloworld.Greeter")[..7],
+            [0, 0, 0, 0, 20, 0x0a, 18]
+        );
+
+        let mut data = BytesMut::from(&[encode_response(1), encode_response(2)].concat()[..]);
+        let serving = next_message(&mut data).unwrap().unwrap();
+        assert_eq!(serving_status(serving), Ok(()));
+        let not_serving = next_message(&mut data).unwrap().unwrap();
+        assert_eq!(
+            serving_status(not_serving).unwrap_err(),
+            "health check returned NOT_SERVING"
+        );
+        assert_eq!(next_message(&mut data), Ok(None));
+
+        // an empty message is the default status, UNKNOWN
+        assert_eq!(
+            serving_status(Bytes::new()).unwrap_err(),
+            "health check returned UNKNOWN"
+        );
+        // unknown fields are skipped
+        let message = Bytes::from_static(&[0x12, 2, b'h', b'i', 0x08, 1]);
+        assert_eq!(serving_status(message), Ok(()));
+        let mut partial = BytesMut::from(&encode_response(1)[..6]);
+        assert_eq!(next_message(&mut partial), Ok(None));
+        // the length is checked before waiting for the message
+        let mut large = BytesMut::from(&[0, 0, 0, 4, 1][..]);
+        assert_eq!(
+            next_message(&mut large).unwrap_err(),
+            "health check response of 1025 bytes over the limit of 1024"
+        );
+    }
+
+    #[tokio::test]
+    async fn health_check_gates_backend() {
+        for watch in [false, true] {
+            let token = CancellationToken::new();
+            let socket = temp_socket_path(if watch {
+                "health-watch"
+            } else {
+                "health-check"
+            });
+            let status = Arc::new(AtomicU64::new(1));
+            spawn_health_backend(&socket, status.clone(), token.child_token());
+            let config = HealthCheckConfig {
+                interval: Duration::from_millis(20),
+                timeout: Duration::from_millis(500),
+                healthy_threshold: 2,
+                unhealthy_threshold: 2,
+                watch,
+                ..Default::default()
+            };
+            let health = spawn_health_check(
+                "greeter",
+                UdsConnector::new(&socket),
+                config,
+                token.child_token(),
+            );
+            let service = ProxyService::new(UdsConnector::new(&socket)).with_health(health.clone());
+            let (server_config, cert) = test_util::load_test_server_config();
+            let (addr, proxy) =
+                spawn_proxy_service(server_config, service, token.child_token()).await;
+            let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+            let say_hello = || Request::builder().uri("/helloworld.Greeter/SayHello");
+
+            let result = post(&mut sender, say_hello()).await.unwrap();
+            assert_eq!(result.grpc_status(), Some("0"));
+
+            status.store(2, Ordering::Relaxed);
+            wait_until(|| !health.is_healthy()).await;
+            let result = post(&mut sender, say_hello()).await.unwrap();
+            assert_eq!(result.grpc_status(), Some("14"));
+            assert_eq!(
+                result.head.headers["grpc-message"],
+                "backend greeter is unhealthy: health check returned NOT_SERVING"
+            );
+
+            status.store(1, Ordering::Relaxed);
+            wait_until(|| health.is_healthy()).await;
+            let result = post(&mut sender, say_hello()).await.unwrap();
+            assert_eq!(result.grpc_status(), Some("0"));
+
+            drop(sender);
+            token.cancel();
+            proxy.await.unwrap();
+            let _ = std::fs::remove_file(&socket);
+        }
+    }
+
+    #[tokio::test]
+    async fn health_watch_fails_on_silent_backend() {
+        let token = CancellationToken::new();
+        let backend = temp_socket_path("health-silent-backend");
+        let socket = temp_socket_path("health-silent-relay");
+        let status = Arc::new(AtomicU64::new(2));
+        spawn_health_backend(&backend, status.clone(), token.child_token());
+        let frozen = Arc::new(AtomicBool::new(false));
+        spawn_freezable_relay(&socket, &backend, frozen.clone());
+        let config = HealthCheckConfig {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(200),
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+            watch: true,
+            ..Default::default()
+        };
+        let health = spawn_health_check(
+            "greeter",
+            UdsConnector::new(&socket),
+            config,
+            token.child_token(),
+        );
+        // changes pushed on the watch stream
+        wait_until(|| !health.is_healthy()).await;
+        status.store(1, Ordering::Relaxed);
+        wait_until(|| health.is_healthy()).await;
+
+        // the open watch stream stays silent, unanswered pings end it
+        frozen.store(true, Ordering::Relaxed);
+        wait_until(|| !health.is_healthy()).await;
+
+        token.cancel();
+        let _ = std::fs::remove_file(&backend);
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,71 @@
+//! Sockets the proxy accepts connections on.
+
+use std::{fmt, future::Future, io, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+/// Pause after a failed accept, which is mostly the process running out
+/// of file descriptors (`EMFILE`) and would fail again right away.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Address of the peer of an accepted connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Unix socket peers are usually unnamed.
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+/// Accept side of [`crate::proxy::serve_with_incoming`], implemented for
+/// tokio's TCP and unix domain socket listeners.
+pub trait Listener: Send + 'static {
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Io, PeerAddr)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Io = TcpStream;
+
+    async fn accept(&mut self) -> io::Result<(TcpStream, PeerAddr)> {
+        let (stream, addr) = TcpListener::accept(self).await?;
+        stream.set_nodelay(true)?;
+        Ok((stream, PeerAddr::Tcp(addr)))
+    }
+}
+
+impl Listener for UnixListener {
+    type Io = UnixStream;
+
+    async fn accept(&mut self) -> io::Result<(UnixStream, PeerAddr)> {
+        let (stream, _) = UnixListener::accept(self).await?;
+        Ok((stream, PeerAddr::Unix))
+    }
+}
+
+/// The next connection of `listener`. Failed accepts are logged and retried
+/// after [`ACCEPT_RETRY_DELAY`] rather than ending the server, so callers
+/// race this against their shutdown signal.
+pub(crate) async fn accept_retrying<L: Listener>(listener: &mut L) -> (L::Io, PeerAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                eprintln!("accept failed: {e}");
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,539 @@
+//! gRPC message framing, followed as bodies stream through the proxy.
+//!
+//! Each message is a 5 byte prefix (a compressed flag and a big-endian
+//! `u32` length) and then the payload. [`MessageCounter`] counts them for
+//! metrics and access logs. [`MessageDecoder`] also reads their lengths,
+//! holding back at most the first bytes of a prefix split over frames and
+//! never a payload.
+//!
+//! [`MessageLimitLayer`] ends calls with a message over the limits of the
+//! route with `RESOURCE_EXHAUSTED`. A request message never reaches the
+//! backend, whose stream is reset. The messages of a response before the
+//! large one are sent, followed by the trailers. Limits apply to the
+//! length on the wire, compressed or not.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use http::{Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use super::{
+    boxed_call,
+    status::{self, Code},
+    BoxError, ProxyBody,
+};
+
+/// Length of the prefix of every message.
+pub const PREFIX_LEN: usize = 5;
+
+/// Counts the gRPC messages of a body from their length prefixes, which
+/// may be split across data frames.
+#[derive(Debug, Default)]
+pub struct MessageCounter {
+    count: u64,
+    /// Bytes of the current message not seen yet.
+    remaining: u64,
+    prefix: [u8; PREFIX_LEN],
+    prefix_len: usize,
+}
+
+impl MessageCounter {
+    /// Messages whose prefix was seen so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = data.len().min(self.remaining as usize);
+                self.remaining -= n as u64;
+                data = &data[n..];
+                continue;
+            }
+            let n = data.len().min(PREFIX_LEN - self.prefix_len);
+            self.prefix[self.prefix_len..self.prefix_len + n].copy_from_slice(&data[..n]);
+            self.prefix_len += n;
+            data = &data[n..];
+            if self.prefix_len == PREFIX_LEN {
+                let [_, a, b, c, d] = self.prefix;
+                self.remaining = u32::from_be_bytes([a, b, c, d]).into();
+                self.prefix_len = 0;
+                self.count += 1;
+            }
+        }
+    }
+}
+
+/// A message longer than the limit of its direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    pub len: usize,
+    pub max: usize,
+}
+
+/// Follows the messages of one direction of a call to stop before one
+/// over the limit.
+#[derive(Debug, Default)]
+pub struct MessageDecoder {
+    max: Option<usize>,
+    /// Start of a prefix that did not fit in the last frame.
+    held: BytesMut,
+    /// Payload bytes of the current message still to come.
+    remaining: usize,
+}
+
+impl MessageDecoder {
+    /// Decoder rejecting messages over `max` bytes, none when `None`.
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            max,
+            ..Self::default()
+        }
+    }
+
+    /// Reads the next data frame of the stream. Returns the bytes to
+    /// forward, which leave out the start of a prefix split with the next
+    /// frame, or those before the prefix of a message over the limit.
+    pub fn decode(&mut self, data: Bytes) -> (Bytes, Option<MessageTooLarge>) {
+        let data = if self.held.is_empty() {
+            data
+        } else {
+            // rare, prefixes are seldom split
+            self.held.extend_from_slice(&data);
+            self.held.split().freeze()
+        };
+        let mut pos = 0;
+        loop {
+            let skip = self.remaining.min(data.len() - pos);
+            self.remaining -= skip;
+            pos += skip;
+            if data.len() - pos < PREFIX_LEN {
+                self.held.extend_from_slice(&data[pos..]);
+                return (data.slice(..pos), None);
+            }
+            let mut prefix = &data[pos + 1..pos + PREFIX_LEN];
+            let len = prefix.get_u32() as usize;
+            if let Some(max) = self.max.filter(|&max| len > max) {
+                return (data.slice(..pos), Some(MessageTooLarge { len, max }));
+            }
+            self.remaining = len;
+            pos += PREFIX_LEN;
+        }
+    }
+
+    /// Bytes held back at the end of the stream, which end it with an
+    /// incomplete prefix the peer will reject.
+    pub fn take_held(&mut self) -> Option<Bytes> {
+        (!self.held.is_empty()).then(|| self.held.split().freeze())
+    }
+}
+
+/// Message size limits of a route, none by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageLimits {
+    /// Longest request message in bytes.
+    pub max_request: Option<usize>,
+    /// Longest response message in bytes.
+    pub max_response: Option<usize>,
+}
+
+/// Enforces [`MessageLimits`], see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct MessageLimitLayer {
+    limits: MessageLimits,
+}
+
+impl MessageLimitLayer {
+    pub fn new(limits: MessageLimits) -> Self {
+        Self { limits }
+    }
+}
+
+impl<S> tower::Layer<S> for MessageLimitLayer {
+    type Service = MessageLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MessageLimitService {
+            inner,
+            limits: self.limits,
+        }
+    }
+}
+
+/// Request bodies reach the inner service as a [`ProxyBody`] that fails
+/// before a message over the limit.
+#[derive(Debug, Clone)]
+pub struct MessageLimitService<S> {
+    inner: S,
+    limits: MessageLimits,
+}
+
+impl<S, B> tower::Service<Request<B>> for MessageLimitService<S>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let rejection = Rejection::default();
+        let max_response = self.limits.max_response;
+        let req = req.map(|body| {
+            MessageBody {
+                inner: Some(body.map_err(Into::into).boxed()),
+                decoder: MessageDecoder::new(self.limits.max_request),
+                rejection: rejection.clone(),
+                rejected: None,
+                pending: None,
+            }
+            .boxed()
+        });
+        let fut = boxed_call(self.inner.call(req));
+        Box::pin(async move {
+            // dropping the call resets the backend stream
+            let resp = tokio::select! {
+                biased;
+                _ = rejection.token.cancelled() => return Ok(rejection.trailers_only()),
+                resp = fut => resp,
+            };
+            let resp = match resp {
+                // the request body failed on a large message
+                Err(_) if rejection.token.is_cancelled() => return Ok(rejection.trailers_only()),
+                resp => resp?,
+            };
+            let rejected = Box::pin(rejection.token.clone().cancelled_owned());
+            Ok(resp.map(|inner| {
+                MessageBody {
+                    inner: Some(inner),
+                    decoder: MessageDecoder::new(max_response),
+                    rejection,
+                    rejected: Some(rejected),
+                    pending: None,
+                }
+                .boxed()
+            }))
+        })
+    }
+}
+
+/// Why the request of a call was rejected, shared with its response.
+#[derive(Clone, Default)]
+struct Rejection {
+    token: CancellationToken,
+    message: Arc<OnceLock<String>>,
+}
+
+impl Rejection {
+    fn reject(&self, message: String) {
+        let _ = self.message.set(message);
+        self.token.cancel();
+    }
+
+    fn message(&self) -> &str {
+        self.message.get().map_or("", String::as_str)
+    }
+
+    fn trailers_only(&self) -> Response<ProxyBody> {
+        status::trailers_only(Code::ResourceExhausted, self.message())
+    }
+}
+
+/// Body checking the messages of one direction of a call. A request body
+/// fails on a large message, resetting the backend stream, while a
+/// response body ends with trailers.
+struct MessageBody {
+    /// `None` once finished or rejected.
+    inner: Option<ProxyBody>,
+    decoder: MessageDecoder,
+    rejection: Rejection,
+    /// Of the request, for response bodies only.
+    rejected: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+    /// Frame (or end of stream) due after the held bytes or data before
+    /// a large message.
+    pending: Option<Option<Frame<Bytes>>>,
+}
+
+impl MessageBody {
+    /// Sends `data` first when not empty, then `next`.
+    fn then(&mut self, data: Bytes, next: Option<Frame<Bytes>>) -> Option<Frame<Bytes>> {
+        if data.is_empty() {
+            return next;
+        }
+        self.pending = Some(next);
+        Some(Frame::data(data))
+    }
+}
+
+impl Body for MessageBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        if let Some(frame) = this.pending.take() {
+            return Poll::Ready(frame.map(Ok));
+        }
+        let Some(inner) = this.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        if let Some(rejected) = &mut this.rejected {
+            if rejected.as_mut().poll(cx).is_ready() {
+                this.inner = None;
+                let trailers = status::trailers(Code::ResourceExhausted, this.rejection.message());
+                return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+            }
+        }
+        loop {
+            let frame = match ready!(Pin::new(&mut *inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    this.inner = None;
+                    let held = this.decoder.take_held().unwrap_or_default();
+                    return Poll::Ready(this.then(held, None).map(Ok));
+                }
+            };
+            let data = match frame.into_data() {
+                Ok(data) => data,
+                Err(frame) => {
+                    let held = this.decoder.take_held().unwrap_or_default();
+                    return Poll::Ready(this.then(held, Some(frame)).map(Ok));
+                }
+            };
+            match this.decoder.decode(data) {
+                (data, None) if data.is_empty() => continue,
+                (data, None) => return Poll::Ready(Some(Ok(Frame::data(data)))),
+                (data, Some(too_large)) => {
+                    this.inner = None;
+                    if this.rejected.is_none() {
+                        let message = format!("request {}", describe(too_large));
+                        this.rejection.reject(message.clone());
+                        return Poll::Ready(Some(Err(message.into())));
+                    }
+                    let message = format!("response {}", describe(too_large));
+                    let trailers = status::trailers(Code::ResourceExhausted, message);
+                    return Poll::Ready(this.then(data, Some(Frame::trailers(trailers))).map(Ok));
+                }
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.pending.is_none()
+            && self
+                .inner
+                .as_ref()
+                .is_none_or(|inner| inner.is_end_stream())
+    }
+}
+
+fn describe(too_large: MessageTooLarge) -> String {
+    let MessageTooLarge { len, max } = too_large;
+    format!("message of {len} bytes is over the limit of {max} in proxy")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::{BufMut, Bytes, BytesMut};
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        MessageCounter, MessageDecoder, MessageLimitLayer, MessageLimits, MessageTooLarge,
+    };
+    use crate::proxy::{
+        connector::UdsConnector,
+        metrics::Metrics,
+        proxy_service::ProxyService,
+        test_util::{
+            self, client_config, h2_client, open_echo_stream, send, spawn_echo_backend,
+            spawn_proxy_service, temp_socket_path, ChannelBody,
+        },
+    };
+
+    fn message(len: usize) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(0);
+        buf.put_u32(len as u32);
+        buf.put_bytes(b'x', len);
+        buf
+    }
+
+    #[test]
+    fn counts_split_messages() {
+        let mut counter = MessageCounter::default();
+        // 3 byte message, empty message, 2 byte message in odd chunks
+        let body = [0, 0, 0, 0, 3, 1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 4, 5];
+        for chunk in body.chunks(3) {
+            counter.feed(chunk);
+        }
+        assert_eq!(counter.count, 3);
+        assert_eq!(counter.remaining, 0);
+        counter.feed(&[0, 0, 0]);
+        assert_eq!(counter.count, 3);
+    }
+
+    #[test]
+    fn follows_messages_across_frames() {
+        let mut stream = message(3);
+        stream.extend_from_slice(&message(0));
+        stream.extend_from_slice(&message(10));
+        let stream = stream.freeze();
+
+        // every split point, forwarding everything in order
+        for split in 0..=stream.len() {
+            let mut decoder = MessageDecoder::new(Some(10));
+            let mut out = BytesMut::new();
+            for part in [stream.slice(..split), stream.slice(split..)] {
+                let (data, too_large) = decoder.decode(part);
+                assert_eq!(too_large, None);
+                out.extend_from_slice(&data);
+            }
+            assert_eq!(decoder.take_held(), None);
+            assert_eq!(out.freeze(), stream, "split at {split}");
+        }
+    }
+
+    #[test]
+    fn stops_before_large_message() {
+        let mut decoder = MessageDecoder::new(Some(4));
+        let mut stream = message(4);
+        stream.extend_from_slice(&message(5));
+        let stream = stream.freeze();
+
+        // the prefix of the large message is split over two frames
+        let (data, too_large) = decoder.decode(stream.slice(..11));
+        assert_eq!((data, too_large), (stream.slice(..9), None));
+        let (data, too_large) = decoder.decode(stream.slice(11..));
+        assert_eq!(data, Bytes::new());
+        assert_eq!(too_large, Some(MessageTooLarge { len: 5, max: 4 }));
+
+        // an incomplete prefix at the end is still forwarded
+        let mut decoder = MessageDecoder::new(None);
+        let (data, _) = decoder.decode(stream.slice(..11));
+        assert_eq!(data, stream.slice(..9));
+        assert_eq!(decoder.take_held(), Some(stream.slice(9..11)));
+    }
+
+    /// A gRPC message with a payload of `len` bytes.
+    fn grpc_message(len: usize) -> Bytes {
+        let mut buf = vec![0];
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+        buf.resize(5 + len, b'x');
+        buf.into()
+    }
+
+    /// Reads data frames up to `len` bytes.
+    async fn read_data(body: &mut Incoming, len: usize) -> Bytes {
+        let mut out = Vec::new();
+        while out.len() < len {
+            let frame = body.frame().await.unwrap().unwrap();
+            out.extend_from_slice(&frame.into_data().unwrap());
+        }
+        out.into()
+    }
+
+    #[tokio::test]
+    async fn limits_message_sizes() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("messages");
+        let mut heads = spawn_echo_backend(&socket, token.child_token());
+        let metrics = Metrics::new();
+        let limits = MessageLimits {
+            max_request: Some(16),
+            max_response: Some(8),
+        };
+        let service = tower::Layer::layer(
+            &MessageLimitLayer::new(limits),
+            ProxyService::new(UdsConnector::new(&socket)),
+        );
+        let service = tower::Layer::layer(&metrics.layer("messages"), service);
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, service, token.child_token()).await;
+        let mut sender = h2_client::<ChannelBody>(addr, client_config(cert, None))
+            .await
+            .unwrap();
+
+        // messages within both limits are echoed, split prefixes too
+        let (frames, mut body) = open_echo_stream(&mut sender).await;
+        heads.recv().await.unwrap();
+        let small = grpc_message(4);
+        send(&frames, small.clone()).await;
+        assert_eq!(read_data(&mut body, small.len()).await, small);
+        let split = grpc_message(8);
+        send(&frames, split.slice(..3)).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        send(&frames, split.slice(3..)).await;
+        assert_eq!(read_data(&mut body, split.len()).await, split);
+
+        // a large response message ends the call after the ones before
+        send(&frames, grpc_message(12)).await;
+        let trailers = body
+            .frame()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_trailers()
+            .unwrap();
+        assert_eq!(trailers["grpc-status"], "8");
+        assert_eq!(
+            trailers["grpc-message"],
+            "response message of 12 bytes is over the limit of 8 in proxy"
+        );
+        drop(frames);
+
+        // a large request message never reaches the backend
+        let (frames, mut body) = open_echo_stream(&mut sender).await;
+        heads.recv().await.unwrap();
+        send(&frames, grpc_message(100)).await;
+        let trailers = body
+            .frame()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_trailers()
+            .unwrap();
+        assert_eq!(trailers["grpc-status"], "8");
+        assert_eq!(
+            trailers["grpc-message"],
+            "request message of 100 bytes is over the limit of 16 in proxy"
+        );
+        drop(frames);
+
+        let text = metrics.render();
+        for line in [
+            r#"grpc_proxy_received_messages_total{route="messages"} 4"#,
+            r#"grpc_proxy_sent_messages_total{route="messages"} 2"#,
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing in\n{text}");
+        }
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
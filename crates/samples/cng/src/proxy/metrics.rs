@@ -0,0 +1,792 @@
+//! Prometheus metrics of the proxy, served on a separate admin listener.
+//!
+//! [`MetricsLayer`] records every call of a route: its gRPC status (from
+//! the response headers of trailers-only responses, the trailers
+//! otherwise), its duration until the response body ended, and the bytes
+//! and gRPC messages in both directions. The backend a call went to is taken from the
+//! [`BackendName`] the [`ProxyService`] tags responses with. Servers added
+//! with [`Metrics::register_server`] export their [`ServerStats`], and
+//! passthrough servers added with [`Metrics::register_passthrough`] their
+//! [`PassthroughStats`].
+//!
+//! [`serve_admin`] answers `GET /metrics` in the text exposition format,
+//! written by hand as the proxy only needs counters and one histogram.
+//!
+//! [`ProxyService`]: super::proxy_service::ProxyService
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http::{header::CONTENT_TYPE, HeaderMap, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame, Incoming};
+use hyper_util::{rt::TokioIo, server::graceful::GracefulShutdown};
+
+use super::{
+    boxed_call,
+    listener::{accept_retrying, Listener},
+    message::MessageCounter,
+    passthrough::PassthroughStats,
+    proxy_service::BackendName,
+    server::ServerStats,
+    status::{Code, GRPC_STATUS},
+    BoxError, ProxyBody,
+};
+
+/// Upper bounds in seconds of the call duration histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long open scrape connections get to finish after the signal.
+const ADMIN_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Shared registry of the proxy metrics; clones record into the same one.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    servers: Mutex<Vec<(String, Arc<ServerStats>)>>,
+    passthrough: Mutex<Vec<(String, Arc<PassthroughStats>)>>,
+    routes: Mutex<BTreeMap<String, Arc<RouteTraffic>>>,
+    /// By `(route, backend)`.
+    calls: Mutex<BTreeMap<(String, String), CallStats>>,
+}
+
+#[derive(Default)]
+struct RouteTraffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+    received_messages: AtomicU64,
+    sent_messages: AtomicU64,
+}
+
+#[derive(Default)]
+struct CallStats {
+    codes: BTreeMap<i32, u64>,
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exports the connection, stream and TLS handshake counters of a
+    /// server serving `route`, see [`super::ServeOptions::stats`].
+    pub fn register_server(&self, route: impl Into<String>, stats: Arc<ServerStats>) {
+        self.inner
+            .servers
+            .lock()
+            .unwrap()
+            .push((route.into(), stats));
+    }
+
+    /// Exports the connection and byte counters of a passthrough server
+    /// serving `route`, see [`super::passthrough::PassthroughOptions::stats`].
+    pub fn register_passthrough(&self, route: impl Into<String>, stats: Arc<PassthroughStats>) {
+        self.inner
+            .passthrough
+            .lock()
+            .unwrap()
+            .push((route.into(), stats));
+    }
+
+    /// Layer recording the calls of `route`, e.g. the listener name.
+    pub fn layer(&self, route: impl Into<String>) -> MetricsLayer {
+        let route: String = route.into();
+        let traffic = self
+            .inner
+            .routes
+            .lock()
+            .unwrap()
+            .entry(route.clone())
+            .or_default()
+            .clone();
+        MetricsLayer {
+            metrics: self.clone(),
+            route: route.into(),
+            traffic,
+        }
+    }
+
+    fn record(&self, route: &str, backend: &str, code: Code, elapsed: Duration) {
+        let mut calls = self.inner.calls.lock().unwrap();
+        let stats = calls
+            .entry((route.to_string(), backend.to_string()))
+            .or_default();
+        *stats.codes.entry(code.as_i32()).or_default() += 1;
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in stats.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        stats.count += 1;
+        stats.sum += secs;
+    }
+
+    /// All metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        {
+            let calls = self.inner.calls.lock().unwrap();
+            header(
+                &mut out,
+                "grpc_proxy_requests_total",
+                "counter",
+                "Calls completed, by gRPC status code.",
+            );
+            for ((route, backend), stats) in calls.iter() {
+                for (&code, count) in &stats.codes {
+                    let code = Code::from_i32(code).name();
+                    let labels = labels(&[("route", route), ("backend", backend)]);
+                    let _ = writeln!(
+                        out,
+                        "grpc_proxy_requests_total{{{labels},grpc_code=\"{code}\"}} {count}"
+                    );
+                }
+            }
+            header(
+                &mut out,
+                "grpc_proxy_request_duration_seconds",
+                "histogram",
+                "Time from the request headers to the end of the response.",
+            );
+            for ((route, backend), stats) in calls.iter() {
+                let labels = labels(&[("route", route), ("backend", backend)]);
+                for (count, bound) in stats.buckets.iter().zip(LATENCY_BUCKETS) {
+                    let _ = writeln!(
+                        out,
+                        "grpc_proxy_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {count}"
+                    );
+                }
+                let _ = writeln!(
+                    out,
+                    "grpc_proxy_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                    stats.count
+                );
+                let _ = writeln!(
+                    out,
+                    "grpc_proxy_request_duration_seconds_sum{{{labels}}} {}",
+                    stats.sum
+                );
+                let _ = writeln!(
+                    out,
+                    "grpc_proxy_request_duration_seconds_count{{{labels}}} {}",
+                    stats.count
+                );
+            }
+        }
+
+        let routes = self.inner.routes.lock().unwrap();
+        let received = routes
+            .iter()
+            .map(|(route, b)| (route.as_str(), b.received.load(Ordering::Relaxed)));
+        per_route(
+            &mut out,
+            "grpc_proxy_received_bytes_total",
+            "counter",
+            "Request body bytes received from clients.",
+            received,
+        );
+        let sent = routes
+            .iter()
+            .map(|(route, b)| (route.as_str(), b.sent.load(Ordering::Relaxed)));
+        per_route(
+            &mut out,
+            "grpc_proxy_sent_bytes_total",
+            "counter",
+            "Response body bytes sent to clients.",
+            sent,
+        );
+        let received_messages = routes.iter().map(|(route, t)| {
+            let count = t.received_messages.load(Ordering::Relaxed);
+            (route.as_str(), count)
+        });
+        per_route(
+            &mut out,
+            "grpc_proxy_received_messages_total",
+            "counter",
+            "gRPC messages received from clients.",
+            received_messages,
+        );
+        let sent_messages = routes.iter().map(|(route, t)| {
+            let count = t.sent_messages.load(Ordering::Relaxed);
+            (route.as_str(), count)
+        });
+        per_route(
+            &mut out,
+            "grpc_proxy_sent_messages_total",
+            "counter",
+            "gRPC messages sent to clients.",
+            sent_messages,
+        );
+        drop(routes);
+
+        let servers = self.inner.servers.lock().unwrap();
+        let server = |value: fn(&ServerStats) -> u64| {
+            servers
+                .iter()
+                .map(move |(route, stats)| (route.as_str(), value(stats)))
+        };
+        per_route(
+            &mut out,
+            "grpc_proxy_active_connections",
+            "gauge",
+            "Client connections currently open.",
+            server(|s| s.active_connections() as u64),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_active_streams",
+            "gauge",
+            "Calls whose response has not been fully sent yet.",
+            server(|s| s.active_streams() as u64),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_tls_handshake_failures_total",
+            "counter",
+            "TLS handshakes with clients that failed.",
+            server(ServerStats::tls_handshake_failures),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_cancelled_streams_total",
+            "counter",
+            "Calls ended with UNAVAILABLE because the drain timeout expired.",
+            server(ServerStats::cancelled_streams),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_shed_calls_total",
+            "counter",
+            "Calls ended with RESOURCE_EXHAUSTED because too many were in flight.",
+            server(ServerStats::shed_calls),
+        );
+        drop(servers);
+
+        let passthrough = self.inner.passthrough.lock().unwrap();
+        let server = |value: fn(&PassthroughStats) -> u64| {
+            passthrough
+                .iter()
+                .map(move |(route, stats)| (route.as_str(), value(stats)))
+        };
+        per_route(
+            &mut out,
+            "grpc_proxy_passthrough_active_connections",
+            "gauge",
+            "Passthrough connections currently open.",
+            server(|s| s.active_connections() as u64),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_passthrough_received_bytes_total",
+            "counter",
+            "Bytes received from passthrough clients.",
+            server(PassthroughStats::received_bytes),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_passthrough_sent_bytes_total",
+            "counter",
+            "Bytes sent to passthrough clients.",
+            server(PassthroughStats::sent_bytes),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_passthrough_unrouted_connections_total",
+            "counter",
+            "Passthrough connections closed without a ClientHello or a route.",
+            server(PassthroughStats::unrouted_connections),
+        );
+        per_route(
+            &mut out,
+            "grpc_proxy_passthrough_connect_failures_total",
+            "counter",
+            "Passthrough backend connections that failed or timed out.",
+            server(PassthroughStats::connect_failures),
+        );
+        out
+    }
+}
+
+/// A metric with a `route` label only.
+fn per_route<'a>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    header(out, name, kind, help);
+    for (route, value) in values {
+        let labels = labels(&[("route", route)]);
+        let _ = writeln!(out, "{name}{{{labels}}} {value}");
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// `a="x",b="y"` with the values escaped.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    for (i, (name, value)) in pairs.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{name}=\"");
+        for c in value.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    out
+}
+
+/// Records the calls of one route into [`Metrics`], see
+/// [`Metrics::layer`].
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Metrics,
+    route: Arc<str>,
+    traffic: Arc<RouteTraffic>,
+}
+
+impl<S> tower::Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Request bodies reach the inner service as a [`ProxyBody`] counting
+/// the bytes and messages received.
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    layer: MetricsLayer,
+}
+
+impl<S, B> tower::Service<Request<B>> for MetricsService<S>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mut call = Call {
+            layer: self.layer.clone(),
+            backend: None,
+            start: Instant::now(),
+            code: None,
+            ended: false,
+        };
+        let traffic = self.layer.traffic.clone();
+        let mut messages = MessageCounter::default();
+        let req = req.map(|body| {
+            body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref() {
+                    traffic
+                        .received
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                    let before = messages.count();
+                    messages.feed(data);
+                    traffic
+                        .received_messages
+                        .fetch_add(messages.count() - before, Ordering::Relaxed);
+                }
+                frame
+            })
+            .map_err(Into::into)
+            .boxed()
+        });
+        let fut = boxed_call(self.inner.call(req));
+        Box::pin(async move {
+            // an error resets the stream, the call is recorded as UNKNOWN
+            let resp = fut.await?;
+            call.backend = resp.extensions().get::<BackendName>().cloned();
+            call.code = grpc_status(resp.headers());
+            Ok(resp.map(|inner| {
+                MetricsBody {
+                    inner,
+                    call,
+                    messages: MessageCounter::default(),
+                }
+                .boxed()
+            }))
+        })
+    }
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<Code> {
+    headers.get(GRPC_STATUS).and_then(Code::from_header)
+}
+
+/// One call in flight, recorded when dropped.
+struct Call {
+    layer: MetricsLayer,
+    backend: Option<BackendName>,
+    start: Instant,
+    code: Option<Code>,
+    /// The response body ended; without a status that is `UNKNOWN`, while
+    /// a body dropped before its end was `CANCELLED` by the client.
+    ended: bool,
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let code = self.code.unwrap_or(if self.ended {
+            Code::Unknown
+        } else {
+            Code::Cancelled
+        });
+        let backend = self.backend.as_ref().map_or("", |b| b.as_str());
+        self.layer
+            .metrics
+            .record(&self.layer.route, backend, code, self.start.elapsed());
+    }
+}
+
+/// Response body counting the bytes and messages sent and reading the
+/// status from the trailers.
+struct MetricsBody {
+    inner: ProxyBody,
+    call: Call,
+    messages: MessageCounter,
+}
+
+impl Body for MetricsBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    let traffic = &this.call.layer.traffic;
+                    traffic.sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    let before = this.messages.count();
+                    this.messages.feed(data);
+                    traffic
+                        .sent_messages
+                        .fetch_add(this.messages.count() - before, Ordering::Relaxed);
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    this.call.code = grpc_status(trailers).or(this.call.code);
+                }
+            }
+            Some(Err(_)) => {}
+            None => this.call.ended = true,
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Serves `GET /metrics` over HTTP/1.1 on `listener` until `signal`
+/// resolves.
+pub async fn serve_admin(
+    mut listener: impl Listener,
+    metrics: Metrics,
+    signal: impl Future<Output = ()>,
+) -> Result<(), BoxError> {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(signal);
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = accept_retrying(&mut listener) => accepted,
+            _ = &mut signal => break,
+        };
+        let metrics = metrics.clone();
+        let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+            let resp = admin_response(&req, &metrics);
+            async move { Ok::<_, std::convert::Infallible>(resp) }
+        });
+        let conn = hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service);
+        let conn = graceful.watch(conn);
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                eprintln!("admin connection from {peer_addr} error: {e}");
+            }
+        });
+    }
+    drop(listener);
+    let _ = tokio::time::timeout(ADMIN_SHUTDOWN_TIMEOUT, graceful.shutdown()).await;
+    Ok(())
+}
+
+fn admin_response(req: &Request<Incoming>, metrics: &Metrics) -> Response<Full<Bytes>> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => (StatusCode::OK, metrics.render()),
+        (_, "/metrics") => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+        _ => (StatusCode::NOT_FOUND, String::new()),
+    };
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    *resp.status_mut() = status;
+    if status == StatusCode::OK {
+        resp.headers_mut().insert(
+            CONTENT_TYPE,
+            http::HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        );
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+    use grpc_tests::helloworld_tonic::{greeter_client::GreeterClient, HelloRequest};
+    use http::Request;
+    use http_body_util::{BodyExt, Empty};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsAcceptor;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{labels, serve_admin, Metrics};
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        serve_with_options,
+        status::Code,
+        test_util::{
+            self, client_config, h2_client, post, spawn_uds_greeter, temp_socket_path, tls_channel,
+        },
+        Listener, PeerAddr, ServeOptions,
+    };
+
+    #[test]
+    fn renders_histogram_and_escapes_labels() {
+        assert_eq!(
+            labels(&[("route", "a\"b\\c\nd"), ("backend", "")]),
+            r#"route="a\"b\\c\nd",backend="""#
+        );
+
+        let metrics = Metrics::new();
+        metrics.record("public", "unix:/a", Code::Ok, Duration::from_millis(20));
+        metrics.record("public", "unix:/a", Code::Ok, Duration::from_millis(300));
+        metrics.record("public", "unix:/a", Code::NotFound, Duration::from_secs(20));
+        let text = metrics.render();
+        for line in [
+            r#"grpc_proxy_requests_total{route="public",backend="unix:/a",grpc_code="OK"} 2"#,
+            r#"grpc_proxy_requests_total{route="public",backend="unix:/a",grpc_code="NOT_FOUND"} 1"#,
+            r#"grpc_proxy_request_duration_seconds_bucket{route="public",backend="unix:/a",le="0.025"} 1"#,
+            r#"grpc_proxy_request_duration_seconds_bucket{route="public",backend="unix:/a",le="0.5"} 2"#,
+            r#"grpc_proxy_request_duration_seconds_bucket{route="public",backend="unix:/a",le="10"} 2"#,
+            r#"grpc_proxy_request_duration_seconds_bucket{route="public",backend="unix:/a",le="+Inf"} 3"#,
+            r#"grpc_proxy_request_duration_seconds_sum{route="public",backend="unix:/a"} 20.32"#,
+            r#"grpc_proxy_request_duration_seconds_count{route="public",backend="unix:/a"} 3"#,
+            "# TYPE grpc_proxy_request_duration_seconds histogram",
+        ] {
+            assert!(text.lines().any(|l| l == line), "{line} missing in\n{text}");
+        }
+    }
+
+    /// `GET path` on the admin listener, returning status and body.
+    async fn admin_get(addr: SocketAddr, path: &str) -> (http::StatusCode, String) {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tcp))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let req = Request::get(path)
+            .header("host", "localhost")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Scrapes until every line in `expected` shows up, as calls are recorded
+    /// once the proxy dropped their response body.
+    async fn scrape_until(addr: SocketAddr, expected: &[&str]) -> String {
+        for _ in 0..200 {
+            let (status, text) = admin_get(addr, "/metrics").await;
+            assert_eq!(status, 200);
+            if expected.iter().all(|e| text.lines().any(|l| l == *e)) {
+                return text;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{expected:?} not scraped");
+    }
+
+    #[tokio::test]
+    async fn exports_metrics_on_admin_listener() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("metrics");
+        let backend = spawn_uds_greeter(&socket, token.child_token());
+
+        let metrics = Metrics::new();
+        let options = ServeOptions::default();
+        metrics.register_server("public", options.stats.clone());
+        let service = tower::Layer::layer(
+            &metrics.layer("public"),
+            ProxyService::new(UdsConnector::new(&socket)).with_name("greeter"),
+        );
+        let (mut server_config, cert) = test_util::load_test_server_config();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let proxy = tokio::spawn(serve_with_options(
+            listener,
+            Some(TlsAcceptor::from(Arc::new(server_config))),
+            service,
+            options,
+            token.child_token().cancelled_owned(),
+        ));
+        let admin_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = admin_listener.local_addr().unwrap();
+        let admin = tokio::spawn(serve_admin(
+            admin_listener,
+            metrics,
+            token.child_token().cancelled_owned(),
+        ));
+
+        let mut client = GreeterClient::new(tls_channel(addr, cert.clone()).await);
+        for _ in 0..3 {
+            client
+                .say_hello(HelloRequest {
+                    name: "metrics".to_string(),
+                })
+                .await
+                .unwrap();
+        }
+        let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+        let resp = post(
+            &mut sender,
+            Request::builder().uri("/helloworld.Greeter/Nope"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.grpc_status(), Some("12"));
+        // a client that does not speak TLS
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut tcp, b"GET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let _ = tokio::io::AsyncReadExt::read_to_end(&mut tcp, &mut Vec::new()).await;
+
+        // 3 requests of 5 + 9 bytes, 3 replies of 5 + 16 bytes
+        let text = scrape_until(
+            admin_addr,
+            &[
+                r#"grpc_proxy_requests_total{route="public",backend="greeter",grpc_code="OK"} 3"#,
+                r#"grpc_proxy_requests_total{route="public",backend="greeter",grpc_code="UNIMPLEMENTED"} 1"#,
+                r#"grpc_proxy_request_duration_seconds_count{route="public",backend="greeter"} 4"#,
+                r#"grpc_proxy_received_bytes_total{route="public"} 42"#,
+                r#"grpc_proxy_sent_bytes_total{route="public"} 63"#,
+                r#"grpc_proxy_tls_handshake_failures_total{route="public"} 1"#,
+                r#"grpc_proxy_active_streams{route="public"} 0"#,
+            ],
+        )
+        .await;
+        assert!(
+            text.contains("# TYPE grpc_proxy_request_duration_seconds histogram"),
+            "{text}"
+        );
+        assert_eq!(
+            admin_get(admin_addr, "/other").await.0,
+            http::StatusCode::NOT_FOUND
+        );
+
+        drop((client, sender));
+        token.cancel();
+        proxy.await.unwrap().unwrap();
+        admin.await.unwrap().unwrap();
+        backend.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    /// TCP listener whose first `failures` accepts fail like a process out of
+    /// file descriptors.
+    struct FlakyListener {
+        inner: TcpListener,
+        failures: usize,
+    }
+
+    impl Listener for FlakyListener {
+        type Io = TcpStream;
+
+        async fn accept(&mut self) -> std::io::Result<(TcpStream, PeerAddr)> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(std::io::Error::from_raw_os_error(24));
+            }
+            Listener::accept(&mut self.inner).await
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_accepting_after_accept_errors() {
+        let token = CancellationToken::new();
+        let inner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = inner.local_addr().unwrap();
+        let listener = FlakyListener { inner, failures: 3 };
+        let admin = tokio::spawn(serve_admin(
+            listener,
+            Metrics::new(),
+            token.child_token().cancelled_owned(),
+        ));
+
+        assert_eq!(admin_get(addr, "/metrics").await.0, 200);
+        assert!(!admin.is_finished());
+        token.cancel();
+        admin.await.unwrap().unwrap();
+    }
+}
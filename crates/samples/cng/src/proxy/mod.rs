@@ -0,0 +1,56 @@
+//! Linux counterpart of the yarrp/yarrp-rustls pipeline used on Windows.
+//!
+//! TLS is terminated with rustls (ring provider) and the decrypted h2
+//! stream is forwarded to a backend socket, mirroring the structure of
+//! `yarrp::proxy_service::ProxyService` + `yarrp::connector::UdsConnector`.
+
+pub mod access_log;
+pub mod activation;
+mod activity;
+pub mod auth;
+pub mod balancer;
+pub mod client_cert;
+pub mod connector;
+pub mod deadline;
+pub mod grpc_web;
+pub mod health;
+pub mod listener;
+pub mod message;
+pub mod metrics;
+pub mod passthrough;
+mod proto;
+pub mod proxy_protocol;
+pub mod proxy_service;
+pub mod rate_limit;
+pub mod retry;
+pub mod router;
+pub mod server;
+pub mod status;
+pub mod test_util;
+pub mod trace;
+
+pub use listener::{Listener, PeerAddr};
+pub use server::{
+    serve_plaintext_with_incoming, serve_with_incoming, serve_with_options, CallLimit,
+    ConnectionInfo, ServeOptions, ServerStats,
+};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Body type produced by the proxy for both directions.
+pub type ProxyBody = http_body_util::combinators::BoxBody<bytes::Bytes, BoxError>;
+
+/// Future of a call to a layer wrapping a [`ProxyBody`] service.
+pub type CallFuture = std::pin::Pin<
+    Box<dyn std::future::Future<Output = Result<http::Response<ProxyBody>, BoxError>> + Send>,
+>;
+
+/// Boxes the future of an inner service for a layer to await in its own
+/// `async` block. Awaiting the inner future there directly trips the
+/// higher-ranked `Send` check on the error type of the request body.
+pub(crate) fn boxed_call<F>(fut: F) -> CallFuture
+where
+    F: std::future::Future<Output = Result<http::Response<ProxyBody>, BoxError>> + Send + 'static,
+{
+    Box::pin(fut)
+}
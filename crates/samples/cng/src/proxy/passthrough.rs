@@ -0,0 +1,690 @@
+//! Layer-4 TLS passthrough, routed by SNI, for backends that terminate TLS
+//! themselves.
+//!
+//! Where [`serve_with_options`](super::serve_with_options) ends TLS and
+//! forwards h2 streams, [`serve_passthrough`] only reads the TLS records of
+//! the ClientHello of every connection, without decrypting anything, to
+//! learn the SNI name and ALPN protocols the client asked for. A
+//! [`PassthroughRouter`] picks the backend by name, the records are
+//! replayed to it and bytes are then copied both ways until either side
+//! closes, or until none moved for [`PassthroughOptions::idle_timeout`].
+//! [`PassthroughStats`] count connections and bytes.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::Semaphore,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use super::{
+    activity::Activity,
+    connector::Connector,
+    listener::{accept_retrying, Listener, PeerAddr},
+    server::acquire,
+    BoxError,
+};
+
+/// Default of [`PassthroughOptions::connect_timeout`].
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client may take to send its ClientHello.
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TLS record content type of handshake messages.
+const HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+
+/// Largest record payload, with room for the expansion TLS allows.
+const MAX_RECORD_LEN: usize = (1 << 14) + 2048;
+
+/// Largest ClientHello read; real ones stay well below.
+const MAX_HELLO_LEN: usize = 1 << 16;
+
+const SERVER_NAME: u16 = 0;
+const ALPN: u16 = 16;
+const HOST_NAME: u8 = 0;
+
+/// What a client asked for in its ClientHello.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// SNI host name, lowercase and without a trailing dot.
+    pub server_name: Option<String>,
+    /// ALPN protocols in the client's order of preference.
+    pub alpn_protocols: Vec<Vec<u8>>,
+}
+
+/// Reads the TLS records holding the ClientHello from `io` and nothing
+/// after them. Returns the hello and the bytes read, to be replayed to the
+/// backend.
+pub async fn read_client_hello<IO>(io: &mut IO) -> io::Result<(ClientHello, Bytes)>
+where
+    IO: AsyncRead + Unpin,
+{
+    let mut records = BytesMut::new();
+    let mut handshake = Vec::new();
+    loop {
+        let mut header = [0; 5];
+        io.read_exact(&mut header).await?;
+        if header[0] != HANDSHAKE {
+            return Err(invalid("not a TLS handshake"));
+        }
+        let len = usize::from(u16::from_be_bytes([header[3], header[4]]));
+        if len == 0 || len > MAX_RECORD_LEN {
+            return Err(invalid("invalid TLS record length"));
+        }
+        let start = handshake.len();
+        handshake.resize(start + len, 0);
+        io.read_exact(&mut handshake[start..]).await?;
+        records.extend_from_slice(&header);
+        records.extend_from_slice(&handshake[start..]);
+
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != CLIENT_HELLO {
+            return Err(invalid("first handshake message is not a ClientHello"));
+        }
+        let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]);
+        let hello_len = hello_len as usize;
+        if hello_len > MAX_HELLO_LEN {
+            return Err(invalid("ClientHello too long"));
+        }
+        if handshake.len() >= 4 + hello_len {
+            let hello = parse_client_hello(&handshake[4..4 + hello_len])?;
+            return Ok((hello, records.freeze()));
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads the fields of a handshake message.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid("truncated ClientHello"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A field prefixed with its one byte length.
+    fn vec8(&mut self) -> io::Result<Reader<'a>> {
+        let len = self.u8()?;
+        Ok(Reader(self.take(len.into())?))
+    }
+
+    /// A field prefixed with its two byte length.
+    fn vec16(&mut self) -> io::Result<Reader<'a>> {
+        let len = self.u16()?;
+        Ok(Reader(self.take(len.into())?))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Body of a ClientHello, after the handshake message header.
+fn parse_client_hello(body: &[u8]) -> io::Result<ClientHello> {
+    let mut hello = Reader(body);
+    // legacy_version and random
+    hello.take(2 + 32)?;
+    hello.vec8()?; // legacy_session_id
+    hello.vec16()?; // cipher_suites
+    hello.vec8()?; // legacy_compression_methods
+    let mut result = ClientHello::default();
+    if hello.is_empty() {
+        return Ok(result);
+    }
+    let mut extensions = hello.vec16()?;
+    while !extensions.is_empty() {
+        let kind = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        match kind {
+            SERVER_NAME => {
+                let mut names = data.vec16()?;
+                while !names.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vec16()?.0;
+                    if name_type != HOST_NAME {
+                        continue;
+                    }
+                    let name =
+                        std::str::from_utf8(name).map_err(|_| invalid("SNI name is not ASCII"))?;
+                    let name = name.strip_suffix('.').unwrap_or(name);
+                    result.server_name = Some(name.to_ascii_lowercase());
+                }
+            }
+            ALPN => {
+                let mut protocols = data.vec16()?;
+                while !protocols.is_empty() {
+                    result.alpn_protocols.push(protocols.vec8()?.0.to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(result)
+}
+
+/// Backends by SNI name, and the one for other names or clients sending
+/// none.
+#[derive(Debug)]
+pub struct PassthroughRouter<C> {
+    routes: HashMap<String, C>,
+    default: Option<C>,
+}
+
+impl<C> PassthroughRouter<C> {
+    pub fn new(routes: impl IntoIterator<Item = (String, C)>, default: Option<C>) -> Self {
+        let routes = (routes.into_iter())
+            .map(|(name, connector)| (name.to_ascii_lowercase(), connector))
+            .collect();
+        Self { routes, default }
+    }
+
+    /// Backend of a client that sent `hello`; connections without one are
+    /// closed.
+    pub fn route(&self, hello: &ClientHello) -> Option<&C> {
+        (hello.server_name.as_deref())
+            .and_then(|name| self.routes.get(name))
+            .or(self.default.as_ref())
+    }
+}
+
+/// Settings of [`serve_passthrough`].
+#[derive(Debug, Clone)]
+pub struct PassthroughOptions {
+    /// How long connecting to a backend may take.
+    pub connect_timeout: Duration,
+    /// Connections without bytes in either direction for this long are
+    /// closed; never when `None`.
+    pub idle_timeout: Option<Duration>,
+    /// How long open connections may continue once shutdown started
+    /// before they are closed.
+    pub drain_timeout: Duration,
+    /// Connections open at once; further clients wait in the listen
+    /// backlog. Unlimited when `None`.
+    pub max_connections: Option<usize>,
+    /// Counters of this server, shared with the caller.
+    pub stats: Arc<PassthroughStats>,
+}
+
+impl Default for PassthroughOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            idle_timeout: None,
+            drain_timeout: super::server::DEFAULT_DRAIN_TIMEOUT,
+            max_connections: None,
+            stats: Arc::default(),
+        }
+    }
+}
+
+/// Live counters of a passthrough server.
+#[derive(Debug, Default)]
+pub struct PassthroughStats {
+    active_connections: AtomicUsize,
+    received_bytes: AtomicU64,
+    sent_bytes: AtomicU64,
+    unrouted_connections: AtomicU64,
+    connect_failures: AtomicU64,
+}
+
+impl PassthroughStats {
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from clients, the ClientHello included.
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to clients.
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed without a backend: no valid ClientHello in time,
+    /// or no route for its name.
+    pub fn unrouted_connections(&self) -> u64 {
+        self.unrouted_connections.load(Ordering::Relaxed)
+    }
+
+    /// Backend connections that failed or timed out.
+    pub fn connect_failures(&self) -> u64 {
+        self.connect_failures.load(Ordering::Relaxed)
+    }
+}
+
+/// Accepts connections on `listener` and splices each to the backend
+/// `router` picks, see the [module docs](self), until `signal` resolves.
+/// The listener is then closed, and connections still open after
+/// `options.drain_timeout` are closed too.
+pub async fn serve_passthrough<C: Connector>(
+    mut listener: impl Listener,
+    router: PassthroughRouter<C>,
+    options: PassthroughOptions,
+    signal: impl Future<Output = ()>,
+) -> Result<(), BoxError> {
+    let router = Arc::new(router);
+    let connections = TaskTracker::new();
+    let drain = CancellationToken::new();
+    let stats = options.stats.clone();
+    let connection_slots = options
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+    tokio::pin!(signal);
+    loop {
+        // taken before accepting, like `serve_with_options` does
+        let connection_slot = tokio::select! {
+            slot = acquire(&connection_slots) => slot,
+            _ = &mut signal => break,
+        };
+        let (stream, peer_addr) = tokio::select! {
+            accepted = accept_retrying(&mut listener) => accepted,
+            _ = &mut signal => break,
+        };
+        let router = router.clone();
+        let options = options.clone();
+        let drain = drain.clone();
+        connections.spawn(async move {
+            let _connection_slot = connection_slot;
+            tokio::select! {
+                _ = splice(stream, &peer_addr, &router, &options) => {}
+                _ = drain.cancelled() => {}
+            }
+        });
+    }
+    drop(listener);
+
+    println!(
+        "shutting down: draining {} passthrough connections",
+        stats.active_connections()
+    );
+    connections.close();
+    if tokio::time::timeout(options.drain_timeout, connections.wait())
+        .await
+        .is_err()
+    {
+        println!(
+            "drain timeout of {:?} expired: closing {} connections",
+            options.drain_timeout,
+            stats.active_connections()
+        );
+        drain.cancel();
+        connections.wait().await;
+    }
+    println!("shutdown complete");
+    Ok(())
+}
+
+/// Serves one client connection until either side closes it or it idles.
+async fn splice<IO, C>(
+    stream: IO,
+    peer_addr: &PeerAddr,
+    router: &PassthroughRouter<C>,
+    options: &PassthroughOptions,
+) where
+    IO: AsyncRead + AsyncWrite + Unpin,
+    C: Connector,
+{
+    let stats = &options.stats;
+    let _connection = ActiveConnection::new(stats.clone());
+    let activity = Arc::new(Activity::new());
+    let mut client = CountingIo {
+        inner: stream,
+        stats: stats.clone(),
+        activity: activity.clone(),
+    };
+
+    let hello = match tokio::time::timeout(HELLO_TIMEOUT, read_client_hello(&mut client)).await {
+        Ok(Ok(hello)) => hello,
+        Ok(Err(e)) => {
+            stats.unrouted_connections.fetch_add(1, Ordering::Relaxed);
+            eprintln!("connection from {peer_addr} closed: {e}");
+            return;
+        }
+        Err(_) => {
+            stats.unrouted_connections.fetch_add(1, Ordering::Relaxed);
+            eprintln!("connection from {peer_addr} closed: no ClientHello in time");
+            return;
+        }
+    };
+    let (hello, records) = hello;
+    let Some(connector) = router.route(&hello) else {
+        stats.unrouted_connections.fetch_add(1, Ordering::Relaxed);
+        let name = hello.server_name.as_deref().unwrap_or("(none)");
+        eprintln!("connection from {peer_addr} closed: no route for server name {name}");
+        return;
+    };
+    let backend = match tokio::time::timeout(options.connect_timeout, connector.connect()).await {
+        Ok(Ok(backend)) => backend,
+        Ok(Err(e)) => {
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            eprintln!("connection from {peer_addr} closed: backend connect failed: {e}");
+            return;
+        }
+        Err(_) => {
+            stats.connect_failures.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "connection from {peer_addr} closed: backend connect timed out after {:?}",
+                options.connect_timeout
+            );
+            return;
+        }
+    };
+    let mut backend = backend;
+    let copy = async {
+        backend.write_all(&records).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut backend).await
+    };
+    let idle = async {
+        match options.idle_timeout {
+            Some(timeout) => activity.idle(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        res = copy => {
+            if let Err(e) = res {
+                eprintln!("connection from {peer_addr} error: {e}");
+            }
+        }
+        _ = idle => {}
+    }
+}
+
+/// Counts as an open connection until dropped.
+struct ActiveConnection(Arc<PassthroughStats>);
+
+impl ActiveConnection {
+    fn new(stats: Arc<PassthroughStats>) -> Self {
+        stats.active_connections.fetch_add(1, Ordering::Relaxed);
+        Self(stats)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Client side of a connection, counting bytes into the stats.
+struct CountingIo<IO> {
+    inner: IO,
+    stats: Arc<PassthroughStats>,
+    activity: Arc<Activity>,
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for CountingIo<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - before;
+        if read > 0 {
+            self.stats
+                .received_bytes
+                .fetch_add(read as u64, Ordering::Relaxed);
+            self.activity.touch();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for CountingIo<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if written > 0 {
+            self.stats
+                .sent_bytes
+                .fetch_add(written as u64, Ordering::Relaxed);
+            self.activity.touch();
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use http::Request;
+    use http_body_util::Empty;
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+    use tokio::net::TcpListener;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        read_client_hello, serve_passthrough, ClientHello, PassthroughOptions, PassthroughRouter,
+    };
+    use crate::{
+        proxy::{
+            connector::{TcpConnector, UdsConnector},
+            metrics::Metrics,
+            proxy_service::ProxyService,
+            test_util::{
+                client_config, h2_client_for, post, served_by, spawn_echo_backend,
+                spawn_proxy_service, temp_socket_path, wait_until,
+            },
+        },
+        tls::test_certs::{write_ca, write_signed},
+        util::temp_dir,
+    };
+
+    /// ClientHello with the given SNI name and ALPN protocols, split over
+    /// records of at most `record_len` bytes.
+    fn client_hello(server_name: Option<&str>, alpn: &[&[u8]], record_len: usize) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            let list_len = 3 + name.len() as u16;
+            extensions.extend_from_slice(&0u16.to_be_bytes());
+            extensions.extend_from_slice(&(list_len + 2).to_be_bytes());
+            extensions.extend_from_slice(&list_len.to_be_bytes());
+            extensions.push(0);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+        if !alpn.is_empty() {
+            let list: Vec<u8> = (alpn.iter())
+                .flat_map(|p| std::iter::once(p.len() as u8).chain(p.iter().copied()))
+                .collect();
+            extensions.extend_from_slice(&16u16.to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16 + 2).to_be_bytes());
+            extensions.extend_from_slice(&(list.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(&list);
+        }
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[7; 32]);
+        body.push(0);
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]);
+        body.extend_from_slice(&[1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        handshake
+            .chunks(record_len)
+            .flat_map(|chunk| {
+                let mut record = vec![22, 3, 1];
+                record.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+                record.extend_from_slice(chunk);
+                record
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_sni_and_alpn() {
+        for record_len in [1 << 14, 10, 3] {
+            let mut bytes =
+                client_hello(Some("Billing.Example."), &[b"h2", b"http/1.1"], record_len);
+            let hello_len = bytes.len();
+            bytes.extend_from_slice(b"after");
+            let mut io = &bytes[..];
+            let (hello, records) = read_client_hello(&mut io).await.unwrap();
+            assert_eq!(
+                hello,
+                ClientHello {
+                    server_name: Some("billing.example".to_string()),
+                    alpn_protocols: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                }
+            );
+            assert_eq!(records.len(), hello_len);
+            assert_eq!(io, b"after");
+        }
+
+        let bytes = client_hello(None, &[], 1 << 14);
+        let (hello, _) = read_client_hello(&mut &bytes[..]).await.unwrap();
+        assert_eq!(hello, ClientHello::default());
+
+        let hello = client_hello(Some("a.example"), &[], 1 << 14);
+        let truncated = &hello[..hello.len() - 3];
+        for invalid in [&b"PRI * HTTP/2.0\r\n"[..], truncated] {
+            assert!(read_client_hello(&mut &invalid[..]).await.is_err());
+        }
+    }
+
+    #[test]
+    fn routes_by_server_name() {
+        let router = PassthroughRouter::new([("A.example".to_string(), 'a')], Some('d'));
+        let hello = |name: Option<&str>| ClientHello {
+            server_name: name.map(str::to_string),
+            alpn_protocols: Vec::new(),
+        };
+        assert_eq!(router.route(&hello(Some("a.example"))), Some(&'a'));
+        assert_eq!(router.route(&hello(Some("b.example"))), Some(&'d'));
+        assert_eq!(router.route(&hello(None)), Some(&'d'));
+        let strict = PassthroughRouter::new([("a.example".to_string(), 'a')], None);
+        assert_eq!(strict.route(&hello(Some("b.example"))), None);
+    }
+
+    #[tokio::test]
+    async fn passes_tls_through_by_sni() {
+        let token = CancellationToken::new();
+        let dir = temp_dir("passthrough");
+        let ca = write_ca(&dir, "ca");
+        let root = CertificateDer::from_pem_file(dir.join("ca.crt")).unwrap();
+
+        // two backends ending TLS themselves, each with its own certificate
+        let sockets = ["a", "b"].map(|name| temp_socket_path(&format!("passthrough-{name}")));
+        let mut heads = Vec::new();
+        let mut backends = Vec::new();
+        for (socket, name) in sockets.iter().zip(["a.example", "b.example"]) {
+            heads.push(spawn_echo_backend(socket, token.child_token()));
+            let params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+            let identity = write_signed(&dir, name, params, &ca);
+            let service = ProxyService::new(UdsConnector::new(socket));
+            let (server_config, _) = identity.load_reloadable_server_config(None).unwrap();
+            let (addr, handle) =
+                spawn_proxy_service(server_config, service, token.child_token()).await;
+            backends.push((name.to_string(), TcpConnector::new(addr.to_string())));
+            drop(handle);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = PassthroughOptions {
+            idle_timeout: Some(Duration::from_millis(300)),
+            max_connections: Some(1),
+            ..PassthroughOptions::default()
+        };
+        let stats = options.stats.clone();
+        let router = PassthroughRouter::new(backends, None);
+        let signal = token.child_token().cancelled_owned();
+        let server = tokio::spawn(serve_passthrough(listener, router, options, signal));
+
+        for (name, backend) in [("a.example", 0), ("B.example", 1)] {
+            let config = client_config(root.clone(), None);
+            let mut sender = h2_client_for(addr, config, name).await.unwrap();
+            let res = post(&mut sender, Request::builder().uri("/echo.Echo/Say")).await;
+            assert_eq!(res.unwrap().grpc_status(), Some("0"), "{name}");
+            assert_eq!(served_by(&mut heads), backend, "{name}");
+        }
+        assert!(stats.received_bytes() > 0 && stats.sent_bytes() > 0);
+
+        // no route: closed before any backend sees it
+        let config = client_config(root.clone(), None);
+        assert!(h2_client_for::<Empty<Bytes>>(addr, config, "c.example")
+            .await
+            .is_err());
+        assert_eq!(stats.unrouted_connections(), 1);
+        let metrics = Metrics::new();
+        metrics.register_passthrough("passthrough", stats.clone());
+        let text = metrics.render();
+        let line = r#"grpc_proxy_passthrough_unrouted_connections_total{route="passthrough"} 1"#;
+        assert!(text.lines().any(|l| l == line), "{text}");
+
+        // idle connections are closed
+        wait_until(|| stats.active_connections() == 0).await;
+
+        // a second connection waits for the first to close
+        let config = client_config(root.clone(), None);
+        let held = h2_client_for::<Empty<Bytes>>(addr, config, "a.example")
+            .await
+            .unwrap();
+        let config = client_config(root, None);
+        let pending = tokio::spawn(h2_client_for::<Empty<Bytes>>(addr, config, "b.example"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!pending.is_finished());
+        drop(held);
+        pending.await.unwrap().unwrap();
+
+        token.cancel();
+        server.await.unwrap().unwrap();
+        for socket in sockets {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Just enough protobuf wire format for the few messages the proxy sends
+//! itself (health checks, OTLP spans), without a protobuf runtime.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Wire type of varint fields (`int64`, `uint64`, `enum`, `bool`).
+const VARINT: u64 = 0;
+/// Wire type of `fixed64` fields.
+const FIXED64: u64 = 1;
+/// Wire type of strings, bytes and embedded messages.
+const LEN: u64 = 2;
+
+pub fn put_varint(buf: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        buf.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+pub fn get_varint(buf: &mut Bytes) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            return None;
+        }
+        let b = buf.get_u8();
+        value |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn put_key(buf: &mut BytesMut, field: u32, wire_type: u64) {
+    put_varint(buf, (u64::from(field) << 3) | wire_type);
+}
+
+/// Varint field, written even when zero as `oneof` members must be.
+pub fn put_uint(buf: &mut BytesMut, field: u32, value: u64) {
+    put_key(buf, field, VARINT);
+    put_varint(buf, value);
+}
+
+pub fn put_fixed64(buf: &mut BytesMut, field: u32, value: u64) {
+    put_key(buf, field, FIXED64);
+    buf.put_u64_le(value);
+}
+
+/// String, bytes or embedded message field; empty values are left out.
+pub fn put_bytes(buf: &mut BytesMut, field: u32, value: &[u8]) {
+    if !value.is_empty() {
+        put_key(buf, field, LEN);
+        put_varint(buf, value.len() as u64);
+        buf.put_slice(value);
+    }
+}
+
+/// Embedded message field written by `encode`, kept even when empty.
+pub fn put_message(buf: &mut BytesMut, field: u32, encode: impl FnOnce(&mut BytesMut)) {
+    let mut message = BytesMut::new();
+    encode(&mut message);
+    put_key(buf, field, LEN);
+    put_varint(buf, message.len() as u64);
+    buf.put_slice(&message);
+}
+
+/// `message` in an uncompressed gRPC length-prefixed frame.
+pub fn grpc_frame(message: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(5 + message.len());
+    frame.put_u8(0);
+    frame.put_u32(message.len() as u32);
+    frame.put_slice(message);
+    frame.freeze()
+}
@@ -0,0 +1,406 @@
+//! PROXY protocol v1 and v2 headers, to learn the client's address behind
+//! an L4 load balancer and to pass it on to backends.
+//!
+//! With [`ServeOptions::proxy_protocol`](super::ServeOptions::proxy_protocol)
+//! every accepted connection must start with a header (read by
+//! [`read_header`]) before TLS, whose source address replaces the peer
+//! address in [`ConnectionInfo`](super::ConnectionInfo). A
+//! [`ProxyService`](super::proxy_service::ProxyService) passes it on as
+//! configured by [`ForwardClientAddr`].
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::HeaderName;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// First bytes of a v2 header.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// How long a client may take to send its header.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// Version 2 and the `PROXY` or `LOCAL` command.
+const V2_PROXY: u8 = 0x21;
+const V2_LOCAL: u8 = 0x20;
+
+/// Address family and `STREAM` protocol.
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// How a [`ProxyService`](super::proxy_service::ProxyService) tells the
+/// backend the client's address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardClientAddr {
+    /// A v2 header on a backend connection of its own per client
+    /// connection.
+    ProxyProtocol,
+    /// The client's IP in `x-forwarded-for`, replacing any it sent.
+    XForwardedFor,
+}
+
+/// Addresses of a proxied connection as seen by the load balancer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddrs {
+    /// The client.
+    pub source: SocketAddr,
+    /// The address the client connected to.
+    pub destination: SocketAddr,
+}
+
+/// Reads a v1 or v2 header from `io`, and nothing after it. `None` for
+/// headers without addresses, sent e.g. by health checks of the balancer
+/// (`LOCAL`, `UNKNOWN` or non-IP families).
+pub async fn read_header<IO>(io: &mut IO) -> io::Result<Option<ProxiedAddrs>>
+where
+    IO: AsyncRead + Unpin,
+{
+    // shorter than either header, so no TLS bytes are consumed
+    let mut head = [0; 8];
+    io.read_exact(&mut head).await?;
+    if head == V2_SIGNATURE[..8] {
+        let mut rest = [0; 8];
+        io.read_exact(&mut rest).await?;
+        if rest[..4] != V2_SIGNATURE[8..] {
+            return Err(invalid("invalid v2 signature"));
+        }
+        let len = u16::from_be_bytes([rest[6], rest[7]]);
+        let mut payload = vec![0; len.into()];
+        io.read_exact(&mut payload).await?;
+        return parse_v2(rest[4], rest[5], &payload);
+    }
+    if !head.starts_with(b"PROXY ") {
+        return Err(invalid("no PROXY protocol header"));
+    }
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("PROXY protocol: {message}"),
+    )
+}
+
+/// `PROXY TCP4 <src> <dst> <src port> <dst port>\r\n`.
+fn parse_v1(line: &[u8]) -> io::Result<Option<ProxiedAddrs>> {
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("v1 header is not ASCII"))?;
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let ip = |s: &str| {
+                let ip: IpAddr = s.parse().map_err(|_| invalid("invalid v1 address"))?;
+                if ip.is_ipv4() != (family == "TCP4") {
+                    return Err(invalid("v1 address of the wrong family"));
+                }
+                Ok(ip)
+            };
+            let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("invalid v1 port"));
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(src)?, port(src_port)?),
+                destination: SocketAddr::new(ip(dst)?, port(dst_port)?),
+            }))
+        }
+        _ => Err(invalid("malformed v1 header")),
+    }
+}
+
+/// Address block of a v2 header; TLVs after it are ignored.
+fn parse_v2(command: u8, family: u8, payload: &[u8]) -> io::Result<Option<ProxiedAddrs>> {
+    match command {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => return Err(invalid("unsupported v2 version or command")),
+    }
+    let addrs = match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = |at: usize| {
+                let octets: [u8; 4] = payload[at..at + 4].try_into().expect("4 bytes");
+                IpAddr::from(Ipv4Addr::from(octets))
+            };
+            (ip(0), ip(4), &payload[8..12])
+        }
+        2 if payload.len() >= 36 => {
+            let ip = |at: usize| {
+                let octets: [u8; 16] = payload[at..at + 16].try_into().expect("16 bytes");
+                IpAddr::from(Ipv6Addr::from(octets))
+            };
+            (ip(0), ip(16), &payload[32..36])
+        }
+        1 | 2 => return Err(invalid("v2 address block too short")),
+        // unspecified or unix: nothing to replace the peer address with
+        _ => return Ok(None),
+    };
+    let (source, destination, ports) = addrs;
+    Ok(Some(ProxiedAddrs {
+        source: SocketAddr::new(source, u16::from_be_bytes([ports[0], ports[1]])),
+        destination: SocketAddr::new(destination, u16::from_be_bytes([ports[2], ports[3]])),
+    }))
+}
+
+/// v2 header for a connection made on behalf of the client at `addrs`, or
+/// a `LOCAL` one for connections of the proxy's own (e.g. health checks).
+pub fn encode_v2(addrs: Option<&ProxiedAddrs>) -> Bytes {
+    let mut header = BytesMut::with_capacity(16 + 36);
+    header.put_slice(&V2_SIGNATURE);
+    let Some(addrs) = addrs else {
+        header.put_slice(&[V2_LOCAL, 0, 0, 0]);
+        return header.freeze();
+    };
+    header.put_u8(V2_PROXY);
+    match (addrs.source, addrs.destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            header.put_slice(&[V2_TCP4, 0, 12]);
+            header.put_slice(&source.ip().octets());
+            header.put_slice(&destination.ip().octets());
+        }
+        // mixed families go as IPv4-mapped IPv6
+        (source, destination) => {
+            let v6 = |addr: SocketAddr| match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.put_slice(&[V2_TCP6, 0, 36]);
+            header.put_slice(&v6(source).octets());
+            header.put_slice(&v6(destination).octets());
+        }
+    }
+    header.put_u16(addrs.source.port());
+    header.put_u16(addrs.destination.port());
+    header.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc},
+    };
+
+    use bytes::Bytes;
+    use http::Request;
+    use http_body_util::Empty;
+    use hyper::client::conn::http2::SendRequest;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{encode_v2, read_header, ForwardClientAddr, ProxiedAddrs};
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_protocol,
+        proxy_service::ProxyService,
+        serve_with_options,
+        test_util::{
+            self, client_config, post, spawn_echo_backend, spawn_proxy_protocol_backend,
+            temp_socket_path, wait_until,
+        },
+        BoxError, ServeOptions,
+    };
+
+    async fn read(mut header: &[u8]) -> std::io::Result<Option<ProxiedAddrs>> {
+        read_header(&mut header).await
+    }
+
+    fn addrs(source: &str, destination: &str) -> Option<ProxiedAddrs> {
+        Some(ProxiedAddrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        })
+    }
+
+    #[tokio::test]
+    async fn parses_v1() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n")
+                .await
+                .unwrap(),
+            addrs("192.0.2.1:56324", "198.51.100.1:443")
+        );
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+                .await
+                .unwrap(),
+            addrs("[2001:db8::1]:56324", "[2001:db8::2]:443")
+        );
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        for invalid in [
+            &b"PROXY TCP4 192.0.2.1 2001:db8::2 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 443\r\n",
+            b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc",
+            &b"PROXY ".repeat(20),
+        ] {
+            assert!(read(invalid).await.is_err(), "{invalid:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_v2() {
+        for (source, destination) in [
+            ("192.0.2.1:56324", "198.51.100.1:443"),
+            ("[2001:db8::1]:56324", "[2001:db8::2]:443"),
+        ] {
+            let expected = addrs(source, destination);
+            let header = encode_v2(expected.as_ref());
+            assert_eq!(read(&header).await.unwrap(), expected);
+        }
+        let local = encode_v2(None);
+        assert_eq!(local.len(), 16);
+        assert_eq!(read(&local).await.unwrap(), None);
+
+        // TLVs after the addresses are skipped, and nothing after them read
+        let mut header = encode_v2(addrs("192.0.2.1:1", "192.0.2.2:2").as_ref()).to_vec();
+        header[15] += 4;
+        header.extend_from_slice(&[0x04, 0, 1, 0, 0x16]);
+        let mut rest = &header[..];
+        read_header(&mut rest).await.unwrap();
+        assert_eq!(rest, [0x16]);
+    }
+
+    /// Proxy with `proxy_protocol` on, behind TLS on an ephemeral port.
+    async fn spawn_proxy_protocol_proxy(
+        service: ProxyService<UdsConnector>,
+        token: CancellationToken,
+    ) -> (
+        SocketAddr,
+        CertificateDer<'static>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let (mut server_config, cert) = test_util::load_test_server_config();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ServeOptions {
+            proxy_protocol: true,
+            ..Default::default()
+        };
+        let proxy = tokio::spawn(async move {
+            serve_with_options(
+                listener,
+                Some(TlsAcceptor::from(Arc::new(server_config))),
+                service,
+                options,
+                token.cancelled_owned(),
+            )
+            .await
+            .unwrap()
+        });
+        (addr, cert, proxy)
+    }
+
+    /// h2 client over TLS to `addr`, sending `header` before the handshake as
+    /// a load balancer would.
+    async fn proxied_client(
+        addr: SocketAddr,
+        cert: CertificateDer<'static>,
+        header: &[u8],
+    ) -> Result<SendRequest<Empty<Bytes>>, BoxError> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut tcp = TcpStream::connect(addr).await?;
+        tcp.write_all(header).await?;
+        let tls = TlsConnector::from(Arc::new(client_config(cert, None)))
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await?;
+        let (sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tls)).await?;
+        tokio::spawn(conn);
+        Ok(sender)
+    }
+
+    #[tokio::test]
+    async fn forwards_proxy_protocol_to_backend() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("proxy-protocol");
+        let (mut backend, open) = spawn_proxy_protocol_backend(&socket, token.child_token());
+        let service = ProxyService::new(UdsConnector::new(&socket))
+            .with_client_addr(ForwardClientAddr::ProxyProtocol);
+        let (addr, cert, proxy) = spawn_proxy_protocol_proxy(service, token.child_token()).await;
+
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        let mut first = proxied_client(addr, cert.clone(), v1).await.unwrap();
+        for _ in 0..2 {
+            let res = post(&mut first, Request::builder().uri("/echo.Echo/Say")).await;
+            assert_eq!(res.unwrap().grpc_status(), Some("0"));
+        }
+        let v2 = proxy_protocol::encode_v2(Some(&ProxiedAddrs {
+            source: "[2001:db8::1]:40000".parse().unwrap(),
+            destination: "[2001:db8::2]:443".parse().unwrap(),
+        }));
+        let mut second = proxied_client(addr, cert.clone(), &v2).await.unwrap();
+        let res = post(&mut second, Request::builder().uri("/echo.Echo/Say")).await;
+        assert_eq!(res.unwrap().grpc_status(), Some("0"));
+
+        // a backend connection per client connection, with its addresses
+        let header = backend.recv().await.unwrap().unwrap();
+        assert_eq!(header.source, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(header.destination, "198.51.100.1:443".parse().unwrap());
+        let header = backend.recv().await.unwrap().unwrap();
+        assert_eq!(header.source, "[2001:db8::1]:40000".parse().unwrap());
+        assert!(backend.try_recv().is_err());
+
+        // and closed with it
+        assert_eq!(open.load(Ordering::Relaxed), 2);
+        drop(first);
+        wait_until(|| open.load(Ordering::Relaxed) == 1).await;
+
+        // connections without a header never get to the TLS handshake
+        assert!(proxied_client(addr, cert, b"").await.is_err());
+
+        drop(second);
+        token.cancel();
+        proxy.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn sets_x_forwarded_for() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("x-forwarded-for");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let service = ProxyService::new(UdsConnector::new(&socket))
+            .with_client_addr(ForwardClientAddr::XForwardedFor);
+        let (addr, cert, proxy) = spawn_proxy_protocol_proxy(service, token.child_token()).await;
+
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        let mut sender = proxied_client(addr, cert, header).await.unwrap();
+        let req = Request::builder()
+            .uri("/echo.Echo/Say")
+            .header("x-forwarded-for", "10.9.8.7");
+        assert_eq!(
+            post(&mut sender, req).await.unwrap().grpc_status(),
+            Some("0")
+        );
+        let head = backend.recv().await.unwrap();
+        let forwarded: Vec<_> = head.headers.get_all("x-forwarded-for").iter().collect();
+        assert_eq!(forwarded, ["192.0.2.1"]);
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::{uri, HeaderMap, HeaderValue, Request, Response, Uri};
+use http_body_util::BodyExt;
+use hyper::{body::Body, client::conn::http2::SendRequest};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::watch,
+};
+
+use super::{
+    client_cert,
+    connector::Connector,
+    health::BackendHealth,
+    passthrough::DEFAULT_CONNECT_TIMEOUT,
+    proxy_protocol::{self, ForwardClientAddr, ProxiedAddrs, X_FORWARDED_FOR},
+    status::{self, Code},
+    trace::{CallTrace, TRACEPARENT},
+    BoxError, ConnectionInfo, PeerAddr, ProxyBody,
+};
+
+/// `grpc-message` of calls whose backend could not be reached.
+const UNAVAILABLE_MESSAGE: &str = "backend unavailable";
+
+/// Forwards every request to the backend reached through the connector.
+///
+/// Identity headers (see [`client_cert`]) are rebuilt from the
+/// [`ConnectionInfo`] of the request before forwarding. A backend that
+/// cannot be reached is reported to the client as `UNAVAILABLE`.
+///
+/// A single h2 connection to the backend is shared by all requests and
+/// re-established lazily once it is closed. Connecting (handshake
+/// included) is bounded by [`Self::with_connect_timeout`].
+///
+/// With [`Self::with_health`], requests fail fast with `UNAVAILABLE` while
+/// the health checker considers the backend down.
+///
+/// Calls traced by a [`super::trace::TraceLayer`] get a client span, which
+/// the backend sees as parent in `traceparent`.
+///
+/// With [`Self::with_client_addr`] the backend learns the client's address,
+/// see [`ForwardClientAddr`].
+pub struct ProxyService<C> {
+    inner: Arc<Inner<C>>,
+    health: Option<BackendHealth>,
+    name: Option<BackendName>,
+    client_addr: Option<ForwardClientAddr>,
+    connect_timeout: Duration,
+}
+
+/// Response extension naming the backend that handled a call, set by a
+/// [`ProxyService`] built [`with_name`](ProxyService::with_name).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendName(Arc<str>);
+
+impl BackendName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+struct Inner<C> {
+    connector: C,
+    shared: Mutex<SharedConnection>,
+    /// Backend connections opened with a PROXY protocol header, by
+    /// [`ConnectionInfo::connection`] of the client connection, dropped
+    /// (and so closed) once the client connection closed.
+    clients: Arc<Mutex<HashMap<usize, ClientConnection>>>,
+}
+
+/// The backend connection shared by all calls, see [`Inner::sender`].
+enum SharedConnection {
+    Idle,
+    /// One call connects, the others wait for its result here.
+    Connecting(watch::Receiver<Option<Result<SendRequest<ProxyBody>, String>>>),
+    Connected(SendRequest<ProxyBody>),
+}
+
+/// Backend connection of a client connection, see [`Inner::client_sender`].
+type ClientConnection = (Weak<()>, SendRequest<ProxyBody>);
+
+impl<C> Clone for ProxyService<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            health: self.health.clone(),
+            name: self.name.clone(),
+            client_addr: self.client_addr,
+            connect_timeout: self.connect_timeout,
+        }
+    }
+}
+
+impl<C: Connector> ProxyService<C> {
+    pub fn new(connector: C) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                connector,
+                shared: Mutex::new(SharedConnection::Idle),
+                clients: Default::default(),
+            }),
+            health: None,
+            name: None,
+            client_addr: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    /// How long connecting to the backend may take, TLS and h2 handshakes
+    /// included; 5 seconds by default.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Tells the backend the address of the client, see [`ForwardClientAddr`].
+    pub fn with_client_addr(mut self, forward: ForwardClientAddr) -> Self {
+        self.client_addr = Some(forward);
+        self
+    }
+
+    /// Tags every response with `name` as [`BackendName`], e.g. for
+    /// [`super::metrics`].
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(BackendName(name.into().into()));
+        self
+    }
+
+    /// Gates requests on `health`, see [`super::health::spawn_health_check`].
+    pub fn with_health(mut self, health: BackendHealth) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    pub fn health(&self) -> Option<&BackendHealth> {
+        self.health.as_ref()
+    }
+}
+
+impl<C: Connector> Inner<C> {
+    /// Returns a sender on the shared backend connection, connecting first
+    /// (and sending `preamble`) if there is none or the previous one went
+    /// away. Calls arriving meanwhile wait for that connect rather than
+    /// starting their own.
+    async fn sender(
+        &self,
+        preamble: &[u8],
+        timeout: Duration,
+    ) -> Result<SendRequest<ProxyBody>, BoxError> {
+        loop {
+            let mut leader = None;
+            let mut connecting = {
+                let mut shared = self.shared.lock().unwrap();
+                match &*shared {
+                    SharedConnection::Connected(sender) if !sender.is_closed() => {
+                        return Ok(sender.clone());
+                    }
+                    // unless the call connecting went away
+                    SharedConnection::Connecting(rx) if rx.has_changed().is_ok() => rx.clone(),
+                    _ => {
+                        let (tx, rx) = watch::channel(None);
+                        *shared = SharedConnection::Connecting(rx.clone());
+                        leader = Some(tx);
+                        rx
+                    }
+                }
+            };
+            if let Some(tx) = leader {
+                let res = self.connect(preamble, timeout).await;
+                *self.shared.lock().unwrap() = match &res {
+                    Ok(sender) => SharedConnection::Connected(sender.clone()),
+                    Err(_) => SharedConnection::Idle,
+                };
+                let shared = res.as_ref().map(SendRequest::clone);
+                tx.send_replace(Some(shared.map_err(ToString::to_string)));
+                return res;
+            }
+            let res = connecting
+                .wait_for(Option::is_some)
+                .await
+                .map(|res| res.clone());
+            if let Ok(Some(res)) = res {
+                return res.map_err(BoxError::from);
+            }
+        }
+    }
+
+    /// Connects to the backend, sending `preamble` first, within `timeout`.
+    async fn connect(
+        &self,
+        preamble: &[u8],
+        timeout: Duration,
+    ) -> Result<SendRequest<ProxyBody>, BoxError> {
+        let connect = async {
+            let io = self.connector.connect_with_preamble(preamble).await?;
+            handshake(io).await
+        };
+        match tokio::time::timeout(timeout, connect).await {
+            Ok(res) => res,
+            Err(_) => Err(format!("connecting to backend timed out after {timeout:?}").into()),
+        }
+    }
+
+    /// Returns a sender on the backend connection of the client connection
+    /// of `info`, connecting first with its PROXY protocol header.
+    async fn client_sender(
+        &self,
+        info: &ConnectionInfo,
+        timeout: Duration,
+    ) -> Result<SendRequest<ProxyBody>, BoxError> {
+        let connection = info.connection();
+        // the weak reference keeps the address from being reused
+        let key = connection.as_ptr() as usize;
+        if let Some((_, sender)) = self.clients.lock().unwrap().get(&key) {
+            if !sender.is_closed() {
+                return Ok(sender.clone());
+            }
+        }
+        let header = proxy_protocol::encode_v2(proxied_addrs(info).as_ref());
+        let sender = self.connect(&header, timeout).await?;
+        let replaced = self
+            .clients
+            .lock()
+            .unwrap()
+            .insert(key, (connection, sender.clone()));
+        if replaced.is_none() {
+            let clients = self.clients.clone();
+            let closed = info.closed();
+            tokio::spawn(async move {
+                closed.await;
+                clients.lock().unwrap().remove(&key);
+            });
+        }
+        Ok(sender)
+    }
+
+    async fn forward(
+        &self,
+        req: Request<ProxyBody>,
+        client_addr: Option<ForwardClientAddr>,
+        connect_timeout: Duration,
+    ) -> Result<Response<ProxyBody>, BoxError> {
+        let client = req.extensions().get::<ConnectionInfo>().cloned();
+        let mut sender = match (client_addr, client) {
+            (Some(ForwardClientAddr::ProxyProtocol), Some(info)) => {
+                self.client_sender(&info, connect_timeout).await?
+            }
+            // not on behalf of a client connection
+            (Some(ForwardClientAddr::ProxyProtocol), None) => {
+                self.sender(&proxy_protocol::encode_v2(None), connect_timeout)
+                    .await?
+            }
+            _ => self.sender(&[], connect_timeout).await?,
+        };
+        sender.ready().await?;
+        let resp = sender.send_request(req).await?;
+        Ok(resp.map(|b| b.map_err(BoxError::from).boxed()))
+    }
+}
+
+async fn handshake<I>(io: I) -> Result<SendRequest<ProxyBody>, BoxError>
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (sender, conn) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(io)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            eprintln!("backend connection error: {e}");
+        }
+    });
+    Ok(sender)
+}
+
+/// Addresses for the PROXY protocol header of a client connection: those
+/// of its own header, or the peer's toward an unknown destination. `None`
+/// (a `LOCAL` header) for unix socket peers.
+fn proxied_addrs(info: &ConnectionInfo) -> Option<ProxiedAddrs> {
+    match (info.proxied, &info.peer_addr) {
+        (Some(proxied), _) => Some(proxied),
+        (None, PeerAddr::Tcp(source)) => {
+            let unspecified = match source.ip() {
+                IpAddr::V4(_) => IpAddr::from([0; 4]),
+                IpAddr::V6(_) => IpAddr::from([0; 16]),
+            };
+            Some(ProxiedAddrs {
+                source: *source,
+                destination: SocketAddr::new(unspecified, 0),
+            })
+        }
+        (None, PeerAddr::Unix) => None,
+    }
+}
+
+/// Replaces `x-forwarded-for` with the IP of the client, removing it for
+/// unix socket peers.
+fn set_forwarded_for(headers: &mut HeaderMap, info: Option<&ConnectionInfo>) {
+    headers.remove(X_FORWARDED_FOR);
+    if let Some(PeerAddr::Tcp(addr)) = info.map(|info| &info.peer_addr) {
+        let ip = HeaderValue::from_str(&addr.ip().to_string()).expect("an IP address");
+        headers.insert(X_FORWARDED_FOR, ip);
+    }
+}
+
+/// Rewrites the request uri to the absolute form the h2 client needs,
+/// keeping the original authority so the backend sees what the client sent.
+fn backend_uri(uri: &Uri) -> Result<Uri, http::Error> {
+    let authority = uri
+        .authority()
+        .cloned()
+        .unwrap_or_else(|| uri::Authority::from_static("localhost"));
+    let path = uri
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| uri::PathAndQuery::from_static("/"));
+    Uri::builder()
+        .scheme(uri::Scheme::HTTP)
+        .authority(authority)
+        .path_and_query(path)
+        .build()
+}
+
+impl<C, B> tower::Service<Request<B>> for ProxyService<C>
+where
+    C: Connector,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        if let Some(reason) = self.health.as_ref().and_then(BackendHealth::reason) {
+            let name = self
+                .health
+                .as_ref()
+                .map(BackendHealth::name)
+                .unwrap_or_default();
+            let resp = status::trailers_only(
+                Code::Unavailable,
+                format!("backend {name} is unhealthy: {reason}"),
+            );
+            let resp = tagged(resp, self.name.clone());
+            return Box::pin(async move { Ok(resp) });
+        }
+        let inner = self.inner.clone();
+        let name = self.name.clone();
+        let client_addr = self.client_addr;
+        let connect_timeout = self.connect_timeout;
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            parts.uri = backend_uri(&parts.uri)?;
+            let info = parts.extensions.get::<ConnectionInfo>();
+            let identity = info.and_then(|info| info.client_identity.clone());
+            if client_addr == Some(ForwardClientAddr::XForwardedFor) {
+                set_forwarded_for(&mut parts.headers, info);
+            }
+            client_cert::set_identity_headers(&mut parts.headers, identity.as_deref());
+            let span = parts.extensions.remove::<CallTrace>().map(|trace| {
+                let (span, traceparent) = trace.client_span(parts.uri.path(), name.as_ref());
+                parts.headers.insert(TRACEPARENT, traceparent);
+                span
+            });
+            let req = Request::from_parts(parts, body.map_err(Into::into).boxed());
+            let resp = match inner.forward(req, client_addr, connect_timeout).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    // the error names backend addresses, clients only get
+                    // the status
+                    eprintln!("forward to backend failed: {e}");
+                    status::trailers_only(Code::Unavailable, UNAVAILABLE_MESSAGE)
+                }
+            };
+            let resp = match span {
+                Some(span) => span.finish_with(resp),
+                None => resp,
+            };
+            Ok(tagged(resp, name))
+        })
+    }
+}
+
+fn tagged(mut resp: Response<ProxyBody>, name: Option<BackendName>) -> Response<ProxyBody> {
+    if let Some(name) = name {
+        resp.extensions_mut().insert(name);
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use http::Request;
+    use http_body_util::Empty;
+    use tokio::net::UnixStream;
+
+    use super::ProxyService;
+    use crate::proxy::connector::Connector;
+
+    /// Connector whose connects never finish, like a backend dropping SYNs.
+    #[derive(Clone, Default)]
+    struct HangingConnector {
+        connects: Arc<AtomicUsize>,
+    }
+
+    impl Connector for HangingConnector {
+        type Io = UnixStream;
+
+        async fn connect(&self) -> std::io::Result<UnixStream> {
+            self.connects.fetch_add(1, Ordering::Relaxed);
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn times_out_backend_connects_once_for_waiting_calls() {
+        let connector = HangingConnector::default();
+        let connects = connector.connects.clone();
+        let service = ProxyService::new(connector).with_connect_timeout(Duration::from_millis(200));
+        let call = || {
+            let req = Request::post("/echo.Echo/Say")
+                .body(Empty::<Bytes>::new())
+                .unwrap();
+            tower::ServiceExt::oneshot(service.clone(), req)
+        };
+
+        // the calls arriving while one connects wait for it, not for the lock
+        let calls = tokio::time::timeout(Duration::from_secs(2), async {
+            tokio::join!(call(), call(), call())
+        });
+        let (a, b, c) = calls.await.unwrap();
+        for resp in [a, b, c] {
+            let resp = resp.unwrap();
+            assert_eq!(resp.headers()["grpc-status"], "14");
+            assert_eq!(resp.headers()["grpc-message"], "backend unavailable");
+        }
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+
+        // a failed connect is not cached
+        let resp = call().await.unwrap();
+        assert_eq!(resp.headers()["grpc-status"], "14");
+        assert_eq!(connects.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,473 @@
+//! Token bucket rate limits in front of the backend.
+//!
+//! Each [`RateLimitRule`] applies to the calls of a method (or all calls)
+//! and keeps one bucket per client, told apart by [`ClientKey`]. A call
+//! takes a token from the bucket of every rule it matches; when one of
+//! them is empty the call fails with `RESOURCE_EXHAUSTED` without reaching
+//! the inner service, and the time until a token is back is sent as
+//! `grpc-retry-pushback-ms` and, rounded up to seconds, `retry-after`.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http::{HeaderName, HeaderValue, Request, Response};
+
+use super::{
+    status::{self, Code},
+    BoxError, ConnectionInfo, PeerAddr, ProxyBody,
+};
+
+pub const GRPC_RETRY_PUSHBACK_MS: HeaderName = HeaderName::from_static("grpc-retry-pushback-ms");
+pub const RETRY_AFTER: HeaderName = http::header::RETRY_AFTER;
+
+/// Buckets kept at most. Keys come from clients, so one making up header
+/// values would grow the map without bound otherwise.
+const MAX_BUCKETS: usize = 4096;
+
+/// How often a full map is swept for buckets that refilled completely at
+/// most, as a sweep walks every bucket.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What calls are counted by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientKey {
+    /// Subject of the verified client certificate.
+    Subject,
+    /// Value of a request header, e.g. `authorization`.
+    Header(HeaderName),
+    /// IP address of the peer; all unix socket clients share a bucket.
+    PeerIp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitRule {
+    /// Calls this rule applies to: a `/package.Service/Method` path, or a
+    /// `/package.Service/` prefix for all methods of a service. All calls
+    /// when `None`.
+    pub method: Option<String>,
+    /// Bucket per client; all clients share one when `None`. Calls without
+    /// the key (anonymous clients, missing header) share one too.
+    pub client: Option<ClientKey>,
+    /// Calls per second refilled.
+    pub rate: f64,
+    /// Calls allowed at once after being idle, at least 1.
+    pub burst: u32,
+}
+
+impl RateLimitRule {
+    fn matches(&self, path: &str) -> bool {
+        match &self.method {
+            None => true,
+            Some(method) if method.ends_with('/') => path.starts_with(method.as_str()),
+            Some(method) => path == method,
+        }
+    }
+
+    fn client_key<B>(&self, req: &Request<B>) -> String {
+        let info = req.extensions().get::<ConnectionInfo>();
+        match &self.client {
+            None => String::new(),
+            Some(ClientKey::Subject) => info
+                .and_then(|info| info.client_identity.as_ref())
+                .map(|identity| identity.subject.clone())
+                .unwrap_or_default(),
+            Some(ClientKey::Header(name)) => req
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+                .unwrap_or_default(),
+            Some(ClientKey::PeerIp) => match info.map(|info| &info.peer_addr) {
+                Some(PeerAddr::Tcp(addr)) => canonical_ip(addr.ip()).to_string(),
+                _ => String::new(),
+            },
+        }
+    }
+}
+
+/// IPv4 clients on a dual-stack listener show up as mapped IPv6.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(rule: &RateLimitRule, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rule.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rule: &RateLimitRule, now: Instant) {
+        self.tokens = self.tokens_at(rule, now);
+        self.updated = now;
+    }
+
+    fn tokens_at(&self, rule: &RateLimitRule, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rule.rate).min(f64::from(rule.burst))
+    }
+
+    /// Time until a token is available, zero when one is.
+    fn wait(&self, rule: &RateLimitRule) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / rule.rate)
+        }
+    }
+}
+
+/// Rejects calls over the rules' limits, see the [module docs](self).
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+struct Limiter {
+    rules: Vec<RateLimitRule>,
+    buckets: Mutex<Buckets>,
+}
+
+/// Buckets of the clients, bounded by [`MAX_BUCKETS`]. Only buckets that
+/// refilled completely, and so are as good as new, are ever dropped: a
+/// drained bucket stays until it refilled, however many other keys show
+/// up. Keys finding no room share an overflow bucket per rule instead, so
+/// made-up keys (e.g. unverified tokens) only compete with each other.
+#[derive(Default)]
+struct Buckets {
+    /// By `(rule index, client key)`.
+    clients: HashMap<(usize, String), TokenBucket>,
+    /// By rule index.
+    overflow: HashMap<usize, TokenBucket>,
+    swept: Option<Instant>,
+}
+
+impl Buckets {
+    fn get(
+        &mut self,
+        key: &(usize, String),
+        rule: &RateLimitRule,
+        now: Instant,
+    ) -> &mut TokenBucket {
+        let full = || TokenBucket::full(rule, now);
+        if !self.clients.contains_key(key) && self.clients.len() >= MAX_BUCKETS {
+            return self.overflow.entry(key.0).or_insert_with(full);
+        }
+        self.clients.entry(key.clone()).or_insert_with(full)
+    }
+
+    /// Drops the buckets that refilled completely.
+    fn sweep(&mut self, rules: &[RateLimitRule], now: Instant) {
+        if self.swept.is_some_and(|swept| now < swept + SWEEP_INTERVAL) {
+            return;
+        }
+        self.swept = Some(now);
+        self.clients.retain(|(rule, _), bucket| {
+            let rule = &rules[*rule];
+            bucket.tokens_at(rule, now) < f64::from(rule.burst)
+        });
+    }
+}
+
+impl RateLimitLayer {
+    /// `rules` must have positive rates and bursts.
+    pub fn new(rules: Vec<RateLimitRule>) -> Self {
+        assert!(
+            rules.iter().all(|r| r.rate > 0.0 && r.burst >= 1),
+            "rate limits need a positive rate and burst"
+        );
+        Self {
+            limiter: Arc::new(Limiter {
+                rules,
+                buckets: Mutex::default(),
+            }),
+        }
+    }
+}
+
+impl Limiter {
+    /// Takes a token from every matching bucket, or none and returns how
+    /// long to wait when one is empty.
+    fn acquire(&self, keys: Vec<(usize, String)>, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let new = (keys.iter())
+            .filter(|key| !buckets.clients.contains_key(key))
+            .count();
+        if new > 0 && buckets.clients.len() + new > MAX_BUCKETS {
+            buckets.sweep(&self.rules, now);
+        }
+        let mut wait = Duration::ZERO;
+        for key in &keys {
+            let rule = &self.rules[key.0];
+            let bucket = buckets.get(key, rule, now);
+            bucket.refill(rule, now);
+            wait = wait.max(bucket.wait(rule));
+        }
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        for key in &keys {
+            buckets.get(key, &self.rules[key.0], now).tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
+
+impl<S> tower::Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S, B> tower::Service<Request<B>> for RateLimitService<S>
+where
+    S: tower::Service<Request<B>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let path = req.uri().path();
+        let keys = (self.limiter.rules.iter().enumerate())
+            .filter(|(_, rule)| rule.matches(path))
+            .map(|(i, rule)| (i, rule.client_key(&req)))
+            .collect();
+        match self.limiter.acquire(keys, Instant::now()) {
+            Ok(()) => Box::pin(self.inner.call(req)),
+            Err(wait) => {
+                let resp = exhausted(path, wait);
+                Box::pin(async move { Ok(resp) })
+            }
+        }
+    }
+}
+
+fn exhausted(path: &str, wait: Duration) -> Response<ProxyBody> {
+    let millis = wait.as_millis().max(1);
+    let mut resp = status::trailers_only(
+        Code::ResourceExhausted,
+        format!("rate limit of {path} exceeded, retry in {millis}ms"),
+    );
+    let headers = resp.headers_mut();
+    headers.insert(GRPC_RETRY_PUSHBACK_MS, HeaderValue::from(millis as u64));
+    headers.insert(
+        RETRY_AFTER,
+        HeaderValue::from(wait.as_secs_f64().ceil() as u64),
+    );
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http::Request;
+    use tokio_util::sync::CancellationToken;
+
+    use super::{ClientKey, Limiter, RateLimitLayer, RateLimitRule, MAX_BUCKETS};
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        test_util::{
+            self, client_config, h2_client, post, spawn_echo_backend, spawn_proxy_service,
+            temp_socket_path,
+        },
+    };
+
+    fn limiter(rules: Vec<RateLimitRule>) -> Limiter {
+        Limiter {
+            rules,
+            buckets: Default::default(),
+        }
+    }
+
+    #[test]
+    fn refills_at_rate() {
+        let limiter = limiter(vec![RateLimitRule {
+            method: None,
+            client: None,
+            rate: 2.0,
+            burst: 3,
+        }]);
+        let start = Instant::now();
+        let key = || vec![(0, String::new())];
+        for _ in 0..3 {
+            limiter.acquire(key(), start).unwrap();
+        }
+        assert_eq!(
+            limiter.acquire(key(), start),
+            Err(Duration::from_millis(500))
+        );
+        assert_eq!(
+            limiter.acquire(key(), start + Duration::from_millis(250)),
+            Err(Duration::from_millis(250))
+        );
+        let later = start + Duration::from_millis(500);
+        limiter.acquire(key(), later).unwrap();
+        assert!(limiter.acquire(key(), later).is_err());
+        // never more than the burst
+        let idle = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            limiter.acquire(key(), idle).unwrap();
+        }
+        assert!(limiter.acquire(key(), idle).is_err());
+    }
+
+    #[test]
+    fn rejected_calls_take_no_tokens() {
+        let rule = |method: Option<&str>, burst| RateLimitRule {
+            method: method.map(str::to_string),
+            client: None,
+            rate: 1.0,
+            burst,
+        };
+        let limiter = limiter(vec![rule(None, 2), rule(Some("/a.A/"), 1)]);
+        let now = Instant::now();
+        let both = || vec![(0, String::new()), (1, String::new())];
+        limiter.acquire(both(), now).unwrap();
+        // the method bucket is empty, the global one keeps its token
+        assert!(limiter.acquire(both(), now).is_err());
+        limiter.acquire(vec![(0, String::new())], now).unwrap();
+        assert!(limiter.acquire(vec![(0, String::new())], now).is_err());
+
+        assert!(limiter.rules[1].matches("/a.A/Get"));
+        assert!(!limiter.rules[1].matches("/a.AB/Get"));
+        let exact = rule(Some("/a.A/Get"), 1);
+        assert!(exact.matches("/a.A/Get") && !exact.matches("/a.A/GetAll"));
+    }
+
+    #[test]
+    fn bounds_buckets_of_rotating_clients() {
+        let limiter = limiter(vec![RateLimitRule {
+            method: None,
+            client: None,
+            rate: 1.0,
+            burst: 1,
+        }]);
+        let start = Instant::now();
+        let key = |i: usize| vec![(0, format!("client-{i}"))];
+        for i in 0..3 * MAX_BUCKETS {
+            let now = start + Duration::from_millis(i as u64);
+            // refilled buckets make room, so every client keeps its own
+            limiter.acquire(key(i), now).unwrap();
+            assert!(limiter.buckets.lock().unwrap().clients.len() <= MAX_BUCKETS);
+        }
+    }
+
+    #[test]
+    fn new_clients_leave_drained_buckets_alone() {
+        let limiter = limiter(vec![RateLimitRule {
+            method: None,
+            client: None,
+            rate: 0.001,
+            burst: 2,
+        }]);
+        let now = Instant::now();
+        let real = || vec![(0, "Bearer real".to_string())];
+        for _ in 0..2 {
+            limiter.acquire(real(), now).unwrap();
+        }
+        assert!(limiter.acquire(real(), now).is_err());
+
+        // made-up tokens fill the map, then share one overflow bucket
+        let made_up = |i: usize| vec![(0, format!("Bearer made-up-{i}"))];
+        for i in 0..MAX_BUCKETS - 1 {
+            limiter.acquire(made_up(i), now).unwrap();
+        }
+        let later = now + Duration::from_secs(5);
+        limiter.acquire(made_up(MAX_BUCKETS), later).unwrap();
+        limiter.acquire(made_up(MAX_BUCKETS + 1), later).unwrap();
+        for i in MAX_BUCKETS + 2..3 * MAX_BUCKETS {
+            assert!(limiter.acquire(made_up(i), later).is_err());
+        }
+        assert_eq!(limiter.buckets.lock().unwrap().clients.len(), MAX_BUCKETS);
+
+        // the real client's bucket is still drained
+        assert!(limiter.acquire(real(), later).is_err());
+        limiter.acquire(made_up(0), later).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limits_per_client() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("rate-limit");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let service = tower::Layer::layer(
+            &RateLimitLayer::new(vec![RateLimitRule {
+                method: Some("/helloworld.Greeter/".to_string()),
+                client: Some(ClientKey::Header(http::HeaderName::from_static("x-user"))),
+                rate: 0.1,
+                burst: 2,
+            }]),
+            ProxyService::new(UdsConnector::new(&socket)),
+        );
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, service, token.child_token()).await;
+        let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+        let call = |path: &str, user: &str| {
+            Request::builder()
+                .uri(format!("/helloworld.Greeter/{path}"))
+                .header("x-user", user)
+        };
+
+        for path in ["SayHello", "Other"] {
+            let resp = post(&mut sender, call(path, "a")).await.unwrap();
+            assert_eq!(resp.grpc_status(), Some("0"));
+        }
+        let resp = post(&mut sender, call("SayHello", "a")).await.unwrap();
+        assert_eq!(resp.grpc_status(), Some("8"));
+        let headers = &resp.head.headers;
+        let pushback: u64 = headers["grpc-retry-pushback-ms"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((9_000..=10_000).contains(&pushback), "{pushback}");
+        assert_eq!(headers["retry-after"], "10");
+        // other clients have their own bucket
+        let resp = post(&mut sender, call("SayHello", "b")).await.unwrap();
+        assert_eq!(resp.grpc_status(), Some("0"));
+        // the rejected call never reached the backend
+        for user in ["a", "a", "b"] {
+            assert_eq!(backend.recv().await.unwrap().headers["x-user"], user);
+        }
+        assert!(backend.try_recv().is_err());
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+}
@@ -0,0 +1,713 @@
+//! Retries and hedging of calls, modeled on the `retryPolicy` and
+//! `hedgingPolicy` of the gRPC service config.
+//!
+//! A call matching a [`RetryRule`] is sent again when its backend answers
+//! trailers-only (no response data yet) with a retryable status, which
+//! includes backends the proxy failed to connect to. With hedging, more
+//! attempts are started every `hedging_delay` while the earlier ones are
+//! still waiting, and the first answer that is not a non-fatal failure
+//! wins; the others are reset. `grpc-retry-pushback-ms` from the backend
+//! replaces the backoff, or stops further attempts when negative.
+//!
+//! The request body is buffered up to a limit so it can be replayed; a
+//! call sending more than that sticks with the attempt that read it.
+//! Only configure rules for methods that are safe to run more than once.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::{request::Parts, HeaderMap, HeaderName, HeaderValue, Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::{task::JoinSet, time::Instant};
+use tower::ServiceExt;
+
+use super::{
+    deadline::{encode_timeout, parse_timeout, GRPC_TIMEOUT},
+    rate_limit::GRPC_RETRY_PUSHBACK_MS,
+    status::{Code, GRPC_STATUS},
+    BoxError, ProxyBody,
+};
+
+/// Number of earlier attempts, sent on every attempt after the first.
+pub const GRPC_PREVIOUS_RPC_ATTEMPTS: HeaderName =
+    HeaderName::from_static("grpc-previous-rpc-attempts");
+
+/// Request bytes buffered per call by default.
+pub const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// `retryPolicy`: attempts one after the other, with exponential backoff.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub backoff_multiplier: f64,
+    pub retryable_codes: Vec<Code>,
+}
+
+/// `hedgingPolicy`: attempts started while earlier ones are in flight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HedgingPolicy {
+    /// Including the first one.
+    pub max_attempts: u32,
+    pub hedging_delay: Duration,
+    /// Failures that let the other attempts continue; any other answer
+    /// is returned right away.
+    pub non_fatal_codes: Vec<Code>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttemptPolicy {
+    Retry(RetryPolicy),
+    Hedge(HedgingPolicy),
+}
+
+impl AttemptPolicy {
+    fn max_attempts(&self) -> u32 {
+        match self {
+            AttemptPolicy::Retry(policy) => policy.max_attempts,
+            AttemptPolicy::Hedge(policy) => policy.max_attempts,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryRule {
+    /// Calls this rule applies to: a `/package.Service/Method` path, or a
+    /// `/package.Service/` prefix for all methods of a service. All calls
+    /// when `None`. Exact paths win over prefixes, which win over `None`.
+    pub method: Option<String>,
+    pub policy: AttemptPolicy,
+}
+
+/// Policy of the most specific rule matching `path`.
+fn policy_for<'a>(rules: &'a [RetryRule], path: &str) -> Option<&'a AttemptPolicy> {
+    let exact = |rule: &&RetryRule| rule.method.as_deref() == Some(path);
+    let prefix = |rule: &&RetryRule| {
+        (rule.method.as_deref()).is_some_and(|m| m.ends_with('/') && path.starts_with(m))
+    };
+    let any = |rule: &&RetryRule| rule.method.is_none();
+    (rules.iter().find(exact))
+        .or_else(|| rules.iter().find(prefix))
+        .or_else(|| rules.iter().find(any))
+        .map(|rule| &rule.policy)
+}
+
+/// Retries calls as their [`RetryRule`] says, see the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct RetryLayer {
+    rules: Arc<[RetryRule]>,
+    buffer_limit: usize,
+}
+
+impl RetryLayer {
+    /// Every policy needs at least one attempt.
+    pub fn new(rules: Vec<RetryRule>) -> Self {
+        assert!(
+            rules.iter().all(|r| r.policy.max_attempts() >= 1),
+            "retry policies need at least one attempt"
+        );
+        Self {
+            rules: rules.into(),
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    /// Request bytes buffered per call for replays, see
+    /// [`DEFAULT_BUFFER_LIMIT`].
+    pub fn with_buffer_limit(mut self, bytes: usize) -> Self {
+        self.buffer_limit = bytes;
+        self
+    }
+}
+
+impl<S> tower::Layer<S> for RetryLayer {
+    type Service = RetryService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RetryService {
+            inner,
+            rules: self.rules.clone(),
+            buffer_limit: self.buffer_limit,
+        }
+    }
+}
+
+/// Each attempt is a call to a clone of the inner service.
+#[derive(Debug, Clone)]
+pub struct RetryService<S> {
+    inner: S,
+    rules: Arc<[RetryRule]>,
+    buffer_limit: usize,
+}
+
+impl<S, B> tower::Service<Request<B>> for RetryService<S>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let req = req.map(|body| body.map_err(Into::into).boxed());
+        let Some(policy) = policy_for(&self.rules, req.uri().path()).cloned() else {
+            return Box::pin(self.inner.clone().oneshot(req));
+        };
+        let (parts, body) = req.into_parts();
+        let attempts = Attempts {
+            inner: self.inner.clone(),
+            deadline: (parts.headers.get(GRPC_TIMEOUT))
+                .and_then(parse_timeout)
+                .map(|timeout| Instant::now() + timeout),
+            parts,
+            replay: Arc::new(Replay::new(body, self.buffer_limit)),
+        };
+        Box::pin(async move {
+            match policy {
+                AttemptPolicy::Retry(policy) => attempts.retry(policy).await,
+                AttemptPolicy::Hedge(policy) => attempts.hedge(policy).await,
+            }
+        })
+    }
+}
+
+type AttemptFuture = Pin<Box<dyn Future<Output = Result<Response<ProxyBody>, BoxError>> + Send>>;
+
+/// One call and what its attempts need.
+struct Attempts<S> {
+    inner: S,
+    parts: Parts,
+    /// From the client's `grpc-timeout`, shortened on every attempt.
+    deadline: Option<Instant>,
+    replay: Arc<Replay>,
+}
+
+impl<S> Attempts<S>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    /// Request of attempt `attempt`, counting from 0.
+    fn request(&self, attempt: u32) -> Request<ProxyBody> {
+        let mut req = Request::new(
+            ReplayBody {
+                replay: self.replay.clone(),
+                attempt,
+                pos: 0,
+            }
+            .boxed(),
+        );
+        *req.method_mut() = self.parts.method.clone();
+        *req.uri_mut() = self.parts.uri.clone();
+        *req.version_mut() = self.parts.version;
+        *req.headers_mut() = self.parts.headers.clone();
+        *req.extensions_mut() = self.parts.extensions.clone();
+        if attempt > 0 {
+            let headers = req.headers_mut();
+            headers.insert(GRPC_PREVIOUS_RPC_ATTEMPTS, HeaderValue::from(attempt));
+            if let Some(deadline) = self.deadline {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                headers.insert(GRPC_TIMEOUT, encode_timeout(remaining));
+            }
+        }
+        req
+    }
+
+    /// Sends attempt `attempt`, boxed as in `MetricsService`.
+    fn start(&self, attempt: u32) -> AttemptFuture {
+        Box::pin(self.inner.clone().oneshot(self.request(attempt)))
+    }
+
+    /// Whether another attempt can start `delay` from now.
+    fn can_retry(&self, delay: Duration) -> bool {
+        self.replay.replayable() && self.deadline.is_none_or(|d| Instant::now() + delay < d)
+    }
+
+    async fn retry(self, policy: RetryPolicy) -> Result<Response<ProxyBody>, BoxError> {
+        let mut backoff = policy.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            let resp = self.start(attempt).await?;
+            let retryable = failed_before_data(&resp)
+                .is_some_and(|code| policy.retryable_codes.contains(&code));
+            attempt += 1;
+            if !retryable || attempt >= policy.max_attempts {
+                self.replay.commit(attempt - 1);
+                return Ok(resp);
+            }
+            self.replay.abandon(attempt - 1);
+            let delay = match pushback(&resp) {
+                Some(Some(delay)) => {
+                    backoff = policy.initial_backoff;
+                    delay
+                }
+                Some(None) => return Ok(resp),
+                None => {
+                    let delay = jitter(backoff);
+                    backoff = backoff
+                        .mul_f64(policy.backoff_multiplier)
+                        .min(policy.max_backoff);
+                    delay
+                }
+            };
+            if !self.can_retry(delay) {
+                return Ok(resp);
+            }
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    async fn hedge(self, policy: HedgingPolicy) -> Result<Response<ProxyBody>, BoxError> {
+        // dropping the set resets the attempts still running
+        let mut running = JoinSet::new();
+        let mut started = 0;
+        let mut next = Instant::now();
+        let mut stopped = false;
+        let mut last = None;
+        loop {
+            let can_start = !stopped
+                && started < policy.max_attempts
+                && (started == 0 || self.can_retry(Duration::ZERO));
+            if running.is_empty() && !can_start {
+                return last.expect("an attempt was started");
+            }
+            tokio::select! {
+                Some(res) = running.join_next() => {
+                    let (attempt, res) = res?;
+                    if self.replay.owner().is_some_and(|owner| owner != attempt) {
+                        // lost the request body to the owner
+                        continue;
+                    }
+                    let resp: Response<ProxyBody> = res?;
+                    match failed_before_data(&resp) {
+                        Some(code) if policy.non_fatal_codes.contains(&code) => {}
+                        _ => {
+                            self.replay.commit(attempt);
+                            return Ok(resp);
+                        }
+                    }
+                    match pushback(&resp) {
+                        Some(Some(delay)) => next = Instant::now() + delay,
+                        Some(None) => stopped = true,
+                        // the next one in line goes right away
+                        None => next = Instant::now(),
+                    }
+                    self.replay.abandon(attempt);
+                    last = Some(Ok(resp));
+                }
+                _ = tokio::time::sleep_until(next), if can_start => {
+                    let attempt = self.start(started);
+                    let id = started;
+                    running.spawn(async move { (id, attempt.await) });
+                    started += 1;
+                    next = Instant::now() + policy.hedging_delay;
+                }
+            }
+        }
+    }
+}
+
+/// Status of a trailers-only response, which has sent no data yet.
+fn failed_before_data(resp: &Response<ProxyBody>) -> Option<Code> {
+    let code = Code::from_header(resp.headers().get(GRPC_STATUS)?)?;
+    (code != Code::Ok && resp.body().is_end_stream()).then_some(code)
+}
+
+/// `grpc-retry-pushback-ms` of a response: `Some(None)` when it asks not
+/// to retry (negative or malformed).
+fn pushback(resp: &Response<ProxyBody>) -> Option<Option<Duration>> {
+    let value = resp.headers().get(GRPC_RETRY_PUSHBACK_MS)?;
+    let millis = value.to_str().ok().and_then(|v| v.parse::<u64>().ok());
+    Some(millis.map(Duration::from_millis))
+}
+
+/// Random delay up to `backoff`, as the gRPC retry design asks.
+fn jitter(backoff: Duration) -> Duration {
+    let mut bytes = [0; 4];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return backoff;
+    }
+    backoff.mul_f64(f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX))
+}
+
+/// Request body shared by the attempts of a call: frames read by one are
+/// kept for the others, up to a limit.
+struct Replay {
+    state: Mutex<ReplayState>,
+}
+
+enum Buffered {
+    Data(Bytes),
+    Trailers(HeaderMap),
+}
+
+struct ReplayState {
+    /// `None` once it ended or failed.
+    source: Option<ProxyBody>,
+    frames: Vec<Buffered>,
+    size: usize,
+    limit: usize,
+    /// Attempt reading past the buffer, after it overflowed or was
+    /// committed to; the others cannot go on.
+    owner: Option<u32>,
+    /// Error of the source, for the attempts that did not see it.
+    error: Option<String>,
+    /// Failed attempts, which must not read on.
+    abandoned: Vec<u32>,
+    /// Attempts waiting for the source.
+    waiting: Vec<Waker>,
+}
+
+impl Replay {
+    fn new(source: ProxyBody, limit: usize) -> Self {
+        Self {
+            state: Mutex::new(ReplayState {
+                source: Some(source),
+                frames: Vec::new(),
+                size: 0,
+                limit,
+                owner: None,
+                error: None,
+                abandoned: Vec::new(),
+                waiting: Vec::new(),
+            }),
+        }
+    }
+
+    /// Attempt the body is left to, see [`ReplayState::owner`].
+    fn owner(&self) -> Option<u32> {
+        self.state.lock().unwrap().owner
+    }
+
+    /// Whether a new attempt would see the whole body.
+    fn replayable(&self) -> bool {
+        self.owner().is_none()
+    }
+
+    /// Ends the body of a failed `attempt`, which the client connection
+    /// may still be sending.
+    fn abandon(&self, attempt: u32) {
+        self.state.lock().unwrap().abandoned.push(attempt);
+    }
+
+    /// Stops buffering, `attempt` is the one that stays.
+    fn commit(&self, attempt: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.owner.get_or_insert(attempt);
+        state.wake_all();
+    }
+}
+
+impl ReplayState {
+    fn wake_all(&mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// What one attempt sends of a [`Replay`].
+struct ReplayBody {
+    replay: Arc<Replay>,
+    attempt: u32,
+    /// Next buffered frame.
+    pos: usize,
+}
+
+impl Body for ReplayBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        let mut state = this.replay.state.lock().unwrap();
+        if state.abandoned.contains(&this.attempt) {
+            return Poll::Ready(Some(Err("attempt was abandoned".into())));
+        }
+        if let Some(buffered) = state.frames.get(this.pos) {
+            this.pos += 1;
+            let frame = match buffered {
+                Buffered::Data(data) => Frame::data(data.clone()),
+                Buffered::Trailers(trailers) => Frame::trailers(trailers.clone()),
+            };
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        if let Some(error) = &state.error {
+            return Poll::Ready(Some(Err(error.clone().into())));
+        }
+        if state.owner.is_some_and(|owner| owner != this.attempt) {
+            return Poll::Ready(Some(Err("request body is past the retry buffer".into())));
+        }
+        let Some(source) = state.source.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let frame = match Pin::new(source).poll_frame(cx) {
+            Poll::Pending => {
+                if !state.waiting.iter().any(|w| w.will_wake(cx.waker())) {
+                    state.waiting.push(cx.waker().clone());
+                }
+                return Poll::Pending;
+            }
+            Poll::Ready(frame) => frame,
+        };
+        state.wake_all();
+        let frame = match frame {
+            None => {
+                state.source = None;
+                return Poll::Ready(None);
+            }
+            Some(Err(e)) => {
+                state.source = None;
+                state.error = Some(e.to_string());
+                return Poll::Ready(Some(Err(e)));
+            }
+            Some(Ok(frame)) => frame,
+        };
+        if state.owner.is_some() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        let size = frame.data_ref().map_or(0, Bytes::len);
+        if state.size + size > state.limit {
+            state.owner = Some(this.attempt);
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        state.size += size;
+        let buffered = match frame.data_ref() {
+            Some(data) => Buffered::Data(data.clone()),
+            None => match frame.trailers_ref() {
+                Some(trailers) => Buffered::Trailers(trailers.clone()),
+                // unknown frame kinds are not replayed
+                None => return Poll::Ready(Some(Ok(frame))),
+            },
+        };
+        state.frames.push(buffered);
+        this.pos += 1;
+        Poll::Ready(Some(Ok(frame)))
+    }
+}
+
+impl Drop for ReplayBody {
+    fn drop(&mut self) {
+        // another attempt has to poll the source now
+        if let Ok(mut state) = self.replay.state.lock() {
+            state.wake_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+    use http::Request;
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::{body::Frame, client::conn::http2::SendRequest};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        jitter, policy_for, AttemptPolicy, HedgingPolicy, Replay, ReplayBody, RetryLayer,
+        RetryPolicy, RetryRule,
+    };
+    use crate::proxy::{
+        connector::UdsConnector,
+        deadline::parse_timeout,
+        proxy_service::ProxyService,
+        status::Code,
+        test_util::{
+            self, client_config, h2_client, spawn_flaky_backend, spawn_proxy_service,
+            temp_socket_path,
+        },
+    };
+
+    fn body(chunks: &[&'static str]) -> super::ProxyBody {
+        let frames: Vec<Result<_, Infallible>> = chunks
+            .iter()
+            .map(|c| Ok(Frame::data(Bytes::from_static(c.as_bytes()))))
+            .collect();
+        StreamBody::new(tokio_stream::iter(frames))
+            .map_err(|e| match e {})
+            .boxed()
+    }
+
+    fn attempt(replay: &Arc<Replay>, attempt: u32) -> ReplayBody {
+        ReplayBody {
+            replay: replay.clone(),
+            attempt,
+            pos: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_buffered_body() {
+        let replay = Arc::new(Replay::new(body(&["ab", "cd"]), 16));
+        let mut first = attempt(&replay, 0);
+        assert_eq!(
+            first.frame().await.unwrap().unwrap().into_data().unwrap(),
+            "ab"
+        );
+        // the second attempt catches up and reads the rest for both
+        let second = attempt(&replay, 1).collect().await.unwrap().to_bytes();
+        assert_eq!(second, "abcd");
+        let rest = first.collect().await.unwrap().to_bytes();
+        assert_eq!(rest, "cd");
+        assert!(replay.replayable());
+    }
+
+    #[tokio::test]
+    async fn overflow_keeps_first_attempt() {
+        let replay = Arc::new(Replay::new(body(&["abc", "def"]), 4));
+        let first = attempt(&replay, 0).collect().await.unwrap().to_bytes();
+        assert_eq!(first, "abcdef");
+        assert!(!replay.replayable());
+        assert!(attempt(&replay, 1).collect().await.is_err());
+    }
+
+    #[test]
+    fn picks_most_specific_rule() {
+        let rule = |method: Option<&str>, max_attempts| RetryRule {
+            method: method.map(str::to_string),
+            policy: AttemptPolicy::Hedge(HedgingPolicy {
+                max_attempts,
+                hedging_delay: Duration::ZERO,
+                non_fatal_codes: Vec::new(),
+            }),
+        };
+        let rules = [
+            rule(None, 1),
+            rule(Some("/a.A/"), 2),
+            rule(Some("/a.A/Get"), 3),
+        ];
+        let attempts = |path| policy_for(&rules, path).map(|p| p.max_attempts());
+        assert_eq!(attempts("/a.A/Get"), Some(3));
+        assert_eq!(attempts("/a.A/List"), Some(2));
+        assert_eq!(attempts("/b.B/Get"), Some(1));
+        assert_eq!(policy_for(&rules[1..], "/b.B/Get"), None);
+
+        for _ in 0..100 {
+            assert!(jitter(Duration::from_millis(10)) <= Duration::from_millis(10));
+        }
+    }
+
+    /// Unary call sending `message`, returning the response data and status.
+    async fn call_with_body(
+        sender: &mut SendRequest<http_body_util::Full<Bytes>>,
+        path: &str,
+        message: &'static str,
+    ) -> (Bytes, String) {
+        sender.ready().await.unwrap();
+        let resp = sender
+            .send_request(
+                Request::post(path)
+                    .header("content-type", "application/grpc")
+                    .header("grpc-timeout", "10S")
+                    .body(http_body_util::Full::new(Bytes::from_static(
+                        message.as_bytes(),
+                    )))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let (head, body) = resp.into_parts();
+        let body = body.collect().await.unwrap();
+        let status = (body.trailers().and_then(|t| t.get("grpc-status")))
+            .or_else(|| head.headers.get("grpc-status"))
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        (body.to_bytes(), status)
+    }
+
+    #[tokio::test]
+    async fn retries_and_hedges_calls() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("retry");
+        let mut backend = spawn_flaky_backend(&socket, token.child_token());
+        let retry = AttemptPolicy::Retry(RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+            backoff_multiplier: 2.0,
+            retryable_codes: vec![Code::Unavailable],
+        });
+        let hedge = AttemptPolicy::Hedge(HedgingPolicy {
+            max_attempts: 2,
+            hedging_delay: Duration::from_millis(100),
+            non_fatal_codes: vec![Code::Unavailable],
+        });
+        let service = tower::Layer::layer(
+            &RetryLayer::new(vec![
+                RetryRule {
+                    method: Some("/retry.Test/".to_string()),
+                    policy: retry,
+                },
+                RetryRule {
+                    method: Some("/retry.Test/Slow".to_string()),
+                    policy: hedge,
+                },
+            ]),
+            ProxyService::new(UdsConnector::new(&socket)),
+        );
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, service, token.child_token()).await;
+        let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+
+        // every attempt gets the whole request body
+        let (data, status) = call_with_body(&mut sender, "/retry.Test/Flaky", "hello").await;
+        assert_eq!((&data[..], status.as_str()), (&b"hello"[..], "0"));
+        for previous in [None, Some("1"), Some("2")] {
+            let head = backend.recv().await.unwrap();
+            let attempts = head.headers.get("grpc-previous-rpc-attempts");
+            assert_eq!(attempts.map(|v| v.to_str().unwrap()), previous);
+            let timeout = parse_timeout(&head.headers["grpc-timeout"]).unwrap();
+            assert!(timeout <= Duration::from_secs(10), "{timeout:?}");
+        }
+
+        let (_, status) = call_with_body(&mut sender, "/retry.Test/Pushback", "hello").await;
+        assert_eq!(status, "14");
+        backend.recv().await.unwrap();
+        assert!(backend.try_recv().is_err());
+
+        // the hedge answers while the first attempt hangs
+        let started = std::time::Instant::now();
+        let (data, status) = call_with_body(&mut sender, "/retry.Test/Slow", "hedged").await;
+        assert_eq!((&data[..], status.as_str()), (&b"hedged"[..], "0"));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        let first = backend.recv().await.unwrap();
+        assert!(!first.headers.contains_key("grpc-previous-rpc-attempts"));
+        let hedge = backend.recv().await.unwrap();
+        assert_eq!(hedge.headers["grpc-previous-rpc-attempts"], "1");
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,364 @@
+//! Routing by `X-Target-InstanceId`, the Rust port of the C#
+//! `ProxyServer.RunAsync` forwarding route, and by TLS SNI name.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::{uri::PathAndQuery, HeaderName, Request, Response, Uri};
+use hyper::body::Body;
+use tower::ServiceExt;
+
+use super::{
+    connector::{BackendAddr, BackendConnector},
+    proxy_service::ProxyService,
+    status::{self, Code},
+    BoxError, ConnectionInfo, ProxyBody,
+};
+
+/// Header selecting the backend instance, see `GrpcProxyConstants` in C#.
+pub const TARGET_INSTANCE_ID_HEADER: HeaderName = HeaderName::from_static("x-target-instanceid");
+
+/// Routes `{prefix}/**` requests to the backend named by
+/// [`TARGET_INSTANCE_ID_HEADER`], stripping the prefix and the header.
+///
+/// Failures are reported as trailers-only gRPC statuses:
+/// - path outside the prefix: `UNIMPLEMENTED`
+/// - missing or non-utf8 header: `INVALID_ARGUMENT`
+/// - unknown instance id: `NOT_FOUND`
+#[derive(Clone)]
+pub struct HeaderRouter {
+    prefix: Arc<str>,
+    backends: Arc<HashMap<String, ProxyService<BackendConnector>>>,
+}
+
+impl HeaderRouter {
+    /// `prefix` is e.g. `/proxy`; an empty prefix routes every path.
+    pub fn new(
+        prefix: impl Into<String>,
+        backends: impl IntoIterator<Item = (String, BackendAddr)>,
+    ) -> Self {
+        Self::with_connectors(
+            prefix,
+            backends
+                .into_iter()
+                .map(|(id, addr)| (id, BackendConnector::from(addr))),
+        )
+    }
+
+    /// Like [`Self::new`] but with prebuilt connectors, e.g. TLS backends.
+    pub fn with_connectors(
+        prefix: impl Into<String>,
+        backends: impl IntoIterator<Item = (String, BackendConnector)>,
+    ) -> Self {
+        Self::with_services(
+            prefix,
+            backends
+                .into_iter()
+                .map(|(id, connector)| (id, ProxyService::new(connector))),
+        )
+    }
+
+    /// Like [`Self::new`] but with prebuilt services, e.g. health checked
+    /// ones.
+    pub fn with_services(
+        prefix: impl Into<String>,
+        backends: impl IntoIterator<Item = (String, ProxyService<BackendConnector>)>,
+    ) -> Self {
+        let prefix = prefix.into();
+        Self {
+            prefix: prefix.trim_end_matches('/').into(),
+            backends: Arc::new(backends.into_iter().collect()),
+        }
+    }
+
+    /// Path without the prefix, or `None` when the path is not under it.
+    fn strip_prefix<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(&*self.prefix)?;
+        rest.starts_with('/').then_some(rest)
+    }
+
+    fn route<B>(
+        &self,
+        mut req: Request<B>,
+    ) -> Result<(ProxyService<BackendConnector>, Request<B>), (Code, String)> {
+        let Some(path) = self.strip_prefix(req.uri().path()) else {
+            return Err((
+                Code::Unimplemented,
+                format!("no route for {}", req.uri().path()),
+            ));
+        };
+        let path = match req.uri().query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        let Some(instance_id) = req.headers_mut().remove(TARGET_INSTANCE_ID_HEADER) else {
+            return Err((
+                Code::InvalidArgument,
+                format!("Missing {TARGET_INSTANCE_ID_HEADER} header"),
+            ));
+        };
+        let Ok(instance_id) = instance_id.to_str() else {
+            return Err((
+                Code::InvalidArgument,
+                format!("Invalid {TARGET_INSTANCE_ID_HEADER} header"),
+            ));
+        };
+        let Some(backend) = self.backends.get(instance_id.trim()) else {
+            return Err((
+                Code::NotFound,
+                format!("Unknown {TARGET_INSTANCE_ID_HEADER} value: {instance_id}"),
+            ));
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = Some(
+            PathAndQuery::try_from(path)
+                .map_err(|e| (Code::InvalidArgument, format!("invalid path: {e}")))?,
+        );
+        *req.uri_mut() = Uri::from_parts(parts)
+            .map_err(|e| (Code::InvalidArgument, format!("invalid path: {e}")))?;
+        Ok((backend.clone(), req))
+    }
+}
+
+impl<B> tower::Service<Request<B>> for HeaderRouter
+where
+    B: Body<Data = Bytes> + Send + Sync + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        match self.route(req) {
+            Ok((mut backend, req)) => {
+                Box::pin(async move { tower::Service::call(&mut backend, req).await })
+            }
+            Err((code, message)) => {
+                let resp = status::trailers_only(code, message);
+                Box::pin(async move { Ok(resp) })
+            }
+        }
+    }
+}
+
+/// Routes every request to the service of the SNI name its connection
+/// asked for (see [`ConnectionInfo::server_name`]), or else to the default
+/// service. Requests without either fail with `NOT_FOUND`.
+///
+/// Pair it with a [`crate::tls::SniCertResolver`] for the certificates.
+#[derive(Clone)]
+pub struct SniRouter<S> {
+    routes: Arc<HashMap<String, S>>,
+    default: Option<S>,
+}
+
+impl<S> SniRouter<S> {
+    pub fn new(routes: impl IntoIterator<Item = (String, S)>, default: Option<S>) -> Self {
+        let routes = (routes.into_iter())
+            .map(|(name, service)| (name.to_ascii_lowercase(), service))
+            .collect();
+        Self {
+            routes: Arc::new(routes),
+            default,
+        }
+    }
+}
+
+impl<S, B> tower::Service<Request<B>> for SniRouter<S>
+where
+    S: tower::Service<Request<B>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let server_name =
+            (req.extensions().get::<ConnectionInfo>()).and_then(|info| info.server_name.clone());
+        let route = (server_name.as_deref())
+            .and_then(|name| self.routes.get(name))
+            .or(self.default.as_ref());
+        match route {
+            Some(service) => Box::pin(service.clone().oneshot(req)),
+            None => {
+                let name = server_name.as_deref().unwrap_or("(none)");
+                let resp = status::trailers_only(
+                    Code::NotFound,
+                    format!("no route for server name {name}"),
+                );
+                Box::pin(async move { Ok(resp) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use http::Request;
+    use http_body_util::Empty;
+    use rustls::pki_types::{pem::PemObject, CertificateDer};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{HeaderRouter, SniRouter, TARGET_INSTANCE_ID_HEADER};
+    use crate::{
+        proxy::{
+            connector::{BackendAddr, UdsConnector},
+            proxy_service::ProxyService,
+            test_util::{
+                self, client_config, h2_client, h2_client_for, post, served_by, spawn_echo_backend,
+                spawn_echo_backend_tcp, spawn_proxy_service, temp_socket_path,
+            },
+        },
+        tls::{
+            test_certs::{localhost_params, write_ca, write_signed},
+            TlsIdentityConfig,
+        },
+        util::temp_dir,
+    };
+
+    #[tokio::test]
+    async fn routes_by_target_instance_id() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("route-uds");
+        let mut uds_backend = spawn_echo_backend(&socket, token.child_token());
+        let (tcp_addr, mut tcp_backend) = spawn_echo_backend_tcp(token.child_token()).await;
+        let router = HeaderRouter::new(
+            "/proxy",
+            [
+                ("1".to_string(), BackendAddr::Uds(socket.clone())),
+                ("2".to_string(), BackendAddr::Tcp(tcp_addr.to_string())),
+                (
+                    "3".to_string(),
+                    BackendAddr::Uds(temp_socket_path("route-missing")),
+                ),
+            ],
+        );
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, router, token.child_token()).await;
+        let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+
+        for (id, backend) in [("1", &mut uds_backend), ("2", &mut tcp_backend)] {
+            let resp = post(
+                &mut sender,
+                Request::builder()
+                    .uri("/proxy/helloworld.Greeter/SayHello")
+                    .header(TARGET_INSTANCE_ID_HEADER, id),
+            )
+            .await
+            .unwrap();
+            assert_eq!(resp.grpc_status(), Some("0"));
+            let parts = backend.recv().await.unwrap();
+            assert_eq!(parts.uri.path(), "/helloworld.Greeter/SayHello");
+            assert!(!parts.headers.contains_key(TARGET_INSTANCE_ID_HEADER));
+        }
+
+        let failures = [
+            (None, "/proxy/helloworld.Greeter/SayHello", "3"),
+            (Some("42"), "/proxy/helloworld.Greeter/SayHello", "5"),
+            (Some("1"), "/helloworld.Greeter/SayHello", "12"),
+            (Some("1"), "/proxyx/helloworld.Greeter/SayHello", "12"),
+            (Some("3"), "/proxy/helloworld.Greeter/SayHello", "14"),
+        ];
+        for (id, path, code) in failures {
+            let mut req = Request::builder().uri(path);
+            if let Some(id) = id {
+                req = req.header(TARGET_INSTANCE_ID_HEADER, id);
+            }
+            let resp = post(&mut sender, req).await.unwrap();
+            assert_eq!(resp.head.status, 200);
+            assert_eq!(resp.grpc_status(), Some(code), "{id:?} {path}");
+            assert!(resp.head.headers.contains_key("grpc-message"));
+        }
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn routes_by_sni() {
+        let token = CancellationToken::new();
+        let dir = temp_dir("sni");
+        let ca = write_ca(&dir, "ca");
+        let named = |name: &str| {
+            let params = rcgen::CertificateParams::new(vec![name.to_string()]).unwrap();
+            write_signed(&dir, name, params, &ca)
+        };
+        let (a, b) = (named("a.example"), named("b.example"));
+        let fallback = write_signed(&dir, "localhost", localhost_params(), &ca);
+        let root = CertificateDer::from_pem_file(dir.join("ca.crt")).unwrap();
+
+        let sockets = ["a", "b", "default"].map(|name| temp_socket_path(&format!("sni-{name}")));
+        let mut heads: Vec<_> = (sockets.iter())
+            .map(|socket| spawn_echo_backend(socket, token.child_token()))
+            .collect();
+        let services = sockets
+            .each_ref()
+            .map(|socket| ProxyService::new(UdsConnector::new(socket)));
+        let router = SniRouter::new(
+            [
+                ("a.example".to_string(), services[0].clone()),
+                ("B.example".to_string(), services[1].clone()),
+            ],
+            Some(services[2].clone()),
+        );
+        let names = [("a.example", &a), ("B.example", &b)];
+        let (server_config, resolver) =
+            TlsIdentityConfig::load_sni_server_config(names, Some(&fallback), None).unwrap();
+        assert_eq!(resolver.resolvers().count(), 3);
+        let (addr, proxy) =
+            spawn_proxy_service(server_config, router.clone(), token.child_token()).await;
+
+        // each name gets its own certificate and backend
+        for (name, backend) in [("a.example", 0), ("b.example", 1), ("localhost", 2)] {
+            let config = client_config(root.clone(), None);
+            let mut sender = h2_client_for(addr, config, name).await.unwrap();
+            let res = post(&mut sender, Request::builder().uri("/echo.Echo/Say")).await;
+            assert_eq!(res.unwrap().grpc_status(), Some("0"), "{name}");
+            assert_eq!(served_by(&mut heads), backend, "{name}");
+        }
+
+        // without a default certificate unknown names fail the handshake
+        let (server_config, _) =
+            TlsIdentityConfig::load_sni_server_config(names, None, None).unwrap();
+        let (strict, strict_proxy) =
+            spawn_proxy_service(server_config, router, token.child_token()).await;
+        let config = client_config(root.clone(), None);
+        assert!(h2_client_for::<Empty<Bytes>>(strict, config, "localhost")
+            .await
+            .is_err());
+        let config = client_config(root, None);
+        let mut sender = h2_client_for(strict, config, "a.example").await.unwrap();
+        let res = post(&mut sender, Request::builder().uri("/echo.Echo/Say")).await;
+        assert_eq!(res.unwrap().grpc_status(), Some("0"));
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+        strict_proxy.await.unwrap();
+        for socket in sockets {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
+}
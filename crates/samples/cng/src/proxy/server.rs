@@ -0,0 +1,1118 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http::{Request, Response};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, Incoming};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{
+    sync::{CancellationToken, WaitForCancellationFutureOwned},
+    task::TaskTracker,
+};
+use tower::{util::option_layer, Layer, ServiceExt};
+
+use super::{
+    client_cert::ClientIdentity,
+    grpc_web::{CorsConfig, GrpcWebLayer},
+    listener::{accept_retrying, Listener, PeerAddr},
+    proxy_protocol::{self, ProxiedAddrs},
+    status::{self, Code},
+    BoxError, ProxyBody,
+};
+
+/// Default of [`ServeOptions::drain_timeout`].
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default of [`ServeOptions::handshake_timeout`].
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long connections get to flush the `UNAVAILABLE` trailers of
+/// cancelled streams before they are abandoned.
+const CANCEL_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+const SHUTDOWN_MESSAGE: &str = "proxy is shutting down";
+
+const OVERLOAD_MESSAGE: &str = "too many calls in flight in proxy";
+
+/// Settings of [`serve_with_options`].
+#[derive(Debug, Clone)]
+pub struct ServeOptions {
+    /// How long in-flight streams may continue once shutdown started
+    /// (listener closed, GOAWAY sent) before they are cancelled with
+    /// `UNAVAILABLE`.
+    pub drain_timeout: Duration,
+    /// Connections without streams for this long are closed with a
+    /// GOAWAY; never when `None`.
+    pub idle_timeout: Option<Duration>,
+    /// Also serve gRPC-Web clients (see [`grpc_web`](super::grpc_web)),
+    /// over HTTP/1.1 as well as h2; TLS listeners then need `http/1.1` in
+    /// their ALPN protocols too. h2 only otherwise. The translation wraps
+    /// everything else, so browsers also read the status of calls shed
+    /// over the `call_limit` or cancelled by the drain timeout.
+    pub grpc_web: Option<CorsConfig>,
+    /// Every connection starts with a PROXY protocol header, see
+    /// [`proxy_protocol`](super::proxy_protocol); connections without one
+    /// are closed.
+    pub proxy_protocol: bool,
+    /// Connections open at once; further clients wait in the listen
+    /// backlog. Unlimited when `None`.
+    pub max_connections: Option<usize>,
+    /// TLS handshakes (and PROXY protocol headers) read at once; further
+    /// clients wait in the listen backlog. Unlimited when `None`.
+    pub max_handshakes: Option<usize>,
+    /// How long a client may take to complete its TLS handshake, so idle
+    /// sockets give their handshake slot back.
+    pub handshake_timeout: Duration,
+    /// Streams a client may open at once on a connection, advertised in
+    /// the HTTP/2 SETTINGS; hyper's default (200) when `None`.
+    pub max_concurrent_streams: Option<u32>,
+    /// Calls over this limit fail right away with `RESOURCE_EXHAUSTED`.
+    pub call_limit: Option<CallLimit>,
+    /// Counters of this server, shared with the caller.
+    pub stats: Arc<ServerStats>,
+}
+
+impl Default for ServeOptions {
+    fn default() -> Self {
+        Self {
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            idle_timeout: None,
+            grpc_web: None,
+            proxy_protocol: false,
+            max_connections: None,
+            max_handshakes: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_concurrent_streams: None,
+            call_limit: None,
+            stats: Arc::default(),
+        }
+    }
+}
+
+/// Live counters of a server.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    active_connections: AtomicUsize,
+    active_streams: AtomicUsize,
+    cancelled_streams: AtomicU64,
+    tls_handshake_failures: AtomicU64,
+    shed_calls: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Requests whose response has not been fully sent yet.
+    pub fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    /// Streams ended with `UNAVAILABLE` because the drain timeout expired.
+    pub fn cancelled_streams(&self) -> u64 {
+        self.cancelled_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn tls_handshake_failures(&self) -> u64 {
+        self.tls_handshake_failures.load(Ordering::Relaxed)
+    }
+
+    /// Calls failed with `RESOURCE_EXHAUSTED` over the
+    /// [`ServeOptions::call_limit`].
+    pub fn shed_calls(&self) -> u64 {
+        self.shed_calls.load(Ordering::Relaxed)
+    }
+}
+
+/// Caps the calls in flight over every server given a clone, see
+/// [`ServeOptions::call_limit`].
+#[derive(Debug, Clone)]
+pub struct CallLimit {
+    slots: Arc<Semaphore>,
+    max: usize,
+}
+
+impl CallLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max)),
+            max,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Calls holding a slot, until their response was fully sent.
+    pub fn in_flight(&self) -> usize {
+        self.max - self.slots.available_permits()
+    }
+}
+
+/// Holds one unit of a [`ServerStats`] gauge until dropped.
+struct GaugeGuard {
+    stats: Arc<ServerStats>,
+    gauge: fn(&ServerStats) -> &AtomicUsize,
+}
+
+impl GaugeGuard {
+    fn new(stats: Arc<ServerStats>, gauge: fn(&ServerStats) -> &AtomicUsize) -> Self {
+        gauge(&stats).fetch_add(1, Ordering::Relaxed);
+        Self { stats, gauge }
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        (self.gauge)(&self.stats).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Open streams of one connection, for its idle timeout.
+struct ConnectionStreams {
+    active: AtomicUsize,
+    /// When the last stream ended, or the connection was accepted.
+    idle_since: Mutex<Instant>,
+}
+
+impl ConnectionStreams {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            active: AtomicUsize::new(0),
+            idle_since: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Resolves once the connection had no streams for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        let mut deadline = *self.idle_since.lock().unwrap() + timeout;
+        loop {
+            tokio::time::sleep_until(deadline.into()).await;
+            let idle_since = *self.idle_since.lock().unwrap();
+            if self.active.load(Ordering::Relaxed) > 0 {
+                deadline = Instant::now() + timeout;
+            } else if idle_since + timeout > Instant::now() {
+                deadline = idle_since + timeout;
+            } else {
+                return;
+            }
+        }
+    }
+}
+
+/// One stream of a [`ConnectionStreams`] until dropped.
+struct ConnectionStream(Arc<ConnectionStreams>);
+
+impl ConnectionStream {
+    fn new(streams: Arc<ConnectionStreams>) -> Self {
+        streams.active.fetch_add(1, Ordering::Relaxed);
+        Self(streams)
+    }
+}
+
+impl Drop for ConnectionStream {
+    fn drop(&mut self) {
+        let mut idle_since = self.0.idle_since.lock().unwrap();
+        if self.0.active.fetch_sub(1, Ordering::Relaxed) == 1 {
+            *idle_since = Instant::now();
+        }
+    }
+}
+
+/// Per-connection data attached to every request as an extension.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// The client, from its PROXY protocol header if there was one.
+    pub peer_addr: PeerAddr,
+    /// Addresses from the PROXY protocol header.
+    pub proxied: Option<ProxiedAddrs>,
+    /// Verified mTLS client identity, if the client presented a certificate.
+    pub client_identity: Option<Arc<ClientIdentity>>,
+    /// SNI name the client asked for in the TLS handshake, lowercase.
+    pub server_name: Option<Arc<str>>,
+    /// Shared by the requests of the connection only.
+    connection: Arc<()>,
+    /// Cancelled once the connection closed.
+    closed: CancellationToken,
+}
+
+impl ConnectionInfo {
+    /// Handle of the connection, dead once it closed and its requests
+    /// ended.
+    pub fn connection(&self) -> Weak<()> {
+        Arc::downgrade(&self.connection)
+    }
+
+    /// Resolves once the connection closed.
+    pub fn closed(&self) -> WaitForCancellationFutureOwned {
+        self.closed.clone().cancelled_owned()
+    }
+}
+
+/// Accepts TLS connections on `listener` (TCP or unix socket) and serves h2 on them with
+/// `service` until `signal` resolves. Open connections are then drained as
+/// described in [`serve_with_options`], with the default options.
+pub async fn serve_with_incoming<S>(
+    listener: impl Listener,
+    acceptor: TlsAcceptor,
+    service: S,
+    signal: impl Future<Output = ()>,
+) -> Result<(), BoxError>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    serve_with_options(
+        listener,
+        Some(acceptor),
+        service,
+        ServeOptions::default(),
+        signal,
+    )
+    .await
+}
+
+/// Same as [`serve_with_incoming`] but for cleartext h2 with prior
+/// knowledge (h2c), for pods where the mesh sidecar terminates TLS.
+pub async fn serve_plaintext_with_incoming<S>(
+    listener: impl Listener,
+    service: S,
+    signal: impl Future<Output = ()>,
+) -> Result<(), BoxError>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    serve_with_options(listener, None, service, ServeOptions::default(), signal).await
+}
+
+/// Serves `listener` with TLS, or h2c when `acceptor` is `None`, until
+/// `signal` resolves, then shuts down in two phases:
+///
+/// 1. the listener is closed and every connection gets a GOAWAY, so clients
+///    move new calls elsewhere while in-flight streams continue;
+/// 2. streams still open after `options.drain_timeout` are ended with
+///    `UNAVAILABLE` trailers (or a trailers-only response when the backend
+///    did not answer yet) and their backend streams are reset.
+pub async fn serve_with_options<S>(
+    mut listener: impl Listener,
+    acceptor: Option<TlsAcceptor>,
+    service: S,
+    options: ServeOptions,
+    signal: impl Future<Output = ()>,
+) -> Result<(), BoxError>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let connections = TaskTracker::new();
+    // sends GOAWAY on every connection
+    let shutdown = CancellationToken::new();
+    // fires when the drain timeout expires
+    let drain = CancellationToken::new();
+    let stats = options.stats;
+    let connection_slots = options
+        .max_connections
+        .map(|max| Arc::new(Semaphore::new(max)));
+    let handshake_slots = options
+        .max_handshakes
+        .map(|max| Arc::new(Semaphore::new(max)));
+    tokio::pin!(signal);
+    loop {
+        // taken before accepting, so clients over the limits wait in the
+        // backlog instead of taking file descriptors
+        let slots = async {
+            (
+                acquire(&connection_slots).await,
+                acquire(&handshake_slots).await,
+            )
+        };
+        let (connection_slot, handshake_slot) = tokio::select! {
+            slots = slots => slots,
+            _ = &mut signal => break,
+        };
+        let (stream, peer_addr) = tokio::select! {
+            accepted = accept_retrying(&mut listener) => accepted,
+            _ = &mut signal => break,
+        };
+        let acceptor = acceptor.clone();
+        let control = ConnectionControl {
+            shutdown: shutdown.clone(),
+            drain: drain.clone(),
+            idle_timeout: options.idle_timeout,
+            grpc_web: options.grpc_web.clone(),
+            max_concurrent_streams: options.max_concurrent_streams,
+            call_limit: options.call_limit.clone(),
+            stats: stats.clone(),
+            streams: ConnectionStreams::new(),
+        };
+        let service = connection_service(service.clone(), &control);
+        let proxy_protocol = options.proxy_protocol;
+        let handshake_timeout = options.handshake_timeout;
+        connections.spawn(async move {
+            let _connection = (
+                GaugeGuard::new(control.stats.clone(), |s| &s.active_connections),
+                connection_slot,
+            );
+            let mut stream = stream;
+            let (peer_addr, proxied) = if proxy_protocol {
+                let Ok(proxied) = read_proxy_header(&mut stream, &peer_addr, &control).await else {
+                    return;
+                };
+                let peer_addr = proxied.map_or(peer_addr, |addrs| PeerAddr::Tcp(addrs.source));
+                (peer_addr, proxied)
+            } else {
+                (peer_addr, None)
+            };
+            let Some(acceptor) = acceptor else {
+                drop(handshake_slot);
+                let info = ConnectionInfo {
+                    peer_addr,
+                    proxied,
+                    client_identity: None,
+                    server_name: None,
+                    connection: Arc::default(),
+                    closed: CancellationToken::new(),
+                };
+                return serve_connection(stream, info, service, control).await;
+            };
+            let stream = tokio::select! {
+                res = tokio::time::timeout(handshake_timeout, acceptor.accept(stream)) => {
+                    match res.unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into())) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            control.stats.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+                            eprintln!("tls handshake with {peer_addr} failed: {e}");
+                            return;
+                        }
+                    }
+                }
+                // nothing to drain yet
+                _ = control.shutdown.cancelled() => return,
+            };
+            drop(handshake_slot);
+            let tls = stream.get_ref().1;
+            let info = ConnectionInfo {
+                peer_addr,
+                proxied,
+                client_identity: ClientIdentity::from_connection(tls).map(Arc::new),
+                server_name: tls.server_name().map(Arc::from),
+                connection: Arc::default(),
+                closed: CancellationToken::new(),
+            };
+            serve_connection(stream, info, service, control).await
+        });
+    }
+    drop(listener);
+
+    println!(
+        "shutting down: draining {} connections with {} streams",
+        stats.active_connections(),
+        stats.active_streams()
+    );
+    shutdown.cancel();
+    connections.close();
+    if tokio::time::timeout(options.drain_timeout, connections.wait())
+        .await
+        .is_err()
+    {
+        println!(
+            "drain timeout of {:?} expired: cancelling {} streams",
+            options.drain_timeout,
+            stats.active_streams()
+        );
+        drain.cancel();
+        if tokio::time::timeout(CANCEL_FLUSH_TIMEOUT, connections.wait())
+            .await
+            .is_err()
+        {
+            eprintln!(
+                "abandoning {} connections that did not close",
+                stats.active_connections()
+            );
+        }
+    }
+    println!("shutdown complete");
+    Ok(())
+}
+
+/// A slot of `slots`, unless unlimited.
+pub(super) async fn acquire(slots: &Option<Arc<Semaphore>>) -> Option<OwnedSemaphorePermit> {
+    let slots = slots.clone()?;
+    Some(slots.acquire_owned().await.expect("never closed"))
+}
+
+/// Reads the PROXY protocol header of a new connection, `Err` once the
+/// connection is to be closed.
+async fn read_proxy_header<I>(
+    stream: &mut I,
+    peer_addr: &PeerAddr,
+    control: &ConnectionControl,
+) -> Result<Option<ProxiedAddrs>, ()>
+where
+    I: AsyncRead + Unpin,
+{
+    let header = tokio::select! {
+        res = tokio::time::timeout(
+            proxy_protocol::HEADER_TIMEOUT,
+            proxy_protocol::read_header(stream),
+        ) => res,
+        _ = control.shutdown.cancelled() => return Err(()),
+    };
+    match header {
+        Ok(Ok(proxied)) => Ok(proxied),
+        Ok(Err(e)) => {
+            eprintln!("connection from {peer_addr} closed: {e}");
+            Err(())
+        }
+        Err(_) => {
+            eprintln!("connection from {peer_addr} closed: no PROXY protocol header in time");
+            Err(())
+        }
+    }
+}
+
+/// Server-wide state a connection task follows.
+struct ConnectionControl {
+    /// Starts a graceful shutdown (GOAWAY) of the connection.
+    shutdown: CancellationToken,
+    /// Cancels the streams still open.
+    drain: CancellationToken,
+    idle_timeout: Option<Duration>,
+    grpc_web: Option<CorsConfig>,
+    max_concurrent_streams: Option<u32>,
+    call_limit: Option<CallLimit>,
+    stats: Arc<ServerStats>,
+    /// The streams of the connection.
+    streams: Arc<ConnectionStreams>,
+}
+
+/// Serves h2 (and HTTP/1.1 for `control.grpc_web`) on one accepted (and
+/// possibly decrypted) connection, tagging every request with `info`, until
+/// the client closes it or it shut down gracefully, after
+/// `control.shutdown` fired or once it was idle for `control.idle_timeout`.
+/// Streams are cancelled once `control.drain` fires.
+async fn serve_connection<I, S>(io: I, info: ConnectionInfo, service: S, control: ConnectionControl)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: tower::Service<Request<Incoming>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let _closed = info.closed.clone().drop_guard();
+    let peer_addr = info.peer_addr.clone();
+    let service =
+        TowerToHyperService::new(service.map_request(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(info.clone());
+            req
+        }));
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if let Some(max) = control.max_concurrent_streams {
+        builder.http2().max_concurrent_streams(max);
+    }
+    if control.grpc_web.is_none() {
+        builder = builder.http2_only();
+    }
+    let conn = builder.serve_connection(TokioIo::new(io), service);
+    tokio::pin!(conn);
+    let idle = async {
+        match control.idle_timeout {
+            Some(timeout) => control.streams.idle(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(idle);
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            res = conn.as_mut() => {
+                if let Err(e) = res {
+                    eprintln!("connection from {peer_addr} error: {e}");
+                }
+                return;
+            }
+            _ = control.shutdown.cancelled(), if !closing => {
+                closing = true;
+                conn.as_mut().graceful_shutdown();
+            }
+            _ = &mut idle, if !closing => {
+                closing = true;
+                conn.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
+
+/// The layers of one connection around `service`: gRPC-Web translation
+/// (outermost, so shed and drained calls are translated too), then the
+/// call limit and drain. Opaque, so the connection task relies on these
+/// bounds: proving them through the layers there trips the higher-ranked
+/// `Send` check on the error type of the request body.
+fn connection_service<S>(
+    service: S,
+    control: &ConnectionControl,
+) -> impl tower::Service<
+    Request<Incoming>,
+    Response = Response<ProxyBody>,
+    Error = BoxError,
+    Future: Send + 'static,
+> + Clone
+       + Send
+       + 'static
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let service = DrainService {
+        inner: service,
+        drain: control.drain.clone(),
+        call_limit: control.call_limit.clone(),
+        stats: control.stats.clone(),
+        connection: control.streams.clone(),
+    };
+    option_layer(control.grpc_web.clone().map(GrpcWebLayer::new))
+        .layer(service)
+        .map_request(|req: Request<Incoming>| req.map(|body| body.map_err(BoxError::from).boxed()))
+}
+
+/// Counts the streams of a connection, sheds calls over the
+/// `call_limit` and cancels streams once `drain` fires.
+#[derive(Clone)]
+struct DrainService<S> {
+    inner: S,
+    drain: CancellationToken,
+    call_limit: Option<CallLimit>,
+    stats: Arc<ServerStats>,
+    connection: Arc<ConnectionStreams>,
+}
+
+/// Held by a stream until its response was fully sent.
+struct StreamGuard {
+    _active: GaugeGuard,
+    _connection: ConnectionStream,
+    _call: Option<OwnedSemaphorePermit>,
+}
+
+impl<S, B> tower::Service<Request<B>> for DrainService<S>
+where
+    S: tower::Service<Request<B>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<ProxyBody>, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let call = match &self.call_limit {
+            Some(limit) => match limit.slots.clone().try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => {
+                    self.stats.shed_calls.fetch_add(1, Ordering::Relaxed);
+                    let resp = status::trailers_only(Code::ResourceExhausted, OVERLOAD_MESSAGE);
+                    return Box::pin(async move { Ok(resp) });
+                }
+            },
+            None => None,
+        };
+        let stream = StreamGuard {
+            _active: GaugeGuard::new(self.stats.clone(), |s| &s.active_streams),
+            _connection: ConnectionStream::new(self.connection.clone()),
+            _call: call,
+        };
+        let fut = self.inner.call(req);
+        let drain = self.drain.clone();
+        let stats = self.stats.clone();
+        Box::pin(async move {
+            tokio::select! {
+                biased;
+                res = fut => {
+                    let resp = res?;
+                    Ok(resp.map(|body| DrainBody::new(body, drain, stats, stream).boxed()))
+                }
+                _ = drain.cancelled() => {
+                    stats.cancelled_streams.fetch_add(1, Ordering::Relaxed);
+                    Ok(status::trailers_only(Code::Unavailable, SHUTDOWN_MESSAGE))
+                }
+            }
+        })
+    }
+}
+
+/// Response body that ends with `UNAVAILABLE` trailers once `drain` fires,
+/// dropping (and so resetting) the backend stream.
+struct DrainBody {
+    /// `None` once finished or cancelled.
+    inner: Option<ProxyBody>,
+    drain: Pin<Box<WaitForCancellationFutureOwned>>,
+    stats: Arc<ServerStats>,
+    _stream: StreamGuard,
+}
+
+impl DrainBody {
+    fn new(
+        inner: ProxyBody,
+        drain: CancellationToken,
+        stats: Arc<ServerStats>,
+        stream: StreamGuard,
+    ) -> Self {
+        Self {
+            inner: Some(inner),
+            drain: Box::pin(drain.cancelled_owned()),
+            stats,
+            _stream: stream,
+        }
+    }
+}
+
+impl Body for DrainBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = &mut *self;
+        let Some(inner) = this.inner.as_mut() else {
+            return Poll::Ready(None);
+        };
+        if this.drain.as_mut().poll(cx).is_ready() {
+            this.inner = None;
+            this.stats.cancelled_streams.fetch_add(1, Ordering::Relaxed);
+            let trailers = status::trailers(Code::Unavailable, SHUTDOWN_MESSAGE);
+            return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+        }
+        let frame = ready!(Pin::new(inner).poll_frame(cx));
+        if frame.is_none() {
+            this.inner = None;
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|inner| inner.is_end_stream())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use bytes::Bytes;
+    use grpc_tests::helloworld_tonic::{greeter_client::GreeterClient, HelloRequest};
+    use http::Request;
+    use http_body_util::{BodyExt, Empty, StreamBody};
+    use hyper::body::Frame;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+    use tokio_rustls::TlsAcceptor;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tokio_util::sync::CancellationToken;
+    use tonic::transport::Endpoint;
+
+    use crate::{
+        proxy::{
+            connector::UdsConnector,
+            proxy_service::ProxyService,
+            serve_plaintext_with_incoming, serve_with_options,
+            test_util::{
+                self, cert_der, client_config, echo, h2_client, open_echo_stream, post,
+                spawn_echo_backend, spawn_proxy, spawn_uds_greeter, temp_socket_path, tls_channel,
+                trailers, ChannelBody,
+            },
+            CallLimit, ServeOptions,
+        },
+        tls::{
+            test_certs::{localhost_params, write_self_signed},
+            TlsConfigError,
+        },
+        util::temp_dir,
+    };
+
+    #[tokio::test]
+    async fn proxy_tls_to_uds() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("tls-to-uds");
+        let backend = spawn_uds_greeter(&socket, token.child_token());
+
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy(server_config, &socket, token.child_token()).await;
+
+        let mut client = GreeterClient::new(tls_channel(addr, cert).await);
+        for _ in 0..2 {
+            let reply = client
+                .say_hello(HelloRequest {
+                    name: "Proxy".to_string(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(reply.into_inner().message, "Hello Proxy!");
+        }
+        drop(client);
+
+        token.cancel();
+        proxy.await.unwrap();
+        backend.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn proxy_h2c_to_uds() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("h2c-to-uds");
+        let backend = spawn_uds_greeter(&socket, token.child_token());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = ProxyService::new(UdsConnector::new(&socket));
+        let proxy_token = token.child_token();
+        let proxy = tokio::spawn(async move {
+            serve_plaintext_with_incoming(listener, service, async move {
+                proxy_token.cancelled().await
+            })
+            .await
+            .unwrap();
+        });
+
+        let channel = Endpoint::from_shared(format!("http://{addr}"))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = GreeterClient::new(channel);
+        for _ in 0..2 {
+            let reply = client
+                .say_hello(HelloRequest {
+                    name: "h2c".to_string(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(reply.into_inner().message, "Hello h2c!");
+        }
+        drop(client);
+
+        token.cancel();
+        proxy.await.unwrap();
+        backend.await.unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn cert_rotation_keeps_open_streams() {
+        let token = CancellationToken::new();
+        let dir = temp_dir("rotation");
+        let identity = write_self_signed(&dir, "server", localhost_params());
+        let old_cert = cert_der(&identity);
+        let socket = temp_socket_path("rotation");
+        let _backend = spawn_echo_backend(&socket, token.child_token());
+        let (server_config, resolver) = identity.load_reloadable_server_config(None).unwrap();
+        let (addr, proxy) = spawn_proxy(server_config, &socket, token.child_token()).await;
+
+        // open a stream on the old certificate
+        let mut sender = h2_client::<ChannelBody>(addr, client_config(old_cert.clone(), None))
+            .await
+            .unwrap();
+        let (frames, rx) = mpsc::channel(4);
+        sender.ready().await.unwrap();
+        let resp = sender
+            .send_request(
+                Request::post("/helloworld.Greeter/SayHello")
+                    .header("content-type", "application/grpc")
+                    .body(StreamBody::new(ReceiverStream::new(rx)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut body = resp.into_body();
+        let mut echo = async |msg: &'static str| {
+            frames
+                .send(Ok(Frame::data(Bytes::from_static(msg.as_bytes()))))
+                .await
+                .unwrap();
+            let frame = body.frame().await.unwrap().unwrap();
+            assert_eq!(frame.into_data().unwrap(), msg);
+        };
+        echo("before").await;
+
+        // rotate, a half-written rotation is refused
+        let rotated = write_self_signed(&dir, "rotated", localhost_params());
+        std::fs::copy(&rotated.cert_chain, &identity.cert_chain).unwrap();
+        assert!(matches!(
+            resolver.reload(),
+            Err(TlsConfigError::KeyMismatch { .. })
+        ));
+        std::fs::copy(&rotated.private_key, &identity.private_key).unwrap();
+        resolver.reload().unwrap();
+
+        // the open stream is unaffected
+        echo("after").await;
+        drop(frames);
+        let trailers = body
+            .frame()
+            .await
+            .unwrap()
+            .unwrap()
+            .into_trailers()
+            .unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+
+        // new handshakes get the new certificate
+        assert!(
+            h2_client::<Empty<Bytes>>(addr, client_config(old_cert, None))
+                .await
+                .is_err()
+        );
+        let mut sender = h2_client(addr, client_config(cert_der(&rotated), None))
+            .await
+            .unwrap();
+        post(
+            &mut sender,
+            Request::builder().uri("/helloworld.Greeter/SayHello"),
+        )
+        .await
+        .unwrap();
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drain_cancels_streams_after_timeout() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("drain");
+        let _backend = spawn_echo_backend(&socket, token.child_token());
+
+        let (mut server_config, cert) = test_util::load_test_server_config();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ServeOptions {
+            drain_timeout: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let stats = options.stats.clone();
+        let shutdown = token.child_token();
+        let proxy = tokio::spawn(serve_with_options(
+            listener,
+            Some(TlsAcceptor::from(Arc::new(server_config))),
+            ProxyService::new(UdsConnector::new(&socket)),
+            options,
+            shutdown.clone().cancelled_owned(),
+        ));
+
+        let mut sender = h2_client::<ChannelBody>(addr, client_config(cert, None))
+            .await
+            .unwrap();
+        let (finishing, mut finishing_body) = open_echo_stream(&mut sender).await;
+        let (held, mut held_body) = open_echo_stream(&mut sender).await;
+        echo(&finishing, &mut finishing_body, "before").await;
+        echo(&held, &mut held_body, "before").await;
+        assert_eq!(stats.active_connections(), 1);
+        assert_eq!(stats.active_streams(), 2);
+
+        // phase 1: no new connections or streams, open streams keep working
+        shutdown.cancel();
+        for _ in 0..100 {
+            if TcpStream::connect(addr).await.is_err() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(TcpStream::connect(addr).await.is_err());
+        echo(&finishing, &mut finishing_body, "draining").await;
+        echo(&held, &mut held_body, "draining").await;
+        assert!(
+            sender.ready().await.is_err() || {
+                let (_, rx) = mpsc::channel(1);
+                let req = Request::post("/helloworld.Greeter/SayHello")
+                    .body(StreamBody::new(ReceiverStream::new(rx)))
+                    .unwrap();
+                sender.send_request(req).await.is_err()
+            }
+        );
+        drop(finishing);
+        assert_eq!(trailers(&mut finishing_body).await["grpc-status"], "0");
+
+        // phase 2: the stream still open at the timeout is cancelled
+        let trailers = trailers(&mut held_body).await;
+        assert_eq!(trailers["grpc-status"], "14");
+        assert_eq!(trailers["grpc-message"], "proxy is shutting down");
+        // the client ends its side on the status, letting the connection close
+        drop(held);
+        proxy.await.unwrap().unwrap();
+        assert_eq!(stats.cancelled_streams(), 1);
+        assert_eq!(stats.active_connections(), 0);
+
+        token.cancel();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn limits_connections_streams_and_calls() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("limits");
+        let mut heads = spawn_echo_backend(&socket, token.child_token());
+
+        let (mut server_config, cert) = test_util::load_test_server_config();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let call_limit = CallLimit::new(1);
+        let options = ServeOptions {
+            max_connections: Some(2),
+            max_handshakes: Some(1),
+            max_concurrent_streams: Some(1),
+            call_limit: Some(call_limit.clone()),
+            ..Default::default()
+        };
+        let stats = options.stats.clone();
+        let proxy = tokio::spawn(serve_with_options(
+            listener,
+            Some(TlsAcceptor::from(Arc::new(server_config))),
+            ProxyService::new(UdsConnector::new(&socket)),
+            options,
+            token.child_token().cancelled_owned(),
+        ));
+        let pause = || tokio::time::sleep(Duration::from_millis(200));
+
+        // a client stuck in its handshake holds the only handshake slot
+        let stalled = TcpStream::connect(addr).await.unwrap();
+        let config = client_config(cert.clone(), None);
+        let pending = tokio::spawn(h2_client::<ChannelBody>(addr, config));
+        pause().await;
+        assert!(!pending.is_finished());
+        drop(stalled);
+        let mut first = pending.await.unwrap().unwrap();
+        let (held, mut held_body) = open_echo_stream(&mut first).await;
+        echo(&held, &mut held_body, "held").await;
+        heads.recv().await.unwrap();
+
+        // calls over the global limit fail right away
+        let mut second = h2_client(addr, client_config(cert.clone(), None))
+            .await
+            .unwrap();
+        let res = post(&mut second, Request::builder().uri("/echo.Echo/Say")).await;
+        let res = res.unwrap();
+        assert_eq!(res.grpc_status(), Some("8"));
+        assert_eq!(
+            res.head.headers["grpc-message"],
+            "too many calls in flight in proxy"
+        );
+        assert_eq!((stats.shed_calls(), call_limit.in_flight()), (1, 1));
+
+        // a third connection waits for one of the two to close
+        let pending = tokio::spawn(h2_client::<Empty<Bytes>>(addr, client_config(cert, None)));
+        pause().await;
+        assert!(!pending.is_finished());
+        drop(second);
+        let third = pending.await.unwrap().unwrap();
+
+        // the client queues streams over the advertised limit itself
+        let mut queued = first.clone();
+        let queued = tokio::spawn(async move { open_echo_stream(&mut queued).await });
+        pause().await;
+        assert!(heads.try_recv().is_err());
+        drop(held);
+        assert_eq!(trailers(&mut held_body).await["grpc-status"], "0");
+        let (frames, mut body) = queued.await.unwrap();
+        echo(&frames, &mut body, "queued").await;
+        drop(frames);
+        assert_eq!(trailers(&mut body).await["grpc-status"], "0");
+        assert_eq!(stats.shed_calls(), 1);
+
+        drop((first, third));
+        token.cancel();
+        proxy.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+
+    #[tokio::test]
+    async fn idle_sockets_time_out_of_handshake_slots() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("handshake-timeout");
+        let _backend = spawn_echo_backend(&socket, token.child_token());
+
+        let (mut server_config, cert) = test_util::load_test_server_config();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ServeOptions {
+            max_handshakes: Some(1),
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let stats = options.stats.clone();
+        let proxy = tokio::spawn(serve_with_options(
+            listener,
+            Some(TlsAcceptor::from(Arc::new(server_config))),
+            ProxyService::new(UdsConnector::new(&socket)),
+            options,
+            token.child_token().cancelled_owned(),
+        ));
+
+        // sockets that never start a handshake only hold the slot until the
+        // timeout, so the client behind them gets through while they stay open
+        let idle = [
+            TcpStream::connect(addr).await.unwrap(),
+            TcpStream::connect(addr).await.unwrap(),
+        ];
+        let mut sender = tokio::time::timeout(
+            Duration::from_secs(5),
+            h2_client::<ChannelBody>(addr, client_config(cert, None)),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let (frames, mut body) = open_echo_stream(&mut sender).await;
+        echo(&frames, &mut body, "through").await;
+        assert_eq!(stats.tls_handshake_failures(), 2);
+
+        drop((idle, frames, body, sender));
+        token.cancel();
+        proxy.await.unwrap().unwrap();
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,162 @@
+//! gRPC status responses generated by the proxy itself.
+
+use std::fmt;
+
+use bytes::Bytes;
+use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, Response};
+use http_body_util::{BodyExt, Empty};
+
+use super::ProxyBody;
+
+/// gRPC status codes, see
+/// <https://github.com/grpc/grpc/blob/master/doc/statuscodes.md>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Code {
+    Ok = 0,
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+impl Code {
+    pub fn from_i32(value: i32) -> Self {
+        match value {
+            0 => Self::Ok,
+            1 => Self::Cancelled,
+            3 => Self::InvalidArgument,
+            4 => Self::DeadlineExceeded,
+            5 => Self::NotFound,
+            6 => Self::AlreadyExists,
+            7 => Self::PermissionDenied,
+            8 => Self::ResourceExhausted,
+            9 => Self::FailedPrecondition,
+            10 => Self::Aborted,
+            11 => Self::OutOfRange,
+            12 => Self::Unimplemented,
+            13 => Self::Internal,
+            14 => Self::Unavailable,
+            15 => Self::DataLoss,
+            16 => Self::Unauthenticated,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Parses a `grpc-status` header value.
+    pub fn from_header(value: &HeaderValue) -> Option<Self> {
+        value.to_str().ok()?.parse().ok().map(Self::from_i32)
+    }
+
+    pub fn as_i32(self) -> i32 {
+        self as i32
+    }
+
+    /// Name as written in gRPC service configs, e.g. `UNAVAILABLE`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Cancelled => "CANCELLED",
+            Self::Unknown => "UNKNOWN",
+            Self::InvalidArgument => "INVALID_ARGUMENT",
+            Self::DeadlineExceeded => "DEADLINE_EXCEEDED",
+            Self::NotFound => "NOT_FOUND",
+            Self::AlreadyExists => "ALREADY_EXISTS",
+            Self::PermissionDenied => "PERMISSION_DENIED",
+            Self::ResourceExhausted => "RESOURCE_EXHAUSTED",
+            Self::FailedPrecondition => "FAILED_PRECONDITION",
+            Self::Aborted => "ABORTED",
+            Self::OutOfRange => "OUT_OF_RANGE",
+            Self::Unimplemented => "UNIMPLEMENTED",
+            Self::Internal => "INTERNAL",
+            Self::Unavailable => "UNAVAILABLE",
+            Self::DataLoss => "DATA_LOSS",
+            Self::Unauthenticated => "UNAUTHENTICATED",
+        }
+    }
+
+    /// Inverse of [`Self::name`].
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..=16)
+            .map(Self::from_i32)
+            .find(|code| code.name() == name)
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+pub const GRPC_STATUS: &str = "grpc-status";
+pub const GRPC_MESSAGE: &str = "grpc-message";
+
+/// Trailers-only response carrying `code` and `message`. The HTTP status is
+/// always 200 as required by the gRPC protocol.
+pub fn trailers_only(code: Code, message: impl AsRef<str>) -> Response<ProxyBody> {
+    let mut resp = Response::new(Empty::<Bytes>::new().map_err(|e| match e {}).boxed());
+    let headers = resp.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.extend(trailers(code, message));
+    resp
+}
+
+/// `grpc-status`/`grpc-message` trailers ending a stream that already sent
+/// its response headers.
+pub fn trailers(code: Code, message: impl AsRef<str>) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    trailers.insert(GRPC_STATUS, HeaderValue::from(code.as_i32()));
+    if let Ok(message) = HeaderValue::from_str(&percent_encode(message.as_ref())) {
+        trailers.insert(GRPC_MESSAGE, message);
+    }
+    trailers
+}
+
+/// Percent-encodes a `grpc-message` value: everything outside printable
+/// ascii, and `%` itself.
+fn percent_encode(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for b in message.bytes() {
+        if (0x20..=0x7e).contains(&b) && b != b'%' {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percent_encode, trailers_only, Code};
+
+    #[test]
+    fn trailers_only_response() {
+        let resp = trailers_only(Code::NotFound, "no backend 100%\n");
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-type"], "application/grpc");
+        assert_eq!(resp.headers()["grpc-status"], "5");
+        assert_eq!(resp.headers()["grpc-message"], "no backend 100%25%0A");
+        assert_eq!(percent_encode("héllo"), "h%C3%A9llo");
+        assert_eq!(
+            Code::from_header(&resp.headers()["grpc-status"]),
+            Some(Code::NotFound)
+        );
+        assert_eq!(Code::from_name("NOT_FOUND"), Some(Code::NotFound));
+        assert_eq!(Code::from_name("UNKNOWN"), Some(Code::Unknown));
+        assert_eq!(Code::from_name("NotFound"), None);
+        assert_eq!(Code::NotFound.to_string(), "NOT_FOUND");
+    }
+}
@@ -0,0 +1,670 @@
+use std::{path::PathBuf, sync::OnceLock};
+
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    ServerConfig,
+};
+
+/// Self-signed `localhost` certificate, generated once per process so a
+/// test client can trust whatever the proxy presents.
+fn test_identity() -> &'static (CertificateDer<'static>, PrivatePkcs8KeyDer<'static>) {
+    static IDENTITY: OnceLock<(CertificateDer<'static>, PrivatePkcs8KeyDer<'static>)> =
+        OnceLock::new();
+    IDENTITY.get_or_init(|| {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("cannot generate test certificate");
+        (
+            cert.cert.der().clone(),
+            PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()),
+        )
+    })
+}
+
+/// Linux replacement of `yarrp_rustls::test_util::load_test_server_config`.
+/// Returns the server config and the certificate it presents.
+pub fn load_test_server_config() -> (ServerConfig, CertificateDer<'static>) {
+    let (cert, key) = test_identity();
+    let config = ServerConfig::builder_with_provider(default_provider().into())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key.clone_key()))
+        .unwrap();
+    (config, cert.clone())
+}
+
+/// Socket the C++ greeter_server listens on (`<temp dir>/my.sock`).
+pub fn get_test_socket_path() -> PathBuf {
+    std::env::temp_dir().join("my.sock")
+}
+
+#[cfg(test)]
+pub use fixtures::*;
+
+/// Backends, clients and proxies shared by the tests of the proxy modules.
+#[cfg(test)]
+mod fixtures {
+    use std::{
+        convert::Infallible,
+        net::SocketAddr,
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use grpc_tests::{helloworld_tonic::greeter_server::GreeterServer, server::MyGreeter};
+    use http::{request::Parts, Request, Response};
+    use http_body_util::{BodyExt, Empty, StreamBody};
+    use hyper::{
+        body::{Frame, Incoming},
+        client::conn::http2::SendRequest,
+    };
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rustls::{
+        crypto::ring::default_provider,
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConfig, RootCertStore, ServerConfig,
+    };
+    use tokio::{
+        net::{TcpListener, TcpStream, UnixListener, UnixStream},
+        sync::mpsc,
+    };
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
+    use tokio_util::sync::CancellationToken;
+    use tonic::transport::{Channel, Endpoint, Uri};
+
+    use crate::{
+        proxy::{
+            balancer::{BalancePolicy, Balancer},
+            connector::UdsConnector,
+            health::encode_response,
+            proxy_protocol::{self, ProxiedAddrs},
+            proxy_service::ProxyService,
+            serve_with_incoming, BoxError, ProxyBody,
+        },
+        tls::TlsIdentityConfig,
+    };
+
+    /// Unique socket path under the temp dir so tests can run in parallel.
+    pub fn temp_socket_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("cng-{}-{name}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Runs the tonic greeter on a unix socket until the token is cancelled.
+    pub fn spawn_uds_greeter(path: &Path, token: CancellationToken) -> tokio::task::JoinHandle<()> {
+        let listener = UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            grpc_tests::server::serve_uds(listener, async move { token.cancelled().await })
+                .await
+                .unwrap();
+        })
+    }
+
+    /// Tonic channel that does TLS (h2 alpn) to `addr` trusting `root`.
+    pub async fn tls_channel(addr: SocketAddr, root: CertificateDer<'static>) -> Channel {
+        let mut root_store = RootCertStore::empty();
+        root_store.add(root).unwrap();
+        let mut config = ClientConfig::builder_with_provider(default_provider().into())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+        Endpoint::from_static("http://localhost")
+            .connect_with_connector(tower::service_fn(move |_: Uri| {
+                let connector = connector.clone();
+                async move {
+                    let tcp = TcpStream::connect(addr).await?;
+                    let domain = ServerName::try_from("localhost").unwrap();
+                    let tls = connector.connect(domain, tcp).await?;
+                    Ok::<_, std::io::Error>(TokioIo::new(tls))
+                }
+            }))
+            .await
+            .unwrap()
+    }
+
+    /// Raw h2 backend on a unix socket that reports every request head it
+    /// receives, streams the request body back and ends with `grpc-status: 0`
+    /// trailers.
+    pub fn spawn_echo_backend(
+        path: &Path,
+        token: CancellationToken,
+    ) -> mpsc::UnboundedReceiver<Parts> {
+        let listener = UnixListener::bind(path).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    res = listener.accept() => res.unwrap().0,
+                    _ = token.cancelled() => break,
+                };
+                serve_echo_connection(stream, tx.clone());
+            }
+        });
+        rx
+    }
+
+    /// [`spawn_echo_backend`] on an ephemeral TCP port (h2c).
+    pub async fn spawn_echo_backend_tcp(
+        token: CancellationToken,
+    ) -> (SocketAddr, mpsc::UnboundedReceiver<Parts>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    res = listener.accept() => res.unwrap().0,
+                    _ = token.cancelled() => break,
+                };
+                serve_echo_connection(stream, tx.clone());
+            }
+        });
+        (addr, rx)
+    }
+
+    /// Serves the echo backend on `io`, the task ending once it closed.
+    fn serve_echo_connection<IO>(
+        io: IO,
+        tx: mpsc::UnboundedSender<Parts>,
+    ) -> tokio::task::JoinHandle<Result<(), hyper::Error>>
+    where
+        IO: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+    {
+        let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+            let (parts, mut body) = req.into_parts();
+            let _ = tx.send(parts);
+            let (frames, rx) = mpsc::channel::<Result<_, Infallible>>(4);
+            tokio::spawn(async move {
+                while let Some(Ok(frame)) = body.frame().await {
+                    match frame.into_data() {
+                        Ok(data) if !data.is_empty() => {
+                            let _ = frames.send(Ok(Frame::data(data))).await;
+                        }
+                        _ => {}
+                    }
+                }
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                let _ = frames.send(Ok(Frame::trailers(trailers))).await;
+            });
+            async {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .header("content-type", "application/grpc")
+                        .body(StreamBody::new(ReceiverStream::new(rx)))
+                        .unwrap(),
+                )
+            }
+        });
+        tokio::spawn(
+            hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(io), service),
+        )
+    }
+
+    pub fn client_config(
+        root: CertificateDer<'static>,
+        identity: Option<&TlsIdentityConfig>,
+    ) -> ClientConfig {
+        let mut root_store = RootCertStore::empty();
+        root_store.add(root).unwrap();
+        let builder = ClientConfig::builder_with_provider(default_provider().into())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(root_store);
+        let mut config = match identity {
+            Some(identity) => builder
+                .with_client_auth_cert(
+                    identity.load_cert_chain().unwrap(),
+                    identity.load_private_key().unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        config
+    }
+
+    /// Request body the test client can keep feeding while the stream is open.
+    pub type ChannelBody = StreamBody<ReceiverStream<Result<Frame<Bytes>, Infallible>>>;
+
+    /// Raw h2 client over TLS to `addr`.
+    pub async fn h2_client<B>(
+        addr: SocketAddr,
+        config: ClientConfig,
+    ) -> Result<SendRequest<B>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        B: hyper::body::Body + Send + Unpin + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        h2_client_for(addr, config, "localhost").await
+    }
+
+    /// Like [`h2_client`] but asking for `server_name` in SNI.
+    pub async fn h2_client_for<B>(
+        addr: SocketAddr,
+        config: ClientConfig,
+        server_name: &str,
+    ) -> Result<SendRequest<B>, Box<dyn std::error::Error + Send + Sync>>
+    where
+        B: hyper::body::Body + Send + Unpin + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let tcp = TcpStream::connect(addr).await?;
+        let domain = ServerName::try_from(server_name.to_string())?;
+        let tls = TlsConnector::from(Arc::new(config))
+            .connect(domain, tcp)
+            .await?;
+        let (sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tls)).await?;
+        tokio::spawn(conn);
+        Ok(sender)
+    }
+
+    /// Response head and trailers of a call made with [`post`].
+    pub struct CallResult {
+        pub head: http::response::Parts,
+        pub trailers: Option<http::HeaderMap>,
+    }
+
+    impl CallResult {
+        /// `grpc-status` from the trailers, or from the head for trailers-only
+        /// responses.
+        pub fn grpc_status(&self) -> Option<&str> {
+            self.trailers
+                .as_ref()
+                .and_then(|t| t.get("grpc-status"))
+                .or_else(|| self.head.headers.get("grpc-status"))
+                .and_then(|v| v.to_str().ok())
+        }
+    }
+
+    /// Sends an empty unary-style POST and waits for the whole response.
+    pub async fn post(
+        sender: &mut SendRequest<Empty<Bytes>>,
+        req: http::request::Builder,
+    ) -> Result<CallResult, Box<dyn std::error::Error + Send + Sync>> {
+        sender.ready().await?;
+        let req = req
+            .method("POST")
+            .header("content-type", "application/grpc")
+            .body(Empty::new())?;
+        let resp = sender.send_request(req).await?;
+        let (head, body) = resp.into_parts();
+        let trailers = body.collect().await?.trailers().cloned();
+        Ok(CallResult { head, trailers })
+    }
+
+    /// Starts the proxy with `server_config` on an ephemeral port.
+    pub async fn spawn_proxy(
+        server_config: ServerConfig,
+        backend: &Path,
+        token: CancellationToken,
+    ) -> (SocketAddr, tokio::task::JoinHandle<()>) {
+        let service = ProxyService::new(UdsConnector::new(backend));
+        spawn_proxy_service(server_config, service, token).await
+    }
+
+    /// Starts `service` behind TLS with `server_config` on an ephemeral port.
+    pub async fn spawn_proxy_service<S>(
+        mut server_config: ServerConfig,
+        service: S,
+        token: CancellationToken,
+    ) -> (SocketAddr, tokio::task::JoinHandle<()>)
+    where
+        S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+            + Clone
+            + Send
+            + 'static,
+        S::Future: Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        server_config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let handle = tokio::spawn(async move {
+            serve_with_incoming(listener, acceptor, service, async move {
+                token.cancelled().await
+            })
+            .await
+            .unwrap()
+        });
+        (addr, handle)
+    }
+
+    pub fn cert_der(identity: &TlsIdentityConfig) -> CertificateDer<'static> {
+        CertificateDer::from_pem_file(&identity.cert_chain).unwrap()
+    }
+
+    pub type FrameSender = mpsc::Sender<Result<Frame<Bytes>, Infallible>>;
+
+    /// Opens a call to the echo backend that stays open until the returned
+    /// sender is dropped.
+    pub async fn open_echo_stream(
+        sender: &mut SendRequest<ChannelBody>,
+    ) -> (FrameSender, Incoming) {
+        let (frames, rx) = mpsc::channel(4);
+        sender.ready().await.unwrap();
+        let resp = sender
+            .send_request(
+                Request::post("/helloworld.Greeter/SayHello")
+                    .header("content-type", "application/grpc")
+                    .body(StreamBody::new(ReceiverStream::new(rx)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        (frames, resp.into_body())
+    }
+
+    pub async fn echo(frames: &FrameSender, body: &mut Incoming, msg: &'static str) {
+        frames
+            .send(Ok(Frame::data(Bytes::from_static(msg.as_bytes()))))
+            .await
+            .unwrap();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), msg);
+    }
+
+    pub async fn trailers(body: &mut Incoming) -> http::HeaderMap {
+        loop {
+            let frame = body.frame().await.unwrap().unwrap();
+            if let Ok(trailers) = frame.into_trailers() {
+                return trailers;
+            }
+        }
+    }
+
+    /// Raw h2 backend on a unix socket implementing `grpc.health.v1.Health`
+    /// with the serving status in `status`. Any other call succeeds.
+    pub fn spawn_health_backend(path: &Path, status: Arc<AtomicU64>, token: CancellationToken) {
+        let listener = UnixListener::bind(path).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    res = listener.accept() => res.unwrap().0,
+                    _ = token.cancelled() => break,
+                };
+                let status = status.clone();
+                let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+                    let (frames, rx) = mpsc::channel::<Result<_, Infallible>>(4);
+                    let status = status.clone();
+                    let ok = || {
+                        let mut trailers = http::HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        Ok(Frame::trailers(trailers))
+                    };
+                    match req.uri().path() {
+                        "/grpc.health.v1.Health/Check" => {
+                            let data = encode_response(status.load(Ordering::Relaxed));
+                            frames.try_send(Ok(Frame::data(data))).unwrap();
+                            frames.try_send(ok()).unwrap();
+                        }
+                        "/grpc.health.v1.Health/Watch" => {
+                            tokio::spawn(async move {
+                                let mut last = None;
+                                while !frames.is_closed() {
+                                    let current = status.load(Ordering::Relaxed);
+                                    if last != Some(current) {
+                                        let data = encode_response(current);
+                                        let _ = frames.send(Ok(Frame::data(data))).await;
+                                        last = Some(current);
+                                    }
+                                    tokio::time::sleep(Duration::from_millis(10)).await;
+                                }
+                            });
+                        }
+                        _ => frames.try_send(ok()).unwrap(),
+                    }
+                    async {
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .header("content-type", "application/grpc")
+                                .body(StreamBody::new(ReceiverStream::new(rx)))
+                                .unwrap(),
+                        )
+                    }
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+    }
+
+    pub async fn wait_until(condition: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("condition not reached");
+    }
+
+    /// Relays unix socket connections from `path` to `backend` until `frozen`
+    /// is set, then holds on to them without passing anything on.
+    pub fn spawn_freezable_relay(path: &Path, backend: &Path, frozen: Arc<AtomicBool>) {
+        async fn relay(
+            mut from: tokio::net::unix::OwnedReadHalf,
+            mut to: tokio::net::unix::OwnedWriteHalf,
+            frozen: Arc<AtomicBool>,
+        ) {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut buf = [0; 4096];
+            while let Ok(n @ 1..) = from.read(&mut buf).await {
+                while frozen.load(Ordering::Relaxed) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                if to.write_all(&buf[..n]).await.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let listener = UnixListener::bind(path).unwrap();
+        let backend = backend.to_path_buf();
+        tokio::spawn(async move {
+            loop {
+                let (client, _) = listener.accept().await.unwrap();
+                let server = UnixStream::connect(&backend).await.unwrap();
+                let (client_read, client_write) = client.into_split();
+                let (server_read, server_write) = server.into_split();
+                tokio::spawn(relay(client_read, server_write, frozen.clone()));
+                tokio::spawn(relay(server_read, client_write, frozen.clone()));
+            }
+        });
+    }
+
+    /// Tonic greeter on a unix socket counting the calls it serves.
+    pub fn spawn_counting_greeter(path: &Path, token: CancellationToken) -> Arc<AtomicUsize> {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let service = GreeterServer::with_interceptor(MyGreeter::default(), move |req| {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Ok(req)
+        });
+        let incoming = UnixListenerStream::new(UnixListener::bind(path).unwrap());
+        tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(incoming, token.cancelled_owned())
+                .await
+                .unwrap();
+        });
+        calls
+    }
+
+    /// Index of the backend in `heads` that received the last call.
+    pub fn served_by(heads: &mut [mpsc::UnboundedReceiver<Parts>]) -> usize {
+        let served: Vec<_> = (0..heads.len())
+            .filter(|&i| heads[i].try_recv().is_ok())
+            .collect();
+        assert_eq!(served.len(), 1, "{served:?}");
+        served[0]
+    }
+
+    /// Three echo backends behind a [`Balancer`] with `policy`.
+    pub async fn spawn_balanced_echo(
+        name: &str,
+        policy: BalancePolicy,
+        token: &CancellationToken,
+    ) -> (
+        SendRequest<ChannelBody>,
+        Vec<mpsc::UnboundedReceiver<Parts>>,
+        Balancer<UdsConnector>,
+    ) {
+        let mut replicas = Vec::new();
+        let mut heads = Vec::new();
+        for i in 0..3 {
+            let socket = temp_socket_path(&format!("{name}-{i}"));
+            heads.push(spawn_echo_backend(&socket, token.child_token()));
+            replicas.push((
+                format!("echo-{i}"),
+                ProxyService::new(UdsConnector::new(&socket)),
+            ));
+        }
+        let balancer = Balancer::new(policy, replicas);
+        let (server_config, cert) = super::load_test_server_config();
+        let (addr, _) =
+            spawn_proxy_service(server_config, balancer.clone(), token.child_token()).await;
+        let sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+        (sender, heads, balancer)
+    }
+
+    pub async fn unary(sender: &mut SendRequest<ChannelBody>, user: Option<&str>) {
+        let (frames, rx) = mpsc::channel(1);
+        drop(frames);
+        let mut req = Request::post("/helloworld.Greeter/SayHello")
+            .header("content-type", "application/grpc");
+        if let Some(user) = user {
+            req = req.header("x-user", user);
+        }
+        sender.ready().await.unwrap();
+        let resp = sender
+            .send_request(req.body(StreamBody::new(ReceiverStream::new(rx))).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(trailers(&mut resp.into_body()).await["grpc-status"], "0");
+    }
+
+    /// Backend on a unix socket answering by method: `Flaky` fails with
+    /// `UNAVAILABLE` twice before echoing the request, `Pushback` always fails
+    /// asking not to retry, and `Slow` never answers its first call. Reports
+    /// every request head it receives.
+    pub fn spawn_flaky_backend(
+        path: &Path,
+        token: CancellationToken,
+    ) -> mpsc::UnboundedReceiver<Parts> {
+        let listener = UnixListener::bind(path).unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let calls = Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
+        let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+            let calls = calls.clone();
+            let tx = tx.clone();
+            async move {
+                let (parts, body) = req.into_parts();
+                let path = parts.uri.path().to_string();
+                let _ = tx.send(parts);
+                // calls of this method before this one
+                let call = {
+                    let mut calls = calls.lock().unwrap();
+                    let count = calls.entry(path.clone()).or_insert(0);
+                    *count += 1;
+                    *count - 1
+                };
+                let unavailable = || {
+                    Response::builder()
+                        .header("content-type", "application/grpc")
+                        .header("grpc-status", "14")
+                };
+                let resp = match path.as_str() {
+                    "/retry.Test/Flaky" if call < 2 => unavailable().body(Empty::new().boxed()),
+                    "/retry.Test/Pushback" => unavailable()
+                        .header("grpc-retry-pushback-ms", "-1")
+                        .body(Empty::new().boxed()),
+                    _ => {
+                        if path == "/retry.Test/Slow" && call == 0 {
+                            std::future::pending::<()>().await;
+                        }
+                        let data = body.collect().await.unwrap().to_bytes();
+                        let mut trailers = http::HeaderMap::new();
+                        trailers.insert("grpc-status", "0".parse().unwrap());
+                        let frames: Vec<Result<_, Infallible>> =
+                            vec![Ok(Frame::data(data)), Ok(Frame::trailers(trailers))];
+                        Response::builder()
+                            .header("content-type", "application/grpc")
+                            .body(StreamBody::new(tokio_stream::iter(frames)).boxed())
+                    }
+                };
+                Ok::<_, Infallible>(resp.unwrap())
+            }
+        });
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    res = listener.accept() => res.unwrap().0,
+                    _ = token.cancelled() => break,
+                };
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service.clone()),
+                );
+            }
+        });
+        rx
+    }
+
+    /// [`spawn_echo_backend`] reading a PROXY protocol header first on every
+    /// connection, and reporting it. Also returns the count of connections
+    /// still open.
+    pub fn spawn_proxy_protocol_backend(
+        path: &Path,
+        token: CancellationToken,
+    ) -> (
+        mpsc::UnboundedReceiver<Option<ProxiedAddrs>>,
+        Arc<AtomicUsize>,
+    ) {
+        let listener = UnixListener::bind(path).unwrap();
+        let (heads, _) = mpsc::unbounded_channel();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let open = Arc::new(AtomicUsize::new(0));
+        let counted = open.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut stream = tokio::select! {
+                    res = listener.accept() => res.unwrap().0,
+                    _ = token.cancelled() => break,
+                };
+                let _ = tx.send(proxy_protocol::read_header(&mut stream).await.unwrap());
+                counted.fetch_add(1, Ordering::Relaxed);
+                let connection = serve_echo_connection(stream, heads.clone());
+                let counted = counted.clone();
+                tokio::spawn(async move {
+                    let _ = connection.await;
+                    counted.fetch_sub(1, Ordering::Relaxed);
+                });
+            }
+        });
+        (rx, open)
+    }
+
+    pub async fn send(frames: &FrameSender, data: Bytes) {
+        frames.send(Ok(Frame::data(data))).await.unwrap();
+    }
+}
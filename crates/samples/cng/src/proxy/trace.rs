@@ -0,0 +1,1036 @@
+//! Distributed tracing of proxied calls with W3C trace context, see
+//! <https://www.w3.org/TR/trace-context/>.
+//!
+//! [`TraceLayer`] starts a server span per call, continuing the trace of the
+//! client's `traceparent` or starting a new one. The [`ProxyService`]
+//! forwarding the call adds a client span and passes its id to the backend
+//! as the parent in `traceparent`; `tracestate` goes through unchanged.
+//! Finished spans go to a [`SpanExporter`]: [`OtlpExporter`] batches them
+//! to an OpenTelemetry collector over OTLP/HTTP, [`InMemoryExporter`] keeps
+//! them for tests. Each call also runs in a `tracing` span carrying the
+//! same ids for local subscribers.
+//!
+//! Calls of unsampled traces (flags `00`) still propagate their context
+//! but are not exported. Like the health check messages, the OTLP request
+//! is encoded by hand.
+//!
+//! [`ProxyService`]: super::proxy_service::ProxyService
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use bytes::{Bytes, BytesMut};
+use http::{
+    header::{CONTENT_TYPE, HOST},
+    HeaderMap, HeaderName, HeaderValue, Request, Response, Uri,
+};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame};
+use hyper_util::rt::TokioIo;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::{net::TcpStream, sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use super::{
+    listener::PeerAddr,
+    proto,
+    proxy_service::BackendName,
+    status::{Code, GRPC_STATUS},
+    BoxError, ConnectionInfo, ProxyBody,
+};
+
+pub const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+pub const TRACESTATE: HeaderName = HeaderName::from_static("tracestate");
+
+/// Spans queued for [`OtlpExporter`]; more are dropped.
+const OTLP_QUEUE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(pub [u8; 16]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanId(pub [u8; 8]);
+
+impl TraceId {
+    fn random() -> Self {
+        Self(random_id())
+    }
+}
+
+impl SpanId {
+    fn random() -> Self {
+        Self(random_id())
+    }
+}
+
+/// Random non-zero id, all zeros being invalid in trace context.
+fn random_id<const N: usize>() -> [u8; N] {
+    let rng = SystemRandom::new();
+    loop {
+        let mut id = [0; N];
+        rng.fill(&mut id).expect("system random source failed");
+        if id != [0; N] {
+            return id;
+        }
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_hex(f, &self.0)
+    }
+}
+
+impl fmt::Display for SpanId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_hex(f, &self.0)
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for b in bytes {
+        write!(f, "{b:02x}")?;
+    }
+    Ok(())
+}
+
+/// Lowercase hex as required by `traceparent`.
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.as_bytes();
+    if hex.len() != 2 * N {
+        return None;
+    }
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    let mut out = [0; N];
+    for (i, pair) in hex.chunks(2).enumerate() {
+        out[i] = (digit(pair[0])? << 4) | digit(pair[1])?;
+    }
+    Some(out)
+}
+
+/// Position of a span in a trace, as carried by `traceparent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: TraceId,
+    pub span_id: SpanId,
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parses a `traceparent` value, `None` when it is invalid. Versions
+    /// after `00` are read as `00`, as the spec asks.
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let version = decode_hex::<1>(value.get(..2)?)?[0];
+        if version == 0xff
+            || value.len() < 55
+            || (version == 0 && value.len() != 55)
+            || (value.len() > 55 && value.as_bytes()[55] != b'-')
+        {
+            return None;
+        }
+        let bytes = value.as_bytes();
+        if bytes[2] != b'-' || bytes[35] != b'-' || bytes[52] != b'-' {
+            return None;
+        }
+        let trace_id = decode_hex(&value[3..35]).filter(|id| *id != [0; 16])?;
+        let span_id = decode_hex(&value[36..52]).filter(|id| *id != [0; 8])?;
+        let flags = decode_hex::<1>(&value[53..55])?[0];
+        Some(Self {
+            trace_id: TraceId(trace_id),
+            span_id: SpanId(span_id),
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            u8::from(self.sampled)
+        )
+    }
+
+    /// New span in the same trace.
+    fn child(&self) -> Self {
+        Self {
+            span_id: SpanId::random(),
+            ..*self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// The call as received from the client.
+    Server,
+    /// The forward of the call to a backend.
+    Client,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+/// A finished span.
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    /// `package.Service/Method` for gRPC paths.
+    pub name: String,
+    pub kind: SpanKind,
+    pub context: SpanContext,
+    pub parent_span_id: Option<SpanId>,
+    /// `tracestate` of the call.
+    pub trace_state: Option<String>,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// OpenTelemetry semantic convention names, e.g. `rpc.method`.
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+    /// `UNKNOWN` when the response ended without a status, `CANCELLED`
+    /// when it was dropped before its end.
+    pub status: Code,
+}
+
+impl SpanData {
+    pub fn attribute(&self, key: &str) -> Option<&AttributeValue> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Error status per the OpenTelemetry gRPC conventions: every failed
+    /// call on the client side, only server faults on the server side.
+    pub fn is_error(&self) -> bool {
+        match self.kind {
+            SpanKind::Client => self.status != Code::Ok,
+            SpanKind::Server => matches!(
+                self.status,
+                Code::Unknown
+                    | Code::DeadlineExceeded
+                    | Code::Unimplemented
+                    | Code::Internal
+                    | Code::Unavailable
+                    | Code::DataLoss
+            ),
+        }
+    }
+}
+
+/// Receives every finished span of a sampled trace; must not block.
+pub trait SpanExporter: Send + Sync + 'static {
+    fn export(&self, span: SpanData);
+}
+
+/// Keeps exported spans in memory, for tests.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryExporter {
+    spans: Arc<Mutex<Vec<SpanData>>>,
+}
+
+impl InMemoryExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spans exported so far, in the order they finished.
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.spans.lock().unwrap().clone()
+    }
+}
+
+impl SpanExporter for InMemoryExporter {
+    fn export(&self, span: SpanData) {
+        self.spans.lock().unwrap().push(span);
+    }
+}
+
+/// `rpc.*` attributes of a call to `path`.
+fn rpc_attributes(path: &str) -> (String, Vec<(&'static str, AttributeValue)>) {
+    let name = path.trim_start_matches('/').to_string();
+    let mut attributes = vec![("rpc.system", AttributeValue::String("grpc".to_string()))];
+    if let Some((service, method)) = name.split_once('/') {
+        attributes.push(("rpc.service", AttributeValue::String(service.to_string())));
+        attributes.push(("rpc.method", AttributeValue::String(method.to_string())));
+    }
+    (name, attributes)
+}
+
+/// Starts a server span per call, see the [module docs](self).
+#[derive(Clone)]
+pub struct TraceLayer {
+    exporter: Arc<dyn SpanExporter>,
+}
+
+impl TraceLayer {
+    /// New traces (calls without a valid `traceparent`) are sampled.
+    pub fn new(exporter: impl SpanExporter) -> Self {
+        Self {
+            exporter: Arc::new(exporter),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for TraceLayer {
+    type Service = TraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceService {
+            inner,
+            exporter: self.exporter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TraceService<S> {
+    inner: S,
+    exporter: Arc<dyn SpanExporter>,
+}
+
+/// Request extension with the server span of a call, under which the
+/// [`ProxyService`](super::proxy_service::ProxyService) starts its client
+/// span.
+#[derive(Clone)]
+pub(crate) struct CallTrace {
+    context: SpanContext,
+    trace_state: Option<String>,
+    exporter: Arc<dyn SpanExporter>,
+}
+
+impl CallTrace {
+    pub(crate) fn trace_id(&self) -> TraceId {
+        self.context.trace_id
+    }
+
+    /// Client span for forwarding a call to `path` to `backend`, and the
+    /// `traceparent` to send along.
+    pub(crate) fn client_span(
+        &self,
+        path: &str,
+        backend: Option<&BackendName>,
+    ) -> (PendingSpan, HeaderValue) {
+        let context = self.context.child();
+        let (name, mut attributes) = rpc_attributes(path);
+        let backend = backend.map_or("", BackendName::as_str);
+        if !backend.is_empty() {
+            attributes.push((
+                "server.address",
+                AttributeValue::String(backend.to_string()),
+            ));
+        }
+        let span = tracing::info_span!(
+            "grpc.client",
+            rpc = %name,
+            backend,
+            span_id = %context.span_id,
+            grpc.status = tracing::field::Empty,
+        );
+        let data = SpanData {
+            name,
+            kind: SpanKind::Client,
+            context,
+            parent_span_id: Some(self.context.span_id),
+            trace_state: self.trace_state.clone(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes,
+            status: Code::Unknown,
+        };
+        let traceparent =
+            HeaderValue::from_str(&context.traceparent()).expect("traceparent is ascii");
+        (
+            PendingSpan::new(data, self.exporter.clone(), span),
+            traceparent,
+        )
+    }
+}
+
+impl<S, B> tower::Service<Request<B>> for TraceService<S>
+where
+    S: tower::Service<Request<B>, Response = Response<ProxyBody>, Error = BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<ProxyBody>;
+    type Error = BoxError;
+    type Future =
+        Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let parent = req
+            .headers()
+            .get(TRACEPARENT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(SpanContext::from_traceparent);
+        // tracestate belongs to the traceparent it came with
+        if parent.is_none() {
+            req.headers_mut().remove(TRACESTATE);
+        }
+        let trace_state = req
+            .headers()
+            .get(TRACESTATE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let context = match parent {
+            Some(parent) => parent.child(),
+            None => SpanContext {
+                trace_id: TraceId::random(),
+                span_id: SpanId::random(),
+                sampled: true,
+            },
+        };
+
+        let (name, mut attributes) = rpc_attributes(req.uri().path());
+        if let Some(ConnectionInfo {
+            peer_addr: PeerAddr::Tcp(addr),
+            ..
+        }) = req.extensions().get::<ConnectionInfo>()
+        {
+            attributes.push((
+                "client.address",
+                AttributeValue::String(addr.ip().to_string()),
+            ));
+            attributes.push(("client.port", AttributeValue::Int(addr.port().into())));
+        }
+        let span = tracing::info_span!(
+            "grpc.server",
+            rpc = %name,
+            trace_id = %context.trace_id,
+            span_id = %context.span_id,
+            backend = tracing::field::Empty,
+            grpc.status = tracing::field::Empty,
+        );
+        let data = SpanData {
+            name,
+            kind: SpanKind::Server,
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+            trace_state: trace_state.clone(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes,
+            status: Code::Unknown,
+        };
+        let mut pending = PendingSpan::new(data, self.exporter.clone(), span.clone());
+        req.extensions_mut().insert(CallTrace {
+            context,
+            trace_state,
+            exporter: self.exporter.clone(),
+        });
+
+        let fut: Self::Future = Box::pin(self.inner.call(req));
+        Box::pin(
+            async move {
+                let resp = match fut.await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        pending.end();
+                        return Err(e);
+                    }
+                };
+                if let Some(backend) = resp.extensions().get::<BackendName>() {
+                    pending.span.record("backend", backend.as_str());
+                    pending.attribute(
+                        "proxy.backend",
+                        AttributeValue::String(backend.as_str().to_string()),
+                    );
+                }
+                Ok(pending.finish_with(resp))
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// A span whose call is still running; exported when dropped.
+pub(crate) struct PendingSpan {
+    /// Taken on drop.
+    data: Option<SpanData>,
+    code: Option<Code>,
+    /// The response ended, see [`SpanData::status`].
+    ended: bool,
+    exporter: Arc<dyn SpanExporter>,
+    span: tracing::Span,
+}
+
+impl PendingSpan {
+    fn new(data: SpanData, exporter: Arc<dyn SpanExporter>, span: tracing::Span) -> Self {
+        Self {
+            data: Some(data),
+            code: None,
+            ended: false,
+            exporter,
+            span,
+        }
+    }
+
+    /// The call ended without a status.
+    fn end(&mut self) {
+        self.ended = true;
+    }
+
+    fn attribute(&mut self, key: &'static str, value: AttributeValue) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value));
+        }
+    }
+
+    /// Ends the span with the response: its status comes from the headers
+    /// of a trailers-only response or else from the trailers.
+    pub(crate) fn finish_with(mut self, resp: Response<ProxyBody>) -> Response<ProxyBody> {
+        self.code = grpc_status(resp.headers());
+        resp.map(|inner| SpanBody { inner, span: self }.boxed())
+    }
+}
+
+impl Drop for PendingSpan {
+    fn drop(&mut self) {
+        let Some(mut data) = self.data.take() else {
+            return;
+        };
+        let code = self.code.unwrap_or(if self.ended {
+            Code::Unknown
+        } else {
+            Code::Cancelled
+        });
+        self.span.record("grpc.status", code.as_i32());
+        if data.context.sampled {
+            data.end = SystemTime::now();
+            data.status = code;
+            data.attributes.push((
+                "rpc.grpc.status_code",
+                AttributeValue::Int(code.as_i32().into()),
+            ));
+            self.exporter.export(data);
+        }
+    }
+}
+
+fn grpc_status(headers: &HeaderMap) -> Option<Code> {
+    headers.get(GRPC_STATUS).and_then(Code::from_header)
+}
+
+/// Response body ending its span.
+struct SpanBody {
+    inner: ProxyBody,
+    span: PendingSpan,
+}
+
+impl Body for SpanBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    self.span.code = grpc_status(trailers).or(self.span.code);
+                }
+            }
+            Some(Err(_)) => {}
+            None => self.span.ended = true,
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Where and how [`OtlpExporter`] sends spans.
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// Collector base url, `http://host:port` (OTLP/HTTP, usually port
+    /// 4318); spans are posted to `/v1/traces`.
+    pub endpoint: String,
+    /// `service.name` resource attribute.
+    pub service_name: String,
+    /// Spans sent in one request at most.
+    pub max_batch: usize,
+    /// Time after which queued spans are sent even if the batch is not
+    /// full.
+    pub flush_interval: Duration,
+    /// Deadline of one export request, including connecting.
+    pub timeout: Duration,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://127.0.0.1:4318".to_string(),
+            service_name: "grpc-proxy".to_string(),
+            max_batch: 512,
+            flush_interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl OtlpConfig {
+    /// `host:port` of the collector; only cleartext http is supported.
+    pub fn authority(&self) -> io::Result<String> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("otlp endpoint {:?} is not http://host:port", self.endpoint),
+            )
+        };
+        let uri: Uri = self.endpoint.parse().map_err(|_| invalid())?;
+        if uri.scheme_str() != Some("http") || !matches!(uri.path(), "" | "/") {
+            return Err(invalid());
+        }
+        let authority = uri.authority().ok_or_else(invalid)?;
+        Ok(format!(
+            "{}:{}",
+            authority.host(),
+            authority.port_u16().unwrap_or(80)
+        ))
+    }
+}
+
+/// Sends spans to an OpenTelemetry collector in batches, from a task
+/// started by [`Self::spawn`]. Spans are dropped while the queue is full.
+pub struct OtlpExporter {
+    tx: mpsc::Sender<SpanData>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl OtlpExporter {
+    /// Starts the export task. Once `token` is cancelled it sends the
+    /// spans still queued and ends.
+    pub fn spawn(
+        config: OtlpConfig,
+        token: CancellationToken,
+    ) -> io::Result<(Self, JoinHandle<()>)> {
+        let authority = config.authority()?;
+        let (tx, rx) = mpsc::channel(OTLP_QUEUE);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let task = tokio::spawn(export_task(config, authority, rx, dropped.clone(), token));
+        Ok((Self { tx, dropped }, task))
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    fn export(&self, span: SpanData) {
+        if self.tx.try_send(span).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+async fn export_task(
+    config: OtlpConfig,
+    authority: String,
+    mut rx: mpsc::Receiver<SpanData>,
+    dropped: Arc<AtomicU64>,
+    token: CancellationToken,
+) {
+    let mut batch = Vec::new();
+    let mut interval = tokio::time::interval(config.flush_interval);
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            span = rx.recv() => match span {
+                Some(span) => {
+                    batch.push(span);
+                    if batch.len() < config.max_batch {
+                        continue;
+                    }
+                }
+                None => break,
+            },
+            _ = interval.tick() => {}
+            _ = token.cancelled() => break,
+        }
+        flush(&config, &authority, &mut batch, &dropped).await;
+    }
+    while let Ok(span) = rx.try_recv() {
+        batch.push(span);
+    }
+    for chunk in batch.chunks(config.max_batch.max(1)) {
+        let mut chunk = chunk.to_vec();
+        flush(&config, &authority, &mut chunk, &dropped).await;
+    }
+}
+
+async fn flush(
+    config: &OtlpConfig,
+    authority: &str,
+    batch: &mut Vec<SpanData>,
+    dropped: &AtomicU64,
+) {
+    let dropped = dropped.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        eprintln!("otlp: dropped {dropped} spans, export queue full");
+    }
+    if batch.is_empty() {
+        return;
+    }
+    let body = encode_export_request(&config.service_name, batch);
+    let res = tokio::time::timeout(config.timeout, post(authority, body))
+        .await
+        .unwrap_or_else(|_| Err("timed out".into()));
+    if let Err(e) = res {
+        eprintln!("otlp: export of {} spans failed: {e}", batch.len());
+    }
+    batch.clear();
+}
+
+async fn post(authority: &str, body: Bytes) -> Result<(), BoxError> {
+    let tcp = TcpStream::connect(authority).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(tcp)).await?;
+    tokio::spawn(conn);
+    let req = Request::post("/v1/traces")
+        .header(HOST, authority)
+        .header(CONTENT_TYPE, "application/x-protobuf")
+        .body(Full::new(body))?;
+    let resp = sender.send_request(req).await?;
+    if !resp.status().is_success() {
+        return Err(format!("collector answered {}", resp.status()).into());
+    }
+    Ok(())
+}
+
+/// `ExportTraceServiceRequest` of opentelemetry-proto, with one resource
+/// and scope holding `spans`.
+fn encode_export_request(service_name: &str, spans: &[SpanData]) -> Bytes {
+    let mut buf = BytesMut::new();
+    // ResourceSpans
+    proto::put_message(&mut buf, 1, |resource_spans| {
+        // Resource
+        proto::put_message(resource_spans, 1, |resource| {
+            let name = AttributeValue::String(service_name.to_string());
+            put_attribute(resource, 1, "service.name", &name);
+        });
+        // ScopeSpans
+        proto::put_message(resource_spans, 2, |scope_spans| {
+            proto::put_message(scope_spans, 1, |scope| {
+                proto::put_bytes(scope, 1, env!("CARGO_PKG_NAME").as_bytes());
+                proto::put_bytes(scope, 2, env!("CARGO_PKG_VERSION").as_bytes());
+            });
+            for span in spans {
+                proto::put_message(scope_spans, 2, |buf| encode_span(buf, span));
+            }
+        });
+    });
+    buf.freeze()
+}
+
+fn encode_span(buf: &mut BytesMut, span: &SpanData) {
+    proto::put_bytes(buf, 1, &span.context.trace_id.0);
+    proto::put_bytes(buf, 2, &span.context.span_id.0);
+    if let Some(trace_state) = &span.trace_state {
+        proto::put_bytes(buf, 3, trace_state.as_bytes());
+    }
+    if let Some(parent) = span.parent_span_id {
+        proto::put_bytes(buf, 4, &parent.0);
+    }
+    proto::put_bytes(buf, 5, span.name.as_bytes());
+    let kind = match span.kind {
+        SpanKind::Server => 2,
+        SpanKind::Client => 3,
+    };
+    proto::put_uint(buf, 6, kind);
+    proto::put_fixed64(buf, 7, unix_nanos(span.start));
+    proto::put_fixed64(buf, 8, unix_nanos(span.end));
+    for (key, value) in &span.attributes {
+        put_attribute(buf, 9, key, value);
+    }
+    // Status, UNSET unless the call failed
+    proto::put_message(buf, 15, |status| {
+        if span.is_error() {
+            proto::put_bytes(status, 2, span.status.to_string().as_bytes());
+            proto::put_uint(status, 3, 2);
+        }
+    });
+}
+
+/// `KeyValue` with an `AnyValue`.
+fn put_attribute(buf: &mut BytesMut, field: u32, key: &str, value: &AttributeValue) {
+    proto::put_message(buf, field, |kv| {
+        proto::put_bytes(kv, 1, key.as_bytes());
+        proto::put_message(kv, 2, |any| match value {
+            AttributeValue::String(s) => proto::put_bytes(any, 1, s.as_bytes()),
+            AttributeValue::Int(i) => proto::put_uint(any, 3, *i as u64),
+        });
+    });
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, time::Duration};
+
+    use bytes::{Bytes, BytesMut};
+    use http::{Request, Response};
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Incoming;
+    use hyper_util::rt::TokioIo;
+    use tokio::{net::TcpListener, sync::mpsc};
+    use tokio_util::sync::CancellationToken;
+
+    use super::{
+        put_attribute, AttributeValue, InMemoryExporter, OtlpConfig, OtlpExporter, SpanContext,
+        SpanData, SpanExporter, SpanId, SpanKind, TraceId, TraceLayer,
+    };
+    use crate::proxy::{
+        connector::UdsConnector,
+        proxy_service::ProxyService,
+        status::Code,
+        test_util::{
+            self, client_config, h2_client, post, spawn_echo_backend, spawn_proxy_service,
+            temp_socket_path, wait_until,
+        },
+    };
+
+    #[test]
+    fn traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = SpanContext::from_traceparent(value).unwrap();
+        assert_eq!(
+            context.trace_id.to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(
+            context.span_id,
+            SpanId([0, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7])
+        );
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), value);
+
+        // later versions may append fields
+        let future = "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-what";
+        let context = SpanContext::from_traceparent(future).unwrap();
+        assert!(!context.sampled);
+        assert_eq!(
+            context.traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"
+        );
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00_4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "cc-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01x",
+        ] {
+            assert_eq!(SpanContext::from_traceparent(invalid), None, "{invalid}");
+        }
+        assert_ne!(TraceId::random(), TraceId::random());
+    }
+
+    #[test]
+    fn encodes_attributes() {
+        let mut buf = BytesMut::new();
+        put_attribute(&mut buf, 9, "a", &AttributeValue::Int(0));
+        // KeyValue { key: "a", value: AnyValue { int_value: 0 } }
+        assert_eq!(&buf[..], [0x4a, 7, 0x0a, 1, b'a', 0x12, 2, 0x18, 0]);
+        buf.clear();
+        put_attribute(&mut buf, 1, "k", &AttributeValue::String("v".to_string()));
+        assert_eq!(&buf[..], [0x0a, 8, 0x0a, 1, b'k', 0x12, 3, 0x0a, 1, b'v']);
+    }
+
+    #[tokio::test]
+    async fn propagates_trace_context_to_backend() {
+        let token = CancellationToken::new();
+        let socket = temp_socket_path("trace");
+        let mut backend = spawn_echo_backend(&socket, token.child_token());
+        let exporter = InMemoryExporter::new();
+        let service = tower::Layer::layer(
+            &TraceLayer::new(exporter.clone()),
+            ProxyService::new(UdsConnector::new(&socket)).with_name("echo"),
+        );
+        let (server_config, cert) = test_util::load_test_server_config();
+        let (addr, proxy) = spawn_proxy_service(server_config, service, token.child_token()).await;
+        let mut sender = h2_client(addr, client_config(cert, None)).await.unwrap();
+
+        let incoming = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let resp = post(
+            &mut sender,
+            Request::builder()
+                .uri("/helloworld.Greeter/SayHello")
+                .header("traceparent", incoming)
+                .header("tracestate", "vendor=value"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.grpc_status(), Some("0"));
+        let parts = backend.recv().await.unwrap();
+        let forwarded =
+            SpanContext::from_traceparent(parts.headers["traceparent"].to_str().unwrap()).unwrap();
+        assert_eq!(parts.headers["tracestate"], "vendor=value");
+
+        wait_until(|| exporter.spans().len() == 2).await;
+        let spans = exporter.spans();
+        let client = spans.iter().find(|s| s.kind == SpanKind::Client).unwrap();
+        let server = spans.iter().find(|s| s.kind == SpanKind::Server).unwrap();
+        let parent = SpanContext::from_traceparent(incoming).unwrap();
+        // incoming span -> server span -> client span -> backend
+        assert_eq!(server.context.trace_id, parent.trace_id);
+        assert_eq!(server.parent_span_id, Some(parent.span_id));
+        assert_eq!(client.context.trace_id, parent.trace_id);
+        assert_eq!(client.parent_span_id, Some(server.context.span_id));
+        assert_eq!(forwarded, client.context);
+        for span in [client, server] {
+            assert_eq!(span.name, "helloworld.Greeter/SayHello");
+            assert_eq!(span.status, Code::Ok);
+            assert_eq!(span.trace_state.as_deref(), Some("vendor=value"));
+            assert_eq!(
+                span.attribute("rpc.method"),
+                Some(&AttributeValue::String("SayHello".to_string()))
+            );
+            assert!(span.start <= span.end);
+        }
+        assert_eq!(
+            server.attribute("proxy.backend"),
+            Some(&AttributeValue::String("echo".to_string()))
+        );
+        assert_eq!(
+            client.attribute("server.address"),
+            Some(&AttributeValue::String("echo".to_string()))
+        );
+        assert!(server.attribute("client.address").is_some());
+
+        // without a valid traceparent a new trace starts, without tracestate
+        for traceparent in [None, Some("00-invalid")] {
+            let mut req = Request::builder()
+                .uri("/helloworld.Greeter/SayHello")
+                .header("tracestate", "vendor=value");
+            if let Some(traceparent) = traceparent {
+                req = req.header("traceparent", traceparent);
+            }
+            post(&mut sender, req).await.unwrap();
+            let parts = backend.recv().await.unwrap();
+            let forwarded =
+                SpanContext::from_traceparent(parts.headers["traceparent"].to_str().unwrap())
+                    .unwrap();
+            assert_ne!(forwarded.trace_id, parent.trace_id);
+            assert!(forwarded.sampled);
+            assert!(!parts.headers.contains_key("tracestate"));
+        }
+        wait_until(|| exporter.spans().len() == 6).await;
+        let server = exporter
+            .spans()
+            .into_iter()
+            .rfind(|s| s.kind == SpanKind::Server)
+            .unwrap();
+        assert_eq!(server.parent_span_id, None);
+
+        // unsampled traces propagate but are not exported
+        let unsampled = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+        post(
+            &mut sender,
+            Request::builder()
+                .uri("/helloworld.Greeter/SayHello")
+                .header("traceparent", unsampled),
+        )
+        .await
+        .unwrap();
+        let parts = backend.recv().await.unwrap();
+        let forwarded =
+            SpanContext::from_traceparent(parts.headers["traceparent"].to_str().unwrap()).unwrap();
+        assert_eq!(forwarded.trace_id, parent.trace_id);
+        assert!(!forwarded.sampled);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(exporter.spans().len(), 6);
+
+        drop(sender);
+        token.cancel();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exports_spans_over_otlp() {
+        let collector = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (tx, mut requests) = mpsc::unbounded_channel();
+        let addr = collector.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = collector.accept().await.unwrap();
+            let service = hyper::service::service_fn(move |req: Request<Incoming>| {
+                let tx = tx.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = body.collect().await.unwrap().to_bytes();
+                    let _ = tx.send((parts, body));
+                    Ok::<_, Infallible>(Response::new(Empty::<Bytes>::new()))
+                }
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+
+        let token = CancellationToken::new();
+        let config = OtlpConfig {
+            endpoint: format!("http://{addr}"),
+            service_name: "trace-test".to_string(),
+            flush_interval: Duration::from_secs(3600),
+            ..OtlpConfig::default()
+        };
+        let (exporter, task) = OtlpExporter::spawn(config, token.clone()).unwrap();
+        let context = SpanContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        exporter.export(SpanData {
+            name: "helloworld.Greeter/SayHello".to_string(),
+            kind: SpanKind::Server,
+            context,
+            parent_span_id: None,
+            trace_state: None,
+            start: std::time::SystemTime::now(),
+            end: std::time::SystemTime::now(),
+            attributes: vec![("rpc.system", AttributeValue::String("grpc".to_string()))],
+            status: Code::Internal,
+        });
+        // queued spans are flushed on shutdown
+        token.cancel();
+        task.await.unwrap();
+
+        let (parts, body) = requests.recv().await.unwrap();
+        assert_eq!(parts.method, http::Method::POST);
+        assert_eq!(parts.uri.path(), "/v1/traces");
+        assert_eq!(parts.headers["content-type"], "application/x-protobuf");
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|w| w == needle);
+        assert!(contains(&context.trace_id.0));
+        assert!(contains(&context.span_id.0));
+        assert!(contains(b"trace-test"));
+        assert!(contains(b"helloworld.Greeter/SayHello"));
+        assert!(contains(b"INTERNAL"));
+    }
+}
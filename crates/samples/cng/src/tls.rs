@@ -0,0 +1,714 @@
+//! Server TLS identity loaded from PEM files, optionally reloaded when the
+//! files are rotated or picked by SNI name, and the client TLS settings used
+//! toward remote backends.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::{
+        danger::ClientCertVerifier, ClientHello, ResolvesServerCert, WantsServerCert,
+        WebPkiClientVerifier,
+    },
+    sign::CertifiedKey,
+    ClientConfig, ConfigBuilder, RootCertStore, ServerConfig,
+};
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+/// Crypto provider used by the proxy: symcrypt on Windows, ring elsewhere.
+pub fn default_provider() -> CryptoProvider {
+    #[cfg(windows)]
+    {
+        rustls_symcrypt::default_symcrypt_provider()
+    }
+    #[cfg(not(windows))]
+    {
+        rustls::crypto::ring::default_provider()
+    }
+}
+
+/// Certificate chain and private key the proxy presents to clients.
+///
+/// `cert_chain` holds one or more `CERTIFICATE` PEM blocks, leaf first.
+/// `private_key` holds a single PKCS#8, PKCS#1 (RSA) or SEC1 (EC) PEM block.
+#[derive(Debug, Clone)]
+pub struct TlsIdentityConfig {
+    pub cert_chain: PathBuf,
+    pub private_key: PathBuf,
+}
+
+/// Whether clients must present a certificate signed by the client CA.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClientAuthMode {
+    /// Handshakes without a valid client certificate are rejected.
+    #[default]
+    Required,
+    /// Anonymous clients are accepted; certificates that are presented
+    /// must still verify against the client CA.
+    Optional,
+}
+
+/// Mutual TLS settings for the proxy listener.
+#[derive(Debug, Clone)]
+pub struct ClientAuthConfig {
+    /// PEM bundle of CA certificates trusted to issue client certificates.
+    pub ca_bundle: PathBuf,
+    pub mode: ClientAuthMode,
+}
+
+/// TLS the proxy opens toward a remote backend.
+#[derive(Debug, Clone)]
+pub struct UpstreamTlsConfig {
+    /// PEM bundle of CA certificates trusted to issue the backend's
+    /// certificate.
+    pub ca_bundle: PathBuf,
+    /// Name sent as SNI and verified against the backend certificate;
+    /// defaults to the host of the backend address.
+    pub server_name: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum TlsConfigError {
+    /// The file could not be read or is not valid PEM.
+    Pem {
+        path: PathBuf,
+        source: rustls::pki_types::pem::Error,
+    },
+    /// The certificate file holds no `CERTIFICATE` block.
+    NoCertificates(PathBuf),
+    /// The key file holds no supported private key block.
+    NoPrivateKey(PathBuf),
+    /// The leaf certificate could not be parsed.
+    InvalidCertificate { path: PathBuf, reason: String },
+    /// The leaf certificate is outside its validity period.
+    CertificateNotValid {
+        path: PathBuf,
+        not_before: String,
+        not_after: String,
+    },
+    /// The client CA bundle cannot be used to verify clients.
+    InvalidClientCa { path: PathBuf, reason: String },
+    /// The upstream CA bundle cannot be used to verify backends.
+    InvalidUpstreamCa { path: PathBuf, reason: String },
+    /// The upstream server name is neither a DNS name nor an IP address.
+    InvalidServerName(String),
+    /// The private key does not belong to the leaf certificate.
+    KeyMismatch { cert: PathBuf, key: PathBuf },
+    /// The key could not be loaded or the config could not be built.
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pem { path, source } => write!(f, "cannot read {}: {source}", path.display()),
+            Self::NoCertificates(path) => {
+                write!(f, "no certificate found in {}", path.display())
+            }
+            Self::NoPrivateKey(path) => write!(
+                f,
+                "no PKCS#8, PKCS#1 or SEC1 private key found in {}",
+                path.display()
+            ),
+            Self::InvalidCertificate { path, reason } => {
+                write!(f, "invalid certificate in {}: {reason}", path.display())
+            }
+            Self::CertificateNotValid {
+                path,
+                not_before,
+                not_after,
+            } => write!(
+                f,
+                "certificate in {} is expired or not yet valid (valid from {not_before} to {not_after})",
+                path.display()
+            ),
+            Self::InvalidClientCa { path, reason } => {
+                write!(f, "invalid client CA bundle {}: {reason}", path.display())
+            }
+            Self::InvalidUpstreamCa { path, reason } => {
+                write!(f, "invalid upstream CA bundle {}: {reason}", path.display())
+            }
+            Self::InvalidServerName(name) => write!(f, "invalid tls server name {name:?}"),
+            Self::KeyMismatch { cert, key } => write!(
+                f,
+                "private key {} does not match certificate {}",
+                key.display(),
+                cert.display()
+            ),
+            Self::Rustls(e) => write!(f, "tls error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Pem { source, .. } => Some(source),
+            Self::Rustls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl TlsIdentityConfig {
+    pub fn new(cert_chain: impl Into<PathBuf>, private_key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_chain: cert_chain.into(),
+            private_key: private_key.into(),
+        }
+    }
+
+    /// Reads and validates the certificate chain.
+    pub fn load_cert_chain(&self) -> Result<Vec<CertificateDer<'static>>, TlsConfigError> {
+        let path = &self.cert_chain;
+        let certs = CertificateDer::pem_file_iter(path)
+            .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+            .map_err(|source| pem_error(path, source))?;
+        let leaf = certs
+            .first()
+            .ok_or_else(|| TlsConfigError::NoCertificates(path.clone()))?;
+        check_validity(path, leaf)?;
+        Ok(certs)
+    }
+
+    /// Reads the private key.
+    pub fn load_private_key(&self) -> Result<PrivateKeyDer<'static>, TlsConfigError> {
+        let path = &self.private_key;
+        PrivateKeyDer::from_pem_file(path).map_err(|source| match source {
+            rustls::pki_types::pem::Error::NoItemsFound => {
+                TlsConfigError::NoPrivateKey(path.clone())
+            }
+            source => pem_error(path, source),
+        })
+    }
+
+    /// Builds a server config presenting this identity, without client auth.
+    pub fn load_server_config(&self) -> Result<ServerConfig, TlsConfigError> {
+        self.load_server_config_with_client_auth(None)
+    }
+
+    /// Builds a server config presenting this identity, verifying client
+    /// certificates when `client_auth` is set.
+    pub fn load_server_config_with_client_auth(
+        &self,
+        client_auth: Option<&ClientAuthConfig>,
+    ) -> Result<ServerConfig, TlsConfigError> {
+        let provider = Arc::new(default_provider());
+        let certs = self.load_cert_chain()?;
+        let key = self.load_private_key()?;
+        server_config_builder(provider, client_auth)?
+            .with_single_cert(certs, key)
+            .map_err(|e| self.key_error(e))
+    }
+
+    /// Like [`Self::load_server_config_with_client_auth`] but the identity
+    /// can be swapped later through the returned resolver.
+    pub fn load_reloadable_server_config(
+        &self,
+        client_auth: Option<&ClientAuthConfig>,
+    ) -> Result<(ServerConfig, Arc<ReloadingCertResolver>), TlsConfigError> {
+        let provider = Arc::new(default_provider());
+        let resolver = Arc::new(ReloadingCertResolver::new(self.clone(), provider.clone())?);
+        let config =
+            server_config_builder(provider, client_auth)?.with_cert_resolver(resolver.clone());
+        Ok((config, resolver))
+    }
+
+    /// Like [`Self::load_reloadable_server_config`] with a certificate per
+    /// SNI name, see [`SniCertResolver`]. `default` (usually `self`) is
+    /// presented for other names; those handshakes fail without one.
+    pub fn load_sni_server_config<'a>(
+        names: impl IntoIterator<Item = (&'a str, &'a TlsIdentityConfig)>,
+        default: Option<&TlsIdentityConfig>,
+        client_auth: Option<&ClientAuthConfig>,
+    ) -> Result<(ServerConfig, Arc<SniCertResolver>), TlsConfigError> {
+        let provider = Arc::new(default_provider());
+        let load = |identity: &TlsIdentityConfig| {
+            ReloadingCertResolver::new(identity.clone(), provider.clone()).map(Arc::new)
+        };
+        let mut resolver = SniCertResolver::new(default.map(load).transpose()?);
+        for (name, identity) in names {
+            resolver = resolver.with_name(name, load(identity)?);
+        }
+        let resolver = Arc::new(resolver);
+        let config =
+            server_config_builder(provider, client_auth)?.with_cert_resolver(resolver.clone());
+        Ok((config, resolver))
+    }
+
+    /// Loads the chain and key and checks that they belong together.
+    pub fn load_certified_key(
+        &self,
+        provider: &CryptoProvider,
+    ) -> Result<CertifiedKey, TlsConfigError> {
+        let certs = self.load_cert_chain()?;
+        let key = self.load_private_key()?;
+        CertifiedKey::from_der(certs, key, provider).map_err(|e| self.key_error(e))
+    }
+
+    fn key_error(&self, e: rustls::Error) -> TlsConfigError {
+        match e {
+            rustls::Error::InconsistentKeys(_) => TlsConfigError::KeyMismatch {
+                cert: self.cert_chain.clone(),
+                key: self.private_key.clone(),
+            },
+            e => TlsConfigError::Rustls(e),
+        }
+    }
+}
+
+fn server_config_builder(
+    provider: Arc<CryptoProvider>,
+    client_auth: Option<&ClientAuthConfig>,
+) -> Result<ConfigBuilder<ServerConfig, WantsServerCert>, TlsConfigError> {
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(TlsConfigError::Rustls)?;
+    Ok(match client_auth {
+        Some(client_auth) => {
+            builder.with_client_cert_verifier(client_auth.load_verifier(provider)?)
+        }
+        None => builder.with_no_client_auth(),
+    })
+}
+
+/// Presents the identity from [`TlsIdentityConfig`] and swaps it for new
+/// handshakes when the files are reloaded. Established connections keep
+/// the certificate they negotiated.
+#[derive(Debug)]
+pub struct ReloadingCertResolver {
+    identity: TlsIdentityConfig,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(
+        identity: TlsIdentityConfig,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Self, TlsConfigError> {
+        let current = identity.load_certified_key(&provider)?;
+        Ok(Self {
+            identity,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Re-reads the files. On error the previous certificate stays active.
+    pub fn reload(&self) -> Result<(), TlsConfigError> {
+        let key = self.identity.load_certified_key(&self.provider)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    /// Certificate presented to new handshakes.
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Polls the cert and key files every `interval` and reloads when either
+    /// changes, until `token` is cancelled. A half-written rotation (e.g. new
+    /// cert with old key) is retried on the next tick.
+    pub fn watch(self: Arc<Self>, interval: Duration, token: CancellationToken) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut seen = self.file_stamps();
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {}
+                    _ = token.cancelled() => break,
+                }
+                let stamps = self.file_stamps();
+                if stamps == seen {
+                    continue;
+                }
+                match self.reload() {
+                    Ok(()) => {
+                        println!(
+                            "reloaded tls identity from {}",
+                            self.identity.cert_chain.display()
+                        );
+                        seen = stamps;
+                    }
+                    Err(e) => eprintln!("tls identity reload failed, keeping current: {e}"),
+                }
+            }
+        })
+    }
+
+    fn file_stamps(&self) -> [Option<(SystemTime, u64)>; 2] {
+        [&self.identity.cert_chain, &self.identity.private_key].map(|path| {
+            let meta = std::fs::metadata(path).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+/// Presents the certificate of the SNI name a client asks for, to host
+/// several services on one listener. Names match case-insensitively;
+/// clients asking for another name (or none) get the default certificate,
+/// and fail the handshake without one.
+#[derive(Debug)]
+pub struct SniCertResolver {
+    names: HashMap<String, Arc<ReloadingCertResolver>>,
+    default: Option<Arc<ReloadingCertResolver>>,
+}
+
+impl SniCertResolver {
+    pub fn new(default: Option<Arc<ReloadingCertResolver>>) -> Self {
+        Self {
+            names: HashMap::new(),
+            default,
+        }
+    }
+
+    pub fn with_name(mut self, name: &str, resolver: Arc<ReloadingCertResolver>) -> Self {
+        self.names.insert(name.to_ascii_lowercase(), resolver);
+        self
+    }
+
+    /// Resolver of every name and the default, e.g. to
+    /// [`watch`](ReloadingCertResolver::watch) them.
+    pub fn resolvers(&self) -> impl Iterator<Item = &Arc<ReloadingCertResolver>> {
+        self.names.values().chain(&self.default)
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        // rustls already lowercased the name
+        let named = client_hello
+            .server_name()
+            .and_then(|name| self.names.get(name));
+        Some(named.or(self.default.as_ref())?.current())
+    }
+}
+
+impl ClientAuthConfig {
+    pub fn new(ca_bundle: impl Into<PathBuf>, mode: ClientAuthMode) -> Self {
+        Self {
+            ca_bundle: ca_bundle.into(),
+            mode,
+        }
+    }
+
+    /// Builds the verifier that checks client certificates against the
+    /// CA bundle.
+    pub fn load_verifier(
+        &self,
+        provider: Arc<CryptoProvider>,
+    ) -> Result<Arc<dyn ClientCertVerifier>, TlsConfigError> {
+        let path = &self.ca_bundle;
+        let roots = load_roots(path, |path, reason| TlsConfigError::InvalidClientCa {
+            path,
+            reason,
+        })?;
+        let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+        let builder = match self.mode {
+            ClientAuthMode::Required => builder,
+            ClientAuthMode::Optional => builder.allow_unauthenticated(),
+        };
+        builder
+            .build()
+            .map_err(|e| TlsConfigError::InvalidClientCa {
+                path: path.clone(),
+                reason: e.to_string(),
+            })
+    }
+}
+
+impl UpstreamTlsConfig {
+    pub fn new(ca_bundle: impl Into<PathBuf>, server_name: Option<String>) -> Self {
+        Self {
+            ca_bundle: ca_bundle.into(),
+            server_name,
+        }
+    }
+
+    /// Builds a client config trusting the CA bundle, offering h2 via ALPN.
+    pub fn load_client_config(&self) -> Result<ClientConfig, TlsConfigError> {
+        let roots = load_roots(&self.ca_bundle, |path, reason| {
+            TlsConfigError::InvalidUpstreamCa { path, reason }
+        })?;
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(TlsConfigError::Rustls)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(config)
+    }
+
+    /// The configured server name, or the host of `addr` (`host:port`).
+    pub fn server_name(&self, addr: &str) -> Result<ServerName<'static>, TlsConfigError> {
+        let name = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => addr
+                .rsplit_once(':')
+                .map_or(addr, |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']'),
+        };
+        ServerName::try_from(name.to_string())
+            .map_err(|_| TlsConfigError::InvalidServerName(name.to_string()))
+    }
+}
+
+/// Reads every certificate of the PEM bundle at `path` into a root store.
+fn load_roots(
+    path: &Path,
+    invalid: impl Fn(PathBuf, String) -> TlsConfigError,
+) -> Result<RootCertStore, TlsConfigError> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(path).map_err(|e| pem_error(path, e))? {
+        roots
+            .add(cert.map_err(|e| pem_error(path, e))?)
+            .map_err(|e| invalid(path.to_path_buf(), e.to_string()))?;
+    }
+    if roots.is_empty() {
+        return Err(TlsConfigError::NoCertificates(path.to_path_buf()));
+    }
+    Ok(roots)
+}
+
+fn pem_error(path: &Path, source: rustls::pki_types::pem::Error) -> TlsConfigError {
+    TlsConfigError::Pem {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// Rejects a leaf certificate that is expired or not yet valid.
+fn check_validity(path: &Path, leaf: &CertificateDer<'_>) -> Result<(), TlsConfigError> {
+    let (_, cert) = x509_parser::parse_x509_certificate(leaf).map_err(|e| {
+        TlsConfigError::InvalidCertificate {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        }
+    })?;
+    let validity = cert.validity();
+    if !validity.is_valid() {
+        return Err(TlsConfigError::CertificateNotValid {
+            path: path.to_path_buf(),
+            not_before: validity.not_before.to_string(),
+            not_after: validity.not_after.to_string(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test_certs {
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+
+    use super::TlsIdentityConfig;
+
+    /// Self-signs `params` with a new key and writes `<name>.crt`/`<name>.key`.
+    pub fn write_self_signed(
+        dir: &std::path::Path,
+        name: &str,
+        params: CertificateParams,
+    ) -> TlsIdentityConfig {
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        let config = TlsIdentityConfig::new(
+            dir.join(format!("{name}.crt")),
+            dir.join(format!("{name}.key")),
+        );
+        std::fs::write(&config.cert_chain, cert.pem()).unwrap();
+        std::fs::write(&config.private_key, key.serialize_pem()).unwrap();
+        config
+    }
+
+    pub fn localhost_params() -> CertificateParams {
+        CertificateParams::new(vec!["localhost".to_string()]).unwrap()
+    }
+
+    /// Self-signed CA written to `<name>.crt`, returned for signing leaves.
+    pub fn write_ca(dir: &std::path::Path, name: &str) -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::default();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, format!("{name} CA"));
+        let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        std::fs::write(dir.join(format!("{name}.crt")), ca.pem()).unwrap();
+        ca
+    }
+
+    /// Like [`write_self_signed`] but issued by `ca`.
+    pub fn write_signed(
+        dir: &std::path::Path,
+        name: &str,
+        params: CertificateParams,
+        ca: &CertifiedIssuer<'static, KeyPair>,
+    ) -> TlsIdentityConfig {
+        let key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key, ca).unwrap();
+        let config = TlsIdentityConfig::new(
+            dir.join(format!("{name}.crt")),
+            dir.join(format!("{name}.key")),
+        );
+        std::fs::write(&config.cert_chain, cert.pem()).unwrap();
+        std::fs::write(&config.private_key, key.serialize_pem()).unwrap();
+        config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        test_certs::{localhost_params, write_self_signed},
+        ClientAuthConfig, ClientAuthMode, TlsConfigError, TlsIdentityConfig, UpstreamTlsConfig,
+    };
+    use crate::util::temp_dir;
+
+    #[test]
+    fn loads_pem_identity() {
+        let dir = temp_dir("valid");
+        let identity = write_self_signed(&dir, "server", localhost_params());
+        assert_eq!(identity.load_cert_chain().unwrap().len(), 1);
+        identity.load_server_config().unwrap();
+    }
+
+    #[test]
+    fn rejects_mismatched_key() {
+        let dir = temp_dir("mismatch");
+        let a = write_self_signed(&dir, "a", localhost_params());
+        let b = write_self_signed(&dir, "b", localhost_params());
+        let err = TlsIdentityConfig::new(&a.cert_chain, &b.private_key)
+            .load_server_config()
+            .unwrap_err();
+        assert!(matches!(err, TlsConfigError::KeyMismatch { .. }), "{err}");
+    }
+
+    #[test]
+    fn rejects_expired_certificate() {
+        let dir = temp_dir("expired");
+        let mut params = localhost_params();
+        params.not_before = rcgen::date_time_ymd(2000, 1, 1);
+        params.not_after = rcgen::date_time_ymd(2001, 1, 1);
+        let identity = write_self_signed(&dir, "server", params);
+        let err = identity.load_server_config().unwrap_err();
+        assert!(
+            matches!(err, TlsConfigError::CertificateNotValid { .. }),
+            "{err}"
+        );
+        assert!(err.to_string().contains("expired"));
+    }
+
+    #[test]
+    fn reports_missing_pem_blocks() {
+        let dir = temp_dir("missing");
+        let identity = write_self_signed(&dir, "server", localhost_params());
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+
+        let err = TlsIdentityConfig::new(&empty, &identity.private_key)
+            .load_server_config()
+            .unwrap_err();
+        assert!(matches!(err, TlsConfigError::NoCertificates(_)), "{err}");
+
+        // a certificate is not a private key
+        let err = TlsIdentityConfig::new(&identity.cert_chain, &identity.cert_chain)
+            .load_server_config()
+            .unwrap_err();
+        assert!(matches!(err, TlsConfigError::NoPrivateKey(_)), "{err}");
+
+        let err = TlsIdentityConfig::new(dir.join("nope.crt"), &identity.private_key)
+            .load_server_config()
+            .unwrap_err();
+        assert!(matches!(err, TlsConfigError::Pem { .. }), "{err}");
+    }
+
+    #[test]
+    fn loads_client_ca_bundle() {
+        let dir = temp_dir("client-ca");
+        let identity = write_self_signed(&dir, "server", localhost_params());
+        super::test_certs::write_ca(&dir, "clients");
+        for mode in [ClientAuthMode::Required, ClientAuthMode::Optional] {
+            let client_auth = ClientAuthConfig::new(dir.join("clients.crt"), mode);
+            identity
+                .load_server_config_with_client_auth(Some(&client_auth))
+                .unwrap();
+        }
+
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+        let client_auth = ClientAuthConfig::new(&empty, ClientAuthMode::Required);
+        let err = identity
+            .load_server_config_with_client_auth(Some(&client_auth))
+            .unwrap_err();
+        assert!(matches!(err, TlsConfigError::NoCertificates(_)), "{err}");
+    }
+
+    #[test]
+    fn upstream_server_name() {
+        let upstream = UpstreamTlsConfig::new("ca.crt", None);
+        for (addr, name) in [
+            ("greeter.example:443", "greeter.example"),
+            ("10.0.0.2:50051", "10.0.0.2"),
+            ("[::1]:50051", "::1"),
+        ] {
+            let expected = rustls::pki_types::ServerName::try_from(name).unwrap();
+            assert_eq!(upstream.server_name(addr).unwrap(), expected);
+        }
+        let upstream = UpstreamTlsConfig::new("ca.crt", Some("localhost".to_string()));
+        assert_eq!(
+            upstream.server_name("10.0.0.2:50051").unwrap(),
+            rustls::pki_types::ServerName::try_from("localhost").unwrap()
+        );
+        let upstream = UpstreamTlsConfig::new("ca.crt", Some("bad name".to_string()));
+        assert!(matches!(
+            upstream.server_name("10.0.0.2:50051"),
+            Err(TlsConfigError::InvalidServerName(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_rotated_files() {
+        let dir = temp_dir("watch");
+        let identity = write_self_signed(&dir, "server", localhost_params());
+        let (_, resolver) = identity.load_reloadable_server_config(None).unwrap();
+        let first = resolver.current();
+        let token = tokio_util::sync::CancellationToken::new();
+        let watcher = resolver
+            .clone()
+            .watch(std::time::Duration::from_millis(20), token.clone());
+
+        // a key that does not match is not picked up
+        let other = write_self_signed(&dir, "other", localhost_params());
+        std::fs::copy(&other.private_key, &identity.private_key).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(resolver.current().cert, first.cert);
+
+        std::fs::copy(&other.cert_chain, &identity.cert_chain).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while resolver.current().cert == first.cert {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("rotated certificate was not loaded");
+        assert_eq!(resolver.current().cert, other.load_cert_chain().unwrap());
+
+        token.cancel();
+        watcher.await.unwrap();
+    }
+}
@@ -1,26 +1,70 @@
-use std::{net::SocketAddr, sync::Arc};
+//! Entry points of the `cng` binary. [`serve_proxy`] and
+//! [`serve_proxy_with_config`] run the yarrp pipeline on Windows and
+//! [`crate::proxy`] on Linux; the others are Linux only.
 
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use rustls::ServerConfig;
+#[cfg(windows)]
 use tokio::net::TcpListener;
-use yarrp::{connector::UdsConnector, proxy_service::ProxyService, CancellationToken};
-use yarrp_rustls::accept_stream::RustlsAcceptStream;
+#[cfg(windows)]
+use yarrp::{connector::UdsConnector, proxy_service::ProxyService};
+#[cfg(windows)]
+use yarrp_rustls::{accept_stream::RustlsAcceptStream, test_util};
+
+#[cfg(unix)]
+use crate::proxy::{
+    activation, connector::UdsConnector, grpc_web::CorsConfig, proxy_service::ProxyService,
+    test_util, BoxError, ProxyBody, ServeOptions,
+};
+#[cfg(unix)]
+use http::{Request, Response};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(windows)]
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Serves the proxy on the addr presenting the test certificate: the one
+/// in the CNG store on Windows, a generated `localhost` one on Linux.
+pub async fn serve_proxy(addr: SocketAddr, token: CancellationToken) -> Result<(), BoxError> {
+    let (server_config, _) = test_util::load_test_server_config();
+    serve_proxy_with_config(addr, server_config, token).await
+}
+
+/// Serves the proxy on the addr presenting the identity in `server_config`,
+/// e.g. one loaded with [`crate::tls::TlsIdentityConfig`], and forwards to
+/// the greeter socket (`<temp dir>/my.sock`).
+pub async fn serve_proxy_with_config(
+    addr: SocketAddr,
+    server_config: ServerConfig,
+    token: CancellationToken,
+) -> Result<(), BoxError> {
+    #[cfg(windows)]
+    let serve = serve_yarrp(addr, server_config, token);
+    #[cfg(unix)]
+    let serve = {
+        let conn = UdsConnector::new(test_util::get_test_socket_path());
+        serve_proxy_with_service(addr, server_config, ProxyService::new(conn), token)
+    };
+    serve.await
+}
 
-/// Serves the proxy on the addr
-pub async fn serve_proxy(
+#[cfg(windows)]
+async fn serve_yarrp(
     addr: SocketAddr,
+    mut server_config: ServerConfig,
     token: CancellationToken,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+) -> Result<(), BoxError> {
     println!("Starting to serve on https://{}", addr);
 
     // Create a TCP listener via tokio.
     let incoming = TcpListener::bind(&addr).await?;
 
     // Build TLS configuration.
-    let (mut server_config, _) = yarrp_rustls::test_util::load_test_server_config();
     server_config.alpn_protocols = vec![b"h2".to_vec()]; // b"http/1.1".to_vec(), b"http/1.0".to_vec()
     let tls_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
 
-    let test_socket = yarrp_rustls::test_util::get_test_socket_path();
-    let conn = UdsConnector::new(test_socket);
+    let conn = UdsConnector::new(test_util::get_test_socket_path());
     let service = ProxyService::new(conn);
 
     let rustls_accept_stream = RustlsAcceptStream::new(incoming, tls_acceptor, None);
@@ -31,3 +75,178 @@ pub async fn serve_proxy(
     .await?;
     Ok(())
 }
+
+/// Serves the proxy on the addr over cleartext h2 (prior knowledge), for
+/// deployments where TLS already ended at a sidecar.
+#[cfg(unix)]
+pub async fn serve_proxy_plaintext(
+    addr: SocketAddr,
+    token: CancellationToken,
+) -> Result<(), BoxError> {
+    let incoming = activation::tcp_listener_or_bind(addr).await?;
+    println!("Starting to serve on http://{}", incoming.local_addr()?);
+    let service = ProxyService::new(UdsConnector::new(test_util::get_test_socket_path()));
+    crate::proxy::serve_plaintext_with_incoming(incoming, service, async move {
+        token.cancelled().await
+    })
+    .await?;
+    Ok(())
+}
+
+/// Serves `service` (e.g. a [`crate::proxy::router::HeaderRouter`]) behind
+/// TLS on the addr, to gRPC clients over h2 and to gRPC-Web clients over
+/// HTTP/1.1 or h2.
+#[cfg(unix)]
+pub async fn serve_proxy_with_service<S>(
+    addr: SocketAddr,
+    server_config: ServerConfig,
+    service: S,
+    token: CancellationToken,
+) -> Result<(), BoxError>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    serve_proxy_with_options(addr, server_config, service, ServeOptions::default(), token).await
+}
+
+/// Same as [`serve_proxy_with_service`] with `options`, e.g. to bound the
+/// connections, handshakes, streams and calls the proxy takes on.
+#[cfg(unix)]
+pub async fn serve_proxy_with_options<S>(
+    addr: SocketAddr,
+    mut server_config: ServerConfig,
+    service: S,
+    options: ServeOptions,
+    token: CancellationToken,
+) -> Result<(), BoxError>
+where
+    S: tower::Service<Request<ProxyBody>, Response = Response<ProxyBody>, Error = BoxError>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    // Take the socket from systemd if it passed one, bind otherwise.
+    let incoming = activation::tcp_listener_or_bind(addr).await?;
+    println!("Starting to serve on https://{}", incoming.local_addr()?);
+
+    // Build TLS configuration.
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let tls_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let options = ServeOptions {
+        grpc_web: Some(CorsConfig::default()),
+        ..options
+    };
+    crate::proxy::serve_with_options(incoming, Some(tls_acceptor), service, options, async move {
+        token.cancelled().await
+    })
+    .await?;
+    Ok(())
+}
+
+/// Fresh, empty `<temp dir>/cng-<pid>-<name>` for the throwaway files of a
+/// test; the pid keeps test binaries running at the same time apart.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cng-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{proxy::test_util, util::serve_proxy};
+
+    async fn invoke_csharp_client(root_dir: &Path) {
+        // send csharp request to server
+        println!("launching csharp client");
+        let mut child_client = std::process::Command::new("dotnet")
+            .current_dir(root_dir)
+            .args([
+                "run",
+                "--project",
+                "./src/greeter_client/greeter_client.csproj",
+            ])
+            .spawn()
+            .expect("Couldn't run client");
+
+        tokio::task::spawn_blocking(move || {
+            let status = child_client.wait().expect("client failed");
+            assert!(status.success());
+        })
+        .await
+        .unwrap();
+    }
+
+    /// Same as the Windows e2e test: C++ greeter_server on the test socket,
+    /// proxy on 5047 and the C# client. Needs the cmake build.
+    #[tokio::test]
+    async fn e2e_test() {
+        let curr_dir = std::env::current_dir().unwrap();
+        let root_dir = curr_dir
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap();
+        let server_exe = root_dir.join("build/examples/helloworld/greeter_server");
+        println!("launching {:?}", server_exe);
+        let _ = std::fs::remove_file(test_util::get_test_socket_path());
+        let mut child_server = std::process::Command::new(server_exe.as_path())
+            .spawn()
+            .expect("Couldn't run server, build it with cmake first");
+
+        // the greeter creates its socket once it serves
+        for _ in 0..100 {
+            if std::os::unix::net::UnixStream::connect(test_util::get_test_socket_path()).is_ok() {
+                break;
+            }
+            if let Some(status) = child_server.try_wait().unwrap() {
+                panic!("greeter_server exited with {status}");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let token = CancellationToken::new();
+        let token_cp = token.clone();
+        // open proxy
+        let proxy_child = tokio::spawn(async {
+            let addr = "127.0.0.1:5047";
+            println!("start proxy at {addr}");
+            serve_proxy(addr.parse().unwrap(), token_cp).await.unwrap();
+        });
+
+        // proxy server might be slow to come up.
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect("127.0.0.1:5047")
+                .await
+                .is_ok()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // call it twice
+        invoke_csharp_client(root_dir).await;
+        invoke_csharp_client(root_dir).await;
+
+        // stop proxy
+        token.cancel();
+        proxy_child.await.unwrap();
+
+        // stop cpp server
+        child_server.kill().expect("!kill");
+        child_server.wait().unwrap();
+    }
+}
@@ -11,9 +11,10 @@ fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed=protos/helloworld.proto");
 
-    // Server-side stubs: standard tonic + prost.
+    // Server-side stubs: standard tonic + prost. The tonic client is also
+    // generated so other crates (e.g. the cng proxy tests) can drive the
+    // server over custom transports.
     tonic_prost_build::configure()
-        .build_client(false)
         .compile_protos(&["protos/helloworld.proto"], &["protos"])
         .unwrap();
 
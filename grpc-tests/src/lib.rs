@@ -12,7 +12,7 @@
 
 #![cfg(not(windows))]
 
-/// Server-side stubs generated by `tonic-prost-build`.
+/// Server and client stubs generated by `tonic-prost-build`.
 pub mod helloworld_tonic {
     tonic::include_proto!("helloworld");
 }
@@ -33,7 +33,8 @@ pub mod server {
         HelloReply, HelloRequest,
         greeter_server::{Greeter, GreeterServer},
     };
-    use tokio::net::TcpListener;
+    use tokio::net::{TcpListener, UnixListener};
+    use tokio_stream::wrappers::UnixListenerStream;
     use tonic::{
         Request, Response, Status,
         transport::{Server, server::TcpIncoming},
@@ -70,6 +71,20 @@ pub mod server {
             .await?;
         Ok(())
     }
+
+    /// Same as [`serve`] but on a pre-bound unix domain socket `listener`,
+    /// which is how the C++ greeter_server is exposed to the proxies.
+    pub async fn serve_uds(
+        listener: UnixListener,
+        shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let incoming = UnixListenerStream::new(listener);
+        Server::builder()
+            .add_service(GreeterServer::new(MyGreeter::default()))
+            .serve_with_incoming_shutdown(incoming, shutdown)
+            .await?;
+        Ok(())
+    }
 }
 
 /// `helloworld.Greeter` client built on the [`grpc`] crate (preview 0.9.x).